@@ -0,0 +1,62 @@
+//! Longest prefix match of the inside CIDRs, the trie against the linear scan it replaced.
+//!
+//! ```sh
+//! cargo bench --bench cidr
+//! ```
+
+mod corpus;
+
+use std::net::IpAddr;
+
+use cidr_utils::cidr::IpCidr;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use lpa::util::CidrTree;
+
+/// Most specific CIDR containing the address, found by checking every one.
+fn linear(cidr_list: &[IpCidr], ip: IpAddr) -> Option<&IpCidr> {
+    cidr_list
+        .iter()
+        .filter(|cidr| cidr.contains(ip))
+        .max_by_key(|cidr| cidr.get_bits())
+}
+
+fn longest_match(c: &mut Criterion) {
+    let mut group = c.benchmark_group("longest_match");
+    group.sample_size(10);
+    for (ip_version, ipv6) in [("v4", false), ("v6", true)] {
+        for count in [500, 1_000] {
+            let prefixes = corpus::prefixes(count, ipv6);
+            let cidr_list = corpus::cidrs(&prefixes);
+            let cidr_tree = CidrTree::new(&cidr_list);
+            let ips: Vec<IpAddr> = corpus::addresses(1_000_000, &prefixes)
+                .into_iter()
+                .map(|(_, addr)| {
+                    <[u8; 4]>::try_from(addr.as_slice()).map_or_else(
+                        |_| IpAddr::from(<[u8; 16]>::try_from(addr.as_slice()).unwrap()),
+                        IpAddr::from,
+                    )
+                })
+                .collect();
+
+            group.throughput(Throughput::Elements(ips.len() as u64));
+            group.bench_function(format!("cidr_tree/{ip_version}/{count}"), |b| {
+                b.iter(|| {
+                    for ip in &ips {
+                        criterion::black_box(cidr_tree.longest_match(*ip));
+                    }
+                });
+            });
+            group.bench_function(format!("linear/{ip_version}/{count}"), |b| {
+                b.iter(|| {
+                    for ip in &ips {
+                        criterion::black_box(linear(&cidr_list, *ip));
+                    }
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, longest_match);
+criterion_main!(benches);
@@ -0,0 +1,162 @@
+//! Throughput of the consume loop by the messages it takes per wakeup. A task sending the
+//! payloads into a bounded channel stands in for the queue rdkafka fetches into in the
+//! background.
+//!
+//! ```sh
+//! cargo bench --bench consume
+//! ```
+
+mod corpus;
+
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use criterion::{
+    criterion_group,
+    criterion_main,
+    measurement::WallTime,
+    BatchSize,
+    BenchmarkGroup,
+    Criterion,
+    Throughput,
+};
+use futures::FutureExt;
+use lpa::{
+    flowprotob::FlowMessage,
+    pipeline::{Pipeline, PipelineCounters},
+};
+use prost::Message;
+use tokio::{runtime::Runtime, sync::mpsc};
+
+/// Messages rdkafka queues ahead of the consumer.
+const QUEUED_MESSAGES: usize = 10_000;
+
+/// Decodes and aggregates every payload. Up to `prefetch` payloads received already, or within
+/// `linger`, are taken per wakeup, like `--kafka-prefetch-messages` and
+/// `--kafka-consumer-linger-ms` in the consume loop.
+async fn consume(
+    pipeline: &mut Pipeline,
+    mut payloads: mpsc::Receiver<Vec<u8>>,
+    prefetch: usize,
+    linger: Duration,
+) {
+    // The loop also waits for the signals, the admin commands and the background writes.
+    let (_shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
+    let mut prefetched = VecDeque::with_capacity(prefetch);
+    loop {
+        tokio::select! {
+            Some(payload) = payloads.recv() => prefetched.push_back(payload),
+            Some(()) = shutdown_rx.recv() => break,
+            else => break,
+        }
+        let linger_until = tokio::time::Instant::now() + linger;
+        while prefetched.len() < prefetch {
+            let next = if linger.is_zero() {
+                payloads.recv().now_or_never()
+            } else {
+                tokio::time::timeout_at(linger_until, payloads.recv())
+                    .await
+                    .ok()
+            };
+            match next {
+                Some(Some(payload)) => prefetched.push_back(payload),
+                _ => break,
+            }
+        }
+
+        for payload in prefetched.drain(..) {
+            let mut message = FlowMessage::decode(payload.as_slice()).unwrap();
+            pipeline
+                .record(&mut message, None, payload.len())
+                .await
+                .unwrap();
+        }
+    }
+    // Waits for the queued records.
+    pipeline.cache().snapshot().await.unwrap();
+}
+
+fn bench_consume(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    runtime: &Runtime,
+    payloads: &[Vec<u8>],
+    prefetch: usize,
+    linger: Duration,
+) {
+    group.throughput(Throughput::Elements(payloads.len() as u64));
+    group.bench_function(name, |b| {
+        b.iter_batched(
+            || {
+                let _runtime = runtime.enter();
+                let pipeline = Pipeline::new(
+                    Arc::new(corpus::config(&[])),
+                    None,
+                    PipelineCounters::default(),
+                );
+                (pipeline, payloads.to_vec())
+            },
+            |(mut pipeline, payloads)| {
+                runtime.block_on(async {
+                    let (sender, receiver) = mpsc::channel(QUEUED_MESSAGES);
+                    tokio::spawn(async move {
+                        for payload in payloads {
+                            sender.send(payload).await.unwrap();
+                        }
+                    });
+                    consume(&mut pipeline, receiver, prefetch, linger).await;
+                });
+            },
+            BatchSize::LargeInput,
+        );
+    });
+}
+
+fn prefetch(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let payloads = corpus::payloads(&corpus::flows(200_000));
+
+    let mut group = c.benchmark_group("consume");
+    group.sample_size(10);
+    for prefetch in [1, 64, 512] {
+        bench_consume(
+            &mut group,
+            &format!("prefetch/{prefetch}"),
+            &runtime,
+            &payloads,
+            prefetch,
+            Duration::ZERO,
+        );
+    }
+    group.finish();
+}
+
+/// The single message path against micro-batches of up to 512 messages lingering for more.
+fn linger(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let payloads = corpus::payloads(&corpus::flows(200_000));
+
+    let mut group = c.benchmark_group("linger");
+    group.sample_size(10);
+    bench_consume(
+        &mut group,
+        "single_message",
+        &runtime,
+        &payloads,
+        1,
+        Duration::ZERO,
+    );
+    for linger_ms in [1, 5] {
+        bench_consume(
+            &mut group,
+            &format!("linger/{linger_ms}ms"),
+            &runtime,
+            &payloads,
+            512,
+            Duration::from_millis(linger_ms),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, prefetch, linger);
+criterion_main!(benches);
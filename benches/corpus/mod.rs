@@ -0,0 +1,167 @@
+//! Flows and CIDRs generated from a fixed seed, so the numbers are comparable across machines
+//! and runs.
+
+#![allow(dead_code)]
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use cidr_utils::cidr::IpCidr;
+use clap::Parser;
+use lpa::{
+    config::{Config, ConfigArgs},
+    flowprotob::FlowMessage,
+};
+use prost::Message;
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+const SEED: u64 = 0x6c70_612d_6265_6e63;
+
+/// Start of the hour the flows are spread over.
+pub const TIME: u64 = 1_700_000_000;
+
+pub const ETYPE_IPV4: u32 = 0x0800;
+pub const ETYPE_IPV6: u32 = 0x86DD;
+
+#[must_use]
+pub fn rng() -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(SEED)
+}
+
+/// Configuration with the inside hosts of [`flows`] and the defaults, `args` are added to it.
+#[must_use]
+pub fn config(args: &[&str]) -> Config {
+    let required = [
+        "lpa",
+        "--group-id=bench",
+        "--topics=flows",
+        "--brokers=localhost:9092",
+        "--influxdb-endpoint=http://localhost:8086",
+        "--influxdb-bucket=flows",
+        "--influxdb-org=org",
+        "--influxdb-token=token",
+        "--cidr-list=10.0.0.0/8,fd00::/8",
+    ];
+    let args = ConfigArgs::try_parse_from(required.iter().chain(args)).unwrap();
+    Config::try_from(args).unwrap()
+}
+
+/// `count` flows of an hour between 2,000 inside hosts and 50,000 outside ones, a third of them
+/// IPv6. Hosts, ports and sizes repeat like in a real exporter, so the flows share keys.
+#[must_use]
+pub fn flows(count: usize) -> Vec<FlowMessage> {
+    let mut rng = rng();
+    let inside: Vec<u32> = (0..2_000).map(|_| rng.gen_range(1..1 << 24)).collect();
+    let outside: Vec<u32> = (0..50_000).map(|_| rng.gen()).collect();
+    let ports = [53, 80, 123, 443, 993, 3478, 8080];
+
+    (0..count)
+        .map(|_| {
+            let inside_host = *inside.choose(&mut rng).unwrap();
+            let outside_host = *outside.choose(&mut rng).unwrap();
+            let ipv6 = rng.gen_bool(1.0 / 3.0);
+            let (mut src_addr, mut dst_addr) = if ipv6 {
+                (
+                    Ipv6Addr::from((0xfd00 << 112) | u128::from(inside_host))
+                        .octets()
+                        .to_vec(),
+                    Ipv6Addr::from((0x2001_0db8 << 96) | u128::from(outside_host))
+                        .octets()
+                        .to_vec(),
+                )
+            } else {
+                (
+                    Ipv4Addr::from(0x0a00_0000 | inside_host).octets().to_vec(),
+                    Ipv4Addr::from(outside_host).octets().to_vec(),
+                )
+            };
+            if rng.gen_bool(0.5) {
+                std::mem::swap(&mut src_addr, &mut dst_addr);
+            }
+            let packets = rng.gen_range(1..100);
+            let time_flow_start = TIME + rng.gen_range(0..3_600);
+
+            FlowMessage {
+                etype: if ipv6 { ETYPE_IPV6 } else { ETYPE_IPV4 },
+                src_addr,
+                dst_addr,
+                proto: *[6, 6, 6, 17, 17, 1].choose(&mut rng).unwrap(),
+                src_port: rng.gen_range(1_024..65_536),
+                dst_port: *ports.choose(&mut rng).unwrap(),
+                packets,
+                bytes: packets * rng.gen_range(64..1_500),
+                src_vlan: rng.gen_range(0..8),
+                time_flow_start,
+                time_flow_end: time_flow_start + rng.gen_range(0..60),
+                time_received: time_flow_start + 60,
+                sampling_rate: 1_000,
+                ..FlowMessage::default()
+            }
+        })
+        .collect()
+}
+
+/// Kafka payloads of the flows.
+#[must_use]
+pub fn payloads(flows: &[FlowMessage]) -> Vec<Vec<u8>> {
+    flows.iter().map(Message::encode_to_vec).collect()
+}
+
+/// `count` networks of /8 to /28, or /16 to /64 of `2001:db8::/32` with `ipv6`, nested and
+/// overlapping.
+#[must_use]
+pub fn prefixes(count: usize, ipv6: bool) -> Vec<(IpAddr, u32)> {
+    let mut rng = rng();
+    (0..count)
+        .map(|_| {
+            if ipv6 {
+                let bits = rng.gen_range(16..=64);
+                let network =
+                    ((0x2001_0db8 << 96) | (rng.gen::<u128>() >> 32)) & (u128::MAX << (128 - bits));
+                (IpAddr::from(Ipv6Addr::from(network)), bits)
+            } else {
+                let bits = rng.gen_range(8..=28);
+                let network = rng.gen::<u32>() & (u32::MAX << (32 - bits));
+                (IpAddr::from(Ipv4Addr::from(network)), bits)
+            }
+        })
+        .collect()
+}
+
+#[must_use]
+pub fn cidrs(prefixes: &[(IpAddr, u32)]) -> Vec<IpCidr> {
+    prefixes
+        .iter()
+        .map(|(network, bits)| format!("{network}/{bits}").parse().unwrap())
+        .collect()
+}
+
+/// `count` addresses with their etype, half of them within the prefixes.
+#[must_use]
+pub fn addresses(count: usize, prefixes: &[(IpAddr, u32)]) -> Vec<(u32, Vec<u8>)> {
+    let mut rng = rng();
+    (0..count)
+        .map(|_| {
+            let (network, bits) = *prefixes.choose(&mut rng).unwrap();
+            let inside = rng.gen_bool(0.5);
+            match network {
+                IpAddr::V4(network) => {
+                    let address = if inside {
+                        u32::from(network) | rng.gen::<u32>().checked_shr(bits).unwrap_or(0)
+                    } else {
+                        rng.gen()
+                    };
+                    (ETYPE_IPV4, Ipv4Addr::from(address).octets().to_vec())
+                },
+                IpAddr::V6(network) => {
+                    let address = if inside {
+                        u128::from(network) | rng.gen::<u128>().checked_shr(bits).unwrap_or(0)
+                    } else {
+                        u128::from(network) ^ (rng.gen::<u128>() >> 16)
+                    };
+                    (ETYPE_IPV6, Ipv6Addr::from(address).octets().to_vec())
+                },
+            }
+        })
+        .collect()
+}
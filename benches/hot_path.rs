@@ -0,0 +1,147 @@
+//! Per-flow work of the consume loop, from the Kafka payload to the Influx point.
+//!
+//! ```sh
+//! cargo bench --bench hot_path
+//! ```
+
+mod corpus;
+
+use std::{collections::HashMap, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use lpa::{
+    config::{PayloadCompression, Precision},
+    flowprotob::FlowMessage,
+    influx::{self, PointOptions},
+    pipeline::{Pipeline, PipelineCounters},
+    store::{AggregationStore, ShardedStore},
+    util::{self, AggregatedKey, CidrTree, CommunicationData, SkipCounters},
+};
+use prost::Message;
+
+fn decode(c: &mut Criterion) {
+    let payloads = corpus::payloads(&corpus::flows(10_000));
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(payloads.len() as u64));
+    group.bench_function("flow_message", |b| {
+        b.iter(|| {
+            for payload in &payloads {
+                let decompressed =
+                    util::decompress_payload(payload, PayloadCompression::Auto, usize::MAX)
+                        .unwrap();
+                criterion::black_box(FlowMessage::decode(&*decompressed).unwrap());
+            }
+        });
+    });
+    group.finish();
+}
+
+fn parse_location(c: &mut Criterion) {
+    let skip_counters = SkipCounters::default();
+    let mut group = c.benchmark_group("parse_location");
+    for (ip_version, ipv6) in [("v4", false), ("v6", true)] {
+        for count in [16, 1_000] {
+            let prefixes = corpus::prefixes(count, ipv6);
+            let cidr_tree = CidrTree::new(&corpus::cidrs(&prefixes));
+            let addresses = corpus::addresses(10_000, &prefixes);
+            group.throughput(Throughput::Elements(addresses.len() as u64));
+            group.bench_function(format!("{ip_version}/{count}"), |b| {
+                b.iter(|| {
+                    for (etype, addr) in &addresses {
+                        criterion::black_box(util::parse_location(
+                            *etype,
+                            addr,
+                            &cidr_tree,
+                            &skip_counters,
+                        ));
+                    }
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Keys of the flows as the pipeline aggregates them, with the packets and bytes to record.
+fn keys(flows: Vec<FlowMessage>) -> Vec<(AggregatedKey, bool, u64, u64)> {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _runtime = runtime.enter();
+    let mut pipeline = Pipeline::new(
+        Arc::new(corpus::config(&["--time-alignment-seconds=60"])),
+        None,
+        PipelineCounters::default(),
+    );
+    flows
+        .into_iter()
+        .filter_map(|mut flow| {
+            let (key, reversed) = pipeline.process_message(&mut flow, None)?;
+            Some((key, reversed, flow.packets, flow.bytes))
+        })
+        .collect()
+}
+
+fn record(store: &mut impl AggregationStore, keys: &[(AggregatedKey, bool, u64, u64)]) {
+    for (key, reversed, packets, bytes) in keys {
+        store
+            .upsert(key.clone())
+            .record(*packets, *bytes, *reversed);
+    }
+}
+
+fn upsert(c: &mut Criterion) {
+    let keys = keys(corpus::flows(1_000_000));
+    let mut group = c.benchmark_group("upsert");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(keys.len() as u64));
+    group.bench_function("hash_map", |b| {
+        b.iter_batched_ref(
+            HashMap::new,
+            |store| record(store, &keys),
+            BatchSize::LargeInput,
+        );
+    });
+    group.bench_function("sharded", |b| {
+        b.iter_batched_ref(
+            || ShardedStore::new(16),
+            |store| record(store, &keys),
+            BatchSize::LargeInput,
+        );
+    });
+    group.finish();
+}
+
+fn build_data_point(c: &mut Criterion) {
+    let mut aggregates: HashMap<AggregatedKey, CommunicationData> = HashMap::new();
+    record(&mut aggregates, &keys(corpus::flows(100_000)));
+    let aggregates: Vec<_> = aggregates.into_iter().take(10_000).collect();
+    let options = PointOptions {
+        measurement: "sflow".to_owned(),
+        interfaces: false,
+        interface_names: HashMap::new(),
+        mpls: false,
+        exporter: false,
+        tcp_flags: false,
+        dscp: false,
+        icmp: false,
+        bidirectional: false,
+        extra_tags: Vec::new(),
+        precision: Precision::S,
+    };
+
+    let mut group = c.benchmark_group("build_data_point");
+    group.throughput(Throughput::Elements(aggregates.len() as u64));
+    group.bench_function("batch", |b| {
+        b.iter(|| {
+            aggregates
+                .iter()
+                .map(|(key, value)| {
+                    influx::build_data_point(key, value, Some("batch"), &options).unwrap()
+                })
+                .collect::<Vec<_>>()
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode, parse_location, upsert, build_data_point);
+criterion_main!(benches);
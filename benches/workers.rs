@@ -0,0 +1,71 @@
+//! Aggregation of decoded flows by `--workers` tasks against the single consume loop that
+//! aggregated them in place.
+//!
+//! ```sh
+//! cargo bench --bench workers
+//! ```
+
+mod corpus;
+
+use std::{collections::HashMap, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use lpa::{
+    pipeline::{Pipeline, PipelineCounters},
+    store::AggregationStore,
+};
+use tokio::runtime::Runtime;
+
+fn pipeline(runtime: &Runtime, workers: usize) -> Pipeline {
+    let _runtime = runtime.enter();
+    Pipeline::new(
+        Arc::new(corpus::config(&[&format!("--workers={workers}")])),
+        None,
+        PipelineCounters::default(),
+    )
+}
+
+fn aggregate(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let flows = corpus::flows(200_000);
+
+    let mut group = c.benchmark_group("aggregate");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(flows.len() as u64));
+    group.bench_function("single_loop", |b| {
+        b.iter_batched(
+            || (pipeline(&runtime, 1), flows.clone()),
+            |(mut pipeline, mut flows)| {
+                let mut cache = HashMap::new();
+                for flow in &mut flows {
+                    if let Some((key, reversed)) = pipeline.process_message(flow, None) {
+                        cache.upsert(key).record(flow.packets, flow.bytes, reversed);
+                    }
+                }
+                cache
+            },
+            BatchSize::LargeInput,
+        );
+    });
+    for workers in [1, 2, 4, 8] {
+        group.bench_function(format!("workers/{workers}"), |b| {
+            b.iter_batched(
+                || (pipeline(&runtime, workers), flows.clone()),
+                |(mut pipeline, mut flows)| {
+                    runtime.block_on(async {
+                        for flow in &mut flows {
+                            pipeline.record(flow, None, 0).await.unwrap();
+                        }
+                        // Waits for the queued records.
+                        pipeline.cache().snapshot().await.unwrap()
+                    })
+                },
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, aggregate);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+//! Prints the aggregates streamed by `--unix-socket-path`.
+//!
+//! ```sh
+//! cargo run --example unix-socket-reader -- /run/lpa.sock
+//! ```
+
+use std::{
+    io::{BufRead, BufReader},
+    os::unix::net::UnixStream,
+};
+
+fn main() -> std::io::Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "/run/lpa.sock".to_owned());
+    let stream = UnixStream::connect(&path)?;
+
+    // Every line is one aggregate of a flushed batch, the key and data fields flattened.
+    for line in BufReader::new(stream).lines() {
+        println!("{}", line?);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+-- Table written by the Postgres sink (`--postgres-url`). Rename it together with
+-- `--postgres-table`.
+CREATE TABLE IF NOT EXISTS flow_aggregates (
+    time TIMESTAMPTZ NOT NULL,
+    source TEXT NOT NULL,
+    target TEXT NOT NULL,
+    src_vlan BIGINT NOT NULL,
+    dst_vlan BIGINT NOT NULL,
+    proto BIGINT NOT NULL,
+    packets BIGINT NOT NULL,
+    bytes BIGINT NOT NULL,
+    PRIMARY KEY (time, source, target, src_vlan, dst_vlan, proto)
+);
+
+-- TimescaleDB only, turns the table into a hypertable partitioned by time.
+-- SELECT create_hypertable('flow_aggregates', 'time', if_not_exists => TRUE);
@@ -0,0 +1,254 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::{mpsc, oneshot},
+};
+
+use crate::config::Config;
+
+/// Request from the admin endpoints, answered by the main loop between two messages.
+pub enum AdminRequest {
+    /// Flush the whole cache, answered with the number of written points once the write finished.
+    Flush(oneshot::Sender<Result<usize, String>>),
+    CacheStats(oneshot::Sender<CacheStats>),
+    /// Dump the cache into `--dump-cache-path` without flushing it, answered with the written
+    /// file and the number of entries.
+    DumpCache(oneshot::Sender<Result<(PathBuf, usize), String>>),
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheStats {
+    pub entries: usize,
+    /// Payload bytes aggregated since the last flush.
+    pub bytes: usize,
+    /// Aggregates already drained from the cache that are being written.
+    pub pending_entries: usize,
+    pub oldest_bucket: Option<u64>,
+    pub newest_bucket: Option<u64>,
+}
+
+/// Settings `--enable-admin-stdin` and `--config-reload-on-sighup` can change while running, read
+/// by the main loop.
+#[derive(Debug)]
+pub struct LiveSettings {
+    /// Payload bytes after which the cache is flushed, unused with `--adaptive-batch-max-size` or
+    /// `--lag-batch-max-size`.
+    pub batch_size: AtomicUsize,
+    /// Buckets already in the cache keep their alignment, only new flows use the changed one.
+    pub time_alignment_seconds: AtomicU64,
+}
+
+impl LiveSettings {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        Self {
+            batch_size: AtomicUsize::new(config.batch_size),
+            time_alignment_seconds: AtomicU64::new(config.time_alignment_seconds),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FlushResponse {
+    points: usize,
+}
+
+/// `POST /flush` and `GET /cache`.
+pub fn router(requests: mpsc::Sender<AdminRequest>) -> Router {
+    Router::new()
+        .route("/flush", post(flush_handler))
+        .route("/cache", get(cache_handler))
+        .with_state(requests)
+}
+
+async fn flush_handler(State(requests): State<mpsc::Sender<AdminRequest>>) -> Response {
+    let (reply, result) = oneshot::channel();
+    if requests.send(AdminRequest::Flush(reply)).await.is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Main loop is not running.").into_response();
+    }
+
+    match result.await {
+        Ok(Ok(points)) => Json(FlushResponse { points }).into_response(),
+        Ok(Err(error)) => (StatusCode::INTERNAL_SERVER_ERROR, error).into_response(),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "Main loop is not running.").into_response(),
+    }
+}
+
+async fn cache_handler(State(requests): State<mpsc::Sender<AdminRequest>>) -> Response {
+    let (reply, result) = oneshot::channel();
+    if requests
+        .send(AdminRequest::CacheStats(reply))
+        .await
+        .is_err()
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Main loop is not running.").into_response();
+    }
+
+    match result.await {
+        Ok(stats) => Json(stats).into_response(),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "Main loop is not running.").into_response(),
+    }
+}
+
+/// Dumps the cache on every SIGUSR1.
+pub async fn serve_dump_signal(requests: mpsc::Sender<AdminRequest>) -> anyhow::Result<()> {
+    let mut signals =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+    while signals.recv().await.is_some() {
+        let (reply, result) = oneshot::channel();
+        if requests.send(AdminRequest::DumpCache(reply)).await.is_err() {
+            break;
+        }
+        match result.await {
+            Ok(Ok((path, entries))) => {
+                tracing::info!(path = %path.display(), entries, "Dumped the cache.");
+            },
+            Ok(Err(error)) => tracing::error!(error, "Unable to dump the cache."),
+            Err(_) => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Command read by `--enable-admin-stdin`.
+#[derive(Debug)]
+enum StdinCommand {
+    Flush,
+    SetBatchSize(usize),
+    SetTimeAlignment(u64),
+    ShowStats,
+    ShowConfig,
+}
+
+fn parse_command(line: &str) -> Result<StdinCommand, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["flush"] => Ok(StdinCommand::Flush),
+        ["set", "batch_size", value] => {
+            match value.parse() {
+                Ok(0) | Err(_) => Err(format!("Invalid batch size `{value}`.")),
+                Ok(batch_size) => Ok(StdinCommand::SetBatchSize(batch_size)),
+            }
+        },
+        ["set", "time_alignment", value] => {
+            match value.parse() {
+                Ok(0) | Err(_) => Err(format!("Invalid time alignment `{value}`.")),
+                Ok(seconds) => Ok(StdinCommand::SetTimeAlignment(seconds)),
+            }
+        },
+        ["show", "stats"] => Ok(StdinCommand::ShowStats),
+        ["show", "config"] => Ok(StdinCommand::ShowConfig),
+        _ => {
+            Err(format!(
+                "Unknown command `{line}`, expected `flush`, `set batch_size <bytes>`, `set \
+                 time_alignment <seconds>`, `show stats` or `show config`."
+            ))
+        },
+    }
+}
+
+/// Answers the commands read from stdin until it is closed. Every answer is one JSON line on
+/// stdout.
+pub async fn serve_stdin(
+    requests: mpsc::Sender<AdminRequest>,
+    settings: Arc<LiveSettings>,
+    config: Arc<Config>,
+) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match parse_command(&line) {
+            Ok(command) => execute(command, &requests, &settings, &config).await,
+            Err(error) => serde_json::json!({ "error": error }),
+        };
+        println!("{answer}");
+    }
+
+    Ok(())
+}
+
+async fn execute(
+    command: StdinCommand,
+    requests: &mpsc::Sender<AdminRequest>,
+    settings: &LiveSettings,
+    config: &Config,
+) -> serde_json::Value {
+    const NOT_RUNNING: &str = "Main loop is not running.";
+
+    match command {
+        StdinCommand::Flush => {
+            let (reply, result) = oneshot::channel();
+            if requests.send(AdminRequest::Flush(reply)).await.is_err() {
+                return serde_json::json!({ "error": NOT_RUNNING });
+            }
+            match result.await {
+                Ok(Ok(points)) => serde_json::json!({ "points": points }),
+                Ok(Err(error)) => serde_json::json!({ "error": error }),
+                Err(_) => serde_json::json!({ "error": NOT_RUNNING }),
+            }
+        },
+        StdinCommand::SetBatchSize(batch_size) => {
+            let previous = settings.batch_size.swap(batch_size, Ordering::Relaxed);
+            tracing::info!(previous, batch_size, "Batch size changed from stdin.");
+            serde_json::json!({ "batch_size": batch_size, "previous": previous })
+        },
+        StdinCommand::SetTimeAlignment(seconds) => {
+            // Rolled up buckets must still cover whole buckets of the cache.
+            if let Some(rollup_alignment_seconds) = config.rollup_alignment_seconds {
+                if rollup_alignment_seconds % seconds != 0 {
+                    return serde_json::json!({
+                        "error": format!(
+                            "Rollup alignment {rollup_alignment_seconds}s is not a multiple of \
+                             {seconds}s."
+                        )
+                    });
+                }
+            }
+            let previous = settings
+                .time_alignment_seconds
+                .swap(seconds, Ordering::Relaxed);
+            tracing::info!(previous, seconds, "Time alignment changed from stdin.");
+            serde_json::json!({ "time_alignment": seconds, "previous": previous })
+        },
+        StdinCommand::ShowStats => {
+            let (reply, result) = oneshot::channel();
+            if requests
+                .send(AdminRequest::CacheStats(reply))
+                .await
+                .is_err()
+            {
+                return serde_json::json!({ "error": NOT_RUNNING });
+            }
+            match result.await {
+                Ok(stats) => serde_json::json!(stats),
+                Err(_) => serde_json::json!({ "error": NOT_RUNNING }),
+            }
+        },
+        StdinCommand::ShowConfig => {
+            // Secrets are redacted by their `Debug`.
+            serde_json::json!({
+                "config": format!("{config:?}"),
+                "batch_size": settings.batch_size.load(Ordering::Relaxed),
+                "time_alignment": settings.time_alignment_seconds.load(Ordering::Relaxed),
+            })
+        },
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use influxdb2::models::WriteDataPoint;
+use serde::Serialize;
+
+use crate::{
+    influx,
+    util::{AggregatedKey, CommunicationData},
+};
+
+/// Serializes a batch that could not be written into Influx as line protocol into `dir`, so it
+/// can be replayed later with `influx write --precision <--influxdb-precision>`.
+///
+/// The file is written under a temporary name and renamed once complete, a failed write leaves
+/// nothing behind. Before the rename the oldest backups are deleted until the directory fits into
+/// `max_dir_bytes`.
+pub fn write_failed_batch(
+    dir: &Path,
+    max_dir_bytes: u64,
+    batch: &[(AggregatedKey, CommunicationData)],
+    batch_id: Option<&str>,
+    options: &influx::PointOptions,
+) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("Unable to create `{}`.", dir.display()))?;
+
+    // The time ordered UUID keeps the names of batches written within a millisecond apart, and
+    // in the order they were written.
+    let name = format!(
+        "{}-{}",
+        chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        uuid::Uuid::now_v7().simple()
+    );
+    let temporary_path = dir.join(format!(".{name}.lp.tmp"));
+    let path = dir.join(format!("{name}.lp"));
+
+    let written = write_points(&temporary_path, batch, batch_id, options).and_then(|size| {
+        enforce_dir_limit(dir, max_dir_bytes.saturating_sub(size))?;
+        fs::rename(&temporary_path, &path)?;
+        Ok(())
+    });
+    if let Err(error) = written {
+        let _ = fs::remove_file(&temporary_path);
+        return Err(error);
+    }
+
+    Ok(path)
+}
+
+/// Writes the batch as line protocol into `path` and returns the size of the file.
+fn write_points(
+    path: &Path,
+    batch: &[(AggregatedKey, CommunicationData)],
+    batch_id: Option<&str>,
+    options: &influx::PointOptions,
+) -> anyhow::Result<u64> {
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    for (key, value) in batch {
+        influx::build_data_point(key, value, batch_id, options)?
+            .write_data_point_to(&mut writer)?;
+    }
+    let file = writer.into_inner().map_err(|error| error.into_error())?;
+    file.sync_all()?;
+
+    Ok(file.metadata()?.len())
+}
+
+#[derive(Serialize)]
+struct CacheDumpHeader {
+    dumped_at: String,
+    entries: usize,
+    /// Payload bytes aggregated since the last flush.
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+struct CacheDump<'a> {
+    header: CacheDumpHeader,
+    cache: Vec<CacheDumpEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct CacheDumpEntry<'a> {
+    key: &'a AggregatedKey,
+    data: &'a CommunicationData,
+}
+
+/// Writes the cache entries as pretty JSON next to `path`, named with the dump time inserted
+/// before the extension, e.g. `flow-cache-dump-20240101T120000.000Z.json`.
+pub fn write_cache_dump(
+    path: &Path,
+    entries: &[(AggregatedKey, CommunicationData)],
+    bytes: usize,
+) -> anyhow::Result<PathBuf> {
+    let now = chrono::Utc::now();
+    let stem = path
+        .file_stem()
+        .map_or_else(|| "flow-cache-dump".into(), |stem| stem.to_string_lossy());
+    let mut name = format!("{stem}-{}", now.format("%Y%m%dT%H%M%S%.3fZ"));
+    if let Some(extension) = path.extension() {
+        name = format!("{name}.{}", extension.to_string_lossy());
+    }
+    let path = path.with_file_name(name);
+
+    let dump = CacheDump {
+        header: CacheDumpHeader {
+            dumped_at: now.to_rfc3339(),
+            entries: entries.len(),
+            bytes,
+        },
+        cache: entries
+            .iter()
+            .map(|(key, data)| CacheDumpEntry { key, data })
+            .collect(),
+    };
+    let file = fs::File::create(&path)
+        .with_context(|| format!("Unable to create `{}`.", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &dump)?;
+    writer.flush()?;
+
+    Ok(path)
+}
+
+/// Deletes the oldest backups until the `.lp` files in `dir` take at most `max_bytes`.
+fn enforce_dir_limit(dir: &Path, max_bytes: u64) -> anyhow::Result<()> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "lp") {
+            backups.push((path, entry.metadata()?.len()));
+        }
+    }
+    // Names start with a timestamp, so lexicographic order is chronological.
+    backups.sort();
+
+    let mut total: u64 = backups.iter().map(|(_, size)| size).sum();
+    for (path, size) in backups {
+        if total <= max_bytes {
+            break;
+        }
+        tracing::warn!(path = %path.display(), "Deleting oldest failed batch backup.");
+        fs::remove_file(&path)?;
+        total -= size;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::influx::tests::{key, options};
+
+    /// Empty directory unique to the test `name`.
+    fn backup_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lpa-backup-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn batch() -> Vec<(AggregatedKey, CommunicationData)> {
+        let value = CommunicationData {
+            packets: 2,
+            bytes: 100,
+            ..CommunicationData::default()
+        };
+        vec![(key(), value)]
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn complete_batches_are_renamed_into_place() {
+        let dir = backup_dir("rename");
+
+        let path = write_failed_batch(&dir, u64::MAX, &batch(), Some("1"), &options()).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("sflow,"), "{content}");
+        assert!(content.contains("batch_number=1"), "{content}");
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(file_names(&dir), [name]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batches_of_the_same_millisecond_do_not_collide() {
+        let dir = backup_dir("collide");
+
+        let paths: Vec<PathBuf> = (0..10)
+            .map(|_| write_failed_batch(&dir, u64::MAX, &batch(), None, &options()).unwrap())
+            .collect();
+
+        assert_eq!(file_names(&dir).len(), paths.len());
+        // Listed in the order they were written.
+        let names: Vec<String> = paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(file_names(&dir), names);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oldest_batches_are_deleted_over_the_size_limit() {
+        let dir = backup_dir("limit");
+        let first = write_failed_batch(&dir, u64::MAX, &batch(), None, &options()).unwrap();
+        let size = fs::metadata(&first).unwrap().len();
+
+        let second = write_failed_batch(&dir, 2 * size, &batch(), None, &options()).unwrap();
+        let third = write_failed_batch(&dir, 2 * size, &batch(), None, &options()).unwrap();
+
+        assert!(!first.exists());
+        assert!(second.exists());
+        assert!(third.exists());
+        assert_eq!(file_names(&dir).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
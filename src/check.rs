@@ -0,0 +1,234 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use serde::Serialize;
+
+use crate::{
+    config::{CheckConfigArgs, Config},
+    influx,
+};
+
+/// How long the connectivity checks wait for an answer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Validates the configuration and prints every problem found. Returns whether the configuration
+/// is usable.
+pub async fn run(args: CheckConfigArgs) -> bool {
+    let config = match Config::try_from(args.config) {
+        Ok(config) => config,
+        Err(error) => {
+            println!("Invalid configuration: {error:#}");
+            return false;
+        },
+    };
+    println!("Configuration is valid.");
+
+    if !args.connect {
+        return true;
+    }
+
+    let mut usable = true;
+    match check_kafka(&config) {
+        Ok(()) => println!("Kafka: brokers reachable, all topics exist."),
+        Err(error) => {
+            println!("Kafka: {error:#}");
+            usable = false;
+        },
+    }
+    if !config.writes_to_influx() {
+        println!("Influx: skipped, the NDJSON output replaces it.");
+        return usable;
+    }
+    match check_influx(&config, "ready").await {
+        Ok(()) => println!("Influx: ready."),
+        Err(error) => {
+            println!("Influx: {error:#}");
+            usable = false;
+        },
+    }
+
+    usable
+}
+
+/// Result of one dependency checked by `test-connection`.
+#[derive(Serialize, Debug)]
+struct ComponentStatus {
+    component: &'static str,
+    status: &'static str,
+    latency_ms: u64,
+    error: Option<String>,
+}
+
+impl ComponentStatus {
+    fn new(component: &'static str, started_at: Instant, result: anyhow::Result<()>) -> Self {
+        let latency_ms = u64::try_from(started_at.elapsed().as_millis()).unwrap_or(u64::MAX);
+        match result {
+            Ok(()) => {
+                Self {
+                    component,
+                    status: "healthy",
+                    latency_ms,
+                    error: None,
+                }
+            },
+            Err(error) => {
+                Self {
+                    component,
+                    status: "unhealthy",
+                    latency_ms,
+                    error: Some(format!("{error:#}")),
+                }
+            },
+        }
+    }
+}
+
+/// Checks every external dependency and prints the results as a JSON array. Returns whether all
+/// of them are healthy.
+pub async fn test_connection(config: &Config) -> anyhow::Result<bool> {
+    let started_at = Instant::now();
+    let kafka = ComponentStatus::new("kafka", started_at, check_kafka(config));
+    let started_at = Instant::now();
+    let influx = ComponentStatus::new("influxdb", started_at, check_influx(config, "health").await);
+
+    let statuses = [kafka, influx];
+    println!("{}", serde_json::to_string_pretty(&statuses)?);
+
+    Ok(statuses.iter().all(|status| status.error.is_none()))
+}
+
+/// Fails fast on unreachable or misconfigured dependencies before anything is consumed. Kafka is
+/// skipped for replays, Influx when the NDJSON output replaces it.
+pub async fn startup_checks(config: &Config) -> anyhow::Result<()> {
+    if config.replay_file.is_none() {
+        check_kafka(config).context("Kafka startup check failed")?;
+    }
+    if config.writes_to_influx() {
+        check_influx(config, "ready")
+            .await
+            .context("Influx startup check failed")?;
+        check_influx_bucket(config)
+            .await
+            .context("Influx startup check failed")?;
+    }
+    tracing::info!("Startup checks passed.");
+
+    Ok(())
+}
+
+fn check_kafka(config: &Config) -> anyhow::Result<()> {
+    let consumer: BaseConsumer = config
+        .kafka_client_config()
+        .set("group.id", &config.group_id)
+        .create()?;
+    let topics: Vec<&str> = config.topics.iter().map(String::as_str).collect();
+    consumer
+        .subscribe(&topics)
+        .context("Unable to subscribe to the topics.")?;
+    let metadata = consumer.fetch_metadata(None, CONNECT_TIMEOUT).context(
+        "Unable to fetch metadata from the brokers, check `KAFKA_DUMP_BROKERS` and the Kafka \
+         security settings.",
+    )?;
+
+    let missing: Vec<&str> = config
+        .topics
+        .iter()
+        .filter(|topic| {
+            !metadata
+                .topics()
+                .iter()
+                .any(|metadata| metadata.name() == *topic && !metadata.partitions().is_empty())
+        })
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Topics do not exist: {}. Check `KAFKA_DUMP_TOPICS`.",
+            missing.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Queries an unauthenticated status endpoint of Influx, `ready` or `health`.
+async fn check_influx(config: &Config, endpoint: &str) -> anyhow::Result<()> {
+    let url = format!(
+        "{}/{endpoint}",
+        config.influxdb_endpoint.trim_end_matches('/')
+    );
+    let response = influx::http_client(
+        CONNECT_TIMEOUT,
+        config.influxdb_connect_timeout,
+        config.influxdb_ca_cert.as_deref(),
+        config.influxdb_accept_invalid_certs,
+    )?
+    .get(&url)
+    .send()
+    .await
+    .with_context(|| format!("Unable to reach `{url}`, check `KAFKA_DUMP_INFLUXDB_ENDPOINT`."))?;
+    if !response.status().is_success() {
+        anyhow::bail!("`{url}` responded {}.", response.status());
+    }
+
+    Ok(())
+}
+
+/// Looks the bucket up with the token, so a wrong token, org or bucket fails before the first
+/// write.
+async fn check_influx_bucket(config: &Config) -> anyhow::Result<()> {
+    let url = format!(
+        "{}/api/v2/buckets",
+        config.influxdb_endpoint.trim_end_matches('/')
+    );
+    let response = influx::http_client(
+        CONNECT_TIMEOUT,
+        config.influxdb_connect_timeout,
+        config.influxdb_ca_cert.as_deref(),
+        config.influxdb_accept_invalid_certs,
+    )?
+    .get(&url)
+    .query(&[
+        ("org", config.influxdb_org.as_str()),
+        ("name", config.influxdb_bucket.as_str()),
+    ])
+    .header(
+        reqwest::header::AUTHORIZATION,
+        format!("Token {}", config.influxdb_token.expose()),
+    )
+    .send()
+    .await
+    .with_context(|| format!("Unable to reach `{url}`, check `KAFKA_DUMP_INFLUXDB_ENDPOINT`."))?;
+    match response.status() {
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+            anyhow::bail!(
+                "The token was rejected, check `KAFKA_DUMP_INFLUXDB_TOKEN` or \
+                 `KAFKA_DUMP_INFLUXDB_TOKEN_FILE`."
+            );
+        },
+        reqwest::StatusCode::NOT_FOUND => {
+            anyhow::bail!(
+                "Org `{}` does not exist, check `KAFKA_DUMP_INFLUXDB_ORG`.",
+                config.influxdb_org
+            );
+        },
+        status if !status.is_success() => anyhow::bail!("`{url}` responded {status}."),
+        _ => {},
+    }
+
+    let body: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+    let found = body
+        .get("buckets")
+        .and_then(serde_json::Value::as_array)
+        .is_some_and(|buckets| !buckets.is_empty());
+    if !found {
+        anyhow::bail!(
+            "Bucket `{}` does not exist in org `{}`, check `KAFKA_DUMP_INFLUXDB_BUCKET`.",
+            config.influxdb_bucket,
+            config.influxdb_org
+        );
+    }
+
+    Ok(())
+}
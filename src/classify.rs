@@ -0,0 +1,221 @@
+use std::{net::IpAddr, ops::RangeInclusive, path::Path, sync::Arc};
+
+use anyhow::Context;
+use cidr_utils::cidr::IpCidr;
+use serde::Deserialize;
+
+use crate::config::Config;
+
+/// Rules used by `--classify` without `--classification-rules`.
+const DEFAULT_RULES: &str = r#"
+[[rule]]
+app = "dns"
+proto = ["udp", "tcp"]
+ports = [53]
+
+[[rule]]
+app = "https"
+proto = ["tcp", "udp"]
+ports = [443]
+
+[[rule]]
+app = "http"
+proto = ["tcp"]
+ports = [80, 8080]
+
+[[rule]]
+app = "ssh"
+proto = ["tcp"]
+ports = [22]
+
+[[rule]]
+app = "ntp"
+proto = ["udp"]
+ports = [123]
+"#;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleSpec>,
+}
+
+/// One `[[rule]]`, every given condition must match.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    app: String,
+    #[serde(default)]
+    proto: Vec<Proto>,
+    /// Matches the source or the destination port.
+    #[serde(default)]
+    ports: Vec<PortRange>,
+    /// Matches the source or the destination address.
+    #[serde(default)]
+    cidr: Vec<Cidr>,
+}
+
+/// IP protocol given as a number or a name, e.g. `6` or `"tcp"`.
+#[derive(Deserialize)]
+#[serde(try_from = "toml::Value")]
+struct Proto(u32);
+
+impl TryFrom<toml::Value> for Proto {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        match value {
+            toml::Value::Integer(proto) => {
+                u32::try_from(proto)
+                    .map(Proto)
+                    .map_err(|_| format!("invalid IP protocol `{proto}`"))
+            },
+            toml::Value::String(name) => {
+                crate::util::proto_number(&name)
+                    .map(Proto)
+                    .ok_or_else(|| format!("unknown IP protocol `{name}`"))
+            },
+            value => Err(format!("expected a protocol number or name, got `{value}`")),
+        }
+    }
+}
+
+/// Single port or range given as a number or as `"8000-8999"`.
+#[derive(Deserialize)]
+#[serde(try_from = "toml::Value")]
+struct PortRange(RangeInclusive<u32>);
+
+impl TryFrom<toml::Value> for PortRange {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map(u32::from)
+                .map_err(|error| format!("invalid port `{port}`: {error}"))
+        };
+        match value {
+            toml::Value::Integer(port) => {
+                u16::try_from(port)
+                    .map(|port| PortRange(u32::from(port)..=u32::from(port)))
+                    .map_err(|_| format!("invalid port `{port}`"))
+            },
+            toml::Value::String(range) => {
+                let (start, end) = range.split_once('-').unwrap_or((&range, &range));
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(format!("empty port range `{range}`"));
+                }
+                Ok(PortRange(start..=end))
+            },
+            value => Err(format!("expected a port or a port range, got `{value}`")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+struct Cidr(IpCidr);
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .parse()
+            .map(Cidr)
+            .map_err(|error| format!("invalid CIDR `{value}`: {error}"))
+    }
+}
+
+struct Rule {
+    app: Arc<str>,
+    protos: Vec<u32>,
+    ports: Vec<RangeInclusive<u32>>,
+    cidrs: Vec<IpCidr>,
+}
+
+impl Rule {
+    fn matches(&self, flow: &Flow) -> bool {
+        (self.protos.is_empty() || self.protos.contains(&flow.proto))
+            && (self.ports.is_empty()
+                || self
+                    .ports
+                    .iter()
+                    .any(|range| range.contains(&flow.src_port) || range.contains(&flow.dst_port)))
+            && (self.cidrs.is_empty()
+                || self.cidrs.iter().any(|cidr| {
+                    [flow.src_ip, flow.dst_ip]
+                        .into_iter()
+                        .flatten()
+                        .any(|ip| cidr.contains(ip))
+                }))
+    }
+}
+
+/// Fields of a flow the rules match on.
+pub struct Flow {
+    pub proto: u32,
+    pub src_port: u32,
+    pub dst_port: u32,
+    /// `None` for the unknown endpoint of a `--allow-partial-flows` flow.
+    pub src_ip: Option<IpAddr>,
+    pub dst_ip: Option<IpAddr>,
+}
+
+/// Labels flows with the `app` of the first matching rule, `other` without one.
+pub struct Classifier {
+    rules: Vec<Rule>,
+    other: Arc<str>,
+}
+
+impl Classifier {
+    /// Classifier of `--classification-rules`, or the built-in rules with `--classify`.
+    pub fn load(config: &Config) -> anyhow::Result<Option<Self>> {
+        match &config.classification_rules {
+            Some(path) => Self::from_file(path).map(Some),
+            None if config.classify => Self::parse(DEFAULT_RULES).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| {
+            format!("Unable to read classification rules `{}`.", path.display())
+        })?;
+        Self::parse(&content)
+            .with_context(|| format!("Invalid classification rules in `{}`.", path.display()))
+    }
+
+    /// Errors name the line and column of the offending value.
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let file: RulesFile = toml::from_str(content)?;
+        let rules = file
+            .rules
+            .into_iter()
+            .map(|rule| {
+                Rule {
+                    app: Arc::from(rule.app),
+                    protos: rule.proto.into_iter().map(|proto| proto.0).collect(),
+                    ports: rule.ports.into_iter().map(|range| range.0).collect(),
+                    cidrs: rule.cidr.into_iter().map(|cidr| cidr.0).collect(),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            rules,
+            other: Arc::from("other"),
+        })
+    }
+
+    #[must_use]
+    pub fn classify(&self, flow: &Flow) -> Arc<str> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(flow))
+            .map_or_else(|| self.other.clone(), |rule| rule.app.clone())
+    }
+}
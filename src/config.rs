@@ -1,83 +1,1910 @@
-use std::env;
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use anyhow::Context;
 use cidr_utils::cidr::IpCidr;
-use clap::Parser;
+use clap::{
+    ArgAction,
+    ArgMatches,
+    Args,
+    CommandFactory,
+    FromArgMatches,
+    Parser,
+    Subcommand,
+    ValueEnum,
+};
+use rdkafka::config::ClientConfig;
+use regex::Regex;
+
+use crate::flowprotob::flow_message::FlowType;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub group_id: String,
     pub topics: Vec<String>,
     pub brokers: String,
+    pub kafka_offset_reset: OffsetReset,
+    pub log_format: LogFormat,
+    pub kafka_security_protocol: SecurityProtocol,
+    pub kafka_ssl_endpoint_identification_algorithm: EndpointIdentification,
+    pub kafka_ssl_cipher_suites: Option<String>,
+    pub kafka_sasl_mechanism: SaslMechanism,
+    /// Set exactly with the `sasl-plaintext` and `sasl-ssl` security protocols.
+    pub kafka_sasl_username: Option<String>,
+    pub kafka_sasl_password: Option<Secret>,
+    pub kafka_stats_interval_ms: u64,
     pub batch_size: usize,
+    /// Largest flush threshold the adaptive batch size may grow to, `None` disables it.
+    pub adaptive_batch_max_size: Option<usize>,
+    pub adaptive_batch_lag_threshold: Duration,
+    pub max_cache_memory_bytes: Option<usize>,
+    pub max_unique_sources: Option<usize>,
+    pub max_unique_targets: Option<usize>,
+    /// `--cidr-list` merged with the contents of `--cidr-file`.
     pub cidr_list: Vec<IpCidr>,
+    /// CIDRs given directly with `--cidr-list`, the file contents are merged into them on reload.
+    pub cidr_list_static: Vec<IpCidr>,
+    pub cidr_file: Option<PathBuf>,
+    pub cidr_file_reload_interval: Option<Duration>,
+    /// Group names of CIDRs, tagged as `src_group`/`dst_group` when not empty.
+    pub cidr_groups: Vec<(IpCidr, String)>,
+    pub time_alignment_seconds: u64,
+    pub bucket_timestamp: BucketTimestamp,
+    /// Width of the coarser buckets flushed batches are rolled up into, `None` disables rollups.
+    pub rollup_alignment_seconds: Option<u64>,
+    pub rollup_measurement: String,
+    pub influxdb_measurement: String,
+    /// Static tags added to every point, e.g. to tell instances apart.
+    pub influxdb_tags_extra: Vec<(String, String)>,
+    /// Measurement per topic, topics missing here use `influxdb_measurement`.
+    pub topic_measurement_map: HashMap<String, Arc<str>>,
+    pub rollup_bucket: Option<String>,
+    pub rollup_dimensions: Vec<RollupDimension>,
+    pub src_prefix_len: Option<u8>,
+    pub dst_prefix_len: Option<u8>,
+    pub idle_timeout: Option<Duration>,
+    pub idle_action: IdleAction,
+    pub watchdog_interval: Option<Duration>,
+    pub flush_grace: Duration,
+    pub output_topic: Option<String>,
+    pub unix_socket_path: Option<PathBuf>,
+    pub dead_letter_topic: Option<String>,
+    pub batch_id_strategy: BatchIdStrategy,
+    pub batch_number_file: Option<PathBuf>,
+    pub postgres_url: Option<Secret>,
+    pub postgres_table: String,
+    /// SQLite database holding the batches not written into Influx yet.
+    pub sqlite_recovery_path: Option<PathBuf>,
+    pub postgres_max_connections: u32,
+    pub max_message_age: Option<Duration>,
+    pub max_received_age: Option<Duration>,
+    pub max_future_skew: Option<Duration>,
+    pub future_timestamp_action: FutureTimestampAction,
+    /// Unix time before which flow timestamps are rejected.
+    pub min_timestamp: Option<u64>,
+    pub influxdb_max_retries: Option<u32>,
+    /// Batches whose Influx write may be in flight at once, 1 writes inline.
+    pub influx_write_concurrency: usize,
+    pub influxdb_max_retry_wait: Duration,
+    pub failed_batch_dir: Option<PathBuf>,
+    pub failed_batch_dir_max_bytes: u64,
+    pub stats_history_minutes: usize,
+    pub stats_ema_alpha: f64,
+    pub metrics_listen: Option<SocketAddr>,
+    pub otlp_endpoint: Option<String>,
+    pub replay_file: Option<PathBuf>,
+    pub replay_speed: ReplaySpeed,
+    pub record_file: Option<PathBuf>,
+    pub record_file_max_bytes: u64,
+    pub include_interfaces: bool,
+    pub interface_names: HashMap<u32, String>,
+    pub track_mpls: bool,
+    pub include_exporter: bool,
+    pub include_tcp_flags: bool,
+    pub include_dscp: bool,
+    pub bidirectional: bool,
+    pub track_observation_time: bool,
+    pub track_variance: bool,
+    pub geo_ip_database: Option<PathBuf>,
+    pub host_rollup: bool,
+    /// Partitions assigned per topic, replaces the consumer group subscription when not empty.
+    pub partition_assignment: Vec<(String, Vec<i32>)>,
+    /// Partitions every consumed topic must have at startup.
+    pub expected_partition_count: Option<usize>,
+    pub skip_startup_checks: bool,
+    pub enable_admin_stdin: bool,
+    /// Aggregation worker tasks, each owning a shard of the cache.
+    pub workers: usize,
+    /// Kafka messages taken per wakeup of the consume loop.
+    pub kafka_prefetch_messages: usize,
+    /// Wait for more messages before processing a chunk, zero takes only the fetched ones.
+    pub kafka_consumer_linger: Duration,
+    /// Flows outside the packet and byte bounds are skipped.
+    pub min_packets: u64,
+    pub min_bytes: u64,
+    pub max_packets: Option<u64>,
+    pub max_bytes: Option<u64>,
+    /// Largest flush threshold the consumer lag based batch size may grow to, `None` disables
+    /// it.
+    pub lag_batch_max_size: Option<usize>,
+    pub high_lag_threshold: u64,
+    pub low_lag_threshold: u64,
+    /// IP protocols of the aggregated flows, empty keeps every protocol.
+    pub include_protos: Vec<u32>,
+    /// VLANs of the aggregated flows, on either side, empty keeps every VLAN.
+    pub include_vlans: Vec<u32>,
+    /// Export protocol of the aggregated flows, `None` keeps all of them.
+    pub flow_version: Option<FlowVersion>,
+    pub include_icmp_detail: bool,
+    /// Rules deriving the `app` tag, re-read on SIGHUP.
+    pub classification_rules: Option<PathBuf>,
+    pub classify: bool,
+    /// File re-read on SIGHUP when `config_reload_on_sighup` is set.
+    pub config_file: Option<PathBuf>,
+    pub config_reload_on_sighup: bool,
+    pub dump_cache_path: PathBuf,
+    pub peer_cardinality: bool,
+    pub include_ip_version: bool,
+    /// Flows between two outside or two inside addresses are skipped.
+    pub skip_external_flows: bool,
+    pub skip_internal_flows: bool,
+    pub state_file: Option<PathBuf>,
+    pub allow_partial_flows: bool,
+    /// Limit of establishing the connection to Influx, `None` only limits the whole request.
+    pub influxdb_connect_timeout: Option<Duration>,
+    /// Messages whose key matches, or with `exclude` does not match, are processed.
+    pub key_filter: Option<Regex>,
+    pub key_filter_mode: KeyFilterMode,
+    pub kafka_max_message_bytes: usize,
+    pub payload_envelope: PayloadEnvelope,
+    /// Schema ids accepted in the Confluent envelope, empty accepts any.
+    pub allowed_schema_ids: Vec<u32>,
+    pub payload_compression: PayloadCompression,
+    pub max_decompressed_bytes: usize,
+    pub kafka_backpressure: bool,
+    pub output_ndjson: bool,
+    /// Base path of the daily NDJSON files, stdout if `None`.
+    pub output_ndjson_file: Option<PathBuf>,
+    /// Write into Influx as well as the NDJSON output.
+    pub output_both: bool,
+    /// Longest wait for the flush of the revoked partitions, inline or in the background.
+    pub rebalance_flush_timeout: Duration,
+    /// Longest wait for the final flush after SIGTERM or SIGINT.
+    pub shutdown_timeout: Duration,
+    pub aggregation_store: AggregationStoreKind,
+    /// Tables of every worker with the sharded store.
+    pub store_shards: usize,
+    /// Common name of topics carrying the same data, e.g. from several datacenters.
+    pub topic_alias: HashMap<String, String>,
+    /// `src_datacenter` tag of every aliased topic, the topic itself.
+    pub topic_datacenters: HashMap<String, Arc<str>>,
+    pub influxdb_exit_on_auth_error: bool,
+
+    pub influxdb_token: Secret,
+    /// Source of `influxdb_token`, re-read when Influx rejects the token.
+    pub influxdb_token_file: Option<PathBuf>,
+    pub influxdb_endpoint: String,
+    pub influxdb_bucket: String,
+    pub influxdb_org: String,
+    pub influxdb_timeout: Duration,
+    pub influxdb_precision: Precision,
+    pub influxdb_ca_cert: Option<PathBuf>,
+    pub influxdb_accept_invalid_certs: bool,
+}
+
+/// Field of the flow that determines its bucket.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BucketTimestamp {
+    #[value(alias = "flow_start")]
+    FlowStart,
+    Received,
+    #[value(alias = "flow_end")]
+    FlowEnd,
+}
+
+/// What to do with flows dated too far in the future.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FutureTimestampAction {
+    /// Skip the flow and count it.
+    Drop,
+    /// Replace the offending timestamps with the current time.
+    Clamp,
+}
+
+/// Export protocol the collector received a flow with. The collector decodes all of them into
+/// the same message.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowVersion {
+    #[value(alias = "sflow5")]
+    SflowV5,
+    NetflowV5,
+    NetflowV9,
+    Ipfix,
+}
+
+impl FlowVersion {
+    #[must_use]
+    pub fn flow_type(self) -> FlowType {
+        match self {
+            FlowVersion::SflowV5 => FlowType::Sflow5,
+            FlowVersion::NetflowV5 => FlowType::NetflowV5,
+            FlowVersion::NetflowV9 => FlowType::NetflowV9,
+            FlowVersion::Ipfix => FlowType::Ipfix,
+        }
+    }
+}
+
+/// Table of the aggregates owned by a worker.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregationStoreKind {
+    HashMap,
+    Sharded,
+}
+
+/// Compression the producer applies to every payload.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadCompression {
+    None,
+    Zstd,
+    /// Snappy framing format, the raw format has no magic bytes to detect it by.
+    Snappy,
+    Auto,
+}
+
+/// Header the producer puts in front of the protobuf payload.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadEnvelope {
+    /// Bare protobuf, as written by goflow.
+    None,
+    /// Confluent Schema Registry wire format, a zero magic byte, the big-endian schema id and
+    /// the message indexes, e.g. from Kafka Connect.
+    Confluent,
+}
+
+/// Whether the messages with a key matching `--key-filter` are processed or skipped.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyFilterMode {
+    Include,
+    Exclude,
+}
+
+/// What to do when no message has been processed for `idle_timeout`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdleAction {
+    /// Exit with [`crate::IDLE_EXIT_CODE`] so the supervisor restarts the service.
+    Exit,
+    /// Keep running and log an error once per idle timeout.
+    Warn,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Compact human readable lines.
+    Text,
+    /// One JSON object per line, including the service version and git SHA.
+    Json,
+}
+
+/// Value of the librdkafka `auto.offset.reset` property.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OffsetReset {
+    /// Start from the oldest retained message, processes all historical data.
+    Earliest,
+    /// Start from the newest message, only flows produced from now on are processed.
+    Latest,
+    /// Do not reset, the consumer reports an error for partitions without a valid offset.
+    Error,
+}
+
+impl OffsetReset {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OffsetReset::Earliest => "earliest",
+            OffsetReset::Latest => "latest",
+            OffsetReset::Error => "error",
+        }
+    }
+}
+
+/// Value of the librdkafka `security.protocol` property.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecurityProtocol {
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SecurityProtocol::Plaintext => "plaintext",
+            SecurityProtocol::Ssl => "ssl",
+            SecurityProtocol::SaslPlaintext => "sasl_plaintext",
+            SecurityProtocol::SaslSsl => "sasl_ssl",
+        }
+    }
+
+    fn uses_tls(self) -> bool {
+        matches!(self, SecurityProtocol::Ssl | SecurityProtocol::SaslSsl)
+    }
+
+    fn uses_sasl(self) -> bool {
+        matches!(
+            self,
+            SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl
+        )
+    }
+}
+
+/// Value of the librdkafka `sasl.mechanism` property.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaslMechanism {
+    Plain,
+    ScramSha256,
+    ScramSha512,
+}
+
+impl SaslMechanism {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+        }
+    }
+}
+
+/// Value of the librdkafka `ssl.endpoint.identification.algorithm` property.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointIdentification {
+    /// Verify that the broker certificate matches its hostname.
+    Https,
+    /// Skip the hostname verification.
+    None,
+}
+
+impl EndpointIdentification {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EndpointIdentification::Https => "https",
+            EndpointIdentification::None => "none",
+        }
+    }
+}
+
+/// Timestamp precision of the points written to Influx.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    S,
+    Ms,
+    Us,
+    Ns,
+}
+
+impl Precision {
+    /// Value of the `precision` parameter of the write API.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Precision::S => "s",
+            Precision::Ms => "ms",
+            Precision::Us => "us",
+            Precision::Ns => "ns",
+        }
+    }
+
+    /// Converts Unix seconds into this precision.
+    #[must_use]
+    pub fn multiply_factor(self) -> i64 {
+        match self {
+            Precision::S => 1,
+            Precision::Ms => 1_000,
+            Precision::Us => 1_000_000,
+            Precision::Ns => 1_000_000_000,
+        }
+    }
+}
+
+/// Pace at which `--replay-file` is fed into the pipeline.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplaySpeed {
+    /// As fast as possible.
+    Fast,
+    /// Keep the `time_received` deltas of the recorded flows.
+    Realtime,
+}
+
+/// How the `batch_number` tag that keeps points of different flushes apart is generated.
+///
+/// Influx identifies a point by its measurement, tags and timestamp, so two flushes that write the
+/// same key would overwrite each other without a distinguishing tag.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchIdStrategy {
+    /// Increasing integer prefixed with the startup time in milliseconds, e.g.
+    /// `1700000000000-42`. Readable and unique across restarts of a single instance.
+    RunSequential,
+    /// Increasing integer. Cheap and readable, but restarts from zero after a restart unless it
+    /// is persisted with `--batch-number-file`.
+    Sequential,
+    /// Random UUID per batch. Unique across restarts and instances, but adds a new tag value to
+    /// every series on every flush.
+    UuidV4,
+    /// Time-ordered UUID per batch. Same uniqueness as `uuid-v4` while sorting by flush time.
+    UuidV7,
+    /// Omit the tag. Lowest cardinality, but a key written by two flushes keeps only the latter.
+    None,
+}
+
+/// Arguments whose values are replaced by a placeholder whenever the configuration is printed.
+const SECRET_ARGS: [&str; 3] = ["influxdb_token", "postgres_url", "kafka_sasl_password"];
+
+/// Environment variables accepted in place of the env of an argument, as `(alias, env)`. The
+/// env wins when both are set.
+const ENV_ALIASES: [(&str, &str); 2] = [
+    (
+        "KAFKA_DUMP_INFLUXDB_WRITE_TIMEOUT_SECONDS",
+        "KAFKA_DUMP_INFLUXDB_TIMEOUT_SECS",
+    ),
+    ("KAFKA_DUMP_EXTRA_TAGS", "KAFKA_DUMP_INFLUXDB_TAGS_EXTRA"),
+];
+
+/// Env of the argument that `key` is an alias of, or `key` itself.
+fn resolve_env_alias(key: &str) -> &str {
+    ENV_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == key)
+        .map_or(key, |(_, name)| name)
+}
+
+/// Reads a file with one CIDR per line. Blank lines and everything after `#` are ignored.
+pub fn read_cidr_file(path: &Path) -> anyhow::Result<Vec<IpCidr>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read CIDR file `{}`.", path.display()))?;
+
+    content
+        .lines()
+        .enumerate()
+        .filter_map(|(number, line)| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            (!line.is_empty()).then_some((number + 1, line))
+        })
+        .map(|(number, line)| {
+            line.parse::<IpCidr>().map_err(|error| {
+                anyhow::anyhow!(
+                    "Invalid CIDR `{line}` on line {number} of `{}`: {error}",
+                    path.display()
+                )
+            })
+        })
+        .collect()
+}
+
+/// Reads the `CIDR = "group"` pairs of a TOML file.
+pub fn read_cidr_groups(path: &Path) -> anyhow::Result<Vec<(IpCidr, String)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read CIDR groups file `{}`.", path.display()))?;
+    let table: toml::Table = content
+        .parse()
+        .with_context(|| format!("Invalid TOML in `{}`.", path.display()))?;
+
+    table
+        .into_iter()
+        .map(|(cidr, group)| {
+            let parsed = cidr.parse::<IpCidr>().map_err(|error| {
+                anyhow::anyhow!("Invalid CIDR `{cidr}` in `{}`: {error}", path.display())
+            })?;
+            match group {
+                toml::Value::String(group) => Ok((parsed, group)),
+                _ => anyhow::bail!("Group of `{cidr}` in `{}` is not a string.", path.display()),
+            }
+        })
+        .collect()
+}
+
+/// Reads a token from a file, ignoring the trailing newline. Errors never contain the contents.
+pub fn read_token_file(path: &Path) -> anyhow::Result<Secret> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read token file `{}`.", path.display()))?;
+    let token = content.trim_end_matches(['\n', '\r']);
+    if token.is_empty() {
+        anyhow::bail!("Token file `{}` is empty.", path.display());
+    }
+
+    Ok(Secret(token.to_owned()))
+}
+
+/// Appends the CIDRs of `extra` missing in `base`.
+#[must_use]
+pub fn merge_cidrs(base: &[IpCidr], extra: Vec<IpCidr>) -> Vec<IpCidr> {
+    let mut merged = base.to_vec();
+    for cidr in extra {
+        if !merged.contains(&cidr) {
+            merged.push(cidr);
+        }
+    }
+    merged
+}
+
+/// Credential masked in the `Debug` output, so the configuration can be logged. Only the first
+/// and last 3 characters of long values are shown.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    #[must_use]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let length = self.0.chars().count();
+        if length < 12 {
+            return f.write_str("\"<redacted>\"");
+        }
+
+        let head: String = self.0.chars().take(3).collect();
+        let tail: String = self.0.chars().skip(length - 3).collect();
+        write!(f, "\"{head}...{tail}\"")
+    }
+}
+
+/// What the process was asked to do.
+pub enum Invocation {
+    /// Plain invocation or `run`, consume and aggregate flows.
+    Run(Box<Config>),
+    CheckConfig(CheckConfigArgs),
+    /// Resolved configuration rendered as TOML.
+    PrintConfig(String),
+    Diff(DiffArgs),
+    TestConnection(Box<Config>),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Consume and aggregate flows, same as running without a subcommand.
+    Run(ConfigArgs),
+    /// Validate the configuration, exits with 0 when it is usable and 1 otherwise.
+    CheckConfig(CheckConfigArgs),
+    /// Print the configuration resolved from the CLI, the environment and the defaults as TOML
+    /// with secrets redacted.
+    PrintConfig(ConfigArgs),
+    /// Compare two configuration files and report how the output would change.
+    Diff(DiffArgs),
+    /// Check that Kafka and Influx are reachable and report each as JSON, exits with 0 when all
+    /// of them are healthy and 1 otherwise.
+    TestConnection(ConfigArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct CheckConfigArgs {
+    /// Also check that the Kafka brokers, the topics and Influx are reachable.
+    #[clap(long)]
+    pub connect: bool,
+
+    #[clap(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// Currently deployed configuration, a `.toml` file or an env file with `KAFKA_DUMP_*`
+    /// variables.
+    pub old: PathBuf,
+
+    /// Proposed configuration in either format.
+    pub new: PathBuf,
+
+    #[clap(long, value_enum, default_value_t = DiffFormat::Text)]
+    pub format: DiffFormat,
+}
+
+/// Dimension of [`crate::util::AggregatedKey`] that can be kept in the rollups. The protocol is
+/// always kept.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RollupDimension {
+    Hosts,
+    Vlans,
+    Interfaces,
+    Mpls,
+    Exporter,
+    TcpFlags,
+    Dscp,
+    Icmp,
+    Geo,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffFormat {
+    Text,
+    Json,
+}
+
+impl Invocation {
+    /// Parse from `std::env::args_os()` and/or `std::env::vars()`,
+    ///
+    /// # Panics
+    ///
+    /// Panics if a valid configuration cannot be created from environment variables and CLI
+    /// arguments.
+    #[must_use]
+    pub fn parse_or_exit() -> Self {
+        // `check-config` reports invalid arguments with its own exit code.
+        let check_config = env::args_os()
+            .nth(1)
+            .is_some_and(|arg| arg == "check-config");
+
+        // Clap reads a single env per argument, so the aliases are copied over before any other
+        // thread looks at the environment.
+        for (alias, name) in ENV_ALIASES {
+            if env::var_os(name).is_none() {
+                if let Some(value) = env::var_os(alias) {
+                    env::set_var(name, value);
+                }
+            }
+        }
+
+        // Subcommands bring their own arguments, the run arguments must not be required for them.
+        let matches = match Command::augment_subcommands(ConfigArgs::command())
+            .subcommand_negates_reqs(true)
+            .args_conflicts_with_subcommands(true)
+            .try_get_matches()
+        {
+            Ok(matches) => matches,
+            Err(error) if check_config && error.use_stderr() => {
+                let _ = error.print();
+                std::process::exit(1);
+            },
+            Err(error) => error.exit(),
+        };
+
+        let args = match matches.subcommand() {
+            None => ConfigArgs::from_arg_matches(&matches).unwrap_or_else(|error| error.exit()),
+            Some((_, sub_matches)) => {
+                match Command::from_arg_matches(&matches).unwrap_or_else(|error| error.exit()) {
+                    Command::Run(args) => args,
+                    Command::CheckConfig(args) => return Invocation::CheckConfig(args),
+                    Command::PrintConfig(args) => {
+                        if let Err(error) = Config::try_from(args) {
+                            eprintln!("Invalid configuration: {error:#}");
+                            std::process::exit(1);
+                        }
+                        return Invocation::PrintConfig(render_toml(sub_matches));
+                    },
+                    Command::Diff(args) => return Invocation::Diff(args),
+                    Command::TestConnection(args) => {
+                        match Config::try_from(args) {
+                            Ok(config) => return Invocation::TestConnection(Box::new(config)),
+                            Err(error) => {
+                                eprintln!("Invalid configuration: {error:#}");
+                                std::process::exit(1);
+                            },
+                        }
+                    },
+                }
+            },
+        };
+
+        #[allow(clippy::expect_used)]
+        Invocation::Run(Box::new(
+            args.try_into()
+                .expect("Failed to convert ConfigArgs to Config."),
+        ))
+    }
+}
+
+/// Renders the values resolved from the CLI, the environment and the defaults as TOML accepted by
+/// [`Config::from_file`]. Secrets are redacted and unset options are omitted.
+#[must_use]
+pub fn render_toml(matches: &ArgMatches) -> String {
+    let mut table = toml::Table::new();
+    for arg in ConfigArgs::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(raw_values) = matches.try_get_raw(id).ok().flatten() else {
+            continue;
+        };
+        let values: Vec<String> = raw_values
+            .map(|value| value.to_string_lossy().into_owned())
+            .collect();
+
+        let value = if SECRET_ARGS.contains(&id) {
+            toml::Value::String("<redacted>".to_owned())
+        } else if matches!(arg.get_action(), ArgAction::SetTrue) {
+            toml::Value::Boolean(values.iter().any(|value| value == "true"))
+        } else if let Some(delimiter) = arg.get_value_delimiter() {
+            toml::Value::String(values.join(&delimiter.to_string()))
+        } else {
+            let value = values.concat();
+            match value.parse() {
+                Ok(value) => toml::Value::Integer(value),
+                Err(_) => toml::Value::String(value),
+            }
+        };
+        table.insert(id.to_owned(), value);
+    }
+
+    table.to_string()
+}
+
+impl Config {
+    /// Whether a message with `key` passes `--key-filter`.
+    #[must_use]
+    pub fn admits_key(&self, key: Option<&[u8]>) -> bool {
+        let Some(key_filter) = &self.key_filter else {
+            return true;
+        };
+        let key = key.unwrap_or_default();
+        let matched = match std::str::from_utf8(key) {
+            Ok(key) => key_filter.is_match(key),
+            Err(_) => {
+                let hex: String = key.iter().map(|byte| format!("{byte:02x}")).collect();
+                key_filter.is_match(&hex)
+            },
+        };
+        matched == (self.key_filter_mode == KeyFilterMode::Include)
+    }
+
+    /// Whether the aggregates are written into Influx, i.e. unless the NDJSON output replaces it.
+    #[must_use]
+    pub fn writes_to_influx(&self) -> bool {
+        !self.output_ndjson || self.output_both
+    }
+
+    /// Client configuration shared by every Kafka client, i.e. the brokers and the security
+    /// settings.
+    #[must_use]
+    pub fn kafka_client_config(&self) -> ClientConfig {
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &self.brokers)
+            .set("security.protocol", self.kafka_security_protocol.as_str());
+        if self.kafka_security_protocol.uses_tls() {
+            client_config.set(
+                "ssl.endpoint.identification.algorithm",
+                self.kafka_ssl_endpoint_identification_algorithm.as_str(),
+            );
+        }
+        if let Some(cipher_suites) = &self.kafka_ssl_cipher_suites {
+            client_config.set("ssl.cipher.suites", cipher_suites);
+        }
+        if let (Some(username), Some(password)) =
+            (&self.kafka_sasl_username, &self.kafka_sasl_password)
+        {
+            client_config
+                .set("sasl.mechanism", self.kafka_sasl_mechanism.as_str())
+                .set("sasl.username", username)
+                .set("sasl.password", password.expose());
+        }
+
+        client_config
+    }
+
+    /// Loads the configuration from a TOML file (keys are the argument names in snake case) or
+    /// an env file (`KAFKA_DUMP_*=value` lines).
+    ///
+    /// Values go through the same parsing and validation as the CLI. Settings missing from the
+    /// file still fall back to the process environment.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read `{}`.", path.display()))?;
+
+        let values = if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            let table: toml::Table = content
+                .parse()
+                .with_context(|| format!("Invalid TOML in `{}`.", path.display()))?;
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_value_to_arg(value)))
+                .collect::<Vec<_>>()
+        } else {
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| {
+                    let line = line.strip_prefix("export ").unwrap_or(line);
+                    let (key, value) = line
+                        .split_once('=')
+                        .with_context(|| format!("Expected `KEY=value`, got `{line}`."))?;
+                    let value = value.trim().trim_matches('"').trim_matches('\'');
+                    Ok((resolve_env_alias(key.trim()).to_owned(), value.to_owned()))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
+
+        let command = ConfigArgs::command();
+        let mut args = vec![OsString::from(command.get_name())];
+        for (key, value) in values {
+            // TOML files use argument ids, env files the env variable names.
+            let arg = command
+                .get_arguments()
+                .find(|arg| {
+                    arg.get_id().as_str() == key
+                        || arg.get_env().is_some_and(|env| env == key.as_str())
+                })
+                .with_context(|| {
+                    format!("Unknown configuration key `{key}` in `{}`.", path.display())
+                })?;
+            let long = arg
+                .get_long()
+                .with_context(|| format!("`{key}` cannot be set from a file."))?;
+
+            if arg.get_action().takes_values() {
+                args.push(format!("--{long}={value}").into());
+            } else if matches!(value.as_str(), "true" | "1" | "yes" | "on") {
+                args.push(format!("--{long}").into());
+            }
+        }
+
+        ConfigArgs::try_parse_from(args)
+            .with_context(|| format!("Invalid configuration in `{}`.", path.display()))?
+            .try_into()
+    }
+
+    /// Parses the command line and the environment again, the SIGHUP reload without
+    /// `--config-file`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let matches = Command::augment_subcommands(ConfigArgs::command())
+            .subcommand_negates_reqs(true)
+            .args_conflicts_with_subcommands(true)
+            .try_get_matches()
+            .context("Invalid configuration in the command line or the environment.")?;
+        let args = match matches.subcommand() {
+            None => ConfigArgs::from_arg_matches(&matches)?,
+            Some(_) => {
+                match Command::from_arg_matches(&matches)? {
+                    Command::Run(args) => args,
+                    _ => anyhow::bail!("Only the `run` arguments can be reloaded."),
+                }
+            },
+        };
+
+        args.try_into()
+    }
+}
+
+fn toml_value_to_arg(value: toml::Value) -> String {
+    match value {
+        toml::Value::String(value) => value,
+        toml::Value::Array(values) => {
+            values
+                .into_iter()
+                .map(toml_value_to_arg)
+                .collect::<Vec<_>>()
+                .join(",")
+        },
+        value => value.to_string(),
+    }
+}
+
+// ⚠️ If you add any ENVs here, consider updating `config.dist.toml` and `postinst`. ⚠️
+#[derive(Parser, Debug)]
+#[clap(author, about, long_version = long_version(), version = version())]
+pub struct ConfigArgs {
+    /// Consumer group id.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_GROUP_ID")]
+    group_id: String,
+
+    /// Topics from which read.
+    #[clap(
+        long,
+        value_parser,
+        value_delimiter = ',',
+        env = "KAFKA_DUMP_TOPICS",
+        required = true
+    )]
+    topics: Vec<String>,
+
+    /// Kafka brokers in Kafka format.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_BROKERS")]
+    brokers: String,
+
+    /// Format of the log records written to stdout.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = LogFormat::Text,
+        env = "KAFKA_DUMP_LOG_FORMAT"
+    )]
+    log_format: LogFormat,
+
+    /// Where to start consuming partitions without a committed offset (`auto.offset.reset`).
+    #[clap(
+        long,
+        alias = "kafka-consumer-offset-reset",
+        value_enum,
+        default_value_t = OffsetReset::Latest,
+        env = "KAFKA_DUMP_KAFKA_OFFSET_RESET"
+    )]
+    kafka_offset_reset: OffsetReset,
+
+    /// Protocol used to talk to the brokers (`security.protocol`).
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = SecurityProtocol::Plaintext,
+        env = "KAFKA_DUMP_KAFKA_SECURITY_PROTOCOL"
+    )]
+    kafka_security_protocol: SecurityProtocol,
+
+    /// Broker hostname verification (`ssl.endpoint.identification.algorithm`). `none` accepts
+    /// certificates issued for other names, e.g. brokers with IP based certificates.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = EndpointIdentification::Https,
+        env = "KAFKA_DUMP_KAFKA_SSL_ENDPOINT_ALGO"
+    )]
+    kafka_ssl_endpoint_identification_algorithm: EndpointIdentification,
+
+    /// Allowed TLS cipher suites in the OpenSSL format (`ssl.cipher.suites`).
+    #[clap(long, value_parser, env = "KAFKA_DUMP_KAFKA_SSL_CIPHER_SUITES")]
+    kafka_ssl_cipher_suites: Option<String>,
+
+    /// SASL mechanism of the `sasl-plaintext` and `sasl-ssl` security protocols.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = SaslMechanism::Plain,
+        env = "KAFKA_DUMP_KAFKA_SASL_MECHANISM"
+    )]
+    kafka_sasl_mechanism: SaslMechanism,
+
+    /// SASL username, required by the `sasl-plaintext` and `sasl-ssl` security protocols.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_KAFKA_SASL_USERNAME")]
+    kafka_sasl_username: Option<String>,
+
+    /// SASL password, required by the `sasl-plaintext` and `sasl-ssl` security protocols.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_KAFKA_SASL_PASSWORD")]
+    kafka_sasl_password: Option<String>,
+
+    /// Collect librdkafka statistics this often, logged at debug level and exported as metrics.
+    /// Disabled with 0.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        env = "KAFKA_DUMP_KAFKA_STATS_INTERVAL_MS"
+    )]
+    kafka_stats_interval_ms: u64,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "KAFKA_DUMP_INFLUXDB_TOKEN",
+        required_unless_present = "influxdb_token_file",
+        conflicts_with = "influxdb_token_file"
+    )]
+    influxdb_token: Option<String>,
+
+    /// Read the Influx token from this file instead of `--influxdb-token`. The file is read again
+    /// whenever Influx rejects the token, so it can be rotated without a restart.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_TOKEN_FILE")]
+    influxdb_token_file: Option<PathBuf>,
+
+    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_ENDPOINT")]
+    influxdb_endpoint: String,
+
+    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_BUCKET")]
+    influxdb_bucket: String,
+
+    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_ORG")]
+    influxdb_org: String,
+
+    /// Give up on an Influx request after this many seconds and retry it like a failed write.
+    /// Also read from `KAFKA_DUMP_INFLUXDB_WRITE_TIMEOUT_SECONDS`.
+    #[clap(
+        long,
+        alias = "influxdb-write-timeout-seconds",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 30,
+        env = "KAFKA_DUMP_INFLUXDB_TIMEOUT_SECS"
+    )]
+    influxdb_timeout_secs: u64,
+
+    /// Timestamp precision of the written points. Buckets are whole seconds, so `s` loses
+    /// nothing.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Precision::Ns,
+        env = "KAFKA_DUMP_INFLUXDB_PRECISION"
+    )]
+    influxdb_precision: Precision,
+
+    /// PEM file with an additional root certificate trusted for the Influx endpoint.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_CA_CERT")]
+    influxdb_ca_cert: Option<PathBuf>,
+
+    /// Do not verify the Influx certificate at all. Only for lab environments.
+    #[clap(long, env = "KAFKA_DUMP_INFLUXDB_ACCEPT_INVALID_CERTS")]
+    influxdb_accept_invalid_certs: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        value_delimiter = ',',
+        env = "KAFKA_DUMP_CIDR_LIST",
+        required_unless_present = "cidr_file"
+    )]
+    cidr_list: Vec<IpCidr>,
+
+    /// File with one inside CIDR per line, `#` starts a comment. Merged with `--cidr-list`.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_CIDR_FILE")]
+    cidr_file: Option<PathBuf>,
+
+    /// Re-read `--cidr-file` this often and apply changes without a restart.
+    #[clap(
+        long,
+        value_parser,
+        requires = "cidr_file",
+        env = "KAFKA_DUMP_CIDR_FILE_RELOAD_INTERVAL_SECONDS"
+    )]
+    cidr_file_reload_interval_seconds: Option<u64>,
+
+    /// TOML file mapping CIDRs to group names, e.g. `"10.1.0.0/16" = "datacenter-a"`. Every
+    /// point is tagged with the group of the most specific CIDR containing its source and target
+    /// as `src_group` and `dst_group`, addresses outside all of them as `ungrouped`.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_CIDR_GROUPS")]
+    cidr_groups: Option<PathBuf>,
+
+    #[clap(long, value_parser, env = "KAFKA_DUMP_BATCH_SIZE")]
+    batch_size: usize,
+
+    /// Let the flush threshold grow up to this many bytes while the consumer lags behind by more
+    /// than `--adaptive-batch-lag-threshold-secs`. It decays back to `--batch-size` once caught
+    /// up.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_ADAPTIVE_BATCH_MAX_SIZE")]
+    adaptive_batch_max_size: Option<usize>,
+
+    /// Lag of the latest `time_received` behind the wall clock above which the adaptive batch
+    /// size grows.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 300,
+        env = "KAFKA_DUMP_ADAPTIVE_BATCH_LAG_THRESHOLD_SECS"
+    )]
+    adaptive_batch_lag_threshold_secs: u64,
+
+    /// Flush the whole cache once its estimated memory usage, including the hash map overhead,
+    /// reaches this many bytes.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_MAX_CACHE_MEMORY_BYTES")]
+    max_cache_memory_bytes: Option<usize>,
+
+    /// Distinct inside source hosts kept per bucket, further hosts are aggregated as `overflow`.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_MAX_UNIQUE_SOURCES")]
+    max_unique_sources: Option<usize>,
+
+    /// Distinct inside target hosts kept per bucket, further hosts are aggregated as `overflow`.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_MAX_UNIQUE_TARGETS")]
+    max_unique_targets: Option<usize>,
+
+    /// Width of the time buckets flows are aggregated into.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 300,
+        env = "KAFKA_DUMP_TIME_ALIGNMENT_SECONDS"
+    )]
+    time_alignment_seconds: u64,
+
+    /// Flow timestamp that determines the bucket. When it is zero, the first non-zero of
+    /// `flow-start`, `flow-end` and `received` is used instead.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = BucketTimestamp::FlowStart,
+        env = "KAFKA_DUMP_BUCKET_TIMESTAMP"
+    )]
+    bucket_timestamp: BucketTimestamp,
+
+    /// Additionally roll flushed buckets up into buckets of this width (e.g. 3600) kept in
+    /// memory. A rollup bucket is written to `--rollup-bucket` exactly once, after it closes.
+    /// Must be a multiple of `--time-alignment-seconds`.
+    #[clap(
+        long,
+        alias = "rollup-window-secs",
+        value_parser = clap::value_parser!(u64).range(1..),
+        env = "KAFKA_DUMP_ROLLUP_ALIGNMENT_SECONDS"
+    )]
+    rollup_alignment_seconds: Option<u64>,
+
+    /// Influx measurement the aggregates are written to.
+    #[clap(
+        long,
+        value_parser,
+        default_value = "sflow",
+        env = "KAFKA_DUMP_INFLUXDB_MEASUREMENT"
+    )]
+    influxdb_measurement: String,
+
+    /// Static tags added to every Influx point, e.g. `dc=prague,instance=1`. Also read from
+    /// `KAFKA_DUMP_EXTRA_TAGS`.
+    #[clap(
+        long,
+        alias = "extra-tag",
+        value_parser = parse_extra_tag,
+        value_delimiter = ',',
+        env = "KAFKA_DUMP_INFLUXDB_TAGS_EXTRA"
+    )]
+    influxdb_tags_extra: Vec<(String, String)>,
+
+    /// Measurements of specific topics, e.g. `topic-dc1=sflow,topic-dc2=ipfix`. Other topics use
+    /// `--influxdb-measurement`.
+    #[clap(
+        long,
+        alias = "topic-to-measurement",
+        value_parser = parse_topic_measurement,
+        value_delimiter = ',',
+        env = "KAFKA_DUMP_TOPIC_MEASUREMENT_MAP"
+    )]
+    topic_measurement_map: Vec<(String, String)>,
+
+    /// Influx measurement the rollups are written to.
+    #[clap(
+        long,
+        value_parser,
+        default_value = "sflow_rollup",
+        env = "KAFKA_DUMP_ROLLUP_MEASUREMENT"
+    )]
+    rollup_measurement: String,
+
+    /// Influx bucket the rollups are written to, e.g. one with a longer retention. Defaults to
+    /// `--influxdb-bucket`.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_ROLLUP_BUCKET")]
+    rollup_bucket: Option<String>,
+
+    /// Dimensions kept in the rollups, all others are aggregated away. Without `hosts` the
+    /// inside addresses are collapsed to the `--cidr-list` network they belong to.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "hosts,vlans",
+        env = "KAFKA_DUMP_ROLLUP_DIMENSIONS"
+    )]
+    rollup_dimensions: Vec<RollupDimension>,
+
+    /// Aggregate inside source addresses to this prefix length. Defaults to the full host
+    /// address (/32 for IPv4, /128 for IPv6).
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u8).range(0..=128),
+        env = "KAFKA_DUMP_SRC_PREFIX_LEN"
+    )]
+    src_prefix_len: Option<u8>,
+
+    /// Aggregate inside destination addresses to this prefix length. Defaults to the full host
+    /// address (/32 for IPv4, /128 for IPv6).
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u8).range(0..=128),
+        env = "KAFKA_DUMP_DST_PREFIX_LEN"
+    )]
+    dst_prefix_len: Option<u8>,
+
+    /// Consider the consumer stalled when no message has been processed for this many seconds.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_IDLE_TIMEOUT_SECS")]
+    idle_timeout_secs: Option<u64>,
+
+    /// Action taken once the idle timeout elapses.
+    #[clap(long, value_enum, default_value_t = IdleAction::Exit, env = "KAFKA_DUMP_IDLE_ACTION")]
+    idle_action: IdleAction,
+
+    /// Recreate the Kafka consumer when no message is received for this many seconds. `0`
+    /// disables the watchdog.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 300,
+        env = "KAFKA_DUMP_WATCHDOG_INTERVAL_SECONDS"
+    )]
+    watchdog_interval_seconds: u64,
+
+    /// How long after a time bucket ends it keeps accepting late flows before it is flushed.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 60,
+        env = "KAFKA_DUMP_FLUSH_GRACE_SECS"
+    )]
+    flush_grace_secs: u64,
+
+    /// Also produce every flushed aggregate as JSON to this Kafka topic.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_OUTPUT_TOPIC")]
+    output_topic: Option<String>,
+
+    /// Also stream every flushed aggregate as newline-delimited JSON to the clients connected to
+    /// a Unix socket at this path.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_UNIX_SOCKET_PATH")]
+    unix_socket_path: Option<PathBuf>,
+
+    /// Produce the raw payload of undecodable messages to this Kafka topic, with headers naming
+    /// the error and the original topic, partition and offset, instead of skipping them. Messages
+    /// without a payload, that cannot be decompressed or with an invalid envelope are dead
+    /// lettered too. Flows with malformed addresses are only counted as skipped.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_DEAD_LETTER_TOPIC")]
+    dead_letter_topic: Option<String>,
+
+    /// Strategy used to generate the `batch_number` tag.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = BatchIdStrategy::RunSequential,
+        env = "KAFKA_DUMP_BATCH_ID_STRATEGY"
+    )]
+    batch_id_strategy: BatchIdStrategy,
+
+    /// Persist the sequential batch number to this file so it survives restarts.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_BATCH_NUMBER_FILE")]
+    batch_number_file: Option<PathBuf>,
+
+    /// Also write every flushed batch into Postgres (or Timescale) at this connection string.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_POSTGRES_URL")]
+    postgres_url: Option<String>,
+
+    /// Table the Postgres sink writes into, see `migrations/` for its layout.
+    #[clap(
+        long,
+        value_parser,
+        default_value = "flow_aggregates",
+        env = "KAFKA_DUMP_POSTGRES_TABLE"
+    )]
+    postgres_table: String,
+
+    /// Keep every batch in this SQLite database until Influx, or the NDJSON output replacing it
+    /// without `--output-both`, accepted it. Batches left behind by a crash are written into it
+    /// at the next start, before consuming, under their original batch ids and with the usual
+    /// retries and `--failed-batch-dir` backup.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_SQLITE_RECOVERY_PATH")]
+    sqlite_recovery_path: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 4,
+        env = "KAFKA_DUMP_POSTGRES_MAX_CONNECTIONS"
+    )]
+    postgres_max_connections: u32,
+
+    /// Drop flows that started more than this many seconds ago. Unlimited by default.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_MAX_MESSAGE_AGE_SECONDS")]
+    max_message_age_seconds: Option<u64>,
+
+    /// Drop flows the collector received more than this many seconds ago, e.g. a backlog
+    /// replayed after restoring the Kafka cluster. Unlike `--max-message-age-seconds` it does not
+    /// depend on the exporter clock. Unlimited by default.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_MAX_RECEIVED_AGE_SECS")]
+    max_received_age_secs: Option<u64>,
+
+    /// Flows with `time_flow_start` or `time_received` more than this many seconds ahead of the
+    /// wall clock are handled according to `--future-timestamp-action`. Unchecked by default.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_MAX_FUTURE_SKEW_SECS")]
+    max_future_skew_secs: Option<u64>,
+
+    /// What to do with flows whose timestamps exceed `--max-future-skew-secs`.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = FutureTimestampAction::Drop,
+        env = "KAFKA_DUMP_FUTURE_TIMESTAMP_ACTION"
+    )]
+    future_timestamp_action: FutureTimestampAction,
 
-    pub influxdb_token: String,
-    pub influxdb_endpoint: String,
-    pub influxdb_bucket: String,
-    pub influxdb_org: String,
-}
+    /// Drop flows with `time_flow_start` or `time_received` before this Unix time, e.g.
+    /// `946684800` for 2000-01-01. Unchecked by default.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_MIN_TIMESTAMP")]
+    min_timestamp: Option<u64>,
 
-impl Config {
-    /// Parse from `std::env::args_os()` and/or `std::env::vars()`,
-    ///
-    /// # Panics
-    ///
-    /// Panics if a valid configuration cannot be created from environment variables and CLI
-    /// arguments.
-    #[must_use]
-    pub fn parse_or_exit() -> Self {
-        #[allow(clippy::expect_used)]
-        ConfigArgs::parse()
-            .try_into()
-            .expect("Failed to convert ConfigArgs to Config.")
-    }
-}
+    /// Give up on a batch after this many failed Influx writes. Retries forever by default.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_MAX_RETRIES")]
+    influxdb_max_retries: Option<u32>,
 
-// ⚠️ If you add any ENVs here, consider updating `config.dist.toml` and `postinst`. ⚠️
-#[derive(Parser, Debug)]
-#[clap(author, about, long_version = long_version(), version = version())]
-pub struct ConfigArgs {
-    /// Consumer group id.
-    #[clap(long, value_parser, env = "KAFKA_DUMP_GROUP_ID")]
-    group_id: String,
+    /// Write up to this many batches into Influx at once in the background, so one slow write
+    /// does not hold up consumption. With 1 every batch is written before consuming continues.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 1,
+        env = "KAFKA_DUMP_INFLUX_WRITE_CONCURRENCY"
+    )]
+    influx_write_concurrency: usize,
 
-    /// Topics from which read.
+    /// Upper bound of the `Retry-After` wait honoured when Influx rate limits a write.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 60,
+        env = "KAFKA_DUMP_INFLUXDB_MAX_RETRY_WAIT_SECONDS"
+    )]
+    influxdb_max_retry_wait_seconds: u64,
+
+    /// Batches that exhausted `--influxdb-max-retries` are written here as line protocol instead
+    /// of being dropped.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_FAILED_BATCH_DIR")]
+    failed_batch_dir: Option<PathBuf>,
+
+    /// Total size of `--failed-batch-dir`, the oldest batches are deleted first.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 1024 * 1024 * 1024,
+        env = "KAFKA_DUMP_FAILED_BATCH_DIR_MAX_BYTES"
+    )]
+    failed_batch_dir_max_bytes: u64,
+
+    /// Minutes of per-second throughput samples kept for the stats.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 5,
+        env = "KAFKA_DUMP_STATS_HISTORY_MINUTES"
+    )]
+    stats_history_minutes: usize,
+
+    /// Smoothing factor of the bytes per second moving average, the weight of the newest second.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0.1,
+        env = "KAFKA_DUMP_STATS_EMA_ALPHA"
+    )]
+    stats_ema_alpha: f64,
+
+    /// Serve Prometheus metrics on `http://<address>/metrics`.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Export trace spans of the flushes to this OTLP/gRPC collector, e.g. `http://localhost:4317`.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Read length-prefixed payloads from this file instead of Kafka, flush everything and exit
+    /// once it is exhausted.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_REPLAY_FILE")]
+    replay_file: Option<PathBuf>,
+
+    /// Pace of `--replay-file`.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = ReplaySpeed::Fast,
+        env = "KAFKA_DUMP_REPLAY_SPEED"
+    )]
+    replay_speed: ReplaySpeed,
+
+    /// Append the consumed payloads to this file in the `--replay-file` format.
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with = "replay_file",
+        env = "KAFKA_DUMP_RECORD_FILE"
+    )]
+    record_file: Option<PathBuf>,
+
+    /// Stop recording once `--record-file` reaches this size.
     #[clap(
         long,
         value_parser,
+        default_value_t = 1024 * 1024 * 1024,
+        env = "KAFKA_DUMP_RECORD_FILE_MAX_BYTES"
+    )]
+    record_file_max_bytes: u64,
+
+    /// Aggregate by input/output interface and write them as `in_if`/`out_if` tags. Multiplies
+    /// the cardinality by the number of interfaces.
+    #[clap(long, env = "KAFKA_DUMP_INCLUDE_INTERFACES")]
+    include_interfaces: bool,
+
+    /// Names written instead of interface indices, e.g. `17=uplink-core1,23=customer-foo`.
+    #[clap(
+        long,
+        value_parser = parse_interface_name,
         value_delimiter = ',',
-        env = "KAFKA_DUMP_TOPICS",
-        required = true
+        env = "KAFKA_DUMP_INTERFACE_NAMES"
     )]
-    topics: Vec<String>,
+    interface_names: Vec<(u32, String)>,
 
-    /// Kafka brokers in Kafka format.
-    #[clap(long, value_parser, env = "KAFKA_DUMP_BROKERS")]
-    brokers: String,
+    /// Aggregate MPLS traffic by its top label and write it as the `mpls_label` tag.
+    #[clap(long, env = "KAFKA_DUMP_TRACK_MPLS")]
+    track_mpls: bool,
 
-    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_TOKEN")]
-    influxdb_token: String,
+    /// Aggregate by the exporter (sampler) address and write it as the `exporter` tag. Helps to
+    /// de-duplicate flows reported by several devices.
+    #[clap(long, env = "KAFKA_DUMP_INCLUDE_EXPORTER")]
+    include_exporter: bool,
 
-    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_ENDPOINT")]
-    influxdb_endpoint: String,
+    /// Aggregate TCP flows by their cumulative flags (e.g. `S`, `SA`, `FA`, `R`) and write them as
+    /// the `tcp_flags` tag, other protocols get no tag. Every distinct flag set splits a
+    /// conversation into another series, so expect up to a few dozen series per TCP conversation.
+    #[clap(long, env = "KAFKA_DUMP_INCLUDE_TCP_FLAGS")]
+    include_tcp_flags: bool,
 
-    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_BUCKET")]
-    influxdb_bucket: String,
+    /// Aggregate by the DSCP class (e.g. `BE`, `EF`, `AF41`, `CS6`) and write it as the `dscp`
+    /// tag. Code points without a standard name keep the number.
+    #[clap(long, env = "KAFKA_DUMP_INCLUDE_DSCP")]
+    include_dscp: bool,
 
-    #[clap(long, value_parser, env = "KAFKA_DUMP_INFLUXDB_ORG")]
-    influxdb_org: String,
+    /// Merge both directions of a conversation into one series. The endpoints are ordered
+    /// deterministically and written as the `a`/`b` tags, traffic from `a` to `b` is counted in
+    /// the `*_fwd` fields and the opposite direction in the `*_rev` fields.
+    #[clap(long, env = "KAFKA_DUMP_BIDIRECTIONAL")]
+    bidirectional: bool,
+
+    /// Write when each key was first and last aggregated, as Unix nanoseconds in the
+    /// `first_observed` and `last_observed` fields. Helps debugging delayed or replayed flows.
+    #[clap(long, env = "KAFKA_DUMP_TRACK_OBSERVATION_TIME")]
+    track_observation_time: bool,
+
+    /// Write the standard deviation of the flow sizes aggregated into each key as the
+    /// `std_dev_bytes` field, to tell bursty traffic from a steady stream. Costs one more float
+    /// field per point.
+    #[clap(long, env = "KAFKA_DUMP_TRACK_VARIANCE")]
+    track_variance: bool,
+
+    /// `GeoLite2` MMDB file used to tag outside endpoints with `src_country`/`dst_country` and
+    /// `src_asn`/`dst_asn`. Each distinct country and ASN adds series.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_GEO_IP_DATABASE")]
+    geo_ip_database: Option<PathBuf>,
+
+    /// Also write the traffic sent and received by each inside host per bucket into the
+    /// `sflow_host` measurement. Its cardinality grows with the inside hosts only.
+    #[clap(long, env = "KAFKA_DUMP_HOST_ROLLUP")]
+    host_rollup: bool,
+
+    /// Consume fixed partitions instead of joining the consumer group rebalancing, e.g.
+    /// `flows:0,1,2;other:3`. Every topic must also be listed in `--topics`. The group id is
+    /// still used to store offsets.
+    #[clap(
+        long,
+        value_parser = parse_partition_assignment,
+        value_delimiter = ';',
+        env = "KAFKA_DUMP_PARTITION_ASSIGNMENT"
+    )]
+    kafka_partition_assignment: Vec<(String, Vec<i32>)>,
+
+    /// Exit at startup when any of the `--topics` does not have exactly this many partitions, so
+    /// a repartitioned topic does not leave the fixed assignments with a silent gap.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_EXPECTED_PARTITION_COUNT")]
+    expected_partition_count: Option<usize>,
+
+    /// Do not check at startup that the Kafka topics exist and that Influx accepts the token for
+    /// the bucket, e.g. for air-gapped testing.
+    #[clap(long, env = "KAFKA_DUMP_SKIP_STARTUP_CHECKS")]
+    skip_startup_checks: bool,
+
+    /// Read admin commands from stdin, one per line, and answer each with a JSON line on stdout:
+    /// `flush`, `set batch_size <bytes>`, `set time_alignment <seconds>`, `show stats` and `show
+    /// config`.
+    #[clap(long, env = "KAFKA_DUMP_ENABLE_ADMIN_STDIN")]
+    enable_admin_stdin: bool,
+
+    /// Aggregation worker tasks sharing the cache by the hash of the aggregation key. Defaults to
+    /// the number of assigned partitions, or the number of CPUs without a manual assignment.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_WORKERS")]
+    workers: Option<usize>,
+
+    /// Take up to this many already fetched Kafka messages per wakeup and decode them before the
+    /// flush condition is checked again.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 100,
+        env = "KAFKA_DUMP_KAFKA_PREFETCH_MESSAGES"
+    )]
+    kafka_prefetch_messages: usize,
+
+    /// Wait up to this long for more messages, until `--kafka-prefetch-messages` are taken, before
+    /// the chunk is processed. Zero only takes the messages that are already fetched.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        env = "KAFKA_DUMP_KAFKA_CONSUMER_LINGER_MS"
+    )]
+    kafka_consumer_linger_ms: u64,
+
+    /// Skip flows with fewer packets, e.g. single packet probes and keepalives. 1 keeps every
+    /// flow.
+    #[clap(
+        long,
+        alias = "packet-count-threshold",
+        value_parser,
+        default_value_t = 1,
+        env = "KAFKA_DUMP_MIN_PACKETS"
+    )]
+    min_packets: u64,
+
+    /// Skip flows with fewer bytes.
+    #[clap(
+        long,
+        alias = "min-flow-bytes",
+        value_parser,
+        default_value_t = 0,
+        env = "KAFKA_DUMP_MIN_BYTES"
+    )]
+    min_bytes: u64,
 
+    /// Skip flows with more packets, e.g. anomalous elephant flows that would distort the
+    /// aggregates.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_MAX_PACKETS")]
+    max_packets: Option<u64>,
+
+    /// Skip flows with more bytes.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_MAX_BYTES")]
+    max_bytes: Option<u64>,
+
+    /// Adapt the flush threshold to the consumer lag reported by the Kafka statistics: halve
+    /// `--batch-size` while the lag exceeds `--high-lag-threshold` messages and double it, up to
+    /// this many bytes, while it stays below `--low-lag-threshold`. Requires
+    /// `--kafka-stats-interval-ms`.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_LAG_BATCH_MAX_SIZE")]
+    lag_batch_max_size: Option<usize>,
+
+    /// Consumer lag in messages above which the flush threshold is halved.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 10000,
+        env = "KAFKA_DUMP_HIGH_LAG_THRESHOLD"
+    )]
+    high_lag_threshold: u64,
+
+    /// Consumer lag in messages below which the flush threshold doubles.
     #[clap(
         long,
         value_parser,
+        default_value_t = 100,
+        env = "KAFKA_DUMP_LOW_LAG_THRESHOLD"
+    )]
+    low_lag_threshold: u64,
+
+    /// Aggregate only flows of these IP protocols, given as numbers or names, e.g. `tcp,udp` or
+    /// `6,17`. Keeps every protocol when empty.
+    #[clap(
+        long,
+        value_parser = parse_proto,
         value_delimiter = ',',
-        env = "KAFKA_DUMP_CIDR_LIST",
-        required = true
+        env = "KAFKA_DUMP_INCLUDE_PROTOS"
     )]
-    cidr_list: Vec<IpCidr>,
+    include_protos: Vec<u32>,
 
-    #[clap(long, value_parser, env = "KAFKA_DUMP_BATCH_SIZE")]
-    batch_size: usize,
+    /// Aggregate only flows whose source or destination VLAN is one of these, e.g. `10,20`.
+    /// Keeps every VLAN when empty. Reloaded on SIGHUP with `--config-reload-on-sighup`.
+    #[clap(long, value_delimiter = ',', env = "KAFKA_DUMP_INCLUDE_VLANS")]
+    include_vlans: Vec<u32>,
+
+    /// Aggregate only flows the collector received with this export protocol. Keeps every
+    /// protocol when unset.
+    #[clap(long, value_enum, env = "KAFKA_DUMP_FLOW_VERSION")]
+    flow_version: Option<FlowVersion>,
+
+    /// Aggregate ICMP and ICMPv6 flows by their type and code and write them as the `icmp_type`
+    /// and `icmp_code` tags, e.g. `echo-request` or `port-unreachable`.
+    #[clap(long, env = "KAFKA_DUMP_INCLUDE_ICMP_DETAIL")]
+    include_icmp_detail: bool,
+
+    /// Aggregate by an application label derived from the protocol, the ports and optionally the
+    /// addresses, written as the `app` tag. The TOML file lists `[[rule]]` tables with `app`,
+    /// `proto`, `ports` and `cidr` evaluated in order, unmatched flows are `other`. Re-read on
+    /// SIGHUP.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_CLASSIFICATION_RULES")]
+    classification_rules: Option<PathBuf>,
+
+    /// Aggregate by the `app` tag using the built-in rules for dns, https, http, ssh and ntp.
+    /// Implied by `--classification-rules`.
+    #[clap(long, env = "KAFKA_DUMP_CLASSIFY")]
+    classify: bool,
+
+    /// Configuration file, a `.toml` file or an env file with `KAFKA_DUMP_*` variables, re-read
+    /// on SIGHUP with `--config-reload-on-sighup`.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_CONFIG_FILE")]
+    config_file: Option<PathBuf>,
+
+    /// Apply `--batch-size`, `--time-alignment-seconds`, `--include-vlans` and the inside CIDRs
+    /// of `--config-file`, or of the command line and the environment without one, on SIGHUP
+    /// without a restart. Changes of other settings are logged once and need a restart.
+    #[clap(long, env = "KAFKA_DUMP_CONFIG_RELOAD_ON_SIGHUP")]
+    config_reload_on_sighup: bool,
+
+    /// Where SIGUSR1 dumps the cache as JSON without flushing it. The dump time is inserted
+    /// before the extension, so repeated dumps do not overwrite each other.
+    #[clap(
+        long,
+        value_parser,
+        default_value = "/tmp/flow-cache-dump.json",
+        env = "KAFKA_DUMP_DUMP_CACHE_PATH"
+    )]
+    dump_cache_path: PathBuf,
+
+    /// Also write the number of distinct peers of each inside host as `unique_peers` into the
+    /// `--host-rollup` measurement. Exact up to 64 peers, a HyperLogLog with about 3% standard
+    /// error above, at most about 1 KiB per host, bucket and direction.
+    #[clap(long, requires = "host_rollup", env = "KAFKA_DUMP_PEER_CARDINALITY")]
+    peer_cardinality: bool,
+
+    /// Aggregate by the IP version as well. The `ip_version` tag is written without it whenever
+    /// an endpoint is inside, with it also between two outside endpoints.
+    #[clap(long, env = "KAFKA_DUMP_INCLUDE_IP_VERSION")]
+    include_ip_version: bool,
+
+    /// Skip transit flows whose source and target are both outside.
+    #[clap(
+        long,
+        alias = "skip-outside-to-outside",
+        env = "KAFKA_DUMP_SKIP_EXTERNAL_FLOWS"
+    )]
+    skip_external_flows: bool,
+
+    /// Skip flows whose source and target are both inside, keeping only the edge traffic.
+    #[clap(long, env = "KAFKA_DUMP_SKIP_INTERNAL_FLOWS")]
+    skip_internal_flows: bool,
+
+    /// When the final flush of the cache on SIGTERM and SIGINT fails, the cache and the consumer
+    /// positions are saved into this file, and the next start loads and deletes it. The batch of
+    /// the failed flush is saved with the sinks that already accepted it, only the others get
+    /// it after the restart.
+    #[clap(long, value_parser, env = "KAFKA_DUMP_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Keep flows with one unparsable address, aggregating that endpoint as `unknown` instead of
+    /// skipping the flow.
+    #[clap(long, env = "KAFKA_DUMP_ALLOW_PARTIAL_FLOWS")]
+    allow_partial_flows: bool,
+
+    /// Give up connecting to Influx after this many seconds, within `--influxdb-timeout-secs`.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        env = "KAFKA_DUMP_INFLUXDB_CONNECT_TIMEOUT_SECONDS"
+    )]
+    influxdb_connect_timeout_seconds: Option<u64>,
+
+    /// Process only the messages whose key matches this regex, before their payload is decoded.
+    /// Keys are matched as UTF-8, or as lowercase hex when they are not valid UTF-8. A missing
+    /// key is matched as the empty string, so `^$` selects the messages without a key.
+    #[clap(long, value_parser = parse_regex, env = "KAFKA_DUMP_KEY_FILTER")]
+    key_filter: Option<Regex>,
+
+    /// Whether `--key-filter` selects the processed or the skipped messages.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = KeyFilterMode::Include,
+        requires = "key_filter",
+        env = "KAFKA_DUMP_KEY_FILTER_MODE"
+    )]
+    key_filter_mode: KeyFilterMode,
+
+    /// Drop messages larger than this many bytes before decoding them. Also limits the fetch
+    /// requests of the consumer accordingly.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..=1_000_000_000),
+        default_value_t = 10 * 1024 * 1024,
+        env = "KAFKA_DUMP_KAFKA_MAX_MESSAGE_BYTES"
+    )]
+    kafka_max_message_bytes: u64,
+
+    /// Header in front of the protobuf payload of every message.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = PayloadEnvelope::None,
+        env = "KAFKA_DUMP_PAYLOAD_ENVELOPE"
+    )]
+    payload_envelope: PayloadEnvelope,
+
+    /// Accept only these schema ids of the `confluent` envelope, e.g. `12,13`.
+    #[clap(
+        long,
+        value_parser,
+        value_delimiter = ',',
+        env = "KAFKA_DUMP_ALLOWED_SCHEMA_IDS"
+    )]
+    allowed_schema_ids: Vec<u32>,
+
+    /// Compression of the payload applied by the producer, independent of the Kafka compression.
+    /// `auto` detects zstd and framed snappy by their magic bytes and reads anything else as is.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = PayloadCompression::None,
+        env = "KAFKA_DUMP_PAYLOAD_COMPRESSION"
+    )]
+    payload_compression: PayloadCompression,
+
+    /// Skip compressed payloads that decompress to more than this many bytes.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..=1_000_000_000),
+        default_value_t = 10 * 1024 * 1024,
+        env = "KAFKA_DUMP_MAX_DECOMPRESSED_BYTES"
+    )]
+    max_decompressed_bytes: u64,
+
+    /// Pause the assigned partitions while background Influx writes are in flight and the cache
+    /// holds more than 80% of the batch size. Resumed once the writes finished.
+    #[clap(long, env = "KAFKA_DUMP_KAFKA_BACKPRESSURE")]
+    kafka_backpressure: bool,
+
+    /// Write the flushed aggregates as newline-delimited JSON to stdout instead of Influx, one
+    /// record per line with the schema of `--output-topic`. The logs go to stderr then.
+    #[clap(long, env = "KAFKA_DUMP_OUTPUT_NDJSON")]
+    output_ndjson: bool,
+
+    /// Append the NDJSON records to `<path>.<YYYY-MM-DD>` of the current UTC day instead of
+    /// stdout.
+    #[clap(
+        long,
+        value_parser,
+        requires = "output_ndjson",
+        env = "KAFKA_DUMP_OUTPUT_NDJSON_FILE"
+    )]
+    output_ndjson_file: Option<PathBuf>,
+
+    /// Write into Influx in addition to the NDJSON output.
+    #[clap(long, requires = "output_ndjson", env = "KAFKA_DUMP_OUTPUT_BOTH")]
+    output_both: bool,
+
+    /// After partitions were revoked, wait up to this long for the flush of the whole cache to be
+    /// written before the offsets are committed again. This bounds the retries of the inline
+    /// write as well as the background writes, each attempt is bounded by the Influx timeout. A
+    /// timeout is logged and the writes go on.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 5000,
+        env = "KAFKA_DUMP_REBALANCE_FLUSH_TIMEOUT_MS"
+    )]
+    rebalance_flush_timeout_ms: u64,
+
+    /// After SIGTERM or SIGINT, wait up to this long for the final flush and the background
+    /// writes. A batch still failing afterwards is written into `--failed-batch-dir` or dropped,
+    /// the background writes are aborted. With `--state-file` the first failure saves the cache
+    /// instead.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 30,
+        env = "KAFKA_DUMP_SHUTDOWN_TIMEOUT_SECS"
+    )]
+    shutdown_timeout_secs: u64,
+
+    /// Table every aggregation worker keeps its shard of the cache in. `sharded` splits it further
+    /// into `--store-shards` tables, which are grown and drained one by one.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = AggregationStoreKind::HashMap,
+        env = "KAFKA_DUMP_AGGREGATION_STORE"
+    )]
+    aggregation_store: AggregationStoreKind,
+
+    /// Tables per worker with `--aggregation-store sharded`.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..=4096),
+        default_value_t = 16,
+        env = "KAFKA_DUMP_STORE_SHARDS"
+    )]
+    store_shards: u64,
+
+    /// Common names of topics carrying the same data, e.g. `sflow-dc1=sflow,sflow-dc2=sflow`. An
+    /// aliased topic is written into the measurement of its alias in `--topic-measurement-map`,
+    /// or into the alias itself, unless the topic has a measurement of its own. Its points are
+    /// tagged with the original topic as `src_datacenter`, so the topics stay apart.
+    #[clap(
+        long,
+        value_parser = parse_topic_alias,
+        value_delimiter = ',',
+        env = "KAFKA_DUMP_TOPIC_ALIAS"
+    )]
+    topic_alias: Vec<(String, String)>,
+
+    /// Exit once Influx rejects the credentials with 401 or 403 and no new token could be read
+    /// from `--influxdb-token-file`, instead of retrying with the same token.
+    #[clap(long, env = "KAFKA_DUMP_INFLUXDB_EXIT_ON_AUTH_ERROR")]
+    influxdb_exit_on_auth_error: bool,
+}
+
+fn parse_interface_name(value: &str) -> Result<(u32, String), String> {
+    let (index, name) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<ifIndex>=<name>`, got `{value}`"))?;
+    let index = index
+        .trim()
+        .parse()
+        .map_err(|error| format!("invalid ifIndex `{index}`: {error}"))?;
+
+    Ok((index, name.trim().to_owned()))
+}
+
+fn parse_regex(value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|error| error.to_string())
+}
+
+fn parse_proto(value: &str) -> Result<u32, String> {
+    let value = value.trim();
+    value
+        .parse()
+        .ok()
+        .or_else(|| crate::util::proto_number(value))
+        .ok_or_else(|| format!("unknown IP protocol `{value}`"))
+}
+
+fn parse_topic_measurement(value: &str) -> Result<(String, String), String> {
+    let (topic, measurement) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<topic>=<measurement>`, got `{value}`"))?;
+
+    Ok((topic.trim().to_owned(), measurement.trim().to_owned()))
+}
+
+fn parse_topic_alias(value: &str) -> Result<(String, String), String> {
+    let (topic, alias) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<topic>=<alias>`, got `{value}`"))?;
+    let (topic, alias) = (topic.trim(), alias.trim());
+    if topic.is_empty() || alias.is_empty() {
+        return Err(format!("empty topic or alias in `{value}`"));
+    }
+
+    Ok((topic.to_owned(), alias.to_owned()))
+}
+
+fn parse_extra_tag(value: &str) -> Result<(String, String), String> {
+    let (tag, tag_value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<tag>=<value>`, got `{value}`"))?;
+    let (tag, tag_value) = (tag.trim(), tag_value.trim());
+    if tag.is_empty() || tag_value.is_empty() {
+        return Err(format!("empty tag or value in `{value}`"));
+    }
+    // Influx reserves the names starting with `_` and rejects a `time` tag.
+    if tag.starts_with('_') || tag == "time" {
+        return Err(format!("tag `{tag}` is reserved by Influx"));
+    }
+    if tag.chars().chain(tag_value.chars()).any(char::is_control) {
+        return Err(format!("control character in `{value}`"));
+    }
+
+    Ok((tag.to_owned(), tag_value.to_owned()))
+}
+
+fn parse_partition_assignment(value: &str) -> Result<(String, Vec<i32>), String> {
+    let (topic, partitions) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected `<topic>:<partition>,...`, got `{value}`"))?;
+    let partitions = partitions
+        .split(',')
+        .map(|partition| {
+            partition
+                .trim()
+                .parse()
+                .map_err(|error| format!("invalid partition `{partition}`: {error}"))
+        })
+        .collect::<Result<Vec<i32>, String>>()?;
+
+    Ok((topic.trim().to_owned(), partitions))
 }
 
 impl TryFrom<ConfigArgs> for Config {
@@ -88,24 +1915,443 @@ impl TryFrom<ConfigArgs> for Config {
             group_id,
             topics,
             brokers,
+            kafka_offset_reset,
+            log_format,
+            kafka_security_protocol,
+            kafka_ssl_endpoint_identification_algorithm,
+            kafka_ssl_cipher_suites,
+            kafka_sasl_mechanism,
+            kafka_sasl_username,
+            kafka_sasl_password,
+            kafka_stats_interval_ms,
             influxdb_token,
+            influxdb_token_file,
             influxdb_endpoint,
             influxdb_bucket,
             influxdb_org,
+            influxdb_timeout_secs,
+            influxdb_precision,
+            influxdb_ca_cert,
+            influxdb_accept_invalid_certs,
             cidr_list,
+            cidr_file,
+            cidr_file_reload_interval_seconds,
+            cidr_groups,
             batch_size,
+            adaptive_batch_max_size,
+            adaptive_batch_lag_threshold_secs,
+            max_cache_memory_bytes,
+            max_unique_sources,
+            max_unique_targets,
+            time_alignment_seconds,
+            bucket_timestamp,
+            rollup_alignment_seconds,
+            rollup_measurement,
+            influxdb_measurement,
+            influxdb_tags_extra,
+            topic_measurement_map,
+            rollup_bucket,
+            rollup_dimensions,
+            src_prefix_len,
+            dst_prefix_len,
+            idle_timeout_secs,
+            idle_action,
+            watchdog_interval_seconds,
+            flush_grace_secs,
+            output_topic,
+            unix_socket_path,
+            dead_letter_topic,
+            batch_id_strategy,
+            batch_number_file,
+            postgres_url,
+            postgres_table,
+            sqlite_recovery_path,
+            postgres_max_connections,
+            max_message_age_seconds,
+            max_received_age_secs,
+            max_future_skew_secs,
+            future_timestamp_action,
+            min_timestamp,
+            influxdb_max_retries,
+            influx_write_concurrency,
+            influxdb_max_retry_wait_seconds,
+            failed_batch_dir,
+            failed_batch_dir_max_bytes,
+            stats_history_minutes,
+            stats_ema_alpha,
+            metrics_listen,
+            otlp_endpoint,
+            replay_file,
+            replay_speed,
+            record_file,
+            record_file_max_bytes,
+            include_interfaces,
+            interface_names,
+            track_mpls,
+            include_exporter,
+            include_tcp_flags,
+            include_dscp,
+            bidirectional,
+            track_observation_time,
+            track_variance,
+            geo_ip_database,
+            host_rollup,
+            kafka_partition_assignment,
+            expected_partition_count,
+            skip_startup_checks,
+            enable_admin_stdin,
+            workers,
+            kafka_prefetch_messages,
+            kafka_consumer_linger_ms,
+            min_packets,
+            min_bytes,
+            max_packets,
+            max_bytes,
+            lag_batch_max_size,
+            high_lag_threshold,
+            low_lag_threshold,
+            include_protos,
+            include_vlans,
+            flow_version,
+            include_icmp_detail,
+            classification_rules,
+            classify,
+            config_file,
+            config_reload_on_sighup,
+            dump_cache_path,
+            peer_cardinality,
+            include_ip_version,
+            skip_external_flows,
+            skip_internal_flows,
+            state_file,
+            allow_partial_flows,
+            influxdb_connect_timeout_seconds,
+            key_filter,
+            key_filter_mode,
+            kafka_max_message_bytes,
+            payload_envelope,
+            allowed_schema_ids,
+            payload_compression,
+            max_decompressed_bytes,
+            kafka_backpressure,
+            output_ndjson,
+            output_ndjson_file,
+            output_both,
+            rebalance_flush_timeout_ms,
+            shutdown_timeout_secs,
+            aggregation_store,
+            store_shards,
+            topic_alias,
+            influxdb_exit_on_auth_error,
         } = value;
 
+        let influxdb_token = match (influxdb_token, &influxdb_token_file) {
+            (Some(token), None) => Secret(token),
+            (None, Some(path)) => read_token_file(path)?,
+            _ => {
+                anyhow::bail!(
+                    "Exactly one of `--influxdb-token` and `--influxdb-token-file` is required."
+                )
+            },
+        };
+        let cidr_list_static = cidr_list;
+        let cidr_list = match &cidr_file {
+            Some(path) => merge_cidrs(&cidr_list_static, read_cidr_file(path)?),
+            None => cidr_list_static.clone(),
+        };
+        if cidr_list.is_empty() {
+            anyhow::bail!("No inside CIDR given in `--cidr-list` or `--cidr-file`.");
+        }
+        let cidr_groups = cidr_groups
+            .as_deref()
+            .map(read_cidr_groups)
+            .transpose()?
+            .unwrap_or_default();
+
+        if adaptive_batch_max_size.is_some_and(|max_size| max_size < batch_size) {
+            anyhow::bail!("Adaptive batch max size is smaller than the batch size.");
+        }
+        if influx_write_concurrency == 0 {
+            anyhow::bail!("Influx write concurrency must be at least 1.");
+        }
+        // The table name is interpolated into the SQL statements.
+        if !postgres_table
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            anyhow::bail!("Invalid Postgres table name `{postgres_table}`.");
+        }
+        if let Some(rollup_alignment_seconds) = rollup_alignment_seconds {
+            if rollup_alignment_seconds % time_alignment_seconds != 0 {
+                anyhow::bail!(
+                    "Rollup alignment {rollup_alignment_seconds}s is not a multiple of the time \
+                     alignment {time_alignment_seconds}s."
+                );
+            }
+        }
+        if kafka_ssl_endpoint_identification_algorithm == EndpointIdentification::None
+            && !kafka_security_protocol.uses_tls()
+        {
+            anyhow::bail!(
+                "Disabling the SSL endpoint identification requires the `ssl` or `sasl-ssl` \
+                 security protocol."
+            );
+        }
+        if kafka_security_protocol.uses_sasl()
+            && (kafka_sasl_username.is_none() || kafka_sasl_password.is_none())
+        {
+            anyhow::bail!(
+                "The `sasl-plaintext` and `sasl-ssl` security protocols require \
+                 `--kafka-sasl-username` and `--kafka-sasl-password`."
+            );
+        }
+        if !kafka_security_protocol.uses_sasl()
+            && (kafka_sasl_username.is_some() || kafka_sasl_password.is_some())
+        {
+            anyhow::bail!(
+                "SASL credentials require the `sasl-plaintext` or `sasl-ssl` security protocol."
+            );
+        }
+        if !(stats_ema_alpha > 0.0 && stats_ema_alpha <= 1.0) {
+            anyhow::bail!("Stats EMA alpha {stats_ema_alpha} is not in (0, 1].");
+        }
+        for (index, (tag, _)) in influxdb_tags_extra.iter().enumerate() {
+            if crate::influx::STANDARD_TAGS.contains(&tag.as_str()) {
+                anyhow::bail!("Extra tag `{tag}` conflicts with a standard tag.");
+            }
+            if influxdb_tags_extra
+                .iter()
+                .skip(index + 1)
+                .any(|(other, _)| other == tag)
+            {
+                anyhow::bail!("Extra tag `{tag}` is given more than once.");
+            }
+        }
+        if kafka_prefetch_messages == 0 {
+            anyhow::bail!("Kafka prefetch must take at least one message.");
+        }
+        if workers == Some(0) {
+            anyhow::bail!("At least one worker is required.");
+        }
+        let workers = workers.unwrap_or_else(|| {
+            let assigned: usize = kafka_partition_assignment
+                .iter()
+                .map(|(_, partitions)| partitions.len())
+                .sum();
+            if assigned > 0 {
+                assigned
+            } else {
+                std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+            }
+        });
+        if expected_partition_count == Some(0) {
+            anyhow::bail!("Expected partition count must be at least 1.");
+        }
+        for (topic, _) in &kafka_partition_assignment {
+            if !topics.contains(topic) {
+                anyhow::bail!("Assigned topic `{topic}` is not listed in `--topics`.");
+            }
+        }
+
+        if max_packets.is_some_and(|max_packets| max_packets < min_packets) {
+            anyhow::bail!("Maximum packets are below the minimum of {min_packets}.");
+        }
+        if max_bytes.is_some_and(|max_bytes| max_bytes < min_bytes) {
+            anyhow::bail!("Maximum bytes are below the minimum of {min_bytes}.");
+        }
+        if let Some(lag_batch_max_size) = lag_batch_max_size {
+            if lag_batch_max_size < batch_size {
+                anyhow::bail!("Lag batch max size is smaller than the batch size.");
+            }
+            if adaptive_batch_max_size.is_some() {
+                anyhow::bail!(
+                    "`--lag-batch-max-size` and `--adaptive-batch-max-size` cannot be combined."
+                );
+            }
+            if kafka_stats_interval_ms == 0 {
+                anyhow::bail!("`--lag-batch-max-size` requires `--kafka-stats-interval-ms`.");
+            }
+            if low_lag_threshold >= high_lag_threshold {
+                anyhow::bail!("Low lag threshold must be below the high lag threshold.");
+            }
+        }
+        if let Some(path) = &classification_rules {
+            crate::classify::Classifier::from_file(path)?;
+        }
+        if !allowed_schema_ids.is_empty() && payload_envelope != PayloadEnvelope::Confluent {
+            anyhow::bail!("Allowed schema ids require the `confluent` payload envelope.");
+        }
+        if kafka_backpressure && influx_write_concurrency < 2 {
+            anyhow::bail!(
+                "Kafka backpressure requires an Influx write concurrency of at least 2, inline \
+                 writes already hold up the consumption."
+            );
+        }
+        if output_ndjson && output_ndjson_file.is_none() && enable_admin_stdin {
+            anyhow::bail!(
+                "NDJSON output to stdout cannot be combined with the admin stdin, set an NDJSON \
+                 output file."
+            );
+        }
+        if output_ndjson && !output_both && rollup_alignment_seconds.is_some() {
+            anyhow::bail!("Rollups are only written into Influx, add `--output-both`.");
+        }
+        let mut topic_measurement_map: HashMap<String, Arc<str>> = topic_measurement_map
+            .into_iter()
+            .map(|(topic, measurement)| (topic, Arc::from(measurement)))
+            .collect();
+        // Resolved once, so the consume loop only looks up the topic.
+        let aliased: Vec<(String, Arc<str>)> = topic_alias
+            .iter()
+            .filter(|(topic, _)| !topic_measurement_map.contains_key(topic))
+            .map(|(topic, alias)| {
+                let measurement = topic_measurement_map
+                    .get(alias)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::from(alias.as_str()));
+                (topic.clone(), measurement)
+            })
+            .collect();
+        topic_measurement_map.extend(aliased);
+        let topic_datacenters = topic_alias
+            .iter()
+            .map(|(topic, _)| (topic.clone(), Arc::from(topic.as_str())))
+            .collect();
         Ok(Self {
             group_id,
             topics,
             brokers,
+            kafka_offset_reset,
+            log_format,
+            kafka_security_protocol,
+            kafka_ssl_endpoint_identification_algorithm,
+            kafka_ssl_cipher_suites,
+            kafka_sasl_mechanism,
+            kafka_sasl_username,
+            kafka_sasl_password: kafka_sasl_password.map(Secret),
+            kafka_stats_interval_ms,
             influxdb_token,
+            influxdb_token_file,
             influxdb_endpoint,
             influxdb_bucket,
             batch_size,
+            adaptive_batch_max_size,
+            adaptive_batch_lag_threshold: Duration::from_secs(adaptive_batch_lag_threshold_secs),
+            max_cache_memory_bytes,
+            max_unique_sources,
+            max_unique_targets,
             cidr_list,
+            cidr_list_static,
+            cidr_file,
+            cidr_file_reload_interval: cidr_file_reload_interval_seconds.map(Duration::from_secs),
+            cidr_groups,
+            time_alignment_seconds,
+            bucket_timestamp,
+            rollup_alignment_seconds,
+            rollup_measurement,
+            influxdb_measurement,
+            influxdb_tags_extra,
+            topic_measurement_map,
+            rollup_bucket,
+            rollup_dimensions,
+            src_prefix_len,
+            dst_prefix_len,
+            idle_timeout: idle_timeout_secs.map(Duration::from_secs),
+            idle_action,
+            watchdog_interval: (watchdog_interval_seconds > 0)
+                .then(|| Duration::from_secs(watchdog_interval_seconds)),
+            flush_grace: Duration::from_secs(flush_grace_secs),
+            output_topic,
+            unix_socket_path,
+            dead_letter_topic,
+            batch_id_strategy,
+            batch_number_file,
+            postgres_url: postgres_url.map(Secret),
+            postgres_table,
+            sqlite_recovery_path,
+            postgres_max_connections,
+            max_message_age: max_message_age_seconds.map(Duration::from_secs),
+            max_received_age: max_received_age_secs.map(Duration::from_secs),
+            max_future_skew: max_future_skew_secs.map(Duration::from_secs),
+            future_timestamp_action,
+            min_timestamp,
+            influxdb_max_retries,
+            influx_write_concurrency,
+            influxdb_max_retry_wait: Duration::from_secs(influxdb_max_retry_wait_seconds),
+            failed_batch_dir,
+            failed_batch_dir_max_bytes,
+            stats_history_minutes,
+            stats_ema_alpha,
+            metrics_listen,
+            otlp_endpoint,
+            replay_file,
+            replay_speed,
+            record_file,
+            record_file_max_bytes,
+            include_interfaces,
+            interface_names: interface_names.into_iter().collect(),
+            track_mpls,
+            include_exporter,
+            include_tcp_flags,
+            include_dscp,
+            bidirectional,
+            track_observation_time,
+            track_variance,
+            geo_ip_database,
+            host_rollup,
+            partition_assignment: kafka_partition_assignment,
+            expected_partition_count,
+            skip_startup_checks,
+            enable_admin_stdin,
+            workers,
+            kafka_prefetch_messages,
+            kafka_consumer_linger: Duration::from_millis(kafka_consumer_linger_ms),
+            min_packets,
+            min_bytes,
+            max_packets,
+            max_bytes,
+            lag_batch_max_size,
+            high_lag_threshold,
+            low_lag_threshold,
+            include_protos,
+            include_vlans,
+            flow_version,
+            include_icmp_detail,
+            classification_rules,
+            classify,
+            config_file,
+            config_reload_on_sighup,
+            dump_cache_path,
+            peer_cardinality,
+            include_ip_version,
+            skip_external_flows,
+            skip_internal_flows,
+            state_file,
+            allow_partial_flows,
+            influxdb_connect_timeout: influxdb_connect_timeout_seconds.map(Duration::from_secs),
+            key_filter,
+            key_filter_mode,
+            kafka_max_message_bytes: usize::try_from(kafka_max_message_bytes)?,
+            payload_envelope,
+            allowed_schema_ids,
+            payload_compression,
+            max_decompressed_bytes: usize::try_from(max_decompressed_bytes)?,
+            kafka_backpressure,
+            output_ndjson,
+            output_ndjson_file,
+            output_both,
+            rebalance_flush_timeout: Duration::from_millis(rebalance_flush_timeout_ms),
+            shutdown_timeout: Duration::from_secs(shutdown_timeout_secs),
+            aggregation_store,
+            store_shards: usize::try_from(store_shards)?,
+            topic_alias: topic_alias.into_iter().collect(),
+            topic_datacenters,
+            influxdb_exit_on_auth_error,
             influxdb_org,
+            influxdb_timeout: Duration::from_secs(influxdb_timeout_secs),
+            influxdb_precision,
+            influxdb_ca_cert,
+            influxdb_accept_invalid_certs,
         })
     }
 }
@@ -132,3 +2378,107 @@ const fn long_version() -> &'static str {
         env!("VERGEN_CARGO_PROFILE"),
     )
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Configuration of the required arguments followed by `args`.
+    pub(crate) fn config(args: &[&str]) -> Config {
+        let required = [
+            "lpa",
+            "--group-id=test",
+            "--topics=flows",
+            "--brokers=localhost:9092",
+            "--influxdb-endpoint=http://localhost:8086",
+            "--influxdb-bucket=flows",
+            "--influxdb-org=org",
+            "--cidr-list=10.0.0.0/8",
+            "--batch-size=1000000",
+        ];
+        // The token may only be given once.
+        let token = (!args.iter().any(|arg| arg.starts_with("--influxdb-token=")))
+            .then_some("--influxdb-token=token");
+        let args = ConfigArgs::try_parse_from(
+            required
+                .into_iter()
+                .chain(token)
+                .chain(args.iter().copied()),
+        )
+        .unwrap();
+        Config::try_from(args).unwrap()
+    }
+
+    #[test]
+    fn short_secrets_are_redacted() {
+        let config = config(&[
+            "--influxdb-token=s3cr3t",
+            "--postgres-url=postgres://db",
+            "--kafka-security-protocol=sasl-ssl",
+            "--kafka-sasl-username=lpa",
+            "--kafka-sasl-password=hunter2",
+        ]);
+        let debug = format!("{config:?}");
+
+        for secret in ["s3cr3t", "postgres://db", "hunter2"] {
+            assert!(!debug.contains(secret), "{secret}");
+        }
+        assert_eq!(format!("{:?}", config.influxdb_token), "\"<redacted>\"");
+    }
+
+    #[test]
+    fn long_secrets_show_only_their_ends() {
+        let token = "0123456789abcdef-influx-token";
+        let config = config(&[&format!("--influxdb-token={token}")]);
+        let debug = format!("{config:?}");
+
+        assert!(!debug.contains(token));
+        assert!(!debug.contains("0123456789"));
+        assert!(debug.contains("\"012...ken\""));
+    }
+
+    /// Configuration of an env file with the required settings followed by `lines`.
+    fn env_file_config(name: &str, lines: &[&str]) -> Config {
+        let required = [
+            "KAFKA_DUMP_GROUP_ID=test",
+            "KAFKA_DUMP_TOPICS=flows",
+            "KAFKA_DUMP_BROKERS=localhost:9092",
+            "KAFKA_DUMP_INFLUXDB_TOKEN=token",
+            "KAFKA_DUMP_INFLUXDB_ENDPOINT=http://localhost:8086",
+            "KAFKA_DUMP_INFLUXDB_BUCKET=flows",
+            "KAFKA_DUMP_INFLUXDB_ORG=org",
+            "KAFKA_DUMP_CIDR_LIST=10.0.0.0/8",
+            "KAFKA_DUMP_BATCH_SIZE=1000000",
+        ];
+        let path =
+            std::env::temp_dir().join(format!("lpa-config-{name}-{}.env", std::process::id()));
+        let content: Vec<&str> = required.iter().chain(lines).copied().collect();
+        std::fs::write(&path, content.join("\n")).unwrap();
+        let config = Config::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        config.unwrap()
+    }
+
+    #[test]
+    fn write_timeout_env_alias_is_accepted() {
+        let config = env_file_config(
+            "write-timeout",
+            &["KAFKA_DUMP_INFLUXDB_WRITE_TIMEOUT_SECONDS=5"],
+        );
+
+        assert_eq!(config.influxdb_timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn extra_tags_env_alias_is_accepted() {
+        let config = env_file_config("extra-tags", &["KAFKA_DUMP_EXTRA_TAGS=site=prg,env=prod"]);
+
+        assert_eq!(
+            config.influxdb_tags_extra,
+            [
+                ("site".to_owned(), "prg".to_owned()),
+                ("env".to_owned(), "prod".to_owned())
+            ]
+        );
+    }
+}
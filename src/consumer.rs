@@ -0,0 +1,297 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc,
+        Mutex,
+        PoisonError,
+    },
+};
+
+use rdkafka::{
+    client::ClientContext,
+    consumer::{stream_consumer::StreamConsumer, Consumer, ConsumerContext, Rebalance},
+    error::KafkaResult,
+    statistics::Statistics,
+    topic_partition_list::TopicPartitionList,
+};
+
+// A context can be used to change the behavior of producers and consumers by adding callbacks
+// that will be executed by librdkafka. This particular context logs rebalancing events and counts
+// them, together with the offset commits, for the metrics endpoint. It also keeps the latest
+// librdkafka statistics when `--kafka-stats-interval-ms` enables them.
+//
+// The callbacks run inside `recv()` of the main loop, so they cannot wait for the loop to flush.
+// The offsets are not stored on delivery but once a drain of the whole cache containing their
+// messages is written, so neither the automatic commits nor the one librdkafka makes on a
+// revocation cover unwritten aggregates. A revocation only raises `revoke_pending` and proceeds.
+// Once the already received messages are aggregated, the loop flushes the whole cache, waits for
+// the writes and commits their offsets. The delivery is at-least-once: the new owner of the
+// partitions, or the next run after a crash, consumes the messages since the last commit again
+// and they are counted twice.
+#[derive(Clone, Default)]
+pub struct FlowConsumerContext {
+    pub rebalance_assign_count: Arc<AtomicU64>,
+    pub rebalance_revoke_count: Arc<AtomicU64>,
+    pub commit_success_count: Arc<AtomicU64>,
+    pub commit_failure_count: Arc<AtomicU64>,
+    /// Unix time of the latest assignment or revocation, zero before the first one.
+    pub last_rebalance_timestamp: Arc<AtomicI64>,
+    /// Partitions were revoked and the cache has not been flushed since.
+    pub revoke_pending: Arc<AtomicBool>,
+    /// Messages waiting in the librdkafka queues.
+    pub stats_msg_cnt: Arc<AtomicU64>,
+    /// Requests waiting for a response.
+    pub stats_replyq: Arc<AtomicI64>,
+    /// Bytes received from all brokers.
+    pub stats_rx_bytes: Arc<AtomicU64>,
+    /// Messages behind the high watermark, summed over the partitions with a known lag.
+    pub stats_consumer_lag: Arc<AtomicI64>,
+    /// Partitions paused by `--kafka-backpressure`.
+    pub paused_partitions: Arc<Mutex<HashSet<(String, i32)>>>,
+}
+
+impl ClientContext for FlowConsumerContext {
+    fn stats(&self, statistics: Statistics) {
+        let rx_bytes = statistics
+            .brokers
+            .values()
+            .map(|broker| broker.rxbytes)
+            .sum();
+        for (name, broker) in &statistics.brokers {
+            tracing::debug!(
+                broker = name,
+                rx_bytes = broker.rxbytes,
+                outbuf_cnt = broker.outbuf_cnt,
+                waitresp_cnt = broker.waitresp_cnt,
+                rtt_avg_us = broker.rtt.as_ref().map(|rtt| rtt.avg),
+                "Kafka broker statistics."
+            );
+        }
+        tracing::debug!(
+            msg_cnt = statistics.msg_cnt,
+            replyq = statistics.replyq,
+            rx_bytes,
+            "Kafka client statistics."
+        );
+
+        self.stats_msg_cnt
+            .store(statistics.msg_cnt, Ordering::Relaxed);
+        self.stats_replyq
+            .store(statistics.replyq, Ordering::Relaxed);
+        self.stats_rx_bytes.store(rx_bytes, Ordering::Relaxed);
+
+        let mut consumer_lag = 0;
+        for (topic, topic_statistics) in &statistics.topics {
+            // The internal partition -1 holds messages not assigned to a partition yet.
+            for (partition, partition_statistics) in &topic_statistics.partitions {
+                if *partition < 0 || partition_statistics.consumer_lag < 0 {
+                    continue;
+                }
+                tracing::debug!(
+                    topic,
+                    partition,
+                    consumer_lag = partition_statistics.consumer_lag,
+                    "Kafka partition statistics."
+                );
+                consumer_lag += partition_statistics.consumer_lag;
+            }
+        }
+        self.stats_consumer_lag
+            .store(consumer_lag, Ordering::Relaxed);
+    }
+}
+
+impl ConsumerContext for FlowConsumerContext {
+    fn pre_rebalance(&self, rebalance: &Rebalance) {
+        tracing::info!("Pre rebalance {:?}", rebalance);
+        if let Rebalance::Revoke(partitions) = rebalance {
+            if partitions.count() > 0 {
+                self.revoke_pending.store(true, Ordering::Relaxed);
+            }
+            // A revoked partition is no longer paused, whoever is assigned it next.
+            let mut paused = self
+                .paused_partitions
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            for element in partitions.elements() {
+                paused.remove(&(element.topic().to_owned(), element.partition()));
+            }
+        }
+    }
+
+    fn post_rebalance(&self, rebalance: &Rebalance) {
+        tracing::info!("Post rebalance {:?}", rebalance);
+        let counter = match rebalance {
+            Rebalance::Assign(_) => &self.rebalance_assign_count,
+            Rebalance::Revoke(_) => &self.rebalance_revoke_count,
+            Rebalance::Error(_) => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.last_rebalance_timestamp
+            .store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    fn commit_callback(&self, result: KafkaResult<()>, _offsets: &TopicPartitionList) {
+        tracing::info!("Committing offsets: {:?}", result);
+        let counter = match result {
+            Ok(()) => &self.commit_success_count,
+            Err(_) => &self.commit_failure_count,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Pauses the assigned partitions while the background Influx writes cannot keep up, so the
+/// cache does not grow without bound. The paused partitions are tracked by the consumer context.
+pub struct BackpressureController {
+    pub context: FlowConsumerContext,
+}
+
+impl BackpressureController {
+    /// Fraction of the batch size above which the cache is considered near capacity.
+    const HIGH_WATERMARK: f64 = 0.8;
+
+    #[must_use]
+    pub fn is_paused(&self) -> bool {
+        !self
+            .context
+            .paused_partitions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn is_near_capacity(size_of_cache: usize, batch_size: usize) -> bool {
+        size_of_cache as f64 > Self::HIGH_WATERMARK * batch_size as f64
+    }
+
+    pub fn pause(&self, consumer: &LoggingConsumer) -> KafkaResult<()> {
+        let assignment = consumer.assignment()?;
+        if assignment.count() == 0 {
+            return Ok(());
+        }
+        consumer.pause(&assignment)?;
+        self.context
+            .paused_partitions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend(
+                assignment
+                    .elements()
+                    .iter()
+                    .map(|element| (element.topic().to_owned(), element.partition())),
+            );
+        tracing::warn!(
+            partitions = assignment.count(),
+            "Influx writes are behind, pausing the consumption."
+        );
+
+        Ok(())
+    }
+
+    pub fn resume(&self, consumer: &LoggingConsumer) -> KafkaResult<()> {
+        let mut paused = self
+            .context
+            .paused_partitions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut partitions = TopicPartitionList::new();
+        for (topic, partition) in paused.iter() {
+            partitions.add_partition(topic, *partition);
+        }
+        consumer.resume(&partitions)?;
+        tracing::info!(
+            partitions = paused.len(),
+            "Influx writes caught up, resuming the consumption."
+        );
+        paused.clear();
+
+        Ok(())
+    }
+
+    /// A recreated consumer starts with nothing paused.
+    pub fn forget(&self) {
+        self.context
+            .paused_partitions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+// A type alias with your custom consumer can be created for convenience.
+pub type LoggingConsumer = StreamConsumer<FlowConsumerContext>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partitions() -> TopicPartitionList {
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition("flows", 0);
+        partitions.add_partition("flows", 1);
+        partitions
+    }
+
+    #[test]
+    fn rebalances_are_counted() {
+        let context = FlowConsumerContext::default();
+
+        context.post_rebalance(&Rebalance::Assign(&partitions()));
+        context.post_rebalance(&Rebalance::Revoke(&partitions()));
+        context.post_rebalance(&Rebalance::Assign(&partitions()));
+
+        assert_eq!(context.rebalance_assign_count.load(Ordering::Relaxed), 2);
+        assert_eq!(context.rebalance_revoke_count.load(Ordering::Relaxed), 1);
+        assert!(context.last_rebalance_timestamp.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn revocations_request_a_flush_and_unpause() {
+        let context = FlowConsumerContext::default();
+        context
+            .paused_partitions
+            .lock()
+            .unwrap()
+            .extend([("flows".to_owned(), 0), ("flows".to_owned(), 2)]);
+
+        context.pre_rebalance(&Rebalance::Assign(&partitions()));
+        assert!(!context.revoke_pending.load(Ordering::Relaxed));
+        context.pre_rebalance(&Rebalance::Revoke(&partitions()));
+
+        assert!(context.revoke_pending.load(Ordering::Relaxed));
+        assert_eq!(
+            *context.paused_partitions.lock().unwrap(),
+            HashSet::from([("flows".to_owned(), 2)])
+        );
+    }
+
+    #[test]
+    fn empty_revocations_do_not_request_a_flush() {
+        let context = FlowConsumerContext::default();
+
+        context.pre_rebalance(&Rebalance::Revoke(&TopicPartitionList::new()));
+
+        assert!(!context.revoke_pending.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn commits_are_counted() {
+        let context = FlowConsumerContext::default();
+
+        context.commit_callback(Ok(()), &partitions());
+        context.commit_callback(Ok(()), &partitions());
+        context.commit_callback(
+            Err(rdkafka::error::KafkaError::ConsumerCommit(
+                rdkafka::types::RDKafkaErrorCode::RebalanceInProgress,
+            )),
+            &partitions(),
+        );
+
+        assert_eq!(context.commit_success_count.load(Ordering::Relaxed), 2);
+        assert_eq!(context.commit_failure_count.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use rdkafka::{
+    config::ClientConfig,
+    message::{Message, OwnedHeaders, OwnedMessage},
+    producer::{FutureProducer, FutureRecord},
+};
+
+/// Produces the raw payload of messages that could not be aggregated to
+/// `--dead-letter-topic`, together with headers describing the error and the original message.
+pub struct DeadLetters {
+    producer: FutureProducer,
+    topic: String,
+    /// Shared with the delivery tasks.
+    produced: Arc<AtomicU64>,
+    failed: Arc<AtomicU64>,
+}
+
+impl DeadLetters {
+    /// `client_config` carries the brokers and the security settings.
+    pub fn new(mut client_config: ClientConfig, topic: String) -> anyhow::Result<Self> {
+        let producer = client_config.set("message.timeout.ms", "30000").create()?;
+
+        Ok(Self {
+            producer,
+            topic,
+            produced: Arc::new(AtomicU64::new(0)),
+            failed: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    #[must_use]
+    pub fn produced(&self) -> u64 {
+        self.produced.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Enqueues the payload without waiting for the delivery, failures are only counted.
+    /// `origin` is the consumed message, `None` for replayed payloads.
+    pub fn send(&self, payload: &[u8], error: &anyhow::Error, origin: Option<&OwnedMessage>) {
+        let error = error.to_string();
+        let mut headers = OwnedHeaders::new().add("lpa-error", &error);
+        if let Some(origin) = origin {
+            headers = headers
+                .add("lpa-original-topic", origin.topic())
+                .add("lpa-original-partition", &origin.partition().to_string())
+                .add("lpa-original-offset", &origin.offset().to_string());
+        }
+        let record = FutureRecord::<(), [u8]>::to(&self.topic)
+            .payload(payload)
+            .headers(headers);
+
+        match self.producer.send_result(record) {
+            Ok(delivery) => {
+                let produced = self.produced.clone();
+                let failed = self.failed.clone();
+                tokio::spawn(async move {
+                    match delivery.await {
+                        Ok(Ok(_)) => {
+                            produced.fetch_add(1, Ordering::Relaxed);
+                        },
+                        Ok(Err((error, _))) => {
+                            tracing::warn!(%error, "Unable to deliver dead letter.");
+                            failed.fetch_add(1, Ordering::Relaxed);
+                        },
+                        Err(_) => {
+                            tracing::warn!("Dead letter delivery was cancelled.");
+                            failed.fetch_add(1, Ordering::Relaxed);
+                        },
+                    }
+                });
+            },
+            Err((error, _)) => {
+                tracing::warn!(%error, "Unable to enqueue dead letter.");
+                self.failed.fetch_add(1, Ordering::Relaxed);
+            },
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+
+use crate::config::{Config, DiffArgs, DiffFormat};
+
+/// Compares the `Debug` representation of each listed field.
+macro_rules! diff_fields {
+    ($changes:ident, $old:ident, $new:ident; $($field:ident),* $(,)?) => {
+        $(
+            let (old_value, new_value) = (format!("{:?}", $old.$field), format!("{:?}", $new.$field));
+            if old_value != new_value {
+                $changes.push(Change {
+                    field: stringify!($field),
+                    message: format!("{old_value} -> {new_value}"),
+                });
+            }
+        )*
+    };
+}
+
+/// One semantic difference between two configurations.
+#[derive(Serialize, Debug)]
+pub struct Change {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// Prints the differences between the two configurations and returns whether there were any.
+pub fn run(args: &DiffArgs) -> anyhow::Result<bool> {
+    let old = Config::from_file(&args.old)?;
+    let new = Config::from_file(&args.new)?;
+    let changes = diff(&old, &new);
+
+    match args.format {
+        DiffFormat::Text => {
+            if changes.is_empty() {
+                println!("No differences.");
+            }
+            for change in &changes {
+                println!("{}: {}", change.field, change.message);
+            }
+        },
+        DiffFormat::Json => println!("{}", serde_json::to_string_pretty(&changes)?),
+    }
+
+    Ok(!changes.is_empty())
+}
+
+/// Differences of every setting, secrets are never printed.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn diff(old: &Config, new: &Config) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    diff_sets(
+        &mut changes,
+        "topics",
+        old.topics.iter().cloned(),
+        new.topics.iter().cloned(),
+    );
+    diff_sets(
+        &mut changes,
+        "cidr_list",
+        old.cidr_list.iter().map(ToString::to_string),
+        new.cidr_list.iter().map(ToString::to_string),
+    );
+
+    if old.batch_size != new.batch_size {
+        let delta = new.batch_size as i128 - old.batch_size as i128;
+        let percent = if old.batch_size == 0 {
+            String::new()
+        } else {
+            format!(" ({:+.1}%)", delta as f64 * 100.0 / old.batch_size as f64)
+        };
+        changes.push(Change {
+            field: "batch_size",
+            message: format!(
+                "{} -> {} ({delta:+} bytes{percent})",
+                old.batch_size, new.batch_size
+            ),
+        });
+    }
+
+    if old.time_alignment_seconds != new.time_alignment_seconds {
+        changes.push(Change {
+            field: "time_alignment_seconds",
+            message: format!(
+                "{}s -> {}s, {}",
+                old.time_alignment_seconds,
+                new.time_alignment_seconds,
+                bucket_boundary_effect(old.time_alignment_seconds, new.time_alignment_seconds)
+            ),
+        });
+    }
+
+    // Secrets are never printed.
+    for (field, changed) in [
+        ("influxdb_token", old.influxdb_token != new.influxdb_token),
+        ("postgres_url", old.postgres_url != new.postgres_url),
+        (
+            "kafka_sasl_password",
+            old.kafka_sasl_password != new.kafka_sasl_password,
+        ),
+    ] {
+        if changed {
+            changes.push(Change {
+                field,
+                message: "changed (value redacted)".to_owned(),
+            });
+        }
+    }
+
+    diff_fields!(
+        changes,
+        old,
+        new;
+        group_id,
+        brokers,
+        kafka_offset_reset,
+        log_format,
+        kafka_security_protocol,
+        kafka_ssl_endpoint_identification_algorithm,
+        kafka_ssl_cipher_suites,
+        kafka_sasl_mechanism,
+        kafka_sasl_username,
+        kafka_stats_interval_ms,
+        bucket_timestamp,
+        rollup_alignment_seconds,
+        rollup_measurement,
+        influxdb_measurement,
+        influxdb_tags_extra,
+        topic_measurement_map,
+        rollup_bucket,
+        rollup_dimensions,
+        cidr_file,
+        cidr_file_reload_interval,
+        cidr_groups,
+        adaptive_batch_max_size,
+        adaptive_batch_lag_threshold,
+        max_cache_memory_bytes,
+        max_unique_sources,
+        max_unique_targets,
+        src_prefix_len,
+        dst_prefix_len,
+        idle_timeout,
+        idle_action,
+        watchdog_interval,
+        flush_grace,
+        output_topic,
+        unix_socket_path,
+        dead_letter_topic,
+        batch_id_strategy,
+        batch_number_file,
+        postgres_table,
+        sqlite_recovery_path,
+        postgres_max_connections,
+        max_message_age,
+        max_received_age,
+        max_future_skew,
+        future_timestamp_action,
+        min_timestamp,
+        influxdb_max_retries,
+        influx_write_concurrency,
+        influxdb_max_retry_wait,
+        failed_batch_dir,
+        failed_batch_dir_max_bytes,
+        stats_history_minutes,
+        stats_ema_alpha,
+        metrics_listen,
+        otlp_endpoint,
+        replay_file,
+        replay_speed,
+        record_file,
+        record_file_max_bytes,
+        include_interfaces,
+        interface_names,
+        track_mpls,
+        include_exporter,
+        include_tcp_flags,
+        include_dscp,
+        bidirectional,
+        track_observation_time,
+        track_variance,
+        geo_ip_database,
+        host_rollup,
+        partition_assignment,
+        expected_partition_count,
+        skip_startup_checks,
+        enable_admin_stdin,
+        workers,
+        kafka_prefetch_messages,
+        kafka_consumer_linger,
+        min_packets,
+        min_bytes,
+        max_packets,
+        max_bytes,
+        lag_batch_max_size,
+        high_lag_threshold,
+        low_lag_threshold,
+        include_protos,
+        include_vlans,
+        flow_version,
+        include_icmp_detail,
+        classification_rules,
+        classify,
+        config_file,
+        config_reload_on_sighup,
+        dump_cache_path,
+        peer_cardinality,
+        include_ip_version,
+        skip_external_flows,
+        skip_internal_flows,
+        state_file,
+        allow_partial_flows,
+        influxdb_connect_timeout,
+        key_filter,
+        key_filter_mode,
+        kafka_max_message_bytes,
+        payload_envelope,
+        allowed_schema_ids,
+        payload_compression,
+        max_decompressed_bytes,
+        kafka_backpressure,
+        output_ndjson,
+        output_ndjson_file,
+        output_both,
+        rebalance_flush_timeout,
+        shutdown_timeout,
+        aggregation_store,
+        store_shards,
+        topic_alias,
+        influxdb_exit_on_auth_error,
+        influxdb_token_file,
+        influxdb_endpoint,
+        influxdb_bucket,
+        influxdb_org,
+        influxdb_timeout,
+        influxdb_precision,
+        influxdb_ca_cert,
+        influxdb_accept_invalid_certs,
+    );
+
+    changes
+}
+
+fn diff_sets(
+    changes: &mut Vec<Change>,
+    field: &'static str,
+    old: impl Iterator<Item = String>,
+    new: impl Iterator<Item = String>,
+) {
+    let old: BTreeSet<String> = old.collect();
+    let new: BTreeSet<String> = new.collect();
+
+    let added: Vec<&String> = new.difference(&old).collect();
+    let removed: Vec<&String> = old.difference(&new).collect();
+    if !added.is_empty() {
+        changes.push(Change {
+            field,
+            message: format!("added {added:?}"),
+        });
+    }
+    if !removed.is_empty() {
+        changes.push(Change {
+            field,
+            message: format!("removed {removed:?}"),
+        });
+    }
+}
+
+fn bucket_boundary_effect(old: u64, new: u64) -> String {
+    if new % old == 0 {
+        format!("every new bucket merges {} old buckets", new / old)
+    } else if old % new == 0 {
+        format!("every old bucket is split into {} new buckets", old / new)
+    } else {
+        format!(
+            "bucket boundaries only coincide every {}s, buckets around the switch will be partial",
+            old / gcd(old, new) * new
+        )
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
@@ -0,0 +1,1376 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::{
+    sync::{oneshot, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
+use tracing::{field::Empty, Instrument};
+
+use crate::{
+    backup,
+    config::{self, Config},
+    influx::{self, BatchIds, InfluxWriteError, PointOptions},
+    kafka_output::KafkaOutput,
+    ndjson::NdjsonOutput,
+    postgres,
+    recovery::{RecoveredBatch, Recovery},
+    state::{PartitionOffset, SavedBatch},
+    unix_socket::FlushBroadcast,
+    util::{self, AggregatedKey, CidrTree, CommunicationData, HostKey},
+    INFLUX_AUTH_EXIT_CODE,
+};
+
+/// Wait before a failed write is retried, unless Influx requested another one.
+const RETRY_WAIT: Duration = Duration::from_secs(5);
+
+/// Answer to a `POST /flush`, the number of written points or the error of the write.
+pub type FlushReply = oneshot::Sender<Result<usize, String>>;
+
+/// Outputs of the flushed batches besides Influx.
+#[derive(Default)]
+pub struct Sinks {
+    pub kafka_output: Option<Arc<KafkaOutput>>,
+    pub ndjson_output: Option<NdjsonOutput>,
+    pub postgres_pool: Option<PgPool>,
+    pub flush_broadcast: Option<FlushBroadcast>,
+    /// Copy of the batches that are not in Influx yet.
+    pub recovery: Option<Recovery>,
+}
+
+/// Outcome of [`Flusher::write`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flush {
+    /// Every sink accepted the batch of that many points or gave up on it.
+    Written(usize),
+    /// A sink failed, the batch is kept and written again after the wait.
+    Retry(Duration),
+}
+
+/// Output a batch is written to, as recorded in `--state-file`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Sink {
+    KafkaOutput,
+    Ndjson,
+    Influx,
+    /// The `--host-rollup` points.
+    InfluxHosts,
+    Postgres,
+}
+
+/// Entries taken from the cache that have not been written into every sink yet.
+#[derive(Default)]
+struct PendingBatch {
+    entries: Vec<(AggregatedKey, CommunicationData)>,
+    /// Distinct peers of the inside hosts in the buckets of the batch.
+    peers: HashMap<HostKey, u64>,
+    /// Retries of the same batch reuse its id so they overwrite instead of duplicate.
+    id: Option<String>,
+    /// Number of the batch in the recovery database.
+    recovery: Option<i64>,
+    /// Left behind by the previous run, it is neither broadcast nor rolled up.
+    recovered: bool,
+    /// Next offsets of the messages aggregated into the batch and the batches before it, only
+    /// known for a drain of the whole cache.
+    offsets: Vec<PartitionOffset>,
+    /// Whether the batch was already handed to the Kafka output. It is produced only once, so an
+    /// Influx retry does not duplicate the output records.
+    produced: bool,
+    // Sinks that already accepted the batch, only the failed ones are retried.
+    in_influx: bool,
+    hosts_in_influx: bool,
+    in_postgres: bool,
+    in_ndjson: bool,
+    influx_attempts: u32,
+    hosts_attempts: u32,
+}
+
+/// Writes the batches taken from the cache into every sink. While a batch is pending the failed
+/// sinks are retried and nothing new should be taken from the cache.
+pub struct Flusher {
+    config: Arc<Config>,
+    client: influx::Client,
+    point_options: PointOptions,
+    rollup_point_options: PointOptions,
+    batch_ids: BatchIds,
+    sinks: Sinks,
+    failed_batches_on_disk: Arc<AtomicU64>,
+    /// Limits the background Influx writes of `--influx-write-concurrency` above 1.
+    write_slots: Option<Arc<Semaphore>>,
+    background_writes: JoinSet<()>,
+    /// Dropped by every background write once it finished, panicked or was aborted.
+    background_tokens: Vec<Weak<()>>,
+    /// Offsets of the written batches, each waiting for the background writes started before.
+    written_offsets: VecDeque<(Vec<PartitionOffset>, Vec<Weak<()>>)>,
+    /// Flushed batches merged into the coarser `--rollup-alignment-seconds` buckets.
+    rollup_cache: HashMap<AggregatedKey, CommunicationData>,
+    pending: PendingBatch,
+}
+
+impl Flusher {
+    pub fn new(
+        config: Arc<Config>,
+        client: influx::Client,
+        sinks: Sinks,
+        failed_batches_on_disk: Arc<AtomicU64>,
+    ) -> anyhow::Result<Self> {
+        let point_options = PointOptions {
+            measurement: config.influxdb_measurement.clone(),
+            interfaces: config.include_interfaces,
+            interface_names: config.interface_names.clone(),
+            mpls: config.track_mpls,
+            exporter: config.include_exporter,
+            tcp_flags: config.include_tcp_flags,
+            dscp: config.include_dscp,
+            icmp: config.include_icmp_detail,
+            bidirectional: config.bidirectional,
+            extra_tags: config.influxdb_tags_extra.clone(),
+            precision: config.influxdb_precision,
+        };
+        let rollup_point_options = PointOptions {
+            measurement: config.rollup_measurement.clone(),
+            ..point_options.clone()
+        };
+
+        Ok(Self {
+            batch_ids: BatchIds::new(config.batch_id_strategy, config.batch_number_file.clone())?,
+            write_slots: (config.influx_write_concurrency > 1)
+                .then(|| Arc::new(Semaphore::new(config.influx_write_concurrency))),
+            config,
+            client,
+            point_options,
+            rollup_point_options,
+            sinks,
+            failed_batches_on_disk,
+            background_writes: JoinSet::new(),
+            background_tokens: Vec::new(),
+            written_offsets: VecDeque::new(),
+            rollup_cache: HashMap::new(),
+            pending: PendingBatch::default(),
+        })
+    }
+
+    /// Batches left in the recovery database by the previous run, to be written with
+    /// [`Self::stage_recovered`] before consuming.
+    pub async fn recovered(&self) -> anyhow::Result<Vec<RecoveredBatch>> {
+        let Some(recovery) = &self.sinks.recovery else {
+            return Ok(Vec::new());
+        };
+        let residual = recovery
+            .residual()
+            .await
+            .context("Unable to read the recovery database")?;
+        if !residual.is_empty() {
+            tracing::info!(
+                batches = residual.len(),
+                "Resubmitting batches left behind by the previous run."
+            );
+        }
+
+        Ok(residual)
+    }
+
+    /// Takes a batch left behind by the previous run as the pending batch. It keeps its id, so
+    /// points the previous run already wrote are overwritten, and it is written only into Influx,
+    /// or the NDJSON output replacing it. The other sinks cannot tell whether they got it before
+    /// the crash.
+    pub fn stage_recovered(&mut self, batch: RecoveredBatch) {
+        let to_influx = self.config.writes_to_influx();
+        self.pending = PendingBatch {
+            entries: batch.entries,
+            id: batch.batch_id,
+            recovery: Some(batch.number),
+            recovered: true,
+            produced: true,
+            in_influx: !to_influx,
+            hosts_in_influx: true,
+            in_postgres: true,
+            in_ndjson: to_influx,
+            ..PendingBatch::default()
+        };
+    }
+
+    /// Whether a batch is waiting to be written.
+    #[must_use]
+    pub fn is_pending(&self) -> bool {
+        !self.pending.entries.is_empty()
+    }
+
+    /// Number of entries waiting to be written.
+    #[must_use]
+    pub fn pending_len(&self) -> usize {
+        self.pending.entries.len()
+    }
+
+    /// Whether the Influx writes run in the background with `--influx-write-concurrency` above 1.
+    #[must_use]
+    pub fn writes_in_background(&self) -> bool {
+        self.write_slots.is_some()
+    }
+
+    /// Number of background Influx writes that have not finished yet.
+    #[must_use]
+    pub fn background_writes(&self) -> usize {
+        self.background_writes.len()
+    }
+
+    /// Takes the entries taken from the cache as the next batch. Returns whether there is one.
+    /// The `offsets` of a drained cache are handed out by [`Self::take_written_offsets`] once
+    /// the batch and every batch before it is written.
+    pub async fn stage(
+        &mut self,
+        entries: HashMap<AggregatedKey, CommunicationData>,
+        peers: HashMap<HostKey, u64>,
+        offsets: Vec<PartitionOffset>,
+    ) -> anyhow::Result<bool> {
+        self.pending.entries.extend(entries);
+        self.pending.peers.extend(peers);
+        if self.pending.entries.is_empty() {
+            // Every message was written already or skipped, the offsets only wait for the
+            // background writes.
+            self.hold_offsets(offsets);
+            return Ok(false);
+        }
+        if !offsets.is_empty() {
+            self.pending.offsets = offsets;
+        }
+
+        self.pending.id = self.batch_ids.next()?;
+        if let Some(recovery) = &self.sinks.recovery {
+            self.pending.recovery = Some(
+                recovery
+                    .store(&self.pending.entries, self.pending.id.as_deref())
+                    .await?,
+            );
+        }
+
+        Ok(true)
+    }
+
+    /// Gives up on the pending batch, e.g. to save it on shutdown, together with the sinks that
+    /// already accepted it.
+    pub fn take_unwritten(&mut self) -> Option<SavedBatch> {
+        if !self.is_pending() {
+            return None;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        // A batch in the recovery database is written into Influx, or the NDJSON output replacing
+        // it, at the next start anyway.
+        let recovered = pending.recovery.is_some();
+        let written_to = [
+            (Sink::KafkaOutput, pending.produced),
+            (
+                Sink::Ndjson,
+                pending.in_ndjson || (recovered && !self.config.writes_to_influx()),
+            ),
+            (Sink::Influx, pending.in_influx || recovered),
+            (Sink::InfluxHosts, pending.hosts_in_influx),
+            (Sink::Postgres, pending.in_postgres),
+        ]
+        .into_iter()
+        .filter_map(|(sink, written)| written.then_some(sink))
+        .collect();
+
+        Some(SavedBatch {
+            entries: pending.entries,
+            batch_id: pending.id,
+            written_to,
+        })
+    }
+
+    /// Takes the batch saved by the previous run as the pending batch. It keeps its id and is
+    /// written only into the sinks that did not accept it before.
+    pub async fn restore_pending(&mut self, saved: SavedBatch) -> anyhow::Result<()> {
+        let written = |sink| saved.written_to.contains(&sink);
+        self.pending = PendingBatch {
+            produced: written(Sink::KafkaOutput),
+            in_ndjson: written(Sink::Ndjson),
+            in_influx: written(Sink::Influx),
+            hosts_in_influx: written(Sink::InfluxHosts),
+            in_postgres: written(Sink::Postgres),
+            entries: saved.entries,
+            id: saved.batch_id,
+            ..PendingBatch::default()
+        };
+        if let Some(recovery) = self
+            .sinks
+            .recovery
+            .as_ref()
+            .filter(|_| !self.pending.in_influx)
+        {
+            self.pending.recovery = Some(
+                recovery
+                    .store(&self.pending.entries, self.pending.id.as_deref())
+                    .await?,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Writes the pending batch into the sinks that have not accepted it yet. Once every sink
+    /// has it, it is merged into the rollups, whose buckets ending by `rollup_watermark` are
+    /// written. A failed write is answered on `flush_reply`.
+    pub async fn write(
+        &mut self,
+        flush_reply: &mut Option<FlushReply>,
+        cidr_tree: &CidrTree,
+        rollup_watermark: u64,
+    ) -> anyhow::Result<Flush> {
+        let flush_span = tracing::info_span!(
+            "flush",
+            points = self.pending.entries.len(),
+            batch_id = self.pending.id.as_deref()
+        );
+        if !self.pending.produced {
+            if let Some(kafka_output) = &self.sinks.kafka_output {
+                kafka_output.produce_batch(&self.pending.entries).await;
+            }
+            self.pending.produced = true;
+        }
+        self.write_ndjson(flush_reply).await;
+        // Set by a rate limited Influx write to the wait it requested.
+        let mut retry_delay = None;
+        if !self.pending.in_influx {
+            retry_delay = retry_delay.max(self.write_influx(&flush_span, flush_reply).await?);
+        }
+        // Written separately, so a failure of one measurement does not lose the other.
+        if self.config.host_rollup && !self.pending.hosts_in_influx {
+            retry_delay = retry_delay.max(self.write_hosts(&flush_span).await);
+        }
+        self.write_postgres(flush_reply).await;
+
+        if !self.is_written() {
+            return Ok(Flush::Retry(retry_delay.unwrap_or(RETRY_WAIT)));
+        }
+
+        let PendingBatch {
+            entries: batch,
+            recovered,
+            offsets,
+            ..
+        } = std::mem::take(&mut self.pending);
+        self.hold_offsets(offsets);
+        if let Some(reply) = flush_reply.take() {
+            let _ = reply.send(Ok(batch.len()));
+        }
+        if recovered {
+            return Ok(Flush::Written(batch.len()));
+        }
+        if let Some(flush_broadcast) = &self.sinks.flush_broadcast {
+            flush_broadcast.publish(&batch);
+        }
+        self.roll_up(&batch, cidr_tree, rollup_watermark, &flush_span)
+            .await?;
+
+        Ok(Flush::Written(batch.len()))
+    }
+
+    /// Writes the pending batch like [`Self::write`], sleeping between the attempts until it is
+    /// written.
+    pub async fn write_until_written(&mut self, cidr_tree: &CidrTree) -> anyhow::Result<()> {
+        while let Flush::Retry(retry_delay) = self.write(&mut None, cidr_tree, 0).await? {
+            tokio::time::sleep(retry_delay).await;
+        }
+
+        Ok(())
+    }
+
+    /// Backs up the pending batch into `--failed-batch-dir`, or drops it, when the shutdown
+    /// cannot wait for it any longer. A batch in the recovery database is kept there unless it
+    /// was backed up.
+    pub async fn give_up(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        if pending.entries.is_empty() || pending.in_influx {
+            return;
+        }
+        if back_up_failed_batch(
+            &self.config,
+            &pending.entries,
+            pending.id.as_deref(),
+            &self.point_options,
+            &self.failed_batches_on_disk,
+        ) {
+            forget_recovered_batch(self.sinks.recovery.as_ref(), pending.recovery).await;
+        }
+    }
+
+    /// Latest offsets whose messages are all written, to be stored for the next commit. The
+    /// offsets of a batch written in the background wait for the background writes started
+    /// before it.
+    pub fn take_written_offsets(&mut self) -> Option<Vec<PartitionOffset>> {
+        let mut latest = None;
+        while let Some((_, running)) = self.written_offsets.front() {
+            if running.iter().any(|token| token.strong_count() > 0) {
+                break;
+            }
+            latest = self.written_offsets.pop_front().map(|(offsets, _)| offsets);
+        }
+
+        latest
+    }
+
+    /// Queues the offsets of a written batch until the background writes started before finish.
+    fn hold_offsets(&mut self, offsets: Vec<PartitionOffset>) {
+        if offsets.is_empty() {
+            return;
+        }
+        self.background_tokens
+            .retain(|token| token.strong_count() > 0);
+        self.written_offsets
+            .push_back((offsets, self.background_tokens.clone()));
+    }
+
+    /// Collects the background Influx writes that finished.
+    pub fn reap_background_writes(&mut self) {
+        while let Some(Some(result)) = self.background_writes.join_next().now_or_never() {
+            if let Err(error) = result {
+                tracing::error!(%error, "Background Influx write failed.");
+            }
+        }
+    }
+
+    /// Waits until the next background Influx write finished, `None` without any.
+    pub async fn join_next_background_write(&mut self) -> Option<()> {
+        let result = self.background_writes.join_next().await?;
+        if let Err(error) = result {
+            tracing::error!(%error, "Background Influx write failed.");
+        }
+        Some(())
+    }
+
+    /// Waits until every background Influx write finished.
+    pub async fn join_background_writes(&mut self) {
+        while let Some(result) = self.background_writes.join_next().await {
+            if let Err(error) = result {
+                tracing::error!(%error, "Background Influx write failed.");
+            }
+        }
+    }
+
+    /// Aborts the background Influx writes, their batches stay in the recovery database.
+    pub fn abort_background_writes(&mut self) {
+        if !self.background_writes.is_empty() {
+            tracing::error!(
+                writes = self.background_writes.len(),
+                "Aborting the unfinished background Influx writes."
+            );
+        }
+        self.background_writes.abort_all();
+    }
+
+    fn is_written(&self) -> bool {
+        let pending = &self.pending;
+        pending.in_influx
+            && (!self.config.host_rollup || pending.hosts_in_influx)
+            && (self.sinks.postgres_pool.is_none() || pending.in_postgres)
+            && (self.sinks.ndjson_output.is_none() || pending.in_ndjson)
+    }
+
+    async fn write_ndjson(&mut self, flush_reply: &mut Option<FlushReply>) {
+        let Some(ndjson_output) = self
+            .sinks
+            .ndjson_output
+            .as_mut()
+            .filter(|_| !self.pending.in_ndjson)
+        else {
+            return;
+        };
+        match ndjson_output.write_batch(&self.pending.entries) {
+            Ok(()) => self.pending.in_ndjson = true,
+            Err(error) => {
+                tracing::error!(
+                    error = error.to_string(),
+                    "Unable to write the NDJSON output. Sleeping and retrying."
+                );
+                if let Some(reply) = flush_reply.take() {
+                    let _ = reply.send(Err(error.to_string()));
+                }
+            },
+        }
+        if !self.config.writes_to_influx() {
+            self.pending.in_influx = true;
+            self.pending.hosts_in_influx = true;
+            // The NDJSON output is the copy the recovery database stands in for.
+            if self.pending.in_ndjson {
+                forget_recovered_batch(self.sinks.recovery.as_ref(), self.pending.recovery).await;
+            }
+        }
+    }
+
+    /// Returns the wait requested by a rate limited write.
+    async fn write_influx(
+        &mut self,
+        flush_span: &tracing::Span,
+        flush_reply: &mut Option<FlushReply>,
+    ) -> anyhow::Result<Option<Duration>> {
+        if let Some(slots) = &self.write_slots {
+            // Waits while all slots are taken, holding up consumption like an inline write would.
+            let permit = slots.clone().acquire_owned().await?;
+            let token = Arc::new(());
+            self.background_tokens.push(Arc::downgrade(&token));
+            self.background_writes.spawn(
+                BackgroundWrite {
+                    client: self.client.clone(),
+                    config: self.config.clone(),
+                    batch: self.pending.entries.clone(),
+                    batch_id: self.pending.id.clone(),
+                    options: self.point_options.clone(),
+                    failed_batches_on_disk: self.failed_batches_on_disk.clone(),
+                    flush_reply: flush_reply.take(),
+                    span: flush_span.clone(),
+                    recovery: self.sinks.recovery.clone().zip(self.pending.recovery),
+                    _token: token,
+                }
+                .run(permit),
+            );
+            self.pending.in_influx = true;
+            return Ok(None);
+        }
+
+        let mut retry_delay = None;
+        let mut permanent = false;
+        match traced_influx_write(
+            tracing::info_span!(
+                parent: flush_span,
+                "influx_write",
+                bucket = %self.config.influxdb_bucket,
+                points = self.pending.entries.len(),
+                attempt = self.pending.influx_attempts + 1,
+                duration_ms = Empty,
+            ),
+            influx::insert_data_into_influx(
+                &self.client,
+                &self.config.influxdb_bucket,
+                &self.pending.entries,
+                self.pending.id.as_deref(),
+                &self.point_options,
+            ),
+        )
+        .await
+        {
+            Ok(()) => {
+                self.pending.in_influx = true;
+                forget_recovered_batch(self.sinks.recovery.as_ref(), self.pending.recovery).await;
+            },
+            Err(error) => {
+                tracing::error!(
+                    error = error.to_string(),
+                    "Unable to submit data into influx. Sleeping and retrying."
+                );
+                self.reload_token(&error);
+                if let Some(reply) = flush_reply.take() {
+                    let _ = reply.send(Err(error.to_string()));
+                }
+                retry_delay = rate_limit_wait(&error, &self.config);
+                self.pending.influx_attempts += 1;
+                permanent = error.is_permanent();
+            },
+        }
+
+        if permanent
+            || self
+                .config
+                .influxdb_max_retries
+                .is_some_and(|max_retries| self.pending.influx_attempts > max_retries)
+        {
+            // A batch on disk must not be resubmitted from the recovery database as well.
+            if back_up_failed_batch(
+                &self.config,
+                &self.pending.entries,
+                self.pending.id.as_deref(),
+                &self.point_options,
+                &self.failed_batches_on_disk,
+            ) {
+                forget_recovered_batch(self.sinks.recovery.as_ref(), self.pending.recovery).await;
+            }
+            self.pending.in_influx = true;
+        }
+
+        Ok(retry_delay)
+    }
+
+    /// Returns the wait requested by a rate limited write.
+    async fn write_hosts(&mut self, flush_span: &tracing::Span) -> Option<Duration> {
+        let host_totals = util::host_totals(&self.pending.entries);
+        let mut retry_delay = None;
+        let mut permanent = false;
+        match traced_influx_write(
+            tracing::info_span!(
+                parent: flush_span,
+                "influx_write",
+                bucket = %self.config.influxdb_bucket,
+                measurement = influx::HOST_MEASUREMENT,
+                points = host_totals.len(),
+                attempt = self.pending.hosts_attempts + 1,
+                duration_ms = Empty,
+            ),
+            influx::insert_host_data_into_influx(
+                &self.client,
+                &self.config.influxdb_bucket,
+                &host_totals,
+                &self.pending.peers,
+                self.pending.id.as_deref(),
+                &self.point_options,
+            ),
+        )
+        .await
+        {
+            Ok(()) => self.pending.hosts_in_influx = true,
+            Err(error) => {
+                tracing::error!(
+                    error = error.to_string(),
+                    "Unable to submit host rollup into influx. Sleeping and retrying."
+                );
+                self.reload_token(&error);
+                retry_delay = rate_limit_wait(&error, &self.config);
+                self.pending.hosts_attempts += 1;
+                permanent = error.is_permanent();
+            },
+        }
+
+        if permanent
+            || self
+                .config
+                .influxdb_max_retries
+                .is_some_and(|max_retries| self.pending.hosts_attempts > max_retries)
+        {
+            tracing::error!("Influx retries exhausted. Dropping the host rollup.");
+            self.pending.hosts_in_influx = true;
+        }
+
+        retry_delay
+    }
+
+    async fn write_postgres(&mut self, flush_reply: &mut Option<FlushReply>) {
+        let Some(pool) = self
+            .sinks
+            .postgres_pool
+            .as_ref()
+            .filter(|_| !self.pending.in_postgres)
+        else {
+            return;
+        };
+        match postgres::insert_data_into_postgres(
+            pool,
+            &self.config.postgres_table,
+            &self.pending.entries,
+        )
+        .await
+        {
+            Ok(()) => self.pending.in_postgres = true,
+            Err(error) => {
+                tracing::error!(
+                    error = error.to_string(),
+                    "Unable to submit data into postgres. Sleeping and retrying."
+                );
+                if let Some(reply) = flush_reply.take() {
+                    let _ = reply.send(Err(error.to_string()));
+                }
+            },
+        }
+    }
+
+    /// Merges the written batch into the rollups and writes the closed ones. Failed rollups are
+    /// kept and tried again after the next flush.
+    async fn roll_up(
+        &mut self,
+        batch: &[(AggregatedKey, CommunicationData)],
+        cidr_tree: &CidrTree,
+        watermark: u64,
+        flush_span: &tracing::Span,
+    ) -> anyhow::Result<()> {
+        let Some(rollup_alignment) = self.config.rollup_alignment_seconds else {
+            return Ok(());
+        };
+        for (key, value) in batch {
+            let key = key.rollup(rollup_alignment, &self.config.rollup_dimensions, cidr_tree);
+            self.rollup_cache.entry(key).or_default().merge(value);
+        }
+
+        let mut rollup_batch = Vec::new();
+        self.rollup_cache.retain(|key, value| {
+            let closed = key.bucket_end(rollup_alignment) <= watermark;
+            if closed {
+                rollup_batch.push((key.clone(), value.clone()));
+            }
+            !closed
+        });
+        if rollup_batch.is_empty() {
+            return Ok(());
+        }
+
+        let rollup_bucket = self
+            .config
+            .rollup_bucket
+            .as_deref()
+            .unwrap_or(&self.config.influxdb_bucket);
+        match traced_influx_write(
+            tracing::info_span!(
+                parent: flush_span,
+                "influx_write",
+                bucket = rollup_bucket,
+                points = rollup_batch.len(),
+                attempt = 1,
+                duration_ms = Empty,
+            ),
+            influx::insert_data_into_influx(
+                &self.client,
+                rollup_bucket,
+                &rollup_batch,
+                self.batch_ids.next()?.as_deref(),
+                &self.rollup_point_options,
+            ),
+        )
+        .await
+        {
+            Ok(()) => {
+                tracing::info!(
+                    batch.elements = rollup_batch.len(),
+                    "Inserted rollup batch into the influx."
+                );
+            },
+            Err(error) => {
+                tracing::error!(
+                    error = error.to_string(),
+                    "Unable to submit rollups into influx."
+                );
+                self.rollup_cache.extend(rollup_batch);
+            },
+        }
+
+        Ok(())
+    }
+
+    fn reload_token(&mut self, error: &InfluxWriteError) {
+        if let Some(token) = reload_influx_token(error, &self.config) {
+            self.client = self.client.with_token(token.expose());
+        }
+    }
+}
+
+/// Wait requested by a rate limited Influx write, capped by `--influxdb-max-retry-wait-seconds`.
+fn rate_limit_wait(error: &InfluxWriteError, config: &Config) -> Option<Duration> {
+    let InfluxWriteError::RateLimited(wait) = error else {
+        return None;
+    };
+    let wait = (*wait).min(config.influxdb_max_retry_wait);
+    tracing::warn!(
+        wait_seconds = wait.as_secs(),
+        "Influx rate limited the write."
+    );
+
+    Some(wait)
+}
+
+/// Re-reads `--influxdb-token-file` when a write was rejected for its credentials, so a rotated
+/// token is picked up without a restart. Without a new token `--influxdb-exit-on-auth-error`
+/// exits.
+fn reload_influx_token(error: &InfluxWriteError, config: &Config) -> Option<config::Secret> {
+    if !error.is_auth_error() {
+        return None;
+    }
+    tracing::error!(
+        error = error.to_string(),
+        "Influx rejected the credentials."
+    );
+    let token = read_influx_token_file(config);
+    if token.is_none() && config.influxdb_exit_on_auth_error {
+        std::process::exit(INFLUX_AUTH_EXIT_CODE);
+    }
+
+    token
+}
+
+fn read_influx_token_file(config: &Config) -> Option<config::Secret> {
+    let path = config.influxdb_token_file.as_ref()?;
+    match config::read_token_file(path) {
+        Ok(token) => {
+            tracing::info!(path = %path.display(), "Re-read the Influx token file.");
+            Some(token)
+        },
+        Err(error) => {
+            tracing::error!(
+                error = format!("{error:#}"),
+                "Unable to re-read the Influx token file."
+            );
+            None
+        },
+    }
+}
+
+/// Writes the batch into `--failed-batch-dir` once its Influx retries are exhausted or Influx
+/// rejected it for good, or drops it. Returns whether the batch was written to disk.
+fn back_up_failed_batch(
+    config: &Config,
+    batch: &[(AggregatedKey, CommunicationData)],
+    batch_id: Option<&str>,
+    options: &PointOptions,
+    failed_batches_on_disk: &AtomicU64,
+) -> bool {
+    let Some(dir) = &config.failed_batch_dir else {
+        tracing::error!("Influx retries exhausted. Dropping the batch.");
+        return false;
+    };
+    match backup::write_failed_batch(
+        dir,
+        config.failed_batch_dir_max_bytes,
+        batch,
+        batch_id,
+        options,
+    ) {
+        Ok(path) => {
+            failed_batches_on_disk.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(
+                path = %path.display(),
+                "Influx retries exhausted, batch written to disk."
+            );
+            true
+        },
+        Err(error) => {
+            tracing::error!(
+                error = error.to_string(),
+                "Influx retries exhausted and the batch could not be written to disk. Dropping it."
+            );
+            false
+        },
+    }
+}
+
+/// Deletes a written batch from `--sqlite-recovery-path`. A failure only means the batch is
+/// written again at the next start.
+async fn forget_recovered_batch(recovery: Option<&Recovery>, number: Option<i64>) {
+    let (Some(recovery), Some(number)) = (recovery, number) else {
+        return;
+    };
+    if let Err(error) = recovery.forget(number).await {
+        tracing::error!(%error, "Unable to delete the written batch from the recovery database.");
+    }
+}
+
+/// Influx write of one batch running in the background with `--influx-write-concurrency` above 1.
+struct BackgroundWrite {
+    client: influx::Client,
+    config: Arc<Config>,
+    batch: Vec<(AggregatedKey, CommunicationData)>,
+    batch_id: Option<String>,
+    options: PointOptions,
+    failed_batches_on_disk: Arc<AtomicU64>,
+    flush_reply: Option<FlushReply>,
+    span: tracing::Span,
+    /// Recovery database and the number of the batch in it.
+    recovery: Option<(Recovery, i64)>,
+    /// Tells [`Flusher::take_written_offsets`] the write is still running.
+    _token: Arc<()>,
+}
+
+impl BackgroundWrite {
+    /// Retries like the inline write until the batch is written or given up. The permit is held
+    /// until then.
+    async fn run(mut self, _permit: OwnedSemaphorePermit) {
+        let mut attempts: u32 = 0;
+        loop {
+            let result = traced_influx_write(
+                tracing::info_span!(
+                    parent: &self.span,
+                    "influx_write",
+                    bucket = %self.config.influxdb_bucket,
+                    points = self.batch.len(),
+                    attempt = attempts + 1,
+                    duration_ms = Empty,
+                ),
+                influx::insert_data_into_influx(
+                    &self.client,
+                    &self.config.influxdb_bucket,
+                    &self.batch,
+                    self.batch_id.as_deref(),
+                    &self.options,
+                ),
+            )
+            .await;
+            let error = match result {
+                Ok(()) => {
+                    tracing::info!(
+                        batch.elements = self.batch.len(),
+                        "Inserted new batch into the influx."
+                    );
+                    if let Some(reply) = self.flush_reply.take() {
+                        let _ = reply.send(Ok(self.batch.len()));
+                    }
+                    if let Some((recovery, number)) = &self.recovery {
+                        forget_recovered_batch(Some(recovery), Some(*number)).await;
+                    }
+                    return;
+                },
+                Err(error) => error,
+            };
+
+            tracing::error!(
+                error = error.to_string(),
+                "Unable to submit data into influx. Sleeping and retrying."
+            );
+            if let Some(token) = reload_influx_token(&error, &self.config) {
+                self.client = self.client.with_token(token.expose());
+            }
+            if let Some(reply) = self.flush_reply.take() {
+                let _ = reply.send(Err(error.to_string()));
+            }
+            attempts += 1;
+            if error.is_permanent()
+                || self
+                    .config
+                    .influxdb_max_retries
+                    .is_some_and(|max_retries| attempts > max_retries)
+            {
+                // A batch on disk must not be resubmitted from the recovery database as well.
+                let backed_up = back_up_failed_batch(
+                    &self.config,
+                    &self.batch,
+                    self.batch_id.as_deref(),
+                    &self.options,
+                    &self.failed_batches_on_disk,
+                );
+                if let (true, Some((recovery, number))) = (backed_up, &self.recovery) {
+                    forget_recovered_batch(Some(recovery), Some(*number)).await;
+                }
+                return;
+            }
+            tokio::time::sleep(rate_limit_wait(&error, &self.config).unwrap_or(RETRY_WAIT)).await;
+        }
+    }
+}
+
+/// Runs the write in `span`, recording its duration as `duration_ms`.
+async fn traced_influx_write(
+    span: tracing::Span,
+    write: impl Future<Output = Result<(), InfluxWriteError>>,
+) -> Result<(), InfluxWriteError> {
+    let started_at = Instant::now();
+    let result = write.instrument(span.clone()).await;
+    span.record(
+        "duration_ms",
+        u64::try_from(started_at.elapsed().as_millis()).unwrap_or(u64::MAX),
+    );
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        path::{Path, PathBuf},
+        sync::Mutex,
+    };
+
+    use axum::{http::StatusCode, routing::post, Router};
+
+    use super::*;
+    use crate::{config::tests::config, influx::tests::key};
+
+    /// Influx answering the writes with the queued statuses, 204 once they are used up.
+    #[derive(Clone, Default)]
+    struct MockInflux {
+        statuses: Arc<Mutex<VecDeque<StatusCode>>>,
+        bodies: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockInflux {
+        async fn start(config: &Config, statuses: &[StatusCode]) -> (Self, influx::Client) {
+            let mock = Self::default();
+            mock.statuses.lock().unwrap().extend(statuses);
+            let app = Router::new().route(
+                "/api/v2/write",
+                post({
+                    let mock = mock.clone();
+                    move |body: String| {
+                        async move {
+                            mock.bodies.lock().unwrap().push(body);
+                            let status = mock.statuses.lock().unwrap().pop_front();
+                            status.unwrap_or(StatusCode::NO_CONTENT)
+                        }
+                    }
+                }),
+            );
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            let client = influx::Client::new(
+                reqwest::Client::new(),
+                &endpoint,
+                "org",
+                "token",
+                config.influxdb_precision,
+            );
+            (mock, client)
+        }
+
+        fn writes(&self) -> usize {
+            self.bodies.lock().unwrap().len()
+        }
+    }
+
+    /// Path unique to the test `name`, nothing exists under it.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lpa-flush-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    /// File of today the NDJSON output with the base `path` appends to.
+    fn day_file(path: &Path) -> PathBuf {
+        PathBuf::from(format!(
+            "{}.{}",
+            path.display(),
+            chrono::Utc::now().date_naive().format("%Y-%m-%d")
+        ))
+    }
+
+    fn batch() -> HashMap<AggregatedKey, CommunicationData> {
+        let value = CommunicationData {
+            packets: 2,
+            bytes: 100,
+            ..CommunicationData::default()
+        };
+        HashMap::from([(key(), value)])
+    }
+
+    async fn flusher(
+        args: &[&str],
+        statuses: &[StatusCode],
+        sinks: Sinks,
+    ) -> (MockInflux, Flusher) {
+        let config = Arc::new(config(args));
+        let (influx, client) = MockInflux::start(&config, statuses).await;
+        let flusher = Flusher::new(config, client, sinks, Arc::default()).unwrap();
+        (influx, flusher)
+    }
+
+    async fn write(flusher: &mut Flusher, rollup_watermark: u64) -> Flush {
+        flusher
+            .write(&mut None, &CidrTree::new(&[]), rollup_watermark)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn nothing_is_written_without_entries() {
+        let (influx, mut flusher) = flusher(&[], &[], Sinks::default()).await;
+
+        assert!(!flusher
+            .stage(HashMap::new(), HashMap::new(), Vec::new())
+            .await
+            .unwrap());
+
+        assert!(!flusher.is_pending());
+        assert_eq!(influx.writes(), 0);
+    }
+
+    fn offsets() -> Vec<PartitionOffset> {
+        vec![PartitionOffset {
+            topic: "flows".to_owned(),
+            partition: 0,
+            offset: 42,
+        }]
+    }
+
+    #[tokio::test]
+    async fn offsets_are_handed_out_once_written() {
+        let (_influx, mut flusher) =
+            flusher(&[], &[StatusCode::INTERNAL_SERVER_ERROR], Sinks::default()).await;
+        flusher
+            .stage(batch(), HashMap::new(), offsets())
+            .await
+            .unwrap();
+
+        assert_eq!(write(&mut flusher, 0).await, Flush::Retry(RETRY_WAIT));
+        assert_eq!(flusher.take_written_offsets(), None);
+        assert_eq!(write(&mut flusher, 0).await, Flush::Written(1));
+        assert_eq!(flusher.take_written_offsets(), Some(offsets()));
+        assert_eq!(flusher.take_written_offsets(), None);
+    }
+
+    #[tokio::test]
+    async fn offsets_of_an_empty_drain_are_handed_out() {
+        let (_influx, mut flusher) = flusher(&[], &[], Sinks::default()).await;
+
+        assert!(!flusher
+            .stage(HashMap::new(), HashMap::new(), offsets())
+            .await
+            .unwrap());
+
+        assert_eq!(flusher.take_written_offsets(), Some(offsets()));
+    }
+
+    #[tokio::test]
+    async fn offsets_wait_for_the_background_writes() {
+        let (influx, mut flusher) =
+            flusher(&["--influx-write-concurrency=2"], &[], Sinks::default()).await;
+        flusher
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(write(&mut flusher, 0).await, Flush::Written(1));
+        flusher
+            .stage(batch(), HashMap::new(), offsets())
+            .await
+            .unwrap();
+        assert_eq!(write(&mut flusher, 0).await, Flush::Written(1));
+
+        assert_eq!(flusher.take_written_offsets(), None);
+        flusher.join_background_writes().await;
+        assert_eq!(flusher.take_written_offsets(), Some(offsets()));
+        assert_eq!(influx.writes(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_sinks_are_retried_alone() {
+        let path = temp_path("retried-alone");
+        let output_file = format!("--output-ndjson-file={}", path.display());
+        let (influx, mut flusher) = flusher(
+            &["--output-ndjson", &output_file, "--output-both"],
+            &[StatusCode::INTERNAL_SERVER_ERROR],
+            Sinks {
+                ndjson_output: Some(NdjsonOutput::new(Some(path.clone()))),
+                ..Sinks::default()
+            },
+        )
+        .await;
+        assert!(flusher
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap());
+
+        assert_eq!(write(&mut flusher, 0).await, Flush::Retry(RETRY_WAIT));
+        assert!(flusher.is_pending());
+        assert_eq!(write(&mut flusher, 0).await, Flush::Written(1));
+        assert!(!flusher.is_pending());
+
+        assert_eq!(influx.writes(), 2);
+        let records = std::fs::read_to_string(day_file(&path)).unwrap();
+        assert_eq!(records.lines().count(), 1);
+        std::fs::remove_file(day_file(&path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saved_batches_skip_the_sinks_that_accepted_them() {
+        let path = temp_path("saved");
+        let output_file = format!("--output-ndjson-file={}", path.display());
+        let args = ["--output-ndjson", output_file.as_str(), "--output-both"];
+        let ndjson = || {
+            Sinks {
+                ndjson_output: Some(NdjsonOutput::new(Some(path.clone()))),
+                ..Sinks::default()
+            }
+        };
+        let (_influx, mut first_run) =
+            flusher(&args, &[StatusCode::INTERNAL_SERVER_ERROR], ndjson()).await;
+        first_run
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(write(&mut first_run, 0).await, Flush::Retry(RETRY_WAIT));
+
+        let saved = first_run.take_unwritten().unwrap();
+        assert!(!first_run.is_pending());
+        assert_eq!(saved.written_to, [Sink::KafkaOutput, Sink::Ndjson]);
+        let batch_id = saved.batch_id.clone().unwrap();
+
+        // The next run writes it into Influx under the same id, the NDJSON output already has it.
+        let (influx, mut next_run) = flusher(&args, &[], ndjson()).await;
+        next_run.restore_pending(saved).await.unwrap();
+        assert_eq!(write(&mut next_run, 0).await, Flush::Written(1));
+
+        let bodies = influx.bodies.lock().unwrap().clone();
+        assert_eq!(bodies.len(), 1);
+        assert!(bodies
+            .iter()
+            .all(|body| body.contains(&format!("batch_number={batch_id}"))));
+        let records = std::fs::read_to_string(day_file(&path)).unwrap();
+        assert_eq!(records.lines().count(), 1);
+        std::fs::remove_file(day_file(&path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ndjson_batches_are_forgotten_by_the_recovery_database() {
+        let path = temp_path("ndjson-recovery");
+        let database = path.with_extension("sqlite");
+        let _ = std::fs::remove_file(&database);
+        let recovery = Recovery::open(&database).await.unwrap();
+        let output_file = format!("--output-ndjson-file={}", path.display());
+        let (influx, mut flusher) = flusher(
+            &["--output-ndjson", &output_file],
+            &[],
+            Sinks {
+                ndjson_output: Some(NdjsonOutput::new(Some(path.clone()))),
+                recovery: Some(recovery.clone()),
+                ..Sinks::default()
+            },
+        )
+        .await;
+
+        flusher
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(recovery.residual().await.unwrap().len(), 1);
+        assert_eq!(write(&mut flusher, 0).await, Flush::Written(1));
+
+        // Without `--output-both` the NDJSON output replaces Influx.
+        assert_eq!(influx.writes(), 0);
+        assert!(recovery.residual().await.unwrap().is_empty());
+        std::fs::remove_file(day_file(&path)).unwrap();
+        std::fs::remove_file(database).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limited_writes_wait_at_most_the_maximum() {
+        let (_influx, mut flusher) = flusher(
+            &["--influxdb-max-retry-wait-seconds=1"],
+            &[StatusCode::TOO_MANY_REQUESTS],
+            Sinks::default(),
+        )
+        .await;
+        flusher
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            write(&mut flusher, 0).await,
+            Flush::Retry(Duration::from_secs(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn given_up_batches_are_backed_up() {
+        let dir = temp_path("given-up");
+        let failed_batch_dir = format!("--failed-batch-dir={}", dir.display());
+        let (_influx, mut flusher) = flusher(
+            &[&failed_batch_dir],
+            &[StatusCode::INTERNAL_SERVER_ERROR],
+            Sinks::default(),
+        )
+        .await;
+        flusher
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(write(&mut flusher, 0).await, Flush::Retry(RETRY_WAIT));
+
+        flusher.give_up().await;
+
+        assert!(!flusher.is_pending());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_points_are_not_retried() {
+        let (influx, mut flusher) =
+            flusher(&[], &[StatusCode::PAYLOAD_TOO_LARGE], Sinks::default()).await;
+        flusher
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap();
+
+        assert_eq!(write(&mut flusher, 0).await, Flush::Written(1));
+        assert_eq!(influx.writes(), 1);
+    }
+
+    #[tokio::test]
+    async fn exhausted_batches_are_backed_up() {
+        let dir = temp_path("backed-up");
+        let failed_batch_dir = format!("--failed-batch-dir={}", dir.display());
+        let (influx, mut flusher) = flusher(
+            &["--influxdb-max-retries=1", &failed_batch_dir],
+            &[StatusCode::INTERNAL_SERVER_ERROR; 2],
+            Sinks::default(),
+        )
+        .await;
+        flusher
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap();
+
+        assert_eq!(write(&mut flusher, 0).await, Flush::Retry(RETRY_WAIT));
+        assert_eq!(write(&mut flusher, 0).await, Flush::Written(1));
+
+        assert_eq!(influx.writes(), 2);
+        assert_eq!(flusher.failed_batches_on_disk.load(Ordering::Relaxed), 1);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovered_batches_keep_their_id() {
+        let path = temp_path("recovered");
+        let database = path.with_extension("sqlite");
+        let _ = std::fs::remove_file(&database);
+        let recovery = Recovery::open(&database).await.unwrap();
+        let batch: Vec<_> = batch().into_iter().collect();
+        recovery.store(&batch, Some("17")).await.unwrap();
+        let output_file = format!("--output-ndjson-file={}", path.display());
+        let (influx, mut flusher) = flusher(
+            &["--output-ndjson", &output_file, "--output-both"],
+            &[StatusCode::INTERNAL_SERVER_ERROR],
+            Sinks {
+                ndjson_output: Some(NdjsonOutput::new(Some(path.clone()))),
+                recovery: Some(recovery.clone()),
+                ..Sinks::default()
+            },
+        )
+        .await;
+
+        for batch in flusher.recovered().await.unwrap() {
+            flusher.stage_recovered(batch);
+        }
+        assert_eq!(write(&mut flusher, 0).await, Flush::Retry(RETRY_WAIT));
+        assert_eq!(write(&mut flusher, 0).await, Flush::Written(1));
+
+        // Both attempts use the stored id, only Influx gets the batch again.
+        let bodies = influx.bodies.lock().unwrap().clone();
+        assert_eq!(bodies.len(), 2);
+        assert!(bodies.iter().all(|body| body.contains("batch_number=17")));
+        assert!(!day_file(&path).exists());
+        assert!(recovery.residual().await.unwrap().is_empty());
+        std::fs::remove_file(database).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rollups_are_written_once_closed() {
+        let (influx, mut flusher) =
+            flusher(&["--rollup-alignment-seconds=3600"], &[], Sinks::default()).await;
+
+        flusher
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(write(&mut flusher, 0).await, Flush::Written(1));
+        assert_eq!(influx.writes(), 1);
+
+        flusher
+            .stage(batch(), HashMap::new(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(write(&mut flusher, u64::MAX).await, Flush::Written(1));
+        let bodies = influx.bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 3);
+        // Both batches are merged into the single rollup point.
+        assert!(bodies
+            .last()
+            .is_some_and(|body| body.lines().count() == 1 && body.contains("packets=4i")));
+    }
+}
@@ -0,0 +1,78 @@
+use std::{collections::HashMap, net::IpAddr, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Number of cached lookups after which the cache is cleared, so scans of the whole internet do
+/// not grow it without limit.
+const MAX_CACHED_ADDRESSES: usize = 100_000;
+
+/// Country and autonomous system of an outside address.
+#[derive(Serialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct GeoInfo {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// Subset of an MMDB record. Country and ASN databases fill different fields, so both are
+/// optional and any `GeoLite2` database can be used.
+#[derive(Deserialize)]
+struct Record<'a> {
+    #[serde(borrow)]
+    country: Option<Country<'a>>,
+    autonomous_system_number: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Country<'a> {
+    iso_code: Option<&'a str>,
+}
+
+/// MMDB reader with a cache of the recently looked up addresses.
+pub struct GeoIp {
+    reader: maxminddb::Reader<Vec<u8>>,
+    cache: HashMap<IpAddr, GeoInfo>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .with_context(|| format!("Unable to open GeoIP database `{}`.", path.display()))?;
+
+        Ok(Self {
+            reader,
+            cache: HashMap::new(),
+        })
+    }
+
+    /// Looks the address up, addresses missing in the database get an empty [`GeoInfo`].
+    pub fn lookup(&mut self, ip: IpAddr) -> GeoInfo {
+        if let Some(info) = self.cache.get(&ip) {
+            return info.clone();
+        }
+
+        let info = match self.reader.lookup::<Record>(ip) {
+            Ok(record) => {
+                GeoInfo {
+                    country: record
+                        .country
+                        .and_then(|country| country.iso_code)
+                        .map(str::to_owned),
+                    asn: record.autonomous_system_number,
+                }
+            },
+            Err(maxminddb::MaxMindDBError::AddressNotFoundError(_)) => GeoInfo::default(),
+            Err(error) => {
+                tracing::debug!(%ip, %error, "GeoIP lookup failed.");
+                GeoInfo::default()
+            },
+        };
+
+        if self.cache.len() >= MAX_CACHED_ADDRESSES {
+            self.cache.clear();
+        }
+        self.cache.insert(ip, info.clone());
+
+        info
+    }
+}
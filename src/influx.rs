@@ -1,52 +1,797 @@
 use std::{
     collections::HashMap,
-    sync::atomic::{AtomicI64, Ordering},
+    fmt,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use futures::prelude::*;
-use influxdb2::{
-    models::{data_point::DataPointError, DataPoint},
-    Client,
+use anyhow::Context;
+use influxdb2::models::{data_point::DataPointError, DataPoint, WriteDataPoint};
+use reqwest::{
+    header::{AUTHORIZATION, RETRY_AFTER},
+    StatusCode,
 };
 
-use crate::util::{AggregatedKey, CommunicationData};
+use crate::{
+    config::{BatchIdStrategy, Precision},
+    util::{self, AggregatedKey, CommunicationData, HostKey, Location},
+};
+
+/// Measurement of the per-host rollup.
+pub const HOST_MEASUREMENT: &str = "sflow_host";
+/// Tags written by this application, `--influxdb-tags-extra` must not override them.
+pub const STANDARD_TAGS: &[&str] = &[
+    "source",
+    "target",
+    "a",
+    "b",
+    "src_vlan",
+    "dst_vlan",
+    "proto",
+    "in_if",
+    "out_if",
+    "mpls_label",
+    "exporter",
+    "tcp_flags",
+    "dscp",
+    "icmp_type",
+    "icmp_code",
+    "ip_version",
+    "src_country",
+    "src_asn",
+    "dst_country",
+    "dst_asn",
+    "src_group",
+    "dst_group",
+    "app",
+    "src_datacenter",
+    "batch_number",
+    "host",
+    "direction",
+];
+
+/// Measurement of the points and optional dimensions written as tags, mirrors the `--include-*`
+/// flags.
+#[derive(Debug, Clone)]
+pub struct PointOptions {
+    pub measurement: String,
+    pub interfaces: bool,
+    /// Human readable names of interface indices, unknown indices keep the number.
+    pub interface_names: HashMap<u32, String>,
+    pub mpls: bool,
+    pub exporter: bool,
+    pub tcp_flags: bool,
+    pub dscp: bool,
+    pub icmp: bool,
+    /// Write the endpoints as `a`/`b` tags and the per-direction fields instead of the totals.
+    pub bidirectional: bool,
+    /// Static tags added to every point.
+    pub extra_tags: Vec<(String, String)>,
+    pub precision: Precision,
+}
+
+impl PointOptions {
+    fn interface_tag(&self, index: u32) -> String {
+        self.interface_names
+            .get(&index)
+            .cloned()
+            .unwrap_or_else(|| index.to_string())
+    }
+}
+
+/// Generates the `batch_number` tag according to the configured [`BatchIdStrategy`].
+pub struct BatchIds {
+    strategy: BatchIdStrategy,
+    next_number: AtomicI64,
+    number_file: Option<PathBuf>,
+    /// Startup time in milliseconds, the prefix of `run-sequential`.
+    run_id: i64,
+}
+
+impl BatchIds {
+    pub fn new(strategy: BatchIdStrategy, number_file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let next_number = match &number_file {
+            Some(path) => {
+                match std::fs::read_to_string(path) {
+                    Ok(content) => {
+                        content.trim().parse().with_context(|| {
+                            format!("Invalid batch number in `{}`.", path.display())
+                        })?
+                    },
+                    Err(error) if error.kind() == ErrorKind::NotFound => 0,
+                    Err(error) => {
+                        return Err(error)
+                            .with_context(|| format!("Unable to read `{}`.", path.display()));
+                    },
+                }
+            },
+            None => 0,
+        };
+
+        Ok(Self {
+            strategy,
+            next_number: AtomicI64::new(next_number),
+            number_file,
+            run_id: chrono::Utc::now().timestamp_millis(),
+        })
+    }
+
+    /// Returns the id of the next batch or `None` when the tag should be omitted.
+    pub fn next(&self) -> anyhow::Result<Option<String>> {
+        Ok(match self.strategy {
+            BatchIdStrategy::Sequential => {
+                let batch_number = self.next_number.fetch_add(1, Ordering::SeqCst);
+                if let Some(path) = &self.number_file {
+                    std::fs::write(path, (batch_number + 1).to_string())
+                        .with_context(|| format!("Unable to persist `{}`.", path.display()))?;
+                }
+                Some(batch_number.to_string())
+            },
+            BatchIdStrategy::RunSequential => {
+                let batch_number = self.next_number.fetch_add(1, Ordering::SeqCst);
+                Some(format!("{}-{batch_number}", self.run_id))
+            },
+            BatchIdStrategy::UuidV4 => Some(uuid::Uuid::new_v4().to_string()),
+            BatchIdStrategy::UuidV7 => Some(uuid::Uuid::now_v7().to_string()),
+            BatchIdStrategy::None => None,
+        })
+    }
+}
 
-static BATCH_NUMBER: AtomicI64 = AtomicI64::new(0);
+/// Wait used when Influx rate limits a write without a usable `Retry-After` header.
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum InfluxWriteError {
+    /// Influx answered 429, the write may be retried after the requested duration.
+    RateLimited(Duration),
+    /// Influx answered 401, the token is invalid or was revoked.
+    Unauthorized,
+    /// Influx answered 403, the token lacks the write permission of the bucket.
+    Forbidden,
+    /// Influx answered 413 to a single point.
+    PayloadTooLarge,
+    /// No connection or no answer within `--influxdb-connect-timeout-seconds` or
+    /// `--influxdb-timeout-secs`.
+    Timeout(reqwest::Error),
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for InfluxWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InfluxWriteError::RateLimited(wait) => {
+                write!(f, "rate limited, retry after {}s", wait.as_secs())
+            },
+            InfluxWriteError::Unauthorized => f.write_str("unauthorized, check the token"),
+            InfluxWriteError::Forbidden => {
+                f.write_str("forbidden, check the permissions of the token")
+            },
+            InfluxWriteError::PayloadTooLarge => f.write_str("request body too large"),
+            InfluxWriteError::Timeout(error) => write!(f, "timed out: {error}"),
+            InfluxWriteError::Other(error) => write!(f, "{error:#}"),
+        }
+    }
+}
+
+impl std::error::Error for InfluxWriteError {}
+
+impl InfluxWriteError {
+    /// Influx rejected the credentials, retrying with the same token is pointless.
+    #[must_use]
+    pub fn is_auth_error(&self) -> bool {
+        matches!(
+            self,
+            InfluxWriteError::Unauthorized | InfluxWriteError::Forbidden
+        )
+    }
+
+    /// Influx will reject the same request again, e.g. a single point that is too large, so the
+    /// batch is backed up or dropped right away.
+    #[must_use]
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, InfluxWriteError::PayloadTooLarge)
+    }
+}
+
+/// Rejected writes by the class of the response, so quota and credential problems can be told
+/// apart. Shared by the clones of a [`Client`].
+#[derive(Debug, Default, Clone)]
+pub struct WriteErrorCounters {
+    /// 429 responses.
+    pub rate_limited: Arc<AtomicU64>,
+    /// 413 responses, each halves the points per request.
+    pub payload_too_large: Arc<AtomicU64>,
+    /// 401 and 403 responses.
+    pub auth_failures: Arc<AtomicU64>,
+}
+
+impl From<anyhow::Error> for InfluxWriteError {
+    fn from(error: anyhow::Error) -> Self {
+        InfluxWriteError::Other(error)
+    }
+}
+
+impl From<DataPointError> for InfluxWriteError {
+    fn from(error: DataPointError) -> Self {
+        InfluxWriteError::Other(error.into())
+    }
+}
+
+impl From<std::io::Error> for InfluxWriteError {
+    fn from(error: std::io::Error) -> Self {
+        InfluxWriteError::Other(error.into())
+    }
+}
+
+impl From<reqwest::Error> for InfluxWriteError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            return InfluxWriteError::Timeout(error);
+        }
+        InfluxWriteError::Other(error.into())
+    }
+}
+
+/// Client of the Influx v2 write API. Unlike `influxdb2::Client` it exposes the response
+/// headers, which are needed to honour `Retry-After`.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    write_url: String,
+    org: String,
+    token: String,
+    precision: Precision,
+    errors: WriteErrorCounters,
+}
+
+impl Client {
+    #[must_use]
+    pub fn new(
+        http: reqwest::Client,
+        endpoint: &str,
+        org: &str,
+        token: &str,
+        precision: Precision,
+    ) -> Self {
+        Self {
+            http,
+            write_url: format!("{}/api/v2/write", endpoint.trim_end_matches('/')),
+            org: org.to_owned(),
+            token: token.to_owned(),
+            precision,
+            errors: WriteErrorCounters::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn error_counters(&self) -> &WriteErrorCounters {
+        &self.errors
+    }
+
+    /// Same client authenticated with another token.
+    #[must_use]
+    pub fn with_token(&self, token: &str) -> Self {
+        Self {
+            token: token.to_owned(),
+            ..self.clone()
+        }
+    }
+
+    /// Serializes every point as soon as it is built, so only the request body is held in
+    /// memory.
+    async fn write(
+        &self,
+        bucket_name: &str,
+        points: impl Iterator<Item = Result<DataPoint, DataPointError>>,
+    ) -> Result<(), InfluxWriteError> {
+        let mut body = Vec::new();
+        for point in points {
+            point?.write_data_point_to(&mut body)?;
+        }
+
+        let response = self
+            .http
+            .post(&self.write_url)
+            .query(&[
+                ("org", self.org.as_str()),
+                ("bucket", bucket_name),
+                ("precision", self.precision.as_str()),
+            ])
+            .header(AUTHORIZATION, format!("Token {}", self.token))
+            .body(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            self.errors.rate_limited.fetch_add(1, Ordering::Relaxed);
+            let wait = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after)
+                .unwrap_or(DEFAULT_RATE_LIMIT_WAIT);
+            return Err(InfluxWriteError::RateLimited(wait));
+        }
+        if status == StatusCode::PAYLOAD_TOO_LARGE {
+            self.errors
+                .payload_too_large
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(InfluxWriteError::PayloadTooLarge);
+        }
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            self.errors.auth_failures.fetch_add(1, Ordering::Relaxed);
+            return Err(if status == StatusCode::UNAUTHORIZED {
+                InfluxWriteError::Unauthorized
+            } else {
+                InfluxWriteError::Forbidden
+            });
+        }
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("Influx responded {status}: {text}").into());
+        }
+
+        Ok(())
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> Option<i64> {
+    i64::try_from(time.duration_since(UNIX_EPOCH).ok()?.as_nanos()).ok()
+}
+
+/// HTTP client for Influx. A timed out request fails like any other write and is retried.
+pub fn http_client(
+    timeout: Duration,
+    connect_timeout: Option<Duration>,
+    ca_cert: Option<&Path>,
+    accept_invalid_certs: bool,
+) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .danger_accept_invalid_certs(accept_invalid_certs);
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    if let Some(path) = ca_cert {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Unable to read CA certificate `{}`.", path.display()))?;
+        let certificate = reqwest::Certificate::from_pem(&pem)
+            .with_context(|| format!("Invalid CA certificate `{}`.", path.display()))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    builder
+        .build()
+        .context("Unable to build the Influx HTTP client.")
+}
+
+/// Parses `Retry-After` given either as delta-seconds or as an HTTP-date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    // A date in the past means the write may be retried right away.
+    Some(
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
 
 pub async fn insert_data_into_influx(
     client: &Client,
     bucket_name: &str,
-    edge_cache: &HashMap<AggregatedKey, CommunicationData>,
-) -> anyhow::Result<()> {
-    let batch_number = BATCH_NUMBER.fetch_add(1, Ordering::SeqCst);
+    batch: &[(AggregatedKey, CommunicationData)],
+    batch_id: Option<&str>,
+    options: &PointOptions,
+) -> Result<(), InfluxWriteError> {
+    // A retry of the whole batch writes the accepted chunks again, their points overwrite
+    // themselves. Points per request, halved whenever Influx answers 413. Only this batch is
+    // affected, the next one starts with a single request again.
+    let mut max_points = batch.len();
+    let mut remaining = batch;
+    while !remaining.is_empty() {
+        let (chunk, rest) = remaining.split_at(max_points.min(remaining.len()));
+        let points = chunk
+            .iter()
+            .map(|(key, value)| build_data_point(key, value, batch_id, options));
+        match client.write(bucket_name, points).await {
+            Ok(()) => remaining = rest,
+            Err(InfluxWriteError::PayloadTooLarge) if chunk.len() > 1 => {
+                max_points = chunk.len() / 2;
+                tracing::warn!(max_points, "Influx rejected the request as too large.");
+            },
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Tag value of an endpoint. Inside hosts and outside keep the `Debug` rendering of the existing
+/// series, the overflow and unknown markers are lowercase like in the other outputs.
+fn location_tag(location: Location) -> String {
+    match location {
+        Location::Overflow | Location::Unknown => location.to_string(),
+        Location::Inside(_) | Location::Outside => format!("{location:?}"),
+    }
+}
+
+/// Point of one aggregate, `key.measurement` overrides the one of `options`.
+pub fn build_data_point(
+    key: &AggregatedKey,
+    value: &CommunicationData,
+    batch_id: Option<&str>,
+    options: &PointOptions,
+) -> Result<DataPoint, DataPointError> {
+    let (source_tag, target_tag) = if options.bidirectional {
+        ("a", "b")
+    } else {
+        ("source", "target")
+    };
+    let measurement = key.measurement.as_deref().unwrap_or(&options.measurement);
+    let mut point = DataPoint::builder(measurement)
+        .tag(source_tag, location_tag(key.source))
+        .tag(target_tag, location_tag(key.target))
+        .tag("src_vlan", key.src_vlan.to_string())
+        .tag("dst_vlan", key.dst_vlan.to_string())
+        .tag("proto", key.proto.to_string());
+    if options.interfaces {
+        point = point
+            .tag("in_if", options.interface_tag(key.in_if))
+            .tag("out_if", options.interface_tag(key.out_if));
+    }
+    if options.mpls {
+        point = point.tag(
+            "mpls_label",
+            key.mpls_label
+                .map_or_else(|| "none".to_owned(), |label| label.to_string()),
+        );
+    }
+    if options.exporter {
+        point = point.tag(
+            "exporter",
+            key.exporter
+                .map_or_else(|| "unknown".to_owned(), |exporter| exporter.to_string()),
+        );
+    }
+    for (tag, value) in &options.extra_tags {
+        point = point.tag(tag, value);
+    }
+    if let Some(tcp_flags) = key.tcp_flags.filter(|_| options.tcp_flags) {
+        point = point.tag("tcp_flags", tcp_flags.to_string());
+    }
+    if let Some(dscp) = key.dscp.filter(|_| options.dscp) {
+        point = point.tag("dscp", util::dscp_name(dscp));
+    }
+    if let Some(icmp) = key.icmp.filter(|_| options.icmp) {
+        point = point
+            .tag("icmp_type", icmp.type_name(key.proto))
+            .tag("icmp_code", icmp.code_name(key.proto));
+    }
+    if let Some(ip_version) = key.ip_version() {
+        point = point.tag("ip_version", ip_version.to_string());
+    }
+    for (tag, group) in [("src_group", &key.src_group), ("dst_group", &key.dst_group)] {
+        if let Some(group) = group {
+            point = point.tag(tag, group.as_ref());
+        }
+    }
+    if let Some(app) = &key.app {
+        point = point.tag("app", app.as_ref());
+    }
+    if let Some(datacenter) = &key.datacenter {
+        point = point.tag("src_datacenter", datacenter.as_ref());
+    }
+    for (prefix, geo) in [("src", &key.src_geo), ("dst", &key.dst_geo)] {
+        let Some(geo) = geo else { continue };
+        if let Some(country) = &geo.country {
+            point = point.tag(format!("{prefix}_country"), country);
+        }
+        if let Some(asn) = geo.asn {
+            point = point.tag(format!("{prefix}_asn"), asn.to_string());
+        }
+    }
+    // Primary key consists of tags + timestamp. We cannot guarantee that the same
+    // timestamp and tags will not repeat. Therefore must add something unique to each
+    // insert. Otherwise, we could erase already existing data.
+    if let Some(batch_id) = batch_id {
+        point = point.tag("batch_number", batch_id);
+    }
+    point = if options.bidirectional {
+        point
+            .field("packets_fwd", value.packets_fwd as i64)
+            .field("packets_rev", value.packets_rev as i64)
+            .field("bytes_fwd", value.bytes_fwd as i64)
+            .field("bytes_rev", value.bytes_rev as i64)
+    } else {
+        point
+            .field("packets", value.packets as i64)
+            .field("bytes", value.bytes as i64)
+    };
+    for (field, observed) in [
+        ("first_observed", value.first_observed),
+        ("last_observed", value.last_observed),
+    ] {
+        if let Some(nanos) = observed.and_then(unix_nanos) {
+            point = point.field(field, nanos);
+        }
+    }
+    if let Some(std_dev) = value.std_dev() {
+        point = point.field("std_dev_bytes", std_dev);
+    }
+    point
+        .timestamp(key.bucket_start() as i64 * options.precision.multiply_factor())
+        .build()
+}
+
+pub async fn insert_host_data_into_influx(
+    client: &Client,
+    bucket_name: &str,
+    totals: &HashMap<HostKey, CommunicationData>,
+    unique_peers: &HashMap<HostKey, u64>,
+    batch_id: Option<&str>,
+    options: &PointOptions,
+) -> Result<(), InfluxWriteError> {
     client
         .write(
             bucket_name,
-            stream::iter(
-                edge_cache
-                    .iter()
-                    .map(|(key, value)| {
-                        DataPoint::builder("sflow")
-                            .tag("source", format!("{:?}", key.source))
-                            .tag("target", format!("{:?}", key.target))
-                            .tag("src_vlan", key.src_vlan.to_string())
-                            .tag("dst_vlan", key.dst_vlan.to_string())
-                            .tag("proto", key.proto.to_string())
-                            // Primary key consists of tags + timestamp. We cannot guarantee that
-                            // the same timestamp and tags will not repeat. Therefore must add
-                            // something unique to each insert. Otherwise, we could erase already
-                            // existing data.
-                            .tag("batch_number", batch_number.to_string())
-                            .field("packets", value.packets as i64)
-                            .field("bytes", value.bytes as i64)
-                            // Default time is in seconds but we need it in nanoseconds.
-                            .timestamp(key.time as i64 * 1_000_000_000)
-                            .build()
-                    })
-                    .collect::<Result<Vec<DataPoint>, DataPointError>>()?,
-            ),
+            build_host_points(totals, unique_peers, batch_id, options),
         )
-        .await?;
+        .await
+}
 
-    Ok(())
+/// Only the extra tags and the precision of `options` apply to the host points. `unique_peers`
+/// is written for the hosts it has a count of.
+pub fn build_host_points<'a>(
+    totals: &'a HashMap<HostKey, CommunicationData>,
+    unique_peers: &'a HashMap<HostKey, u64>,
+    batch_id: Option<&'a str>,
+    options: &'a PointOptions,
+) -> impl Iterator<Item = Result<DataPoint, DataPointError>> + 'a {
+    totals.iter().map(move |(key, value)| {
+        let mut point = DataPoint::builder(HOST_MEASUREMENT)
+            .tag("host", key.host.to_string())
+            .tag("direction", key.direction.as_str());
+        for (tag, value) in &options.extra_tags {
+            point = point.tag(tag, value);
+        }
+        if let Some(batch_id) = batch_id {
+            point = point.tag("batch_number", batch_id);
+        }
+        if let Some(peers) = unique_peers.get(key) {
+            point = point.field("unique_peers", *peers as i64);
+        }
+        point
+            .field("packets", value.packets as i64)
+            .field("bytes", value.bytes as i64)
+            .timestamp(key.time as i64 * options.precision.multiply_factor())
+            .build()
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// 2023-11-14 22:10:00 UTC.
+    const TIME: u64 = 1_699_999_800;
+
+    pub(crate) fn options() -> PointOptions {
+        PointOptions {
+            measurement: "sflow".to_owned(),
+            interfaces: false,
+            interface_names: HashMap::new(),
+            mpls: false,
+            exporter: false,
+            tcp_flags: false,
+            dscp: false,
+            icmp: false,
+            bidirectional: false,
+            extra_tags: Vec::new(),
+            precision: Precision::S,
+        }
+    }
+
+    pub(crate) fn key() -> AggregatedKey {
+        AggregatedKey {
+            time: TIME,
+            source: Location::Inside("10.0.0.1".parse().unwrap()),
+            target: Location::Outside,
+            src_vlan: 0,
+            dst_vlan: 0,
+            proto: 6,
+            in_if: 17,
+            out_if: 23,
+            mpls_label: None,
+            exporter: None,
+            tcp_flags: None,
+            dscp: None,
+            icmp: None,
+            ip_version: None,
+            src_geo: None,
+            dst_geo: None,
+            src_group: None,
+            dst_group: None,
+            app: None,
+            datacenter: None,
+            measurement: None,
+        }
+    }
+
+    /// Line protocol of the point of `key`.
+    fn line(key: &AggregatedKey, options: &PointOptions) -> String {
+        batch_line(key, None, options)
+    }
+
+    fn batch_line(key: &AggregatedKey, batch_id: Option<&str>, options: &PointOptions) -> String {
+        let value = CommunicationData {
+            packets: 2,
+            bytes: 100,
+            ..CommunicationData::default()
+        };
+        let mut body = Vec::new();
+        build_data_point(key, &value, batch_id, options)
+            .unwrap()
+            .write_data_point_to(&mut body)
+            .unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn aliased_topics_are_tagged_with_their_datacenter() {
+        let key = AggregatedKey {
+            datacenter: Some(Arc::from("sflow-dc1")),
+            ..key()
+        };
+
+        assert!(line(&key, &options()).contains(",src_datacenter=sflow-dc1,"));
+        assert!(!line(&key(), &options()).contains("src_datacenter"));
+    }
+
+    #[test]
+    fn interfaces_are_tagged_by_name_or_index() {
+        let options = PointOptions {
+            interfaces: true,
+            interface_names: HashMap::from([(17, "uplink-core1".to_owned())]),
+            ..options()
+        };
+        let line = line(&key(), &options);
+
+        assert!(line.contains(",in_if=uplink-core1"), "{line}");
+        assert!(line.contains(",out_if=23"), "{line}");
+    }
+
+    #[test]
+    fn interfaces_are_not_tagged_when_disabled() {
+        let line = line(&key(), &options());
+
+        assert!(!line.contains("in_if="), "{line}");
+        assert!(!line.contains("out_if="), "{line}");
+    }
+
+    #[test]
+    fn missing_mpls_label_is_tagged_none() {
+        let options = PointOptions {
+            mpls: true,
+            ..options()
+        };
+        let labelled = AggregatedKey {
+            mpls_label: Some(100),
+            ..key()
+        };
+
+        assert!(line(&labelled, &options).contains(",mpls_label=100"));
+        assert!(line(&key(), &options).contains(",mpls_label=none"));
+    }
+
+    #[test]
+    fn extra_tags_are_added_to_every_point() {
+        let options = PointOptions {
+            extra_tags: vec![
+                ("datacenter".to_owned(), "fra1".to_owned()),
+                ("instance".to_owned(), "lpa-2".to_owned()),
+            ],
+            ..options()
+        };
+        let line = line(&key(), &options);
+
+        assert!(line.contains(",datacenter=fra1"), "{line}");
+        assert!(line.contains(",instance=lpa-2"), "{line}");
+        assert!(line.contains(",source=Inside(10.0.0.1)"), "{line}");
+
+        let totals = HashMap::from([(
+            HostKey {
+                time: TIME,
+                host: "10.0.0.1".parse().unwrap(),
+                direction: util::Direction::Out,
+            },
+            CommunicationData::default(),
+        )]);
+        let mut body = Vec::new();
+        for point in build_host_points(&totals, &HashMap::new(), None, &options) {
+            point.unwrap().write_data_point_to(&mut body).unwrap();
+        }
+        let line = String::from_utf8(body).unwrap();
+        assert!(line.contains(",datacenter=fra1"), "{line}");
+    }
+
+    #[test]
+    fn timestamps_follow_the_precision() {
+        for (precision, timestamp) in [
+            (Precision::S, "1699999800"),
+            (Precision::Ms, "1699999800000"),
+            (Precision::Us, "1699999800000000"),
+            (Precision::Ns, "1699999800000000000"),
+        ] {
+            let options = PointOptions {
+                precision,
+                ..options()
+            };
+            let line = line(&key(), &options);
+
+            assert_eq!(
+                line.trim_end().rsplit(' ').next(),
+                Some(timestamp),
+                "{precision:?}"
+            );
+        }
+    }
+
+    /// Measurement, tags and timestamp of a line, what Influx identifies a point by.
+    fn point_identity(line: &str) -> String {
+        let line = line.trim_end();
+        let series = line.split(' ').next().unwrap();
+        let timestamp = line.rsplit(' ').next().unwrap();
+        format!("{series} {timestamp}")
+    }
+
+    /// Identities of the same key written by `batches` flushes of a run.
+    fn run_identities(batch_ids: &BatchIds, batches: usize) -> Vec<String> {
+        (0..batches)
+            .map(|_| {
+                let batch_id = batch_ids.next().unwrap().unwrap();
+                point_identity(&batch_line(&key(), Some(&batch_id), &options()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn run_sequential_points_of_two_runs_do_not_collide() {
+        let first_run = BatchIds::new(BatchIdStrategy::RunSequential, None).unwrap();
+        let mut identities = run_identities(&first_run, 3);
+        // A restart takes longer than the millisecond of the run id.
+        std::thread::sleep(Duration::from_millis(2));
+        let second_run = BatchIds::new(BatchIdStrategy::RunSequential, None).unwrap();
+        identities.extend(run_identities(&second_run, 3));
+
+        let unique: HashSet<_> = identities.iter().collect();
+        assert_eq!(unique.len(), identities.len(), "{identities:?}");
+    }
+
+    #[test]
+    fn persisted_sequential_points_of_two_runs_do_not_collide() {
+        let path = std::env::temp_dir().join(format!("lpa-batch-number-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let first_run = BatchIds::new(BatchIdStrategy::Sequential, Some(path.clone())).unwrap();
+        let mut identities = run_identities(&first_run, 3);
+        let second_run = BatchIds::new(BatchIdStrategy::Sequential, Some(path.clone())).unwrap();
+        identities.extend(run_identities(&second_run, 3));
+        std::fs::remove_file(&path).unwrap();
+
+        let unique: HashSet<_> = identities.iter().collect();
+        assert_eq!(unique.len(), identities.len(), "{identities:?}");
+    }
 }
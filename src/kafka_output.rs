@@ -0,0 +1,104 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use rdkafka::{
+    config::ClientConfig,
+    error::KafkaError,
+    producer::{FutureProducer, FutureRecord},
+    types::RDKafkaErrorCode,
+};
+use serde::Serialize;
+
+use crate::util::{AggregatedKey, CommunicationData};
+
+/// JSON document produced for every aggregated edge.
+#[derive(Serialize)]
+pub struct OutputRecord<'a> {
+    #[serde(flatten)]
+    pub key: &'a AggregatedKey,
+    #[serde(flatten)]
+    pub data: &'a CommunicationData,
+}
+
+/// Publishes flushed batches to a Kafka topic so more downstream systems can consume them.
+pub struct KafkaOutput {
+    producer: FutureProducer,
+    topic: String,
+    failed_deliveries: AtomicU64,
+}
+
+impl KafkaOutput {
+    /// `client_config` carries the brokers and the security settings.
+    pub fn new(mut client_config: ClientConfig, topic: String) -> anyhow::Result<Self> {
+        let producer = client_config
+            // librdkafka retries failed deliveries on its own until this timeout elapses.
+            .set("message.timeout.ms", "30000")
+            .create()?;
+
+        Ok(Self {
+            producer,
+            topic,
+            failed_deliveries: AtomicU64::new(0),
+        })
+    }
+
+    #[must_use]
+    pub fn failed_deliveries(&self) -> u64 {
+        self.failed_deliveries.load(Ordering::Relaxed)
+    }
+
+    /// Produces every entry of the batch keyed by source and target for partition affinity.
+    /// Delivery failures are logged and counted rather than returned, a batch is never produced
+    /// twice.
+    pub async fn produce_batch(&self, batch: &[(AggregatedKey, CommunicationData)]) {
+        let mut deliveries = Vec::with_capacity(batch.len());
+        for (key, data) in batch {
+            let payload = match serde_json::to_vec(&OutputRecord { key, data }) {
+                Ok(payload) => payload,
+                Err(error) => {
+                    tracing::error!(%error, %key, "Unable to serialize output record.");
+                    self.failed_deliveries.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                },
+            };
+            let record_key = format!("{}->{}", key.source, key.target);
+
+            let mut record = FutureRecord::to(&self.topic)
+                .key(&record_key)
+                .payload(&payload);
+            loop {
+                match self.producer.send_result(record) {
+                    Ok(delivery) => {
+                        deliveries.push(delivery);
+                        break;
+                    },
+                    Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                        record = returned;
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    },
+                    Err((error, _)) => {
+                        tracing::warn!(%error, %key, "Unable to enqueue output record.");
+                        self.failed_deliveries.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    },
+                }
+            }
+        }
+
+        for delivery in futures::future::join_all(deliveries).await {
+            match delivery {
+                Ok(Ok(_)) => {},
+                Ok(Err((error, _))) => {
+                    tracing::warn!(%error, "Unable to deliver output record.");
+                    self.failed_deliveries.fetch_add(1, Ordering::Relaxed);
+                },
+                Err(_) => {
+                    tracing::warn!("Output record delivery was cancelled.");
+                    self.failed_deliveries.fetch_add(1, Ordering::Relaxed);
+                },
+            }
+        }
+    }
+}
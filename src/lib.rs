@@ -0,0 +1,58 @@
+#![deny(
+    clippy::expect_used,
+    clippy::future_not_send,
+    clippy::indexing_slicing,
+    clippy::panic_in_result_fn,
+    clippy::pedantic,
+    clippy::string_slice,
+    clippy::todo,
+    clippy::unreachable,
+    clippy::unwrap_used,
+    unsafe_code
+)]
+#![allow(
+    clippy::manual_non_exhaustive,
+    clippy::missing_errors_doc,
+    clippy::module_inception,
+    clippy::module_name_repetitions,
+    clippy::needless_return,
+    clippy::single_match_else,
+    clippy::inconsistent_struct_constructor,
+    clippy::cast_possible_wrap
+)]
+
+pub mod admin;
+pub mod backup;
+pub mod check;
+pub mod classify;
+pub mod config;
+pub mod consumer;
+pub mod dead_letter;
+pub mod diff;
+pub mod flowprotob;
+pub mod flush;
+pub mod geoip;
+pub mod influx;
+pub mod kafka_output;
+pub mod log_format;
+pub mod metrics;
+pub mod ndjson;
+pub mod pipeline;
+pub mod postgres;
+pub mod recovery;
+pub mod replay;
+pub mod state;
+pub mod stats;
+pub mod store;
+pub mod unix_socket;
+pub mod util;
+pub mod workers;
+
+/// Exit code used when the idle watchdog gives up on the consumer.
+pub const IDLE_EXIT_CODE: i32 = 3;
+
+/// Exit code used when a topic does not have `--expected-partition-count` partitions.
+pub const PARTITION_COUNT_EXIT_CODE: i32 = 2;
+
+/// Exit code used when Influx rejects the credentials with `--influxdb-exit-on-auth-error`.
+pub const INFLUX_AUTH_EXIT_CODE: i32 = 4;
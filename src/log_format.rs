@@ -0,0 +1,78 @@
+use std::fmt;
+
+use chrono::SecondsFormat;
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    Event,
+    Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+/// Formats every event as one JSON object with an RFC3339 timestamp, the event fields and the
+/// version of the service.
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut fields = JsonFields(Map::new());
+        event.record(&mut fields);
+
+        let mut record = Map::new();
+        record.insert(
+            "timestamp".to_owned(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Micros, true)
+                .into(),
+        );
+        record.insert("level".to_owned(), metadata.level().as_str().into());
+        record.insert("target".to_owned(), metadata.target().into());
+        record.insert("version".to_owned(), env!("CARGO_PKG_VERSION").into());
+        record.insert("git_sha".to_owned(), env!("VERGEN_GIT_SHA").into());
+        record.insert("fields".to_owned(), Value::Object(fields.0));
+
+        writeln!(writer, "{}", Value::Object(record))
+    }
+}
+
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{value:?}").into());
+    }
+}
@@ -22,200 +22,1753 @@
 )]
 
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{HashMap, VecDeque},
+    path::Path,
     sync::{
-        atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        atomic::{AtomicI64, AtomicU64, Ordering},
         Arc,
+        PoisonError,
+        RwLock,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use anyhow::Context;
+use cidr_utils::cidr::IpCidr;
+use futures::FutureExt;
+use lpa::{
+    admin::{self, AdminRequest},
+    backup,
+    check,
+    classify,
+    config::{self, IdleAction, LogFormat},
+    consumer::{BackpressureController, FlowConsumerContext, LoggingConsumer},
+    dead_letter,
+    diff,
+    flowprotob,
+    flush::{Flush, FlushReply, Flusher, Sinks},
+    geoip,
+    influx,
+    kafka_output,
+    log_format,
+    metrics::{self, MetricKind},
+    ndjson,
+    pipeline::{Pipeline, PipelineCounters, TopicTags},
+    postgres,
+    recovery,
+    replay,
+    state,
+    stats,
+    unix_socket,
+    util::{self, SkipReason},
+    IDLE_EXIT_CODE,
+    PARTITION_COUNT_EXIT_CODE,
+};
+use opentelemetry_otlp::WithExportConfig;
 use prost::Message as ProstMessage;
 use rdkafka::{
-    client::ClientContext,
-    config::{ClientConfig, RDKafkaLogLevel},
-    consumer::{stream_consumer::StreamConsumer, Consumer, ConsumerContext, Rebalance},
-    error::KafkaResult,
-    message::Message,
+    config::RDKafkaLogLevel,
+    consumer::{CommitMode, Consumer},
+    error::{KafkaError, KafkaResult},
+    message::{Message, OwnedMessage},
+    offset::Offset,
     topic_partition_list::TopicPartitionList,
+    types::RDKafkaErrorCode,
+};
+use tracing_subscriber::{
+    fmt::{time::ChronoUtc, writer::BoxMakeWriter},
+    prelude::*,
+    util::SubscriberInitExt,
+    EnvFilter,
 };
-use tracing_subscriber::{prelude::*, util::SubscriberInitExt, EnvFilter};
-
-use crate::util::{AggregatedKey, CommunicationData};
 
-mod config;
-mod flowprotob;
-mod influx;
-mod util;
+/// The context is shared by the recreated consumers, so its counters survive a restart.
+fn create_consumer(
+    config: &config::Config,
+    context: &FlowConsumerContext,
+) -> anyhow::Result<LoggingConsumer> {
+    let consumer: LoggingConsumer = config
+        .kafka_client_config()
+        .set("group.id", &config.group_id)
+        .set("auto.offset.reset", config.kafka_offset_reset.as_str())
+        // .set("enable.partition.eof", "true")
+        .set("session.timeout.ms", "6000")
+        .set(
+            "statistics.interval.ms",
+            config.kafka_stats_interval_ms.to_string(),
+        )
+        // .set("enable.auto.commit", "false")
+        // The flush stores the offsets of the written messages, the automatic commits send them.
+        .set("enable.auto.offset.store", "false")
+        // librdkafka requires the response size to exceed the fetch size by 512 bytes and the
+        // partition fetch size to stay within the fetch size.
+        .set(
+            "fetch.message.max.bytes",
+            config.kafka_max_message_bytes.min(1024 * 1024).to_string(),
+        )
+        .set(
+            "fetch.max.bytes",
+            config.kafka_max_message_bytes.to_string(),
+        )
+        .set(
+            "receive.message.max.bytes",
+            (config.kafka_max_message_bytes + 512).to_string(),
+        )
+        .set_log_level(RDKafkaLogLevel::Debug)
+        .create_with_context(context.clone())?;
 
-// A context can be used to change the behavior of producers and consumers by adding callbacks
-// that will be executed by librdkafka. This particular context sets up custom callbacks to log rebalancing events.
-struct CustomContext;
+    if config.partition_assignment.is_empty() {
+        consumer.subscribe(
+            config
+                .topics
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<&str>>()
+                .as_slice(),
+        )?;
+    } else {
+        tracing::warn!(
+            group_id = %config.group_id,
+            "Partitions are assigned manually, the group id is only used to store offsets."
+        );
+        consumer.assign(&partition_assignment(&consumer, config)?)?;
+    }
 
-impl ClientContext for CustomContext {}
+    Ok(consumer)
+}
 
-impl ConsumerContext for CustomContext {
-    fn pre_rebalance(&self, rebalance: &Rebalance) {
-        tracing::info!("Pre rebalance {:?}", rebalance);
+/// Builds the manual assignment and checks that every partition exists in the cluster.
+fn partition_assignment(
+    consumer: &LoggingConsumer,
+    config: &config::Config,
+) -> anyhow::Result<TopicPartitionList> {
+    let mut assignment = TopicPartitionList::new();
+    for (topic, partitions) in &config.partition_assignment {
+        let metadata = consumer.fetch_metadata(Some(topic), Duration::from_secs(10))?;
+        let existing = metadata
+            .topics()
+            .iter()
+            .find(|metadata| metadata.name() == topic)
+            .map(|metadata| metadata.partitions().len())
+            .unwrap_or_default();
+        for &partition in partitions {
+            if usize::try_from(partition).map_or(true, |partition| partition >= existing) {
+                anyhow::bail!(
+                    "Partition {partition} of topic `{topic}` does not exist, the topic has \
+                     {existing} partitions."
+                );
+            }
+            assignment.add_partition(topic, partition);
+        }
     }
 
-    fn post_rebalance(&self, rebalance: &Rebalance) {
-        tracing::info!("Post rebalance {:?}", rebalance);
-    }
+    Ok(assignment)
+}
 
-    fn commit_callback(&self, result: KafkaResult<()>, _offsets: &TopicPartitionList) {
-        tracing::info!("Committing offsets: {:?}", result);
+/// Compares the partition count of every consumed topic with `--expected-partition-count`.
+fn check_partition_count(
+    consumer: &LoggingConsumer,
+    config: &config::Config,
+    expected: usize,
+) -> anyhow::Result<()> {
+    for topic in &config.topics {
+        let metadata = consumer.fetch_metadata(Some(topic), Duration::from_secs(10))?;
+        let existing = metadata
+            .topics()
+            .iter()
+            .find(|metadata| metadata.name() == topic)
+            .map(|metadata| metadata.partitions().len())
+            .unwrap_or_default();
+        if existing != expected {
+            anyhow::bail!(
+                "Topic `{topic}` has {existing} partitions, but {expected} are expected. Check \
+                 the partition assignment of every instance."
+            );
+        }
     }
-}
 
-// A type alias with your custom consumer can be created for convenience.
-type LoggingConsumer = StreamConsumer<CustomContext>;
+    Ok(())
+}
 
-fn initialize_logging() {
+/// Installs the stdout logger and, with an `otlp_endpoint`, a span exporter to it. The logger
+/// writes to stderr instead when stdout carries data.
+fn initialize_logging(
+    log_format: LogFormat,
+    otlp_endpoint: Option<&str>,
+    to_stderr: bool,
+) -> anyhow::Result<()> {
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    let stdout_log = tracing_subscriber::fmt::layer().compact();
+    let writer = if to_stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let stdout_log = match log_format {
+        LogFormat::Text => {
+            tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .compact()
+                .with_timer(ChronoUtc::rfc_3339())
+                .boxed()
+        },
+        LogFormat::Json => {
+            tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .event_format(log_format::JsonFormat)
+                .boxed()
+        },
+    };
+    let otlp = otlp_endpoint
+        .map(|endpoint| {
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+                    opentelemetry_sdk::Resource::new([
+                        opentelemetry::KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+                        opentelemetry::KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+                    ]),
+                ))
+                .install_batch(opentelemetry_sdk::runtime::Tokio)
+        })
+        .transpose()
+        .context("Unable to install the OTLP exporter.")?
+        .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
     tracing_subscriber::registry()
-        .with(env_filter)
         .with(stdout_log)
+        .with(otlp)
+        .with(env_filter)
         .init();
+
+    Ok(())
 }
 
-#[allow(clippy::too_many_lines)]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    initialize_logging();
-    let config = config::Config::parse_or_exit();
-    tracing::info!(?config, "Application initialized.");
+    let invocation = config::Invocation::parse_or_exit();
+    match &invocation {
+        config::Invocation::Run(config) => {
+            initialize_logging(
+                config.log_format,
+                config.otlp_endpoint.as_deref(),
+                config.output_ndjson && config.output_ndjson_file.is_none(),
+            )?;
+        },
+        _ => initialize_logging(LogFormat::Text, None, false)?,
+    }
+    let config = match invocation {
+        config::Invocation::Run(config) => *config,
+        config::Invocation::CheckConfig(args) => {
+            let exit_code = if check::run(args).await { 0 } else { 1 };
+            std::process::exit(exit_code);
+        },
+        config::Invocation::PrintConfig(rendered) => {
+            print!("{rendered}");
+            std::process::exit(0);
+        },
+        config::Invocation::Diff(args) => {
+            let exit_code = match diff::run(&args) {
+                Ok(false) => 0,
+                Ok(true) => 1,
+                Err(error) => {
+                    tracing::error!("{error:#}");
+                    2
+                },
+            };
+            std::process::exit(exit_code);
+        },
+        config::Invocation::TestConnection(config) => {
+            let exit_code = match check::test_connection(&config).await {
+                Ok(true) => 0,
+                Ok(false) => 1,
+                Err(error) => {
+                    tracing::error!("{error:#}");
+                    1
+                },
+            };
+            std::process::exit(exit_code);
+        },
+    };
 
-    let context = CustomContext;
-    let consumer: LoggingConsumer = ClientConfig::new()
-        .set("group.id", &config.group_id)
-        .set("bootstrap.servers", &config.brokers)
-        // .set("enable.partition.eof", "true")
-        .set("session.timeout.ms", "6000")
-        // .set("enable.auto.commit", "false")
-        .set_log_level(RDKafkaLogLevel::Debug)
-        .create_with_context(context)?;
+    let otlp_enabled = config.otlp_endpoint.is_some();
+    let result = run(config).await;
+    if otlp_enabled {
+        // Exports the spans still queued in the batch processor. Shutting down blocks until the
+        // export task running on the runtime finishes.
+        tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await?;
+    }
 
-    consumer.subscribe(
-        config
-            .topics
-            .iter()
-            .map(String::as_str)
-            .collect::<Vec<&str>>()
-            .as_slice(),
-    )?;
+    result
+}
+
+#[allow(clippy::too_many_lines)]
+async fn run(config: config::Config) -> anyhow::Result<()> {
+    tracing::info!(?config, "Application initialized.");
+    // Shared with the background Influx writes.
+    let config = Arc::new(config);
+    if config.kafka_ssl_endpoint_identification_algorithm == config::EndpointIdentification::None {
+        tracing::warn!(
+            "Kafka broker hostname verification is DISABLED, any broker certificate signed by a \
+             trusted CA is accepted."
+        );
+    }
+    if !config.skip_startup_checks {
+        check::startup_checks(&config).await?;
+    }
+
+    let consumer_context = FlowConsumerContext::default();
+    // Replays bypass Kafka entirely.
+    let mut replay = match &config.replay_file {
+        Some(path) => Some(replay::Replay::open(path, config.replay_speed).await?),
+        None => None,
+    };
+    let mut replay_finished = false;
+    let mut consumer = match replay {
+        Some(_) => None,
+        None => Some(create_consumer(&config, &consumer_context)?),
+    };
+    // Only checked at startup, the consumer recreated by the idle watchdog reuses the topics.
+    if let (Some(consumer), Some(expected)) = (&consumer, config.expected_partition_count) {
+        if let Err(error) = check_partition_count(consumer, &config, expected) {
+            tracing::error!(%error, "Unexpected partition count.");
+            std::process::exit(PARTITION_COUNT_EXIT_CODE);
+        }
+    }
+    let mut recorder = config
+        .record_file
+        .as_deref()
+        .map(|path| replay::Recorder::open(path, config.record_file_max_bytes))
+        .transpose()?;
+    let kafka_output = config
+        .output_topic
+        .clone()
+        .map(|topic| kafka_output::KafkaOutput::new(config.kafka_client_config(), topic))
+        .transpose()?
+        .map(Arc::new);
+    let ndjson_output = config
+        .output_ndjson
+        .then(|| ndjson::NdjsonOutput::new(config.output_ndjson_file.clone()));
+    let dead_letters = config
+        .dead_letter_topic
+        .clone()
+        .map(|topic| dead_letter::DeadLetters::new(config.kafka_client_config(), topic))
+        .transpose()?
+        .map(Arc::new);
+    let flush_broadcast = config
+        .unix_socket_path
+        .as_deref()
+        .map(unix_socket::FlushBroadcast::listen)
+        .transpose()?;
 
     let processing_time = Arc::new(AtomicI64::new(0));
-    let size_of_cache = Arc::new(AtomicUsize::new(0));
-    let total_transferred = Arc::new(AtomicU64::new(0));
+    let throughput = stats::ThroughputTracker::new(config.stats_ema_alpha);
+    let counters = PipelineCounters::default();
+    let size_of_cache = counters.cache_bytes.clone();
+    let skip_counters = counters.skipped.clone();
+    // Monotonic clock, flow timestamps can be legitimately old during backfills.
+    let started_at = Instant::now();
+    let last_processed_at = Arc::new(AtomicU64::new(0));
+    let last_received_at = Arc::new(AtomicI64::new(chrono::Utc::now().timestamp()));
+    let consumer_restarts = Arc::new(AtomicU64::new(0));
+    let key_filtered_messages = Arc::new(AtomicU64::new(0));
+    let oversized_messages = Arc::new(AtomicU64::new(0));
+    let invalid_envelopes = Arc::new(AtomicU64::new(0));
+    let undecompressable_messages = Arc::new(AtomicU64::new(0));
+    let undecodable_messages = Arc::new(AtomicU64::new(0));
+    let empty_messages = Arc::new(AtomicU64::new(0));
+    let clamped_timestamps = counters.clamped_timestamps.clone();
+    let timestamp_fallbacks = counters.timestamp_fallbacks.clone();
+    let overflowed_flows = counters.overflowed_flows.clone();
+    let failed_batches_on_disk = Arc::new(AtomicU64::new(0));
+    let throughput_history = Arc::new(stats::ThroughputHistory::new(config.stats_history_minutes));
+    let topic_throughput = Arc::new(stats::TopicThroughput::new(&config.topics));
+    let (restart_consumer_tx, mut restart_consumer_rx) = tokio::sync::watch::channel(false);
+
+    if let Some(watchdog_interval) = config.watchdog_interval {
+        let last_received_at = last_received_at.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let silent_for =
+                    chrono::Utc::now().timestamp() - last_received_at.load(Ordering::Relaxed);
+                if silent_for >= watchdog_interval.as_secs() as i64 {
+                    tracing::warn!(
+                        silent_for,
+                        "No message received within the watchdog interval. Restarting consumer."
+                    );
+                    if restart_consumer_tx.send(true).is_err() {
+                        return;
+                    }
+                    last_received_at.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    let live_settings = Arc::new(admin::LiveSettings::new(&config));
+    // Configuration as last loaded, a reload of `--cidr-file` merges its static CIDRs.
+    let running = Arc::new(RwLock::new((*config).clone()));
+    // Inside CIDRs, replaced whenever a reload of `--cidr-file` changes them.
+    let (cidr_list_tx, mut cidr_list_rx) = tokio::sync::watch::channel(config.cidr_list.clone());
+    let cidr_list_tx = Arc::new(cidr_list_tx);
+    // Replaced by a reload of the configuration.
+    let (include_vlans_tx, mut include_vlans_rx) =
+        tokio::sync::watch::channel(config.include_vlans.clone());
+    if let (Some(path), Some(reload_interval)) =
+        (config.cidr_file.clone(), config.cidr_file_reload_interval)
+    {
+        let running = running.clone();
+        let cidr_list_tx = cidr_list_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(reload_interval).await;
+                reload_cidr_file(&path, &running, &cidr_list_tx);
+            }
+        });
+    }
+    // Classification rules re-read on SIGHUP, taken by the main loop.
+    let (classifier_tx, mut classifier_rx) = tokio::sync::mpsc::channel(1);
+    if config.cidr_file.is_some()
+        || config.classification_rules.is_some()
+        || config.config_reload_on_sighup
+    {
+        let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let config = config.clone();
+        let live_settings = live_settings.clone();
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                tracing::info!("SIGHUP received, reloading.");
+                // The reloaded configuration already merged the CIDR file.
+                if config.config_reload_on_sighup {
+                    reload_config(
+                        config.config_file.as_deref(),
+                        &running,
+                        &live_settings,
+                        &cidr_list_tx,
+                        &include_vlans_tx,
+                    );
+                } else if let Some(path) = &config.cidr_file {
+                    reload_cidr_file(path, &running, &cidr_list_tx);
+                }
+                if let Some(path) = &config.classification_rules {
+                    match classify::Classifier::from_file(path) {
+                        Ok(classifier) => {
+                            if classifier_tx.send(classifier).await.is_err() {
+                                return;
+                            }
+                            tracing::info!(
+                                path = %path.display(),
+                                "Reloaded the classification rules."
+                            );
+                        },
+                        Err(error) => {
+                            tracing::error!(
+                                error = format!("{error:#}"),
+                                "Unable to reload the classification rules, keeping the current \
+                                 ones."
+                            );
+                        },
+                    }
+                }
+            }
+        });
+    }
 
     {
         let processing_time = processing_time.clone();
         let size_of_cache = size_of_cache.clone();
-        let total_transferred = total_transferred.clone();
+        let throughput = throughput.clone();
+        let skip_counters = skip_counters.clone();
+        let last_processed_at = last_processed_at.clone();
+        let consumer_restarts = consumer_restarts.clone();
+        let key_filtered_messages = key_filtered_messages.clone();
+        let oversized_messages = oversized_messages.clone();
+        let invalid_envelopes = invalid_envelopes.clone();
+        let undecompressable_messages = undecompressable_messages.clone();
+        let undecodable_messages = undecodable_messages.clone();
+        let empty_messages = empty_messages.clone();
+        let clamped_timestamps = clamped_timestamps.clone();
+        let timestamp_fallbacks = timestamp_fallbacks.clone();
+        let overflowed_flows = overflowed_flows.clone();
+        let kafka_output = kafka_output.clone();
+        let dead_letters = dead_letters.clone();
+        let failed_batches_on_disk = failed_batches_on_disk.clone();
+        let throughput_history = throughput_history.clone();
+        let topic_throughput = topic_throughput.clone();
+        let idle_timeout = config.idle_timeout;
+        let idle_action = config.idle_action;
         tokio::spawn(async move {
+            let mut last_idle_alert: Option<Instant> = None;
+            let mut last_too_old = [0; 2];
             loop {
                 tokio::time::sleep(Duration::from_secs(1)).await;
+
+                if let Some(idle_timeout) = idle_timeout {
+                    let idle_for = started_at.elapsed().saturating_sub(Duration::from_secs(
+                        last_processed_at.load(Ordering::Relaxed),
+                    ));
+                    let alert_due = last_idle_alert
+                        .map_or(true, |alerted_at| alerted_at.elapsed() >= idle_timeout);
+                    if idle_for >= idle_timeout && alert_due {
+                        tracing::error!(
+                            idle_for = idle_for.as_secs(),
+                            "No message has been processed within the idle timeout."
+                        );
+                        if idle_action == IdleAction::Exit {
+                            std::process::exit(IDLE_EXIT_CODE);
+                        }
+                        last_idle_alert = Some(Instant::now());
+                    }
+                }
+
                 let time =
                     chrono::DateTime::from_timestamp(processing_time.load(Ordering::Relaxed), 0);
                 let size_of_cache = size_of_cache.load(Ordering::Relaxed);
+                let transferred = throughput.tick();
+                throughput_history.record_second(transferred);
+                let transferred_per_topic = topic_throughput.tick();
+                // Warned separately, a skipped backlog is easy to miss in the totals.
+                for ((reason, option), last) in [
+                    (SkipReason::TooOld, "--max-message-age-seconds"),
+                    (SkipReason::ReceivedTooOld, "--max-received-age-secs"),
+                ]
+                .into_iter()
+                .zip(&mut last_too_old)
+                {
+                    let too_old = skip_counters.get(reason);
+                    if too_old > *last {
+                        tracing::warn!(
+                            skipped = too_old - *last,
+                            "Skipped flows older than `{option}`."
+                        );
+                    }
+                    *last = too_old;
+                }
 
                 tracing::info!(
+                    stats.bytes_per_minute_avg = throughput_history.bytes_per_minute_avg(),
+                    stats.bytes_per_minute_peak = throughput_history.bytes_per_minute_peak(),
+                    stats.ema_bytes_per_second = throughput.ema_bytes_per_second(),
+                    stats.bytes_per_topic = ?transferred_per_topic,
+                    skipped.unknown_etype = skip_counters.get(SkipReason::UnknownEtype),
+                    skipped.arp = skip_counters.get(SkipReason::Arp),
+                    skipped.invalid_src = skip_counters.get(SkipReason::InvalidSrc),
+                    skipped.invalid_dst = skip_counters.get(SkipReason::InvalidDst),
+                    skipped.too_old = skip_counters.get(SkipReason::TooOld),
+                    skipped.in_future = skip_counters.get(SkipReason::InFuture),
+                    skipped.before_floor = skip_counters.get(SkipReason::BeforeFloor),
+                    skipped.malformed_addr = skip_counters.get(SkipReason::MalformedAddr),
+                    skipped.other_flow_version = skip_counters.get(SkipReason::OtherFlowVersion),
+                    skipped.excluded_proto = skip_counters.get(SkipReason::ExcludedProto),
+                    skipped.excluded_vlan = skip_counters.get(SkipReason::ExcludedVlan),
+                    skipped.too_small = skip_counters.get(SkipReason::TooSmall),
+                    skipped.too_large = skip_counters.get(SkipReason::TooLarge),
+                    skipped.external_flow = skip_counters.get(SkipReason::ExternalFlow),
+                    skipped.internal_flow = skip_counters.get(SkipReason::InternalFlow),
+                    skipped.received_too_old = skip_counters.get(SkipReason::ReceivedTooOld),
+                    skipped.ipv4 = SkipReason::ALL
+                        .into_iter()
+                        .map(|reason| skip_counters.get_ip_version(reason, 4))
+                        .sum::<u64>(),
+                    skipped.ipv6 = SkipReason::ALL
+                        .into_iter()
+                        .map(|reason| skip_counters.get_ip_version(reason, 6))
+                        .sum::<u64>(),
+                    timestamps.clamped = clamped_timestamps.load(Ordering::Relaxed),
+                    timestamps.fallbacks = timestamp_fallbacks.load(Ordering::Relaxed),
+                    hosts.overflowed_flows = overflowed_flows.load(Ordering::Relaxed),
+                    consumer.restarts = consumer_restarts.load(Ordering::Relaxed),
+                    consumer.key_filtered = key_filtered_messages.load(Ordering::Relaxed),
+                    consumer.oversized = oversized_messages.load(Ordering::Relaxed),
+                    consumer.invalid_envelopes = invalid_envelopes.load(Ordering::Relaxed),
+                    consumer.undecompressable =
+                        undecompressable_messages.load(Ordering::Relaxed),
+                    consumer.undecodable = undecodable_messages.load(Ordering::Relaxed),
+                    consumer.empty = empty_messages.load(Ordering::Relaxed),
+                    output.failed_deliveries = kafka_output
+                        .as_ref()
+                        .map(|output| output.failed_deliveries()),
+                    dead_letters.produced = dead_letters
+                        .as_ref()
+                        .map(|dead_letters| dead_letters.produced()),
+                    dead_letters.failed = dead_letters
+                        .as_ref()
+                        .map(|dead_letters| dead_letters.failed()),
+                    influx.failed_batches_on_disk = failed_batches_on_disk.load(Ordering::Relaxed),
                     "Latest processed: {time:?}, size of cache: {size_of_cache}, bytes transferred \
                      since last print: {}b",
-                    size_format::SizeFormatterBinary::new(
-                        total_transferred.load(Ordering::Acquire) * 8
-                    )
+                    size_format::SizeFormatterBinary::new(transferred * 8)
                 );
-
-                total_transferred.store(0, Ordering::Release);
             }
         });
     }
 
-    let client = influxdb2::Client::new(
-        config.influxdb_endpoint,
-        config.influxdb_org,
-        config.influxdb_token,
+    let (admin_tx, mut admin_rx) = tokio::sync::mpsc::channel(16);
+    if config.enable_admin_stdin {
+        let admin_tx = admin_tx.clone();
+        let live_settings = live_settings.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(error) = admin::serve_stdin(admin_tx, live_settings, config).await {
+                tracing::error!(error = error.to_string(), "Reading admin commands failed.");
+            }
+        });
+    }
+    {
+        let admin_tx = admin_tx.clone();
+        tokio::spawn(async move {
+            if let Err(error) = admin::serve_dump_signal(admin_tx).await {
+                tracing::error!(error = error.to_string(), "Listening for SIGUSR1 failed.");
+            }
+        });
+    }
+    if config.influxdb_accept_invalid_certs {
+        tracing::warn!(
+            "Influx certificate verification is DISABLED, any certificate is accepted. Never use \
+             this outside of a lab."
+        );
+    }
+    let client = influx::Client::new(
+        influx::http_client(
+            config.influxdb_timeout,
+            config.influxdb_connect_timeout,
+            config.influxdb_ca_cert.as_deref(),
+            config.influxdb_accept_invalid_certs,
+        )?,
+        &config.influxdb_endpoint,
+        &config.influxdb_org,
+        config.influxdb_token.expose(),
+        config.influxdb_precision,
     );
-
-    let mut edge_cache: HashMap<AggregatedKey, CommunicationData> = HashMap::new();
-    loop {
-        if size_of_cache.load(Ordering::Relaxed) >= config.batch_size {
-            if let Err(error) =
-                influx::insert_data_into_influx(&client, &config.influxdb_bucket, &edge_cache).await
+    if let Some(metrics_listen) = config.metrics_listen {
+        let mut registry = metrics::Registry::default();
+        for reason in SkipReason::ALL {
+            let skip_counters = skip_counters.clone();
+            registry.register(
+                "lpa_skipped_flows_total",
+                "Flows dropped before aggregation, by reason.",
+                MetricKind::Counter,
+                &[("reason", reason.as_str())],
+                move || metrics::sample(skip_counters.get(reason)),
+            );
+            for ip_version in [4, 6] {
+                let skip_counters = skip_counters.clone();
+                registry.register(
+                    "lpa_skipped_flows_by_ip_version_total",
+                    "Flows with an IPv4 or IPv6 etype dropped before aggregation, by reason.",
+                    MetricKind::Counter,
+                    &[
+                        ("reason", reason.as_str()),
+                        ("ip_version", if ip_version == 4 { "4" } else { "6" }),
+                    ],
+                    move || metrics::sample(skip_counters.get_ip_version(reason, ip_version)),
+                );
+            }
+        }
+        {
+            let skip_counters = skip_counters.clone();
+            registry.register(
+                "lpa_old_messages_dropped_total",
+                "Flows older than `--max-message-age-seconds`.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(skip_counters.get(SkipReason::TooOld)),
+            );
+        }
+        {
+            let processing_time = processing_time.clone();
+            registry.register(
+                "lpa_latest_processed_timestamp_seconds",
+                "`time_received` of the latest processed flow.",
+                MetricKind::Gauge,
+                &[],
+                move || metrics::sample_signed(processing_time.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let size_of_cache = size_of_cache.clone();
+            registry.register(
+                "lpa_cache_bytes",
+                "Payload bytes aggregated in the cache since the last flush.",
+                MetricKind::Gauge,
+                &[],
+                move || metrics::sample(size_of_cache.load(Ordering::Relaxed) as u64),
+            );
+        }
+        {
+            let throughput_history = throughput_history.clone();
+            registry.register(
+                "lpa_bytes_per_minute_avg",
+                "Average of the per-second transferred bytes over the last minute.",
+                MetricKind::Gauge,
+                &[],
+                move || metrics::sample(throughput_history.bytes_per_minute_avg()),
+            );
+        }
+        {
+            let throughput_history = throughput_history.clone();
+            registry.register(
+                "lpa_bytes_per_minute_peak",
+                "Peak of the per-second transferred bytes over the last minute.",
+                MetricKind::Gauge,
+                &[],
+                move || metrics::sample(throughput_history.bytes_per_minute_peak()),
+            );
+        }
+        {
+            let throughput = throughput.clone();
+            registry.register(
+                "lpa_bytes_per_second_ema",
+                "Exponential moving average of the transferred bytes per second.",
+                MetricKind::Gauge,
+                &[],
+                move || throughput.ema_bytes_per_second(),
+            );
+        }
+        for topic in topic_throughput.topics() {
+            let topic_throughput = topic_throughput.clone();
+            let owned_topic = topic.to_owned();
+            registry.register(
+                "lpa_bytes_per_topic",
+                "Bytes transferred in the last second, by topic.",
+                MetricKind::Gauge,
+                &[("topic", topic)],
+                move || metrics::sample(topic_throughput.last_second(&owned_topic)),
+            );
+        }
+        {
+            let paused_partitions = consumer_context.paused_partitions.clone();
+            registry.register(
+                "lpa_kafka_paused_partitions",
+                "Partitions paused by `--kafka-backpressure`.",
+                MetricKind::Gauge,
+                &[],
+                move || {
+                    metrics::sample(
+                        paused_partitions
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .len() as u64,
+                    )
+                },
+            );
+        }
+        for (name, help, counter) in [
+            (
+                "lpa_kafka_rebalance_assign_total",
+                "Partition assignments received from the consumer group.",
+                &consumer_context.rebalance_assign_count,
+            ),
+            (
+                "lpa_kafka_rebalance_revoke_total",
+                "Partition revocations received from the consumer group.",
+                &consumer_context.rebalance_revoke_count,
+            ),
+        ] {
+            let counter = counter.clone();
+            registry.register(name, help, MetricKind::Counter, &[], move || {
+                metrics::sample(counter.load(Ordering::Relaxed))
+            });
+        }
+        for (result, counter) in [
+            ("success", &consumer_context.commit_success_count),
+            ("failure", &consumer_context.commit_failure_count),
+        ] {
+            let counter = counter.clone();
+            registry.register(
+                "lpa_kafka_commits_total",
+                "Offset commits by result.",
+                MetricKind::Counter,
+                &[("result", result)],
+                move || metrics::sample(counter.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let last_rebalance_timestamp = consumer_context.last_rebalance_timestamp.clone();
+            registry.register(
+                "lpa_kafka_last_rebalance_timestamp_seconds",
+                "Unix time of the latest partition assignment or revocation.",
+                MetricKind::Gauge,
+                &[],
+                move || metrics::sample_signed(last_rebalance_timestamp.load(Ordering::Relaxed)),
+            );
+        }
+        if config.kafka_stats_interval_ms > 0 {
+            let stats_msg_cnt = consumer_context.stats_msg_cnt.clone();
+            registry.register(
+                "lpa_kafka_queued_messages",
+                "Messages waiting in the librdkafka queues.",
+                MetricKind::Gauge,
+                &[],
+                move || metrics::sample(stats_msg_cnt.load(Ordering::Relaxed)),
+            );
+            let stats_replyq = consumer_context.stats_replyq.clone();
+            registry.register(
+                "lpa_kafka_pending_requests",
+                "Requests to the brokers waiting for a response.",
+                MetricKind::Gauge,
+                &[],
+                move || metrics::sample_signed(stats_replyq.load(Ordering::Relaxed)),
+            );
+            let stats_rx_bytes = consumer_context.stats_rx_bytes.clone();
+            registry.register(
+                "lpa_kafka_received_bytes_total",
+                "Bytes received from all brokers.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(stats_rx_bytes.load(Ordering::Relaxed)),
+            );
+            let stats_consumer_lag = consumer_context.stats_consumer_lag.clone();
+            registry.register(
+                "lpa_kafka_consumer_lag",
+                "Messages behind the high watermark over all assigned partitions.",
+                MetricKind::Gauge,
+                &[],
+                move || metrics::sample_signed(stats_consumer_lag.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let consumer_restarts = consumer_restarts.clone();
+            registry.register(
+                "lpa_consumer_restarts_total",
+                "Kafka consumers recreated by the watchdog.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(consumer_restarts.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let key_filtered_messages = key_filtered_messages.clone();
+            registry.register(
+                "lpa_key_filtered_messages_total",
+                "Kafka messages skipped by `--key-filter` without decoding them.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(key_filtered_messages.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let oversized_messages = oversized_messages.clone();
+            registry.register(
+                "lpa_oversized_messages_dropped_total",
+                "Messages dropped undecoded for exceeding `--kafka-max-message-bytes`.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(oversized_messages.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let invalid_envelopes = invalid_envelopes.clone();
+            registry.register(
+                "lpa_invalid_envelope_messages_total",
+                "Messages skipped for a missing or unexpected `--payload-envelope`.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(invalid_envelopes.load(Ordering::Relaxed)),
+            );
+        }
+        let errors = client.error_counters();
+        for (class, counter) in [
+            ("rate_limited", &errors.rate_limited),
+            ("payload_too_large", &errors.payload_too_large),
+            ("auth", &errors.auth_failures),
+        ] {
+            let counter = counter.clone();
+            registry.register(
+                "lpa_influx_rejected_writes_total",
+                "Influx writes rejected by the class of the response.",
+                MetricKind::Counter,
+                &[("class", class)],
+                move || metrics::sample(counter.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let undecompressable_messages = undecompressable_messages.clone();
+            registry.register(
+                "lpa_undecompressable_messages_total",
+                "Messages `--payload-compression` could not decompress, skipped or dead lettered.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(undecompressable_messages.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let undecodable_messages = undecodable_messages.clone();
+            registry.register(
+                "lpa_undecodable_messages_total",
+                "Messages that are not a valid flow, skipped or dead lettered.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(undecodable_messages.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let empty_messages = empty_messages.clone();
+            registry.register(
+                "lpa_empty_messages_total",
+                "Messages without a payload, skipped or dead lettered.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(empty_messages.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let clamped_timestamps = clamped_timestamps.clone();
+            registry.register(
+                "lpa_clamped_timestamps_total",
+                "Flows whose future timestamps were clamped to the current time.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(clamped_timestamps.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let timestamp_fallbacks = timestamp_fallbacks.clone();
+            registry.register(
+                "lpa_bucket_timestamp_fallbacks_total",
+                "Flows bucketed by another field because `--bucket-timestamp` was zero.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(timestamp_fallbacks.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let overflowed_flows = overflowed_flows.clone();
+            registry.register(
+                "lpa_overflowed_flows_total",
+                "Flows whose inside hosts were aggregated as overflow due to the unique host \
+                 limits.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(overflowed_flows.load(Ordering::Relaxed)),
+            );
+        }
+        {
+            let failed_batches_on_disk = failed_batches_on_disk.clone();
+            registry.register(
+                "lpa_influx_failed_batches_on_disk_total",
+                "Batches written to `--failed-batch-dir` after exhausting Influx retries.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(failed_batches_on_disk.load(Ordering::Relaxed)),
+            );
+        }
+        if let Some(kafka_output) = kafka_output.clone() {
+            registry.register(
+                "lpa_output_failed_deliveries_total",
+                "Aggregates that could not be produced to `--output-topic`.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(kafka_output.failed_deliveries()),
+            );
+        }
+        if let Some(dead_letters) = dead_letters.clone() {
             {
-                tracing::error!(
-                    error = error.to_string(),
-                    "Unable to submit data into influx. Sleeping and retrying."
+                let dead_letters = dead_letters.clone();
+                registry.register(
+                    "lpa_dead_letters_total",
+                    "Invalid messages produced to `--dead-letter-topic`.",
+                    MetricKind::Counter,
+                    &[],
+                    move || metrics::sample(dead_letters.produced()),
                 );
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                continue;
             }
+            registry.register(
+                "lpa_dead_letter_failures_total",
+                "Invalid messages that could not be produced to `--dead-letter-topic`.",
+                MetricKind::Counter,
+                &[],
+                move || metrics::sample(dead_letters.failed()),
+            );
+        }
+
+        let registry = Arc::new(registry);
+        tokio::spawn(async move {
+            if let Err(error) = metrics::serve(metrics_listen, registry, admin_tx).await {
+                tracing::error!(error = error.to_string(), "Metrics endpoint failed.");
+            }
+        });
+    }
 
+
+    let postgres_pool = match &config.postgres_url {
+        Some(url) => Some(postgres::connect(url.expose(), config.postgres_max_connections).await?),
+        None => None,
+    };
+
+    let geo_ip = config
+        .geo_ip_database
+        .as_deref()
+        .map(geoip::GeoIp::open)
+        .transpose()?;
+    let mut pipeline = Pipeline::new(config.clone(), geo_ip, counters);
+    pipeline.set_classifier(classify::Classifier::load(&config)?);
+
+    // Batch whose final flush failed in the previous run, written before consuming.
+    let mut saved_batch = None;
+    if let Some(path) = &config.state_file {
+        if let Some(restored) = state::load(path)? {
+            let pending_entries = restored
+                .pending
+                .as_ref()
+                .map_or(0, |pending| pending.entries.len());
             tracing::info!(
-                cache.bytes = size_of_cache.load(Ordering::Relaxed),
-                cache.elements = edge_cache.len(),
-                "Inserted new batch into the influx."
+                path = %path.display(),
+                entries = restored.entries.len(),
+                pending_entries,
+                offsets = ?restored.offsets,
+                "Restored the cache saved by the previous run."
             );
+            pipeline.cache().restore(restored.entries).await?;
+            saved_batch = restored.pending;
+            std::fs::remove_file(path)
+                .with_context(|| format!("Unable to delete `{}`.", path.display()))?;
+        }
+    }
+    let recovery = match &config.sqlite_recovery_path {
+        Some(path) => Some(recovery::Recovery::open(path).await?),
+        None => None,
+    };
+    let mut flusher = Flusher::new(
+        config.clone(),
+        client,
+        Sinks {
+            kafka_output,
+            ndjson_output,
+            postgres_pool,
+            flush_broadcast,
+            recovery,
+        },
+        failed_batches_on_disk,
+    )?;
+    // Retried and backed up like any other batch, nothing is consumed until they are written.
+    for batch in flusher.recovered().await? {
+        flusher.stage_recovered(batch);
+        flusher.write_until_written(pipeline.cidr_tree()).await?;
+    }
+    if let Some(saved_batch) = saved_batch {
+        flusher.restore_pending(saved_batch).await?;
+        flusher.write_until_written(pipeline.cidr_tree()).await?;
+    }
+    // A revocation flushed the cache, the offsets are committed once the flush is written or
+    // at this deadline, whichever comes first.
+    let mut commit_deadline: Option<Instant> = None;
+    // Next offset of every partition consumed since the last drain of the whole cache.
+    let mut consumed_offsets: HashMap<String, HashMap<i32, i64>> = HashMap::new();
+    // Offsets covered by the drain in progress, staged with its last table.
+    let mut drain_offsets: Option<Vec<state::PartitionOffset>> = None;
+    // Latest offsets whose messages are all written, committed after a revocation and on exit.
+    let mut written_offsets: Vec<state::PartitionOffset> = Vec::new();
+    // Messages fetched together with the last awaited one, processed before waiting again.
+    let mut prefetched: VecDeque<KafkaResult<OwnedMessage>> = VecDeque::new();
+    // Answer to a `POST /flush` waiting for the flush of the whole cache.
+    let mut flush_reply: Option<FlushReply> = None;
+    // SIGTERM and SIGINT stop the consumption and flush the whole cache, so `main` can still shut
+    // the tracer provider down.
+    let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::channel(1);
+    let mut terminations =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminations.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+        let _ = shutdown_tx.send(()).await;
+    });
+    // Set by SIGTERM or SIGINT to the end of the wait for the final flush.
+    let mut shutdown_deadline: Option<Instant> = None;
+    let backpressure = config.kafka_backpressure.then(|| {
+        BackpressureController {
+            context: consumer_context.clone(),
+        }
+    });
+    loop {
+        flusher.reap_background_writes();
+        if let Some(offsets) = flusher.take_written_offsets() {
+            if let Some(consumer) = &consumer {
+                store_offsets(consumer, &offsets);
+            }
+            written_offsets = offsets;
+        }
 
-            size_of_cache.store(0, Ordering::Relaxed);
-            edge_cache.clear();
+        // Flushes are considered between the prefetched chunks.
+        if !flusher.is_pending() && prefetched.is_empty() {
+            if replay_finished
+                && !pipeline.is_draining()
+                && pipeline.cache().snapshot().await?.entries == 0
+            {
+                flusher.join_background_writes().await;
+                tracing::info!("Replay file exhausted and flushed. Exiting.");
+                return Ok(());
+            }
+            if let Some(deadline) = shutdown_deadline.filter(|_| !pipeline.is_draining()) {
+                if pipeline.cache().snapshot().await?.entries == 0 {
+                    let joined =
+                        tokio::time::timeout_at(deadline.into(), flusher.join_background_writes())
+                            .await;
+                    if joined.is_err() {
+                        flusher.abort_background_writes();
+                    }
+                    if let Some(offsets) = flusher.take_written_offsets() {
+                        written_offsets = offsets;
+                    }
+                    commit_offsets(consumer.as_ref(), &written_offsets, CommitMode::Sync);
+                    tracing::info!("Cache flushed. Exiting.");
+                    return Ok(());
+                }
+            }
+
+            pipeline.set_batch_size(live_settings.batch_size.load(Ordering::Relaxed));
+            pipeline.update_batch_size(consumer_context.stats_consumer_lag.load(Ordering::Relaxed));
+            // A revocation during a drain waits for its end, the drain does not cover the messages
+            // consumed since it started.
+            let revoked = !pipeline.is_draining()
+                && consumer_context
+                    .revoke_pending
+                    .swap(false, Ordering::Relaxed);
+            if revoked {
+                tracing::info!("Partitions were revoked, flushing the whole cache.");
+                commit_deadline
+                    .get_or_insert_with(|| Instant::now() + config.rebalance_flush_timeout);
+            }
+            if !pipeline.is_draining()
+                && (flush_reply.is_some()
+                    || revoked
+                    || replay_finished
+                    || shutdown_deadline.is_some())
+            {
+                pipeline.request_full_flush();
+            }
+            // Only a drain of the whole cache covers every consumed message.
+            if pipeline.drain_starts() {
+                drain_offsets = Some(take_consumed_offsets(&mut consumed_offsets));
+            }
+            let batch = pipeline.take_batch().await?;
+            let offsets = if pipeline.is_draining() {
+                Vec::new()
+            } else {
+                drain_offsets.take().unwrap_or_default()
+            };
+            if !flusher
+                .stage(batch, pipeline.take_batch_peers(), offsets)
+                .await?
+            {
+                if let Some(reply) = flush_reply.take() {
+                    let _ = reply.send(Ok(0));
+                }
+            }
         }
 
-        match consumer.recv().await {
-            Err(error) => tracing::error!("Kafka error: {}", error),
-            Ok(message) => {
-                if let Some(payload) = message.payload() {
-                    let message = flowprotob::FlowMessage::decode(payload)?;
-                    total_transferred.fetch_add(message.bytes, Ordering::Relaxed);
-                    let Some(src_location) =
-                        util::parse_location(message.etype, &message.src_addr, &config.cidr_list)?
-                    else {
-                        // tracing::warn!("Invalid src location.");
-                        continue;
-                    };
-                    let Some(dst_location) =
-                        util::parse_location(message.etype, &message.dst_addr, &config.cidr_list)?
-                    else {
-                        // tracing::warn!("Invalid dst location.");
+        if flusher.is_pending() {
+            // Same watermark as the primary buckets, so a rollup bucket closes together with the
+            // last primary bucket it contains.
+            let rollup_watermark = if replay_finished {
+                u64::MAX
+            } else {
+                let now = u64::try_from(processing_time.load(Ordering::Relaxed)).unwrap_or(0);
+                now.saturating_sub(config.flush_grace.as_secs())
+            };
+            // A drain answers `POST /flush` with its last table.
+            let mut draining_reply = None;
+            let reply = if pipeline.is_draining() {
+                &mut draining_reply
+            } else {
+                &mut flush_reply
+            };
+            match flusher
+                .write(reply, pipeline.cidr_tree(), rollup_watermark)
+                .await?
+            {
+                Flush::Written(points) => {
+                    if !flusher.writes_in_background() {
+                        tracing::info!(
+                            cache.bytes = size_of_cache.load(Ordering::Relaxed),
+                            cache.elements = pipeline.cache().len(),
+                            batch.elements = points,
+                            "Inserted new batch into the influx."
+                        );
+                    }
+                },
+                Flush::Retry(retry_delay) => {
+                    if let Some(path) = config
+                        .state_file
+                        .as_ref()
+                        .filter(|_| shutdown_deadline.is_some())
+                    {
+                        // The batch is saved apart from the rest of the cache, so after the
+                        // restart only the sinks that have not accepted it get it again.
+                        let pending = flusher.take_unwritten();
+                        let entries = pipeline.cache().drain(false).await?;
+                        let offsets = consumer
+                            .as_ref()
+                            .map(consumer_positions)
+                            .transpose()?
+                            .unwrap_or_default();
+                        state::save(path, &entries, pending.as_ref(), offsets.clone())?;
+                        // The state file holds every consumed message that is not written.
+                        commit_offsets(consumer.as_ref(), &offsets, CommitMode::Sync);
+                        tracing::warn!(
+                            path = %path.display(),
+                            entries = entries.len(),
+                            pending_entries = pending.map_or(0, |pending| pending.entries.len()),
+                            "Final flush failed, saved the cache into the state file. Exiting."
+                        );
+                        return Ok(());
+                    }
+                    if let Some(deadline) = commit_deadline {
+                        if Instant::now() >= deadline {
+                            commit_deadline = None;
+                            tracing::warn!(
+                                timeout = ?config.rebalance_flush_timeout,
+                                "Flush of the revoked partitions timed out, committing the written \
+                                 messages while the batch is retried."
+                            );
+                            commit_offsets(
+                                consumer.as_ref(),
+                                &std::mem::take(&mut written_offsets),
+                                CommitMode::Async,
+                            );
+                        }
+                    }
+                    if shutdown_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        tracing::error!(
+                            timeout = ?config.shutdown_timeout,
+                            "Final flush timed out, giving up on the batch."
+                        );
+                        flusher.give_up().await;
                         continue;
-                    };
-
-                    let seconds_alignment = 60 * 5; // 5 minutes
-
-                    match edge_cache.entry(AggregatedKey {
-                        time: message.time_flow_start.div_euclid(seconds_alignment)
-                            * seconds_alignment,
-                        source: src_location,
-                        target: dst_location,
-                        src_vlan: message.src_vlan,
-                        dst_vlan: message.dst_vlan,
-                        proto: message.proto,
-                    }) {
-                        Entry::Occupied(mut entry) => {
-                            let entry = entry.get_mut();
-                            entry.bytes += message.bytes;
-                            entry.packets += message.packets;
-                        },
-                        Entry::Vacant(entry) => {
-                            entry.insert(CommunicationData {
-                                packets: message.packets,
-                                bytes: message.bytes,
-                            });
+                    }
+                    // The wait ends early at the commit and shutdown deadlines, the next attempt
+                    // is bounded by the Influx timeout.
+                    let retry_delay = [commit_deadline, shutdown_deadline]
+                        .into_iter()
+                        .flatten()
+                        .fold(retry_delay, |retry_delay, deadline| {
+                            retry_delay.min(deadline.saturating_duration_since(Instant::now()))
+                        });
+                    tokio::time::sleep(retry_delay).await;
+                    continue;
+                },
+            }
+        }
+
+        if let Some(deadline) = commit_deadline
+            .filter(|_| !flusher.is_pending() && prefetched.is_empty() && !pipeline.is_draining())
+        {
+            commit_deadline = None;
+            // Background writes may still hold aggregates of the revoked partitions.
+            let written =
+                tokio::time::timeout_at(deadline.into(), flusher.join_background_writes()).await;
+            if written.is_err() {
+                tracing::warn!(
+                    writes = flusher.background_writes(),
+                    timeout = ?config.rebalance_flush_timeout,
+                    "Flush of the revoked partitions timed out, committing the written messages \
+                     while the writes go on."
+                );
+            }
+            if let Some(offsets) = flusher.take_written_offsets() {
+                if let Some(consumer) = &consumer {
+                    store_offsets(consumer, &offsets);
+                }
+                written_offsets = offsets;
+            }
+            // The revoked partitions cannot be stored any more, their offsets are committed
+            // explicitly and not again later.
+            commit_offsets(
+                consumer.as_ref(),
+                &std::mem::take(&mut written_offsets),
+                CommitMode::Async,
+            );
+        }
+
+        if let (Some(backpressure), Some(consumer)) = (&backpressure, &consumer) {
+            let paused = backpressure.is_paused();
+            if !paused
+                && flusher.background_writes() > 0
+                && BackpressureController::is_near_capacity(
+                    size_of_cache.load(Ordering::Relaxed),
+                    pipeline.batch_size(),
+                )
+            {
+                backpressure.pause(consumer)?;
+            } else if paused && flusher.background_writes() == 0 {
+                backpressure.resume(consumer)?;
+            }
+        }
+
+        if replay_finished || (shutdown_deadline.is_some() && prefetched.is_empty()) {
+            // Only the final flush is left.
+            continue;
+        }
+
+        let received = if let Some(message) = prefetched.pop_front() {
+            Received::Kafka(message)
+        } else {
+            tokio::select! {
+                Some(()) = async {
+                    match &consumer {
+                        Some(consumer) => {
+                            prefetched
+                                .push_back(consumer.recv().await.map(|message| message.detach()));
+                            // Whatever else is already fetched, or arrives within the linger, is
+                            // taken as well, so the messages are decoded in one go. Every message
+                            // is queued as soon as it is received, a cancelled linger loses none.
+                            let linger_until =
+                                tokio::time::Instant::now() + config.kafka_consumer_linger;
+                            while prefetched.len() < config.kafka_prefetch_messages {
+                                let next = if config.kafka_consumer_linger.is_zero() {
+                                    consumer.recv().now_or_never()
+                                } else {
+                                    tokio::time::timeout_at(linger_until, consumer.recv())
+                                        .await
+                                        .ok()
+                                };
+                                match next {
+                                    Some(next) => {
+                                        prefetched.push_back(next.map(|message| message.detach()));
+                                    },
+                                    None => break,
+                                }
+                            }
+                            Some(())
                         },
+                        None => None,
+                    }
+                } => Received::Fetched,
+                Some(payload) = async {
+                    match &mut replay {
+                        Some(replay) => Some(replay.next_payload().await),
+                        None => None,
                     }
+                } => Received::Replay(payload),
+                // Paused partitions deliver nothing, a finished write has to wake the loop.
+                Some(()) = flusher.join_next_background_write(), if backpressure
+                    .as_ref()
+                    .is_some_and(BackpressureController::is_paused) => Received::Written,
+                Ok(()) = restart_consumer_rx.changed() => Received::Restart,
+                Some(request) = admin_rx.recv() => Received::Admin(request),
+                Some(()) = shutdown_rx.recv() => Received::Shutdown,
+            }
+        };
 
-                    processing_time.store(i64::try_from(message.time_received)?, Ordering::Relaxed);
-                    size_of_cache.fetch_add(
-                        std::mem::size_of::<u32>() + payload.len(),
-                        Ordering::Relaxed,
-                    );
-                } else {
-                    panic!("Unable to decode.");
+        let kafka_message;
+        let replayed_payload;
+        let mut topic = None;
+        let mut origin = None;
+        let payload = match received {
+            Received::Restart => {
+                if *restart_consumer_rx.borrow_and_update() {
+                    if let Some(consumer) = &mut consumer {
+                        consumer.unsubscribe();
+                        *consumer = create_consumer(&config, &consumer_context)?;
+                        if let Some(backpressure) = &backpressure {
+                            backpressure.forget();
+                        }
+                        consumer_restarts.fetch_add(1, Ordering::Relaxed);
+                        tracing::info!("Kafka consumer recreated.");
+                    }
                 }
+                continue;
+            },
+            Received::Written => {
+                // The consumption is paused, the watchdog must not take it for a stuck consumer.
+                last_received_at.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+                continue;
+            },
+            Received::Admin(AdminRequest::Flush(reply)) => {
+                flush_reply = Some(reply);
+                continue;
+            },
+            Received::Admin(AdminRequest::CacheStats(reply)) => {
+                let snapshot = pipeline.cache().snapshot().await?;
+                let _ = reply.send(admin::CacheStats {
+                    entries: snapshot.entries,
+                    bytes: size_of_cache.load(Ordering::Relaxed),
+                    pending_entries: flusher.pending_len(),
+                    oldest_bucket: snapshot.oldest_bucket,
+                    newest_bucket: snapshot.newest_bucket,
+                });
+                continue;
+            },
+            Received::Admin(AdminRequest::DumpCache(reply)) => {
+                let entries = pipeline.cache().entries().await?;
+                let bytes = size_of_cache.load(Ordering::Relaxed);
+                let path = config.dump_cache_path.clone();
+                // Serializing a large cache must not hold up the consumption.
+                tokio::task::spawn_blocking(move || {
+                    let result = backup::write_cache_dump(&path, &entries, bytes)
+                        .map(|path| (path, entries.len()))
+                        .map_err(|error| format!("{error:#}"));
+                    let _ = reply.send(result);
+                });
+                continue;
+            },
+            Received::Shutdown => {
+                tracing::info!("Shutting down, flushing the cache.");
+                shutdown_deadline = Some(Instant::now() + config.shutdown_timeout);
+                continue;
+            },
+            Received::Fetched => continue,
+            Received::Kafka(Err(error)) => {
+                tracing::error!("Kafka error: {}", error);
+                continue;
+            },
+            Received::Kafka(Ok(message)) => {
+                consumed_offsets
+                    .entry(message.topic().to_owned())
+                    .or_default()
+                    .insert(message.partition(), message.offset() + 1);
+                if !config.admits_key(message.key()) {
+                    key_filtered_messages.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                kafka_message = message;
+                origin = Some(&kafka_message);
+                topic = Some(TopicTags::of_topic(&config, kafka_message.topic()));
+                if let (Some(recorder), Some(payload)) = (&mut recorder, kafka_message.payload()) {
+                    recorder.record(payload)?;
+                }
+                kafka_message.payload()
+            },
+            Received::Replay(payload) => {
+                let Some(payload) = payload? else {
+                    replay_finished = true;
+                    continue;
+                };
+                replayed_payload = payload;
+                Some(replayed_payload.as_slice())
             },
         };
+
+        last_received_at.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+        if cidr_list_rx.has_changed().unwrap_or(false) {
+            pipeline.set_cidr_list(&cidr_list_rx.borrow_and_update());
+        }
+        if include_vlans_rx.has_changed().unwrap_or(false) {
+            pipeline.set_include_vlans(&include_vlans_rx.borrow_and_update());
+        }
+        if let Ok(classifier) = classifier_rx.try_recv() {
+            pipeline.set_classifier(Some(classifier));
+        }
+        pipeline.set_time_alignment(live_settings.time_alignment_seconds.load(Ordering::Relaxed));
+        if let Some(payload) = payload {
+            if payload.len() > config.kafka_max_message_bytes {
+                tracing::warn!(
+                    bytes = payload.len(),
+                    topic = origin.map(|message| message.topic()),
+                    partition = origin.map(|message| message.partition()),
+                    offset = origin.map(|message| message.offset()),
+                    "Dropping oversized message."
+                );
+                oversized_messages.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let decompressed = match util::decompress_payload(
+                payload,
+                config.payload_compression,
+                config.max_decompressed_bytes,
+            ) {
+                Ok(decompressed) => decompressed,
+                Err(error) => {
+                    undecompressable_messages.fetch_add(1, Ordering::Relaxed);
+                    dead_letter(
+                        dead_letters.as_deref(),
+                        payload,
+                        anyhow::anyhow!(error),
+                        origin,
+                    );
+                    continue;
+                },
+            };
+            let protobuf = match config.payload_envelope {
+                config::PayloadEnvelope::None => Ok(&*decompressed),
+                config::PayloadEnvelope::Confluent => {
+                    util::strip_confluent_envelope(&decompressed).and_then(
+                        |(schema_id, protobuf)| {
+                            if config.allowed_schema_ids.is_empty()
+                                || config.allowed_schema_ids.contains(&schema_id)
+                            {
+                                Ok(protobuf)
+                            } else {
+                                Err(format!("schema id {schema_id} is not allowed"))
+                            }
+                        },
+                    )
+                },
+            };
+            let protobuf = match protobuf {
+                Ok(protobuf) => protobuf,
+                Err(error) => {
+                    invalid_envelopes.fetch_add(1, Ordering::Relaxed);
+                    dead_letter(
+                        dead_letters.as_deref(),
+                        payload,
+                        anyhow::anyhow!(error),
+                        origin,
+                    );
+                    continue;
+                },
+            };
+            let mut message = match flowprotob::FlowMessage::decode(protobuf) {
+                Ok(message) => message,
+                Err(error) => {
+                    undecodable_messages.fetch_add(1, Ordering::Relaxed);
+                    dead_letter(dead_letters.as_deref(), payload, error.into(), origin);
+                    continue;
+                },
+            };
+            throughput.record(message.bytes);
+            if let Some(origin) = origin {
+                topic_throughput.record(origin.topic(), message.bytes);
+            }
+
+            let payload_bytes = std::mem::size_of::<u32>() + decompressed.len();
+            if !pipeline
+                .record(&mut message, topic.as_ref(), payload_bytes)
+                .await?
+            {
+                continue;
+            }
+
+            processing_time.store(i64::try_from(message.time_received)?, Ordering::Relaxed);
+            last_processed_at.store(started_at.elapsed().as_secs(), Ordering::Relaxed);
+        } else {
+            empty_messages.fetch_add(1, Ordering::Relaxed);
+            dead_letter(
+                dead_letters.as_deref(),
+                &[],
+                anyhow::anyhow!("Message without a payload."),
+                origin,
+            );
+        }
     }
 }
+
+/// Settings of the configuration file applied on SIGHUP, named as in `diff`.
+const RELOADABLE_SETTINGS: &[&str] = &[
+    "batch_size",
+    "time_alignment_seconds",
+    "cidr_list",
+    "cidr_list_static",
+    "include_vlans",
+    "config_reload_on_sighup",
+];
+
+/// Applies the settings of `--config-file`, or of the command line and the environment without
+/// one, that are safe to change at runtime. Changes of the others, e.g. the brokers or the Influx
+/// endpoint, are only logged because they need a restart.
+fn reload_config(
+    path: Option<&Path>,
+    running: &RwLock<config::Config>,
+    live_settings: &admin::LiveSettings,
+    cidr_list_tx: &tokio::sync::watch::Sender<Vec<IpCidr>>,
+    include_vlans_tx: &tokio::sync::watch::Sender<Vec<u32>>,
+) {
+    let mut reloaded = match path.map_or_else(config::Config::from_env, config::Config::from_file) {
+        Ok(reloaded) => reloaded,
+        Err(error) => {
+            tracing::error!(
+                error = format!("{error:#}"),
+                "Unable to reload the configuration, keeping the current configuration."
+            );
+            return;
+        },
+    };
+    let mut running = running.write().unwrap_or_else(PoisonError::into_inner);
+    for change in diff::diff(&running, &reloaded) {
+        if !RELOADABLE_SETTINGS.contains(&change.field) {
+            tracing::warn!(
+                setting = change.field,
+                change = change.message,
+                "Setting cannot be changed without a restart, ignoring it."
+            );
+        }
+    }
+
+    live_settings
+        .batch_size
+        .store(reloaded.batch_size, Ordering::Relaxed);
+    // The rollup alignment is not reloaded, its buckets must still cover whole buckets.
+    if running
+        .rollup_alignment_seconds
+        .is_some_and(|rollup_alignment| rollup_alignment % reloaded.time_alignment_seconds != 0)
+    {
+        tracing::warn!(
+            time_alignment_seconds = reloaded.time_alignment_seconds,
+            "Time alignment does not divide the rollup alignment, ignoring it."
+        );
+        reloaded.time_alignment_seconds = running.time_alignment_seconds;
+    } else {
+        live_settings
+            .time_alignment_seconds
+            .store(reloaded.time_alignment_seconds, Ordering::Relaxed);
+    }
+    cidr_list_tx.send_if_modified(|current| {
+        let changed = *current != reloaded.cidr_list;
+        if changed {
+            current.clone_from(&reloaded.cidr_list);
+        }
+        changed
+    });
+    include_vlans_tx.send_if_modified(|current| {
+        let changed = *current != reloaded.include_vlans;
+        if changed {
+            current.clone_from(&reloaded.include_vlans);
+        }
+        changed
+    });
+    // The next reload is compared with this one, so a change needing a restart is logged once.
+    *running = reloaded;
+    match path {
+        Some(path) => tracing::info!(path = %path.display(), "Reloaded the configuration file."),
+        None => tracing::info!("Reloaded the configuration from the command line and environment."),
+    }
+}
+
+/// Next offsets of the partitions consumed since the last drain, which covers them.
+fn take_consumed_offsets(
+    consumed_offsets: &mut HashMap<String, HashMap<i32, i64>>,
+) -> Vec<state::PartitionOffset> {
+    consumed_offsets
+        .drain()
+        .flat_map(|(topic, partitions)| {
+            partitions.into_iter().map(move |(partition, offset)| {
+                state::PartitionOffset {
+                    topic: topic.clone(),
+                    partition,
+                    offset,
+                }
+            })
+        })
+        .collect()
+}
+
+fn partition_list(offsets: &[state::PartitionOffset]) -> KafkaResult<TopicPartitionList> {
+    let mut list = TopicPartitionList::new();
+    for offset in offsets {
+        list.add_partition_offset(
+            &offset.topic,
+            offset.partition,
+            Offset::Offset(offset.offset),
+        )?;
+    }
+
+    Ok(list)
+}
+
+/// Stores the offsets of the written messages for the next automatic commit.
+fn store_offsets(consumer: &LoggingConsumer, offsets: &[state::PartitionOffset]) {
+    if let Err(error) = partition_list(offsets).and_then(|list| consumer.store_offsets(&list)) {
+        // Revoked partitions are committed explicitly after their flush.
+        tracing::debug!(%error, "Unable to store the offsets of the written messages.");
+    }
+}
+
+/// Commits the offsets right away, after the flush of revoked partitions and on exit.
+fn commit_offsets(
+    consumer: Option<&LoggingConsumer>,
+    offsets: &[state::PartitionOffset],
+    mode: CommitMode,
+) {
+    let Some(consumer) = consumer.filter(|_| !offsets.is_empty()) else {
+        return;
+    };
+    match partition_list(offsets).and_then(|list| consumer.commit(&list, mode)) {
+        Ok(()) | Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => {},
+        Err(error) => {
+            tracing::warn!(%error, "Unable to commit the offsets of the written messages.");
+        },
+    }
+}
+
+/// Next offset of every assigned partition, saved with the cache of a failed final flush.
+fn consumer_positions(consumer: &LoggingConsumer) -> anyhow::Result<Vec<state::PartitionOffset>> {
+    Ok(consumer
+        .position()?
+        .elements()
+        .iter()
+        .filter_map(|element| {
+            Some(state::PartitionOffset {
+                topic: element.topic().to_owned(),
+                partition: element.partition(),
+                offset: element.offset().to_raw()?,
+            })
+        })
+        .collect())
+}
+
+/// Re-reads `--cidr-file` and publishes the CIDRs merged with the static ones when they changed.
+/// A broken file keeps the current CIDRs.
+fn reload_cidr_file(
+    path: &Path,
+    running: &RwLock<config::Config>,
+    cidr_list_tx: &tokio::sync::watch::Sender<Vec<IpCidr>>,
+) {
+    match config::read_cidr_file(path) {
+        Ok(file_cidrs) => {
+            // The static CIDRs of the latest reload of the configuration.
+            let mut running = running.write().unwrap_or_else(PoisonError::into_inner);
+            let cidr_list = config::merge_cidrs(&running.cidr_list_static, file_cidrs);
+            running.cidr_list.clone_from(&cidr_list);
+            let changed = cidr_list_tx.send_if_modified(|current| {
+                let changed = *current != cidr_list;
+                if changed {
+                    *current = cidr_list;
+                }
+                changed
+            });
+            if changed {
+                tracing::info!(path = %path.display(), "Reloaded the CIDR file.");
+            }
+        },
+        Err(error) => {
+            tracing::error!(
+                error = format!("{error:#}"),
+                "Unable to reload the CIDR file, keeping the current CIDRs."
+            );
+        },
+    }
+}
+
+/// Hands an invalid payload to `--dead-letter-topic`, without one it is logged and skipped.
+fn dead_letter(
+    dead_letters: Option<&dead_letter::DeadLetters>,
+    payload: &[u8],
+    error: anyhow::Error,
+    origin: Option<&OwnedMessage>,
+) {
+    let Some(dead_letters) = dead_letters else {
+        tracing::warn!(
+            %error,
+            topic = origin.map(|message| message.topic()),
+            partition = origin.map(|message| message.partition()),
+            offset = origin.map(|message| message.offset()),
+            "Skipping invalid message."
+        );
+        return;
+    };
+    tracing::warn!(%error, "Producing invalid message to the dead letter topic.");
+    dead_letters.send(payload, &error, origin);
+}
+
+/// Outcome of waiting for the next message.
+enum Received {
+    /// Detached, so the prefetched messages do not borrow a consumer the watchdog may replace.
+    Kafka(KafkaResult<OwnedMessage>),
+    /// New messages were queued into the prefetched ones.
+    Fetched,
+    /// Next payload of `--replay-file`, `None` once it is exhausted.
+    Replay(anyhow::Result<Option<Vec<u8>>>),
+    /// A background Influx write finished while the partitions are paused.
+    Written,
+    /// The watchdog asked for a new consumer.
+    Restart,
+    Admin(AdminRequest),
+    /// SIGTERM or SIGINT.
+    Shutdown,
+}
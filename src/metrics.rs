@@ -0,0 +1,133 @@
+use std::{fmt::Write, net::SocketAddr, sync::Arc};
+
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use tokio::sync::mpsc;
+
+use crate::admin::{self, AdminRequest};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+type Sample = Box<dyn Fn() -> f64 + Send + Sync>;
+
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: MetricKind,
+    labels: Vec<(&'static str, String)>,
+    sample: Sample,
+}
+
+/// Metrics rendered in the Prometheus text format. Every metric is sampled from the shared state
+/// that also feeds the periodic stats log, so both always agree.
+#[derive(Default)]
+pub struct Registry {
+    metrics: Vec<Metric>,
+}
+
+impl Registry {
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        kind: MetricKind,
+        labels: &[(&'static str, &str)],
+        sample: impl Fn() -> f64 + Send + Sync + 'static,
+    ) {
+        self.metrics.push(Metric {
+            name,
+            help,
+            kind,
+            labels: labels
+                .iter()
+                .map(|(label, value)| (*label, (*value).to_owned()))
+                .collect(),
+            sample: Box::new(sample),
+        });
+    }
+
+    #[must_use]
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        let mut previous_name = None;
+        for metric in &self.metrics {
+            if previous_name != Some(metric.name) {
+                let _ = writeln!(output, "# HELP {} {}", metric.name, metric.help);
+                let _ = writeln!(output, "# TYPE {} {}", metric.name, metric.kind.as_str());
+                previous_name = Some(metric.name);
+            }
+
+            output.push_str(metric.name);
+            if !metric.labels.is_empty() {
+                let labels = metric
+                    .labels
+                    .iter()
+                    .map(|(label, value)| format!("{label}=\"{}\"", escape_label_value(value)))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let _ = write!(output, "{{{labels}}}");
+            }
+            let _ = writeln!(output, " {}", (metric.sample)());
+        }
+
+        output
+    }
+}
+
+/// Converts a counter or gauge reading into a sample. Readings above 2^53 lose precision, which
+/// no counter of a single process reaches.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn sample(value: u64) -> f64 {
+    value as f64
+}
+
+/// Like [`sample`] for readings that may be negative.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn sample_signed(value: i64) -> f64 {
+    value as f64
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+async fn metrics_handler(State(registry): State<Arc<Registry>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        registry.render(),
+    )
+}
+
+/// Serves `GET /metrics` and the admin endpoints on `address` until the process exits.
+pub async fn serve(
+    address: SocketAddr,
+    registry: Arc<Registry>,
+    admin_requests: mpsc::Sender<AdminRequest>,
+) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(registry)
+        .merge(admin::router(admin_requests));
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    tracing::info!(%address, "Serving metrics.");
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use chrono::NaiveDate;
+
+use crate::{
+    kafka_output::OutputRecord,
+    util::{AggregatedKey, CommunicationData},
+};
+
+/// Writes flushed batches as newline-delimited JSON, one [`OutputRecord`] per line. The records
+/// have the same stable schema as the `--output-topic` messages: the key fields `time`, `source`,
+/// `target`, `src_vlan`, `dst_vlan`, `proto`, `in_if`, `out_if` and the optional dimensions, which
+/// are `null` when disabled, followed by the `packets`, `bytes`, `packets_fwd`, `packets_rev`,
+/// `bytes_fwd` and `bytes_rev` totals.
+pub struct NdjsonOutput {
+    /// Base path of the daily files, stdout if `None`.
+    path: Option<PathBuf>,
+    /// Day and writer of the file currently appended to.
+    file: Option<(NaiveDate, BufWriter<fs::File>)>,
+}
+
+impl NdjsonOutput {
+    /// With a `path` every UTC day is appended to `<path>.<YYYY-MM-DD>`.
+    #[must_use]
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path, file: None }
+    }
+
+    /// Writes every entry of the batch, the output is flushed once the batch is complete.
+    pub fn write_batch(
+        &mut self,
+        batch: &[(AggregatedKey, CommunicationData)],
+    ) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            let mut writer = BufWriter::new(io::stdout().lock());
+            write_records(&mut writer, batch)?;
+            return Ok(());
+        };
+
+        let today = chrono::Utc::now().date_naive();
+        let writer = match &mut self.file {
+            Some((day, writer)) if *day == today => writer,
+            file => {
+                let mut day_path = OsString::from(path.as_os_str());
+                day_path.push(format!(".{}", today.format("%Y-%m-%d")));
+                let day_path = PathBuf::from(day_path);
+                let day_file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&day_path)
+                    .with_context(|| format!("Unable to open `{}`.", day_path.display()))?;
+                &mut file.insert((today, BufWriter::new(day_file))).1
+            },
+        };
+        write_records(writer, batch)
+    }
+}
+
+fn write_records(
+    writer: &mut impl Write,
+    batch: &[(AggregatedKey, CommunicationData)],
+) -> anyhow::Result<()> {
+    for (key, data) in batch {
+        serde_json::to_writer(&mut *writer, &OutputRecord { key, data })?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
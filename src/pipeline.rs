@@ -0,0 +1,1125 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use cidr_utils::cidr::IpCidr;
+use tracing::{field::Empty, Instrument};
+
+use crate::{
+    classify::{Classifier, Flow},
+    config::{Config, FutureTimestampAction},
+    flowprotob::FlowMessage,
+    geoip::GeoIp,
+    util::{
+        self,
+        AdaptiveBatchSize,
+        AdaptiveBatchSizer,
+        AggregatedKey,
+        CidrGroups,
+        CidrTree,
+        CommunicationData,
+        HostKey,
+        HostLimiter,
+        Location,
+        PeerCounter,
+        SkipCounters,
+        SkipReason,
+    },
+    workers::AggregationWorkers,
+};
+
+/// Dimensions of the topic a flow was consumed from, `Default` for replayed flows.
+#[derive(Debug, Clone, Default)]
+pub struct TopicTags {
+    /// Measurement of the topic in `--topic-measurement-map` or `--topic-alias`.
+    pub measurement: Option<Arc<str>>,
+    /// The topic itself when it has a `--topic-alias`.
+    pub datacenter: Option<Arc<str>>,
+}
+
+impl TopicTags {
+    #[must_use]
+    pub fn of_topic(config: &Config, topic: &str) -> Self {
+        Self {
+            measurement: config.topic_measurement_map.get(topic).cloned(),
+            datacenter: config.topic_datacenters.get(topic).cloned(),
+        }
+    }
+}
+
+/// Counters shared with the stats reporter and the metrics endpoint.
+#[derive(Clone, Default)]
+pub struct PipelineCounters {
+    pub skipped: Arc<SkipCounters>,
+    pub clamped_timestamps: Arc<AtomicU64>,
+    pub timestamp_fallbacks: Arc<AtomicU64>,
+    pub overflowed_flows: Arc<AtomicU64>,
+    /// Payload bytes recorded into the cache, compared against the batch size.
+    pub cache_bytes: Arc<AtomicUsize>,
+}
+
+/// Batches taken one table of the aggregation workers at a time, see
+/// [`AggregationWorkers::shards`].
+#[derive(Debug, Clone, Copy)]
+enum Round {
+    /// The whole cache, `shrink` also releases the memory of the tables.
+    Drain { shrink: bool },
+    /// The buckets closed at `watermark`.
+    Closed { watermark: u64 },
+}
+
+/// Turns decoded flows into the keys they are aggregated under and decides when they are flushed.
+///
+/// Validates the timestamps, classifies the addresses against the inside CIDRs, looks up the
+/// optional dimensions and limits the distinct inside hosts per bucket. The flows are aggregated
+/// in the cache it owns, which is flushed once the batch size or the memory limit is reached and
+/// otherwise bucket by bucket. Kafka and the sinks are wired to it by the binary.
+pub struct Pipeline {
+    config: Arc<Config>,
+    seconds_alignment: u64,
+    cidr_tree: CidrTree,
+    /// `--include-vlans`, replaced by a reload of the configuration.
+    include_vlans: Vec<u32>,
+    cidr_groups: Option<CidrGroups>,
+    classifier: Option<Classifier>,
+    geo_ip: Option<GeoIp>,
+    source_limiter: HostLimiter,
+    target_limiter: HostLimiter,
+    /// Only with `--peer-cardinality`.
+    peers: Option<PeerCounter>,
+    counters: PipelineCounters,
+    cache: AggregationWorkers,
+    /// Payload bytes after which the whole cache is flushed, unless a sizer adapts it.
+    batch_size: usize,
+    adaptive_batch_size: Option<AdaptiveBatchSize>,
+    lag_batch_sizer: Option<AdaptiveBatchSizer>,
+    last_batch_size_update: Instant,
+    /// Latest `time_received`, used as "now" so backfills close buckets at the pace of the data
+    /// rather than all at once.
+    latest_received: u64,
+    last_closed_buckets_check: Instant,
+    /// Set until a drain of the whole cache started.
+    full_flush_requested: bool,
+    /// Round in progress and the table it takes next.
+    round: Option<Round>,
+    next_shard: usize,
+    /// Distinct peers of the inside hosts in the buckets of the batches taken so far.
+    batch_peers: HashMap<HostKey, u64>,
+}
+
+impl Pipeline {
+    /// Spawns the aggregation workers of the cache, so it must be called within the runtime.
+    #[must_use]
+    pub fn new(config: Arc<Config>, geo_ip: Option<GeoIp>, counters: PipelineCounters) -> Self {
+        Self {
+            seconds_alignment: config.time_alignment_seconds,
+            cidr_tree: CidrTree::new(&config.cidr_list),
+            include_vlans: config.include_vlans.clone(),
+            cidr_groups: (!config.cidr_groups.is_empty())
+                .then(|| CidrGroups::new(&config.cidr_groups)),
+            classifier: None,
+            geo_ip,
+            source_limiter: HostLimiter::new(config.max_unique_sources),
+            target_limiter: HostLimiter::new(config.max_unique_targets),
+            peers: config.peer_cardinality.then(PeerCounter::default),
+            counters,
+            cache: AggregationWorkers::new(
+                config.workers,
+                config.aggregation_store,
+                config.store_shards,
+            ),
+            batch_size: config.batch_size,
+            adaptive_batch_size: config.adaptive_batch_max_size.map(|max_size| {
+                AdaptiveBatchSize::new(
+                    config.batch_size,
+                    max_size,
+                    config.adaptive_batch_lag_threshold.as_secs(),
+                )
+            }),
+            lag_batch_sizer: config.lag_batch_max_size.map(|max_size| {
+                AdaptiveBatchSizer::new(
+                    config.batch_size,
+                    max_size,
+                    config.high_lag_threshold,
+                    config.low_lag_threshold,
+                )
+            }),
+            last_batch_size_update: Instant::now(),
+            latest_received: 0,
+            last_closed_buckets_check: Instant::now(),
+            full_flush_requested: false,
+            round: None,
+            next_shard: 0,
+            batch_peers: HashMap::new(),
+            config,
+        }
+    }
+
+    /// Cache the flows are aggregated in.
+    #[must_use]
+    pub fn cache(&self) -> &AggregationWorkers {
+        &self.cache
+    }
+
+    /// Replaces the inside CIDRs after `--cidr-file` changed.
+    pub fn set_cidr_list(&mut self, cidr_list: &[IpCidr]) {
+        self.cidr_tree = CidrTree::new(cidr_list);
+    }
+
+    /// Replaces the VLANs of the aggregated flows after a reload of the configuration.
+    pub fn set_include_vlans(&mut self, include_vlans: &[u32]) {
+        include_vlans.clone_into(&mut self.include_vlans);
+    }
+
+    /// Inside CIDRs the flows are classified against.
+    #[must_use]
+    pub fn cidr_tree(&self) -> &CidrTree {
+        &self.cidr_tree
+    }
+
+    /// Replaces the rules of the `app` label, `None` disables it.
+    pub fn set_classifier(&mut self, classifier: Option<Classifier>) {
+        self.classifier = classifier;
+    }
+
+    /// Aligns the following flows to buckets of `seconds`, the buckets already in the cache keep
+    /// theirs.
+    pub fn set_time_alignment(&mut self, seconds: u64) {
+        self.seconds_alignment = seconds;
+    }
+
+    /// Replaces the batch size, ignored with `--adaptive-batch-max-size` or
+    /// `--lag-batch-max-size`.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size;
+    }
+
+    /// Payload bytes after which the whole cache is flushed.
+    #[must_use]
+    pub fn batch_size(&self) -> usize {
+        match (&self.adaptive_batch_size, &self.lag_batch_sizer) {
+            (Some(adaptive_batch_size), _) => adaptive_batch_size.current(),
+            (None, Some(lag_batch_sizer)) => lag_batch_sizer.current(),
+            (None, None) => self.batch_size,
+        }
+    }
+
+    /// Adapts the batch size to the lag behind the latest flow and to the `consumer_lag` in
+    /// messages, at most once a second.
+    pub fn update_batch_size(&mut self, consumer_lag: i64) {
+        if let Some(adaptive_batch_size) = &mut self.adaptive_batch_size {
+            if self.latest_received > 0
+                && self.last_batch_size_update.elapsed() >= Duration::from_secs(1)
+            {
+                self.last_batch_size_update = Instant::now();
+                let lag = u64::try_from(chrono::Utc::now().timestamp())
+                    .unwrap_or(0)
+                    .saturating_sub(self.latest_received);
+                if let Some(batch_size) = adaptive_batch_size.update(lag) {
+                    tracing::info!(lag, batch_size, "Effective batch size changed.");
+                }
+            }
+        }
+        if let Some(lag_batch_sizer) = &mut self.lag_batch_sizer {
+            if self.last_batch_size_update.elapsed() >= Duration::from_secs(1) {
+                self.last_batch_size_update = Instant::now();
+                if let Some(batch_size) =
+                    lag_batch_sizer.update(u64::try_from(consumer_lag).unwrap_or(0))
+                {
+                    tracing::info!(consumer_lag, batch_size, "Effective batch size changed.");
+                }
+            }
+        }
+    }
+
+    /// Makes the next batch take the whole cache, e.g. after a revocation or on shutdown.
+    pub fn request_full_flush(&mut self) {
+        self.full_flush_requested = true;
+    }
+
+    /// Whether the next batch takes the whole cache instead of the closed buckets.
+    #[must_use]
+    pub fn should_flush(&self) -> bool {
+        self.full_flush_requested
+            || self.memory_exceeded()
+            || self.counters.cache_bytes.load(Ordering::Relaxed) >= self.batch_size()
+    }
+
+    /// Whether the next batch starts a drain of the whole cache.
+    #[must_use]
+    pub fn drain_starts(&self) -> bool {
+        self.round.is_none() && self.should_flush()
+    }
+
+    /// Whether a drain of the whole cache has tables left, so the batches taken so far do not
+    /// cover every flow recorded before it.
+    #[must_use]
+    pub fn is_draining(&self) -> bool {
+        matches!(self.round, Some(Round::Drain { .. }))
+    }
+
+    fn memory_exceeded(&self) -> bool {
+        self.config
+            .max_cache_memory_bytes
+            .is_some_and(|max_bytes| self.cache.estimated_memory() >= max_bytes)
+    }
+
+    /// Processes the flow and aggregates it into the cache, its `payload_bytes` count towards the
+    /// batch size. Returns whether the flow was aggregated.
+    pub async fn record(
+        &mut self,
+        message: &mut FlowMessage,
+        topic: Option<&TopicTags>,
+        payload_bytes: usize,
+    ) -> anyhow::Result<bool> {
+        let Some((key, reversed)) = self.process_message(message, topic) else {
+            return Ok(false);
+        };
+        self.cache
+            .record(
+                key,
+                message.packets,
+                message.bytes,
+                reversed,
+                self.config.track_observation_time.then(SystemTime::now),
+                self.config.track_variance,
+            )
+            .await?;
+
+        self.latest_received = message.time_received;
+        self.counters
+            .cache_bytes
+            .fetch_add(payload_bytes, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Entries due to be written, one table of the cache at a time. A round takes the whole cache
+    /// once [`Self::should_flush`], otherwise the buckets closed `--flush-grace` behind the
+    /// latest flow, checked at most once a second. The next calls go on with the round until
+    /// every table is taken, empty tables are skipped.
+    pub async fn take_batch(
+        &mut self,
+    ) -> anyhow::Result<HashMap<AggregatedKey, CommunicationData>> {
+        if self.round.is_none() {
+            self.round = self.start_round();
+            self.next_shard = 0;
+        }
+
+        while let Some(round) = self.round {
+            let shard = self.next_shard;
+            self.next_shard += 1;
+            if self.next_shard >= self.cache.shards() {
+                self.round = None;
+            }
+
+            let drain = matches!(round, Round::Drain { .. });
+            let assemble_span = tracing::info_span!("assemble_batch", drain, shard, points = Empty);
+            let batch: HashMap<_, _> = match round {
+                Round::Drain { shrink } => self.cache.drain_shard(shard, shrink),
+                Round::Closed { watermark } => {
+                    self.cache
+                        .take_closed(shard, watermark, self.seconds_alignment)
+                },
+            }
+            .instrument(assemble_span.clone())
+            .await?
+            .into_iter()
+            .collect();
+            assemble_span.record("points", batch.len());
+
+            let cache_elements = self.cache.len() + batch.len();
+            if !drain && cache_elements > 0 {
+                // Only the payload size of the whole cache is known, shrink it proportionally.
+                let cache_bytes = &self.counters.cache_bytes;
+                cache_bytes.store(
+                    cache_bytes.load(Ordering::Relaxed) * self.cache.len() / cache_elements,
+                    Ordering::Relaxed,
+                );
+            }
+            if !batch.is_empty() {
+                return Ok(batch);
+            }
+        }
+
+        Ok(HashMap::new())
+    }
+
+    /// Round of the next batches, `None` while the closed buckets were checked within the last
+    /// second. The peers and the bucket limits of the round are settled right away.
+    fn start_round(&mut self) -> Option<Round> {
+        if self.should_flush() {
+            // Safety valve, the cache grew too big to wait for the buckets to close. An exhausted
+            // replay and a forced flush write everything as well.
+            if self.memory_exceeded() {
+                tracing::warn!(
+                    cache.elements = self.cache.len(),
+                    "Cache memory limit reached, flushing the whole cache."
+                );
+            }
+            let peers = self.take_unique_peers(None);
+            self.batch_peers.extend(peers);
+            self.clear_buckets();
+            self.counters.cache_bytes.store(0, Ordering::Relaxed);
+            self.full_flush_requested = false;
+            // A drained table keeps its buckets, they are released with a memory limit so the
+            // limit is not hit again right away.
+            return Some(Round::Drain {
+                shrink: self.config.max_cache_memory_bytes.is_some(),
+            });
+        }
+        if self.last_closed_buckets_check.elapsed() < Duration::from_secs(1) {
+            return None;
+        }
+
+        self.last_closed_buckets_check = Instant::now();
+        let watermark = self
+            .latest_received
+            .saturating_sub(self.config.flush_grace.as_secs());
+        let peers = self.take_unique_peers(Some(watermark));
+        self.batch_peers.extend(peers);
+        self.retain_open_buckets(watermark);
+        Some(Round::Closed { watermark })
+    }
+
+    /// Distinct peers of the inside hosts in the buckets of the batches taken since the last call.
+    /// Empty without `--peer-cardinality`.
+    pub fn take_batch_peers(&mut self) -> HashMap<HostKey, u64> {
+        std::mem::take(&mut self.batch_peers)
+    }
+
+    /// Key to aggregate the flow under and whether it goes from the canonical target to the
+    /// source, `None` if the flow is skipped. The skip is counted. Timestamps may be clamped in
+    /// place.
+    pub fn process_message(
+        &mut self,
+        message: &mut FlowMessage,
+        topic: Option<&TopicTags>,
+    ) -> Option<(AggregatedKey, bool)> {
+        let config = &self.config;
+        let skipped = &self.counters.skipped;
+
+        if config
+            .flow_version
+            .is_some_and(|flow_version| message.r#type() != flow_version.flow_type())
+        {
+            skipped.record(SkipReason::OtherFlowVersion, message.etype);
+            return None;
+        }
+        if !config.include_protos.is_empty() && !config.include_protos.contains(&message.proto) {
+            skipped.record(SkipReason::ExcludedProto, message.etype);
+            return None;
+        }
+        if !self.include_vlans.is_empty()
+            && !self.include_vlans.contains(&message.src_vlan)
+            && !self.include_vlans.contains(&message.dst_vlan)
+        {
+            skipped.record(SkipReason::ExcludedVlan, message.etype);
+            return None;
+        }
+        if message.packets < config.min_packets || message.bytes < config.min_bytes {
+            skipped.record(SkipReason::TooSmall, message.etype);
+            return None;
+        }
+        if config
+            .max_packets
+            .is_some_and(|max_packets| message.packets > max_packets)
+            || config
+                .max_bytes
+                .is_some_and(|max_bytes| message.bytes > max_bytes)
+        {
+            skipped.record(SkipReason::TooLarge, message.etype);
+            return None;
+        }
+
+        // Broken exporter clocks must not write points outside the retention or into the far
+        // future.
+        let timestamps = [message.time_flow_start, message.time_received];
+        if let Some(min_timestamp) = config.min_timestamp {
+            if timestamps
+                .iter()
+                .any(|timestamp| *timestamp < min_timestamp)
+            {
+                tracing::debug!(?timestamps, "Dropping flow dated before the floor.");
+                skipped.record(SkipReason::BeforeFloor, message.etype);
+                return None;
+            }
+        }
+        if let Some(max_future_skew) = config.max_future_skew {
+            let now = u64::try_from(chrono::Utc::now().timestamp()).unwrap_or(0);
+            if timestamps
+                .iter()
+                .any(|timestamp| *timestamp > now + max_future_skew.as_secs())
+            {
+                match config.future_timestamp_action {
+                    FutureTimestampAction::Drop => {
+                        tracing::debug!(?timestamps, "Dropping flow dated in the future.");
+                        skipped.record(SkipReason::InFuture, message.etype);
+                        return None;
+                    },
+                    FutureTimestampAction::Clamp => {
+                        message.time_flow_start = message.time_flow_start.min(now);
+                        message.time_received = message.time_received.min(now);
+                        self.counters
+                            .clamped_timestamps
+                            .fetch_add(1, Ordering::Relaxed);
+                    },
+                }
+            }
+        }
+        // Checked before any host is admitted, an ancient backlog must not take the slots of the
+        // host limiters. There is no key yet, so the raw addresses are logged instead.
+        let now = u64::try_from(chrono::Utc::now().timestamp()).unwrap_or(0);
+        for (max_age, timestamp, reason) in [
+            (
+                config.max_message_age,
+                message.time_flow_start,
+                SkipReason::TooOld,
+            ),
+            (
+                config.max_received_age,
+                message.time_received,
+                SkipReason::ReceivedTooOld,
+            ),
+        ] {
+            let age = now.saturating_sub(timestamp);
+            if max_age.is_some_and(|max_age| age > max_age.as_secs()) {
+                let seconds_alignment = self.seconds_alignment;
+                let (bucket_timestamp, _) =
+                    util::bucket_timestamp(message, config.bucket_timestamp);
+                let time = bucket_timestamp.div_euclid(seconds_alignment) * seconds_alignment;
+                tracing::debug!(
+                    age,
+                    bucket = ?(time..time + seconds_alignment),
+                    src_addr = ?util::parse_exporter(&message.src_addr),
+                    dst_addr = ?util::parse_exporter(&message.dst_addr),
+                    "Dropping too old flow."
+                );
+                skipped.record(reason, message.etype);
+                return None;
+            }
+        }
+        // With `--allow-partial-flows` an unparsable endpoint becomes unknown instead of dropping
+        // the flow, as long as the other one is valid.
+        let (src_ip, src_location, dst_ip, dst_location) = match (
+            util::parse_location(message.etype, &message.src_addr, &self.cidr_tree, skipped),
+            util::parse_location(message.etype, &message.dst_addr, &self.cidr_tree, skipped),
+        ) {
+            (Some((src_ip, src_location)), Some((dst_ip, dst_location))) => {
+                (Some(src_ip), src_location, Some(dst_ip), dst_location)
+            },
+            (Some((src_ip, src_location)), None) if config.allow_partial_flows => {
+                (Some(src_ip), src_location, None, Location::Unknown)
+            },
+            (None, Some((dst_ip, dst_location))) if config.allow_partial_flows => {
+                (None, Location::Unknown, Some(dst_ip), dst_location)
+            },
+            (None, _) => {
+                skipped.record(SkipReason::InvalidSrc, message.etype);
+                return None;
+            },
+            (Some(_), None) => {
+                skipped.record(SkipReason::InvalidDst, message.etype);
+                return None;
+            },
+        };
+        match (src_location, dst_location) {
+            (Location::Outside, Location::Outside) if config.skip_external_flows => {
+                skipped.record(SkipReason::ExternalFlow, message.etype);
+                return None;
+            },
+            (Location::Inside(_), Location::Inside(_)) if config.skip_internal_flows => {
+                skipped.record(SkipReason::InternalFlow, message.etype);
+                return None;
+            },
+            _ => {},
+        }
+
+        // Optional dimensions collapse to a constant when disabled.
+        let mut geo_lookup = |ip, location| {
+            match (&mut self.geo_ip, ip, location) {
+                (Some(geo_ip), Some(ip), Location::Outside) => Some(geo_ip.lookup(ip)),
+                _ => None,
+            }
+        };
+        let src_geo = geo_lookup(src_ip, src_location);
+        let dst_geo = geo_lookup(dst_ip, dst_location);
+        let (src_group, dst_group) = match &self.cidr_groups {
+            Some(cidr_groups) => {
+                (
+                    src_ip.map(|ip| cidr_groups.group(ip)),
+                    dst_ip.map(|ip| cidr_groups.group(ip)),
+                )
+            },
+            None => (None, None),
+        };
+        let app = self.classifier.as_ref().map(|classifier| {
+            classifier.classify(&Flow {
+                proto: message.proto,
+                src_port: message.src_port,
+                dst_port: message.dst_port,
+                src_ip,
+                dst_ip,
+            })
+        });
+        let (in_if, out_if) = if config.include_interfaces {
+            (message.in_if, message.out_if)
+        } else {
+            (0, 0)
+        };
+        let (bucket_timestamp, fallback) = util::bucket_timestamp(message, config.bucket_timestamp);
+        if fallback {
+            self.counters
+                .timestamp_fallbacks
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        let seconds_alignment = self.seconds_alignment;
+        let time = bucket_timestamp.div_euclid(seconds_alignment) * seconds_alignment;
+        let (source, source_tripped) = self
+            .source_limiter
+            .admit(time, src_location.with_prefix_len(config.src_prefix_len));
+        let (target, target_tripped) = self
+            .target_limiter
+            .admit(time, dst_location.with_prefix_len(config.dst_prefix_len));
+        if source_tripped || target_tripped {
+            tracing::warn!(
+                time,
+                sources = source_tripped,
+                targets = target_tripped,
+                "Too many distinct inside hosts in the bucket, new hosts are aggregated as \
+                 overflow."
+            );
+        }
+        if let Some(peers) = &mut self.peers {
+            peers.record(time, (source, src_ip), (target, dst_ip));
+        }
+        if source == Location::Overflow || target == Location::Overflow {
+            self.counters
+                .overflowed_flows
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        let key = AggregatedKey {
+            time,
+            source,
+            target,
+            src_vlan: message.src_vlan,
+            dst_vlan: message.dst_vlan,
+            proto: message.proto,
+            in_if,
+            out_if,
+            mpls_label: config
+                .track_mpls
+                .then(|| util::top_mpls_label(message))
+                .flatten(),
+            exporter: config
+                .include_exporter
+                .then(|| util::parse_exporter(&message.sampler_address))
+                .flatten(),
+            tcp_flags: config
+                .include_tcp_flags
+                .then(|| util::tcp_flags(message))
+                .flatten(),
+            dscp: config.include_dscp.then(|| util::dscp(message)),
+            icmp: config
+                .include_icmp_detail
+                .then(|| util::icmp_detail(message))
+                .flatten(),
+            ip_version: config
+                .include_ip_version
+                .then(|| src_ip.or(dst_ip))
+                .flatten()
+                .map(|ip| if ip.is_ipv4() { 4 } else { 6 }),
+            src_geo,
+            dst_geo,
+            src_group,
+            dst_group,
+            app,
+            datacenter: topic.and_then(|topic| topic.datacenter.clone()),
+            measurement: topic.and_then(|topic| topic.measurement.clone()),
+        };
+
+        Some(
+            if config.bidirectional {
+                key.canonicalize()
+            } else {
+                (key, false)
+            },
+        )
+    }
+
+    /// Forgets the distinct hosts of the buckets closed at `watermark`.
+    fn retain_open_buckets(&mut self, watermark: u64) {
+        let seconds_alignment = self.seconds_alignment;
+        self.source_limiter
+            .retain(|time| time + seconds_alignment > watermark);
+        self.target_limiter
+            .retain(|time| time + seconds_alignment > watermark);
+    }
+
+    /// Distinct peers of the inside hosts in the buckets closed at `watermark`, or of all buckets
+    /// with `None`. Empty without `--peer-cardinality`.
+    fn take_unique_peers(&mut self, watermark: Option<u64>) -> HashMap<HostKey, u64> {
+        let seconds_alignment = self.seconds_alignment;
+        match (&mut self.peers, watermark) {
+            (Some(peers), Some(watermark)) => {
+                peers.take(|time| time + seconds_alignment <= watermark)
+            },
+            (Some(peers), None) => peers.take(|_| true),
+            (None, _) => HashMap::new(),
+        }
+    }
+
+    /// Forgets the distinct hosts of all buckets, after the whole cache was flushed.
+    fn clear_buckets(&mut self) {
+        self.source_limiter.clear();
+        self.target_limiter.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    /// 2023-11-14 22:13:00 UTC, aligned to a minute.
+    const TIME: u64 = 1_700_000_040;
+
+    fn pipeline(args: &[&str]) -> Pipeline {
+        Pipeline::new(
+            Arc::new(config::tests::config(args)),
+            None,
+            PipelineCounters::default(),
+        )
+    }
+
+    /// TCP flow from an inside host to an outside one.
+    fn flow() -> FlowMessage {
+        FlowMessage {
+            etype: 0x0800,
+            src_addr: vec![10, 0, 0, 1],
+            dst_addr: vec![192, 0, 2, 1],
+            proto: 6,
+            packets: 2,
+            bytes: 100,
+            time_flow_start: TIME,
+            time_flow_end: TIME + 5,
+            time_received: TIME + 10,
+            ..FlowMessage::default()
+        }
+    }
+
+    fn now() -> u64 {
+        u64::try_from(chrono::Utc::now().timestamp()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn duplicate_keys_are_aggregated() {
+        let mut pipeline = pipeline(&[]);
+        assert!(pipeline.record(&mut flow(), None, 10).await.unwrap());
+        assert!(pipeline.record(&mut flow(), None, 10).await.unwrap());
+        // The ports are not a dimension of the key.
+        let mut other = flow();
+        other.dst_port = 443;
+        assert!(pipeline.record(&mut other, None, 10).await.unwrap());
+
+        pipeline.request_full_flush();
+        let batch = pipeline.take_batch().await.unwrap();
+
+        assert_eq!(batch.len(), 1);
+        let value = batch.values().next().unwrap();
+        assert_eq!((value.packets, value.bytes), (6, 300));
+    }
+
+    #[tokio::test]
+    async fn aliased_topics_are_kept_apart_by_datacenter() {
+        let mut pipeline = pipeline(&["--topic-alias=sflow-dc1=sflow,sflow-dc2=sflow"]);
+        for topic in ["sflow-dc1", "sflow-dc2", "sflow-dc1"] {
+            let tags = TopicTags::of_topic(&pipeline.config, topic);
+            assert!(pipeline.record(&mut flow(), Some(&tags), 10).await.unwrap());
+        }
+
+        pipeline.request_full_flush();
+        let mut batch: Vec<_> = pipeline.take_batch().await.unwrap().into_iter().collect();
+        batch.sort_by(|(a, _), (b, _)| a.datacenter.cmp(&b.datacenter));
+
+        let datacenters: Vec<_> = batch
+            .iter()
+            .map(|(key, value)| {
+                assert_eq!(key.measurement.as_deref(), Some("sflow"));
+                (key.datacenter.as_deref(), value.packets)
+            })
+            .collect();
+        assert_eq!(
+            datacenters,
+            [(Some("sflow-dc1"), 4), (Some("sflow-dc2"), 2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn flows_are_bucketed_by_the_time_alignment() {
+        let mut pipeline = pipeline(&["--time-alignment-seconds=60"]);
+        let mut time_of = |time_flow_start| {
+            let mut message = flow();
+            message.time_flow_start = time_flow_start;
+            pipeline
+                .process_message(&mut message, None)
+                .map(|(key, _)| key.time)
+        };
+
+        assert_eq!(time_of(TIME), Some(TIME));
+        assert_eq!(time_of(TIME + 59), Some(TIME));
+        assert_eq!(time_of(TIME + 60), Some(TIME + 60));
+        assert_eq!(time_of(TIME - 1), Some(TIME - 60));
+    }
+
+    #[tokio::test]
+    async fn adjacent_bucket_ranges_do_not_overlap() {
+        let mut pipeline = pipeline(&[]);
+        for alignment in [1, 10, 60, 300, 3600] {
+            pipeline.set_time_alignment(alignment);
+            let mut ranges = Vec::new();
+            for time_flow_start in TIME - 2 * alignment..TIME + 2 * alignment {
+                let mut message = flow();
+                message.time_flow_start = time_flow_start;
+                message.time_flow_end = time_flow_start + 5;
+                message.time_received = time_flow_start + 10;
+                let (key, _) = pipeline.process_message(&mut message, None).unwrap();
+                let range = key.bucket_range(alignment);
+                assert!(
+                    range.contains(&time_flow_start),
+                    "{alignment}: {time_flow_start}"
+                );
+                ranges.push(range);
+            }
+            ranges.dedup();
+
+            assert!(ranges.len() >= 4, "{alignment}: {ranges:?}");
+            for (previous, next) in ranges.iter().zip(ranges.iter().skip(1)) {
+                assert_eq!(
+                    previous.end, next.start,
+                    "{alignment}: {previous:?} {next:?}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn skipped_flows_are_counted_by_reason() {
+        let cases: [(&[&str], fn(&mut FlowMessage), SkipReason); 16] = [
+            (
+                &[],
+                |message| message.etype = 0x1234,
+                SkipReason::UnknownEtype,
+            ),
+            (&[], |message| message.etype = 0x0806, SkipReason::Arp),
+            (
+                &[],
+                |message| message.src_addr = vec![10, 0, 0],
+                SkipReason::InvalidSrc,
+            ),
+            (
+                &[],
+                |message| message.dst_addr = vec![192, 0, 2],
+                SkipReason::InvalidDst,
+            ),
+            (
+                &[],
+                |message| message.dst_addr = vec![192, 0, 2],
+                SkipReason::MalformedAddr,
+            ),
+            (
+                &["--max-message-age-seconds=3600"],
+                |message| message.time_received = now(),
+                SkipReason::TooOld,
+            ),
+            (
+                &["--max-received-age-secs=3600"],
+                |message| message.time_received = now() - 7200,
+                SkipReason::ReceivedTooOld,
+            ),
+            (
+                &["--max-future-skew-secs=60"],
+                |message| message.time_received = now() + 3600,
+                SkipReason::InFuture,
+            ),
+            (
+                &["--min-timestamp=1700000000"],
+                |message| message.time_flow_start = 1_600_000_000,
+                SkipReason::BeforeFloor,
+            ),
+            (
+                &["--flow-version=ipfix"],
+                |_| {},
+                SkipReason::OtherFlowVersion,
+            ),
+            (&["--include-protos=udp"], |_| {}, SkipReason::ExcludedProto),
+            (&["--include-vlans=10"], |_| {}, SkipReason::ExcludedVlan),
+            (&["--min-packets=3"], |_| {}, SkipReason::TooSmall),
+            (&["--max-bytes=99"], |_| {}, SkipReason::TooLarge),
+            (
+                &["--skip-external-flows"],
+                |message| message.src_addr = vec![198, 51, 100, 1],
+                SkipReason::ExternalFlow,
+            ),
+            (
+                &["--skip-internal-flows"],
+                |message| message.dst_addr = vec![10, 0, 0, 2],
+                SkipReason::InternalFlow,
+            ),
+        ];
+
+        for (args, skip, reason) in cases {
+            let mut pipeline = pipeline(args);
+            let mut message = flow();
+            skip(&mut message);
+
+            assert!(
+                pipeline.process_message(&mut message, None).is_none(),
+                "{reason:?}"
+            );
+            assert_eq!(pipeline.counters.skipped.get(reason), 1, "{reason:?}");
+            assert_eq!(
+                pipeline.counters.skipped.get_ip_version(reason, 4),
+                u64::from(message.etype == 0x0800),
+                "{reason:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reloaded_vlans_apply_to_new_flows() {
+        let mut pipeline = pipeline(&["--include-vlans=20"]);
+        let tagged = || {
+            FlowMessage {
+                src_vlan: 10,
+                ..flow()
+            }
+        };
+        assert!(pipeline.process_message(&mut tagged(), None).is_none());
+
+        pipeline.set_include_vlans(&[10]);
+
+        assert!(pipeline.process_message(&mut tagged(), None).is_some());
+        assert!(pipeline.process_message(&mut flow(), None).is_none());
+    }
+
+    #[tokio::test]
+    async fn interfaces_collapse_to_zero_unless_included() {
+        let interfaces = |args: &[&str]| {
+            let mut message = FlowMessage {
+                in_if: 17,
+                out_if: 23,
+                ..flow()
+            };
+            pipeline(args)
+                .process_message(&mut message, None)
+                .map(|(key, _)| (key.in_if, key.out_if))
+        };
+
+        assert_eq!(interfaces(&[]), Some((0, 0)));
+        assert_eq!(interfaces(&["--include-interfaces"]), Some((17, 23)));
+    }
+
+    #[tokio::test]
+    async fn mpls_flows_aggregate_apart_from_plain_ones() {
+        let mut pipeline = pipeline(&["--track-mpls"]);
+        let mut labelled = FlowMessage {
+            mpls_labels: vec![100, 200],
+            ..flow()
+        };
+        assert!(pipeline.record(&mut labelled, None, 10).await.unwrap());
+        assert!(pipeline.record(&mut flow(), None, 10).await.unwrap());
+        assert!(pipeline.record(&mut flow(), None, 10).await.unwrap());
+
+        pipeline.request_full_flush();
+        let mut labels: Vec<_> = pipeline
+            .take_batch()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, value)| (key.mpls_label, value.packets))
+            .collect();
+        labels.sort_unstable();
+
+        assert_eq!(labels, [(None, 4), (Some(100), 2)]);
+    }
+
+    #[tokio::test]
+    async fn partial_flows_keep_the_valid_endpoint() {
+        let malformed = vec![192, 0, 2];
+        let inside = Location::Inside("10.0.0.1".parse().unwrap());
+        let cases = [
+            (
+                flow().src_addr,
+                malformed.clone(),
+                Some((inside, Location::Unknown)),
+            ),
+            (
+                malformed.clone(),
+                flow().dst_addr,
+                Some((Location::Unknown, Location::Outside)),
+            ),
+            (malformed.clone(), malformed.clone(), None),
+        ];
+
+        for (src_addr, dst_addr, expected) in cases {
+            let mut pipeline = pipeline(&["--allow-partial-flows"]);
+            let mut message = FlowMessage {
+                src_addr,
+                dst_addr,
+                ..flow()
+            };
+
+            let locations = pipeline
+                .process_message(&mut message, None)
+                .map(|(key, _)| (key.source, key.target));
+            assert_eq!(locations, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_flows_are_skipped_by_default() {
+        let mut pipeline = pipeline(&[]);
+        let mut message = FlowMessage {
+            dst_addr: vec![192, 0, 2],
+            ..flow()
+        };
+
+        assert!(pipeline.process_message(&mut message, None).is_none());
+        assert_eq!(pipeline.counters.skipped.get(SkipReason::InvalidDst), 1);
+    }
+
+    #[tokio::test]
+    async fn old_messages_are_dropped() {
+        let mut pipeline = pipeline(&["--max-message-age-seconds=3600"]);
+        let now = now();
+        let mut old = FlowMessage {
+            time_flow_start: now - 3700,
+            time_received: now,
+            ..flow()
+        };
+        let mut recent = FlowMessage {
+            time_flow_start: now - 3500,
+            time_received: now,
+            ..flow()
+        };
+
+        assert!(!pipeline.record(&mut old, None, 10).await.unwrap());
+        assert!(pipeline.record(&mut recent, None, 10).await.unwrap());
+        assert_eq!(pipeline.counters.skipped.get(SkipReason::TooOld), 1);
+        assert_eq!(pipeline.counters.cache_bytes.load(Ordering::Relaxed), 10);
+
+        pipeline.request_full_flush();
+        let batch = pipeline.take_batch().await.unwrap();
+        assert_eq!(batch.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn received_age_ignores_the_exporter_clock() {
+        let mut pipeline = pipeline(&["--max-received-age-secs=3600"]);
+        let mut message = flow();
+        message.time_received = now() - 60;
+
+        assert!(pipeline.process_message(&mut message, None).is_some());
+        assert_eq!(pipeline.counters.skipped.get(SkipReason::ReceivedTooOld), 0);
+    }
+
+    #[tokio::test]
+    async fn size_limits_are_inclusive() {
+        let cases = [
+            ("--min-packets=2", (2, 100), (1, 100), SkipReason::TooSmall),
+            ("--min-bytes=100", (2, 100), (2, 99), SkipReason::TooSmall),
+            ("--max-packets=2", (2, 100), (3, 100), SkipReason::TooLarge),
+            ("--max-bytes=100", (2, 100), (2, 101), SkipReason::TooLarge),
+        ];
+
+        for (arg, (packets, bytes), (skipped_packets, skipped_bytes), reason) in cases {
+            let mut pipeline = pipeline(&[arg]);
+            let mut at_limit = FlowMessage {
+                packets,
+                bytes,
+                ..flow()
+            };
+            let mut beyond_limit = FlowMessage {
+                packets: skipped_packets,
+                bytes: skipped_bytes,
+                ..flow()
+            };
+
+            assert!(
+                pipeline.process_message(&mut at_limit, None).is_some(),
+                "{arg}"
+            );
+            assert!(
+                pipeline.process_message(&mut beyond_limit, None).is_none(),
+                "{arg}"
+            );
+            assert_eq!(pipeline.counters.skipped.get(reason), 1, "{arg}");
+        }
+    }
+
+    #[tokio::test]
+    async fn whole_cache_is_flushed_at_the_batch_size() {
+        let mut pipeline = pipeline(&[]);
+        pipeline.set_batch_size(100);
+
+        assert!(pipeline.record(&mut flow(), None, 60).await.unwrap());
+        assert!(!pipeline.should_flush());
+        let mut other = flow();
+        other.time_flow_start = TIME + 300;
+        assert!(pipeline.record(&mut other, None, 40).await.unwrap());
+        assert!(pipeline.should_flush());
+
+        assert_eq!(pipeline.take_batch().await.unwrap().len(), 2);
+        assert!(!pipeline.should_flush());
+        assert_eq!(pipeline.counters.cache_bytes.load(Ordering::Relaxed), 0);
+        assert_eq!(pipeline.cache().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn closed_buckets_are_taken_below_the_batch_size() {
+        let mut pipeline = pipeline(&["--time-alignment-seconds=60", "--flush-grace-secs=0"]);
+        assert!(pipeline.record(&mut flow(), None, 10).await.unwrap());
+        let mut open = flow();
+        open.time_flow_start = TIME + 60;
+        open.time_received = TIME + 70;
+        assert!(pipeline.record(&mut open, None, 10).await.unwrap());
+        assert!(!pipeline.should_flush());
+
+        // Closed buckets are checked at most once a second.
+        pipeline.last_closed_buckets_check -= Duration::from_secs(1);
+        let batch = pipeline.take_batch().await.unwrap();
+
+        assert_eq!(batch.keys().map(|key| key.time).collect::<Vec<_>>(), [TIME]);
+        assert_eq!(pipeline.cache().len(), 1);
+        assert_eq!(pipeline.counters.cache_bytes.load(Ordering::Relaxed), 10);
+    }
+
+    #[tokio::test]
+    async fn sharded_drains_are_taken_table_by_table() {
+        let mut pipeline = pipeline(&["--aggregation-store=sharded", "--store-shards=4"]);
+        for src_vlan in 0..32 {
+            let mut flow = flow();
+            flow.src_vlan = src_vlan;
+            assert!(pipeline.record(&mut flow, None, 10).await.unwrap());
+        }
+
+        pipeline.request_full_flush();
+        assert!(pipeline.drain_starts());
+        let mut batches = vec![pipeline.take_batch().await.unwrap()];
+        while pipeline.is_draining() {
+            assert!(!pipeline.drain_starts());
+            batches.push(pipeline.take_batch().await.unwrap());
+        }
+
+        batches.retain(|batch| !batch.is_empty());
+        assert!((2..=4).contains(&batches.len()));
+        assert_eq!(batches.iter().map(HashMap::len).sum::<usize>(), 32);
+        assert_eq!(pipeline.cache().len(), 0);
+        assert!(!pipeline.drain_starts());
+    }
+}
@@ -0,0 +1,147 @@
+use sqlx::{postgres::PgPoolOptions, PgPool, Postgres, QueryBuilder};
+
+use crate::util::{AggregatedKey, CommunicationData};
+
+/// Postgres accepts at most 65535 bind parameters per statement, every row binds eight.
+const ROWS_PER_INSERT: usize = 4096;
+
+pub async fn connect(url: &str, max_connections: u32) -> anyhow::Result<PgPool> {
+    Ok(PgPoolOptions::new()
+        .max_connections(max_connections)
+        .connect(url)
+        .await?)
+}
+
+/// Inserts the batch in a single transaction using multi-row `INSERT`s.
+///
+/// Rows that already exist for the same bucket and dimensions have their counters summed, so
+/// several flushes of one bucket (e.g. a size triggered flush followed by the regular one)
+/// accumulate instead of overwriting each other. The table layout is described in
+/// `migrations/`.
+pub async fn insert_data_into_postgres(
+    pool: &PgPool,
+    table: &str,
+    batch: &[(AggregatedKey, CommunicationData)],
+) -> anyhow::Result<()> {
+    let mut transaction = pool.begin().await?;
+
+    for chunk in batch.chunks(ROWS_PER_INSERT) {
+        let mut query = QueryBuilder::<Postgres>::new(format!(
+            "INSERT INTO {table} (time, source, target, src_vlan, dst_vlan, proto, packets, \
+             bytes) "
+        ));
+        query.push_values(chunk, |mut row, (key, value)| {
+            row.push_bind(chrono::DateTime::from_timestamp(key.time as i64, 0))
+                .push_bind(key.source.to_string())
+                .push_bind(key.target.to_string())
+                .push_bind(i64::from(key.src_vlan))
+                .push_bind(i64::from(key.dst_vlan))
+                .push_bind(i64::from(key.proto))
+                .push_bind(value.packets as i64)
+                .push_bind(value.bytes as i64);
+        });
+        query.push(format!(
+            " ON CONFLICT (time, source, target, src_vlan, dst_vlan, proto) DO UPDATE SET packets \
+             = {table}.packets + EXCLUDED.packets, bytes = {table}.bytes + EXCLUDED.bytes"
+        ));
+        query.build().execute(&mut *transaction).await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Executor;
+
+    use super::*;
+    use crate::util::Location;
+
+    const MIGRATION: &str = include_str!("../migrations/0001_create_flow_aggregates.sql");
+
+    fn key(proto: u32) -> AggregatedKey {
+        AggregatedKey {
+            time: 1_699_999_800,
+            source: Location::Inside("10.0.0.1".parse().unwrap()),
+            target: Location::Outside,
+            src_vlan: 0,
+            dst_vlan: 0,
+            proto,
+            in_if: 0,
+            out_if: 0,
+            mpls_label: None,
+            exporter: None,
+            tcp_flags: None,
+            dscp: None,
+            icmp: None,
+            ip_version: None,
+            src_geo: None,
+            dst_geo: None,
+            src_group: None,
+            dst_group: None,
+            app: None,
+            datacenter: None,
+            measurement: None,
+        }
+    }
+
+    fn data(packets: u64, bytes: u64) -> CommunicationData {
+        CommunicationData {
+            packets,
+            bytes,
+            ..CommunicationData::default()
+        }
+    }
+
+    /// Needs a Postgres the test may create a schema in, e.g.
+    /// `LPA_TEST_POSTGRES_URL=postgres://postgres@localhost/postgres cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore = "needs a Postgres server in LPA_TEST_POSTGRES_URL"]
+    async fn migration_creates_the_table_the_sink_writes() {
+        let url = std::env::var("LPA_TEST_POSTGRES_URL").unwrap();
+        // A single connection keeps the search path on the schema of this run.
+        let pool = connect(&url, 1).await.unwrap();
+        let schema = format!("lpa_test_{}", std::process::id());
+        pool.execute(format!("CREATE SCHEMA {schema}; SET search_path TO {schema}").as_str())
+            .await
+            .unwrap();
+
+        // The migration can be applied again.
+        pool.execute(MIGRATION).await.unwrap();
+        pool.execute(MIGRATION).await.unwrap();
+
+        let batch = [(key(6), data(2, 100)), (key(17), data(1, 60))];
+        insert_data_into_postgres(&pool, "flow_aggregates", &batch)
+            .await
+            .unwrap();
+        insert_data_into_postgres(&pool, "flow_aggregates", &batch[..1])
+            .await
+            .unwrap();
+
+        let rows: Vec<(chrono::DateTime<chrono::Utc>, String, String, i64, i64, i64)> =
+            sqlx::query_as(
+                "SELECT time, source, target, proto, packets, bytes FROM flow_aggregates ORDER BY \
+                 proto",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        pool.execute(format!("DROP SCHEMA {schema} CASCADE").as_str())
+            .await
+            .unwrap();
+
+        let time = chrono::DateTime::from_timestamp(1_699_999_800, 0).unwrap();
+        let source = "10.0.0.1".to_owned();
+        let target = "outside".to_owned();
+        // Flushes of the same bucket sum up.
+        assert_eq!(
+            rows,
+            [
+                (time, source.clone(), target.clone(), 6, 4, 200),
+                (time, source, target, 17, 1, 60),
+            ]
+        );
+    }
+}
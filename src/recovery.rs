@@ -0,0 +1,636 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    QueryBuilder,
+    Row,
+    Sqlite,
+    SqliteConnection,
+    SqlitePool,
+};
+
+use crate::{
+    state,
+    util::{AggregatedKey, CommunicationData, Location},
+};
+
+/// Schema version kept in `PRAGMA user_version`. Version 1 had a column per Postgres dimension
+/// and no version, version 2 keeps every aggregate in a single BLOB, version 3 adds the batch ids.
+const SCHEMA_VERSION: i64 = 3;
+
+/// SQLite accepts at most 32766 bind parameters per statement, every row binds two.
+const ROWS_PER_INSERT: usize = 10_000;
+
+/// Copy of the batches that are not in Influx yet, so a crash does not lose them.
+///
+/// Every aggregate is stored with all its dimensions and accumulators, encoded like the entries
+/// of `--state-file`, together with the id the batch is written under. The SQLite driver of
+/// sqlx is used rather than rusqlite, sqlx is already a dependency of the Postgres output.
+#[derive(Clone)]
+pub struct Recovery {
+    pool: SqlitePool,
+}
+
+impl Recovery {
+    pub async fn open(path: &Path) -> anyhow::Result<Self> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(path)
+                    .create_if_missing(true),
+            )
+            .await?;
+
+        let mut transaction = pool.begin().await?;
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&mut *transaction)
+            .await?;
+        let tables: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = \
+             'pending_aggregates'",
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        match (version, tables > 0) {
+            (SCHEMA_VERSION, _) => {},
+            (0, true) => {
+                let migrated = migrate_v1_to_v2(&mut *transaction).await.with_context(|| {
+                    format!("Unable to migrate `{}` to version 2.", path.display())
+                })?;
+                tracing::info!(
+                    aggregates = migrated,
+                    "Migrated the recovery database to version 2, the resubmitted aggregates of \
+                     version 1 have no optional dimensions."
+                );
+                migrate_v2_to_v3(&mut *transaction).await?;
+            },
+            (2, _) => {
+                migrate_v2_to_v3(&mut *transaction).await.with_context(|| {
+                    format!("Unable to migrate `{}` to version 3.", path.display())
+                })?;
+                tracing::info!(
+                    "Migrated the recovery database to version 3, the batches of version 2 are \
+                     resubmitted without a batch id."
+                );
+            },
+            (0, false) => {
+                create_aggregates(&mut *transaction).await?;
+                create_batches(&mut *transaction).await?;
+            },
+            (version, _) => {
+                anyhow::bail!(
+                    "Recovery database `{}` has version {version}, this build only reads version \
+                     {SCHEMA_VERSION}.",
+                    path.display()
+                );
+            },
+        }
+        sqlx::query(&format!("PRAGMA user_version = {SCHEMA_VERSION}"))
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+
+        Ok(Self { pool })
+    }
+
+    /// Stores the batch and its id in a single transaction and returns the number to
+    /// [`Self::forget`] it by.
+    pub async fn store(
+        &self,
+        batch: &[(AggregatedKey, CommunicationData)],
+        batch_id: Option<&str>,
+    ) -> anyhow::Result<i64> {
+        let mut transaction = self.pool.begin().await?;
+        let number: i64 =
+            sqlx::query_scalar("SELECT COALESCE(MAX(batch), 0) + 1 FROM pending_batches")
+                .fetch_one(&mut *transaction)
+                .await?;
+        sqlx::query("INSERT INTO pending_batches (batch, batch_id) VALUES (?, ?)")
+            .bind(number)
+            .bind(batch_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        for chunk in batch.chunks(ROWS_PER_INSERT) {
+            let aggregates = chunk
+                .iter()
+                .map(|(key, value)| state::encode_entry(key, value))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            insert(&mut *transaction, number, aggregates).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(number)
+    }
+
+    /// Deletes a batch once it is written.
+    pub async fn forget(&self, number: i64) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        for table in ["pending_aggregates", "pending_batches"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE batch = ?"))
+                .bind(number)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Batches left behind by a previous run, oldest first.
+    pub async fn residual(&self) -> anyhow::Result<Vec<RecoveredBatch>> {
+        let batches = sqlx::query("SELECT batch, batch_id FROM pending_batches ORDER BY batch")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut residual = Vec::with_capacity(batches.len());
+        for batch in batches {
+            let number: i64 = batch.try_get(0)?;
+            let rows = sqlx::query("SELECT aggregate FROM pending_aggregates WHERE batch = ?")
+                .bind(number)
+                .fetch_all(&self.pool)
+                .await?;
+            let mut entries = Vec::with_capacity(rows.len());
+            for row in rows {
+                match state::decode_entry(row.try_get::<&[u8], _>(0)?) {
+                    Ok(entry) => entries.push(entry),
+                    Err(error) => tracing::warn!(%error, "Skipping invalid recovered aggregate."),
+                }
+            }
+            residual.push(RecoveredBatch {
+                number,
+                batch_id: batch.try_get(1)?,
+                entries,
+            });
+        }
+
+        Ok(residual)
+    }
+}
+
+/// Batch read back from the recovery database.
+#[derive(Debug, PartialEq)]
+pub struct RecoveredBatch {
+    /// Number to [`Recovery::forget`] the batch by.
+    pub number: i64,
+    /// Id the batch was written under, a retry reuses it so its points overwrite themselves.
+    pub batch_id: Option<String>,
+    pub entries: Vec<(AggregatedKey, CommunicationData)>,
+}
+
+async fn create_aggregates(connection: &mut SqliteConnection) -> anyhow::Result<()> {
+    sqlx::query(
+        "CREATE TABLE pending_aggregates (batch INTEGER NOT NULL, aggregate BLOB NOT NULL)",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    Ok(())
+}
+
+async fn create_batches(connection: &mut SqliteConnection) -> anyhow::Result<()> {
+    sqlx::query("CREATE TABLE pending_batches (batch INTEGER PRIMARY KEY, batch_id TEXT)")
+        .execute(&mut *connection)
+        .await?;
+
+    Ok(())
+}
+
+async fn insert(
+    connection: &mut SqliteConnection,
+    batch: i64,
+    aggregates: Vec<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let mut query =
+        QueryBuilder::<Sqlite>::new("INSERT INTO pending_aggregates (batch, aggregate) ");
+    query.push_values(aggregates, |mut row, aggregate| {
+        row.push_bind(batch).push_bind(aggregate);
+    });
+    query.build().execute(&mut *connection).await?;
+
+    Ok(())
+}
+
+/// Moves the rows of the version 1 table into the BLOBs of version 2 and returns their number.
+/// Version 1 only kept the dimensions of the Postgres table, so the others stay empty.
+async fn migrate_v1_to_v2(connection: &mut SqliteConnection) -> anyhow::Result<usize> {
+    sqlx::query("ALTER TABLE pending_aggregates RENAME TO pending_aggregates_v1")
+        .execute(&mut *connection)
+        .await?;
+    create_aggregates(connection).await?;
+
+    let rows = sqlx::query(
+        "SELECT batch, time, source, target, src_vlan, dst_vlan, proto, packets, bytes FROM \
+         pending_aggregates_v1",
+    )
+    .fetch_all(&mut *connection)
+    .await?;
+    let mut batches: HashMap<i64, Vec<Vec<u8>>> = HashMap::new();
+    for row in &rows {
+        let (Ok(source), Ok(target)) = (
+            row.try_get::<String, _>(2)?.parse::<Location>(),
+            row.try_get::<String, _>(3)?.parse::<Location>(),
+        ) else {
+            tracing::warn!("Skipping recovered aggregate with an unknown location.");
+            continue;
+        };
+        let key = AggregatedKey {
+            time: u64::try_from(row.try_get::<i64, _>(1)?)?,
+            source,
+            target,
+            src_vlan: u32::try_from(row.try_get::<i64, _>(4)?)?,
+            dst_vlan: u32::try_from(row.try_get::<i64, _>(5)?)?,
+            proto: u32::try_from(row.try_get::<i64, _>(6)?)?,
+            in_if: 0,
+            out_if: 0,
+            mpls_label: None,
+            exporter: None,
+            tcp_flags: None,
+            dscp: None,
+            icmp: None,
+            ip_version: None,
+            src_geo: None,
+            dst_geo: None,
+            src_group: None,
+            dst_group: None,
+            app: None,
+            datacenter: None,
+            measurement: None,
+        };
+        let mut data = CommunicationData::default();
+        data.record(
+            u64::try_from(row.try_get::<i64, _>(7)?)?,
+            u64::try_from(row.try_get::<i64, _>(8)?)?,
+            false,
+        );
+        batches
+            .entry(row.try_get(0)?)
+            .or_default()
+            .push(state::encode_entry(&key, &data)?);
+    }
+    for (batch, aggregates) in batches {
+        for chunk in aggregates.chunks(ROWS_PER_INSERT) {
+            insert(connection, batch, chunk.to_vec()).await?;
+        }
+    }
+    sqlx::query("DROP TABLE pending_aggregates_v1")
+        .execute(&mut *connection)
+        .await?;
+
+    Ok(rows.len())
+}
+
+/// Version 2 did not keep the batch ids, its batches are written without one.
+async fn migrate_v2_to_v3(connection: &mut SqliteConnection) -> anyhow::Result<()> {
+    create_batches(connection).await?;
+    sqlx::query(
+        "INSERT INTO pending_batches (batch) SELECT DISTINCT batch FROM pending_aggregates",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        path::PathBuf,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        geoip::GeoInfo,
+        util::{IcmpDetail, TcpFlags},
+    };
+
+    /// Path of a database unique to the test `name`, removed if a previous run left it behind.
+    fn database(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("lpa-recovery-{name}-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn location() -> impl Strategy<Value = Location> {
+        prop_oneof![
+            any::<IpAddr>().prop_map(Location::Inside),
+            Just(Location::Outside),
+            Just(Location::Overflow),
+            Just(Location::Unknown),
+        ]
+    }
+
+    fn label() -> impl Strategy<Value = Option<Arc<str>>> {
+        proptest::option::of("[a-z0-9-]{1,12}".prop_map(|label| Arc::from(label.as_str())))
+    }
+
+    fn geo() -> impl Strategy<Value = Option<GeoInfo>> {
+        proptest::option::of(
+            (proptest::option::of("[A-Z]{2}"), any::<Option<u32>>())
+                .prop_map(|(country, asn)| GeoInfo { country, asn }),
+        )
+    }
+
+    fn observed() -> impl Strategy<Value = Option<SystemTime>> {
+        proptest::option::of(
+            (any::<u32>(), 0..1_000_000_000_u32).prop_map(|(seconds, nanos)| {
+                SystemTime::UNIX_EPOCH + Duration::new(u64::from(seconds), nanos)
+            }),
+        )
+    }
+
+    prop_compose! {
+        fn key()(
+            time in any::<u32>(),
+            (source, target) in (location(), location()),
+            (src_vlan, dst_vlan, proto) in any::<(u16, u16, u8)>(),
+            (in_if, out_if, mpls_label) in any::<(u32, u32, Option<u32>)>(),
+            exporter in any::<Option<IpAddr>>(),
+            (tcp_flags, dscp, icmp) in any::<(Option<u8>, Option<u8>, Option<(u8, u8)>)>(),
+            ip_version in proptest::option::of(prop_oneof![Just(4_u8), Just(6_u8)]),
+            (src_geo, dst_geo) in (geo(), geo()),
+            (src_group, dst_group, app) in (label(), label(), label()),
+            (datacenter, measurement) in (label(), label()),
+        ) -> AggregatedKey {
+            AggregatedKey {
+                time: u64::from(time),
+                source,
+                target,
+                src_vlan: u32::from(src_vlan),
+                dst_vlan: u32::from(dst_vlan),
+                proto: u32::from(proto),
+                in_if,
+                out_if,
+                mpls_label,
+                exporter,
+                tcp_flags: tcp_flags.map(|bits| TcpFlags::from_bits(u32::from(bits))),
+                dscp,
+                icmp: icmp.map(|(icmp_type, code)| IcmpDetail { icmp_type, code }),
+                ip_version,
+                src_geo,
+                dst_geo,
+                src_group,
+                dst_group,
+                app,
+                datacenter,
+                measurement,
+            }
+        }
+    }
+
+    prop_compose! {
+        fn data()(
+            (packets_fwd, packets_rev, bytes_fwd, bytes_rev) in any::<(u32, u32, u32, u32)>(),
+            (first_observed, last_observed) in (observed(), observed()),
+            (count, m2) in any::<(u32, u32)>(),
+        ) -> CommunicationData {
+            let (packets_fwd, packets_rev) = (u64::from(packets_fwd), u64::from(packets_rev));
+            let (bytes_fwd, bytes_rev) = (u64::from(bytes_fwd), u64::from(bytes_rev));
+            CommunicationData {
+                packets: packets_fwd + packets_rev,
+                bytes: bytes_fwd + bytes_rev,
+                packets_fwd,
+                packets_rev,
+                bytes_fwd,
+                bytes_rev,
+                first_observed,
+                last_observed,
+                count: u64::from(count),
+                // Integral, so the JSON encoding is exact.
+                m2: f64::from(m2),
+            }
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn aggregates_round_trip(
+            batch in proptest::collection::hash_map(key(), data(), 0..50),
+            batch_id in proptest::option::of("[0-9a-f-]{1,36}"),
+        ) {
+            let path = database("round-trip");
+            let mut batch: Vec<_> = batch.into_iter().collect();
+            let residual = runtime().block_on(async {
+                let recovery = Recovery::open(&path).await.unwrap();
+                let number = recovery.store(&batch, batch_id.as_deref()).await.unwrap();
+                (number, recovery.residual().await.unwrap())
+            });
+            std::fs::remove_file(&path).unwrap();
+
+            let (number, mut residual) = residual;
+            prop_assert_eq!(residual.len(), 1);
+            let mut recovered = residual.remove(0);
+            batch.sort_by(|(a, _), (b, _)| a.cmp(b));
+            recovered.entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            prop_assert_eq!(recovered, RecoveredBatch { number, batch_id, entries: batch });
+        }
+    }
+
+    fn flow_key(time: u64) -> AggregatedKey {
+        AggregatedKey {
+            time,
+            source: Location::Inside("10.0.0.1".parse().unwrap()),
+            target: Location::Outside,
+            src_vlan: 0,
+            dst_vlan: 0,
+            proto: 6,
+            in_if: 0,
+            out_if: 0,
+            mpls_label: None,
+            exporter: None,
+            tcp_flags: None,
+            dscp: None,
+            icmp: None,
+            ip_version: None,
+            src_geo: None,
+            dst_geo: None,
+            src_group: None,
+            dst_group: None,
+            app: None,
+            datacenter: None,
+            measurement: None,
+        }
+    }
+
+    fn counts(packets: u64, bytes: u64) -> CommunicationData {
+        let mut data = CommunicationData::default();
+        data.record(packets, bytes, false);
+        data
+    }
+
+    /// Residual with the entries of every batch in key order.
+    async fn sorted_residual(recovery: &Recovery) -> Vec<RecoveredBatch> {
+        let mut residual = recovery.residual().await.unwrap();
+        for batch in &mut residual {
+            batch.entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        residual
+    }
+
+    #[tokio::test]
+    async fn batches_are_kept_apart_and_forgotten() {
+        let path = database("batches");
+        let recovery = Recovery::open(&path).await.unwrap();
+        let first = recovery
+            .store(
+                &[
+                    (flow_key(60), counts(1, 100)),
+                    (flow_key(120), counts(1, 50)),
+                ],
+                Some("7"),
+            )
+            .await
+            .unwrap();
+        let second = recovery
+            .store(&[(flow_key(60), counts(2, 200))], None)
+            .await
+            .unwrap();
+        let second_batch = || {
+            RecoveredBatch {
+                number: second,
+                batch_id: None,
+                entries: vec![(flow_key(60), counts(2, 200))],
+            }
+        };
+
+        assert_eq!(
+            sorted_residual(&recovery).await,
+            [
+                RecoveredBatch {
+                    number: first,
+                    batch_id: Some("7".to_owned()),
+                    entries: vec![
+                        (flow_key(60), counts(1, 100)),
+                        (flow_key(120), counts(1, 50))
+                    ],
+                },
+                second_batch(),
+            ]
+        );
+
+        recovery.forget(first).await.unwrap();
+        assert_eq!(recovery.residual().await.unwrap(), [second_batch()]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Database at `path` created by `statements`, with the version of the schema.
+    async fn legacy_database(path: &Path, version: i64, statements: &[String]) {
+        let pool = SqlitePoolOptions::new()
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(path)
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap();
+        for statement in statements {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        sqlx::query(&format!("PRAGMA user_version = {version}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn version_1_databases_are_migrated() {
+        let path = database("migrate-v1");
+        legacy_database(
+            &path,
+            0,
+            &[
+                "CREATE TABLE pending_aggregates (batch INTEGER NOT NULL, time INTEGER NOT NULL, \
+                 source TEXT NOT NULL, target TEXT NOT NULL, src_vlan INTEGER NOT NULL, dst_vlan \
+                 INTEGER NOT NULL, proto INTEGER NOT NULL, packets INTEGER NOT NULL, bytes \
+                 INTEGER NOT NULL)"
+                    .to_owned(),
+                "INSERT INTO pending_aggregates VALUES (1, 60, '10.0.0.1', 'outside', 0, 0, 6, 1, \
+                 100), (2, 60, '10.0.0.1', 'outside', 0, 0, 6, 2, 200), (2, 120, '10.0.0.1', \
+                 'outside', 0, 0, 6, 1, 50)"
+                    .to_owned(),
+            ],
+        )
+        .await;
+
+        let recovery = Recovery::open(&path).await.unwrap();
+        let first_batch = || {
+            RecoveredBatch {
+                number: 1,
+                batch_id: None,
+                entries: vec![(flow_key(60), counts(1, 100))],
+            }
+        };
+        assert_eq!(
+            sorted_residual(&recovery).await,
+            [
+                first_batch(),
+                RecoveredBatch {
+                    number: 2,
+                    batch_id: None,
+                    entries: vec![
+                        (flow_key(60), counts(2, 200)),
+                        (flow_key(120), counts(1, 50))
+                    ],
+                },
+            ]
+        );
+        // The batch numbers survive, so a batch is still forgotten as a whole.
+        recovery.forget(2).await.unwrap();
+        assert_eq!(recovery.residual().await.unwrap(), [first_batch()]);
+        drop(recovery);
+
+        // Opening a migrated database again keeps it.
+        let recovery = Recovery::open(&path).await.unwrap();
+        assert_eq!(recovery.residual().await.unwrap().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn version_2_databases_are_migrated() {
+        let path = database("migrate-v2");
+        let aggregate = state::encode_entry(&flow_key(60), &counts(1, 100)).unwrap();
+        let hex: String = aggregate.iter().map(|byte| format!("{byte:02x}")).collect();
+        legacy_database(
+            &path,
+            2,
+            &[
+                "CREATE TABLE pending_aggregates (batch INTEGER NOT NULL, aggregate BLOB NOT NULL)"
+                    .to_owned(),
+                format!("INSERT INTO pending_aggregates VALUES (4, x'{hex}')"),
+            ],
+        )
+        .await;
+
+        let recovery = Recovery::open(&path).await.unwrap();
+        assert_eq!(
+            recovery.residual().await.unwrap(),
+            [RecoveredBatch {
+                number: 4,
+                batch_id: None,
+                entries: vec![(flow_key(60), counts(1, 100))],
+            }]
+        );
+        // New batches are numbered after the migrated ones.
+        assert_eq!(recovery.store(&[], Some("8")).await.unwrap(), 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
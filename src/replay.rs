@@ -0,0 +1,125 @@
+use std::{
+    fs,
+    io::{BufWriter, ErrorKind, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use prost::Message;
+use tokio::io::{AsyncReadExt, BufReader};
+
+use crate::{config::ReplaySpeed, flowprotob::FlowMessage};
+
+/// Reads the frames of a recording, optionally paced like the original traffic.
+///
+/// A recording is a sequence of frames, each being the payload length as a big-endian `u32`
+/// followed by the payload as consumed from Kafka.
+pub struct Replay {
+    reader: BufReader<tokio::fs::File>,
+    speed: ReplaySpeed,
+    /// `time_received` of the first replayed flow and when it was replayed.
+    started: Option<(u64, Instant)>,
+}
+
+impl Replay {
+    pub async fn open(path: &Path, speed: ReplaySpeed) -> anyhow::Result<Self> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("Unable to open replay file `{}`.", path.display()))?;
+
+        Ok(Self {
+            reader: BufReader::new(file),
+            speed,
+            started: None,
+        })
+    }
+
+    /// Next recorded payload, `None` once the file is exhausted.
+    pub async fn next_payload(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let length = match self.reader.read_u32().await {
+            Ok(length) => length,
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let mut payload = vec![0; usize::try_from(length)?];
+        self.reader
+            .read_exact(&mut payload)
+            .await
+            .context("Replay file ends in the middle of a message.")?;
+
+        if self.speed == ReplaySpeed::Realtime {
+            self.pace(&payload).await;
+        }
+
+        Ok(Some(payload))
+    }
+
+    /// Sleeps until the flow is due according to the `time_received` deltas.
+    async fn pace(&mut self, payload: &[u8]) {
+        let Ok(message) = FlowMessage::decode(payload) else {
+            return;
+        };
+        let (first_received, started_at) = *self
+            .started
+            .get_or_insert((message.time_received, Instant::now()));
+        let due = Duration::from_secs(message.time_received.saturating_sub(first_received));
+        if let Some(wait) = due.checked_sub(started_at.elapsed()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Appends received payloads to a recording until it reaches its size limit.
+pub struct Recorder {
+    writer: BufWriter<fs::File>,
+    written_bytes: u64,
+    max_bytes: u64,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    pub fn open(path: &Path, max_bytes: u64) -> anyhow::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Unable to open record file `{}`.", path.display()))?;
+        let written_bytes = file.metadata()?.len();
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            written_bytes,
+            max_bytes,
+            last_flush: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let frame_bytes = std::mem::size_of::<u32>() as u64 + payload.len() as u64;
+        if self.written_bytes + frame_bytes > self.max_bytes {
+            if self.written_bytes < self.max_bytes {
+                tracing::warn!(
+                    max_bytes = self.max_bytes,
+                    "Record file is full, further messages are not recorded."
+                );
+                // Warn only once.
+                self.written_bytes = self.max_bytes;
+                self.writer.flush()?;
+            }
+            return Ok(());
+        }
+
+        self.writer
+            .write_all(&u32::try_from(payload.len())?.to_be_bytes())?;
+        self.writer.write_all(payload)?;
+        self.written_bytes += frame_bytes;
+
+        if self.last_flush.elapsed() >= Duration::from_secs(1) {
+            self.writer.flush()?;
+            self.last_flush = Instant::now();
+        }
+
+        Ok(())
+    }
+}
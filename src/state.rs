@@ -0,0 +1,321 @@
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    flush::Sink,
+    geoip::GeoInfo,
+    util::{AggregatedKey, CommunicationData, IcmpDetail, Location, TcpFlags},
+};
+
+/// Bumped on every change of the format, files of other versions are refused.
+pub const STATE_VERSION: u32 = 2;
+
+/// Consumer position of a partition when the state was saved.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionOffset {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// Cache that could not be flushed at shutdown, restored by the next start.
+pub struct State {
+    pub entries: Vec<(AggregatedKey, CommunicationData)>,
+    pub offsets: Vec<PartitionOffset>,
+    pub pending: Option<SavedBatch>,
+}
+
+/// Batch whose final flush failed. After the restart only the sinks missing in `written_to` get
+/// it, under the same id.
+#[derive(Debug, PartialEq)]
+pub struct SavedBatch {
+    pub entries: Vec<(AggregatedKey, CommunicationData)>,
+    pub batch_id: Option<String>,
+    pub written_to: Vec<Sink>,
+}
+
+#[derive(Deserialize)]
+struct VersionOnly {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    saved_at: String,
+    offsets: Vec<PartitionOffset>,
+    entries: Vec<StateEntry>,
+    pending: Option<SavedBatchFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedBatchFile {
+    batch_id: Option<String>,
+    written_to: Vec<Sink>,
+    entries: Vec<StateEntry>,
+}
+
+/// Every dimension and accumulator of a cache entry, independent of the output formats.
+#[derive(Serialize, Deserialize)]
+struct StateEntry {
+    time: u64,
+    source: String,
+    target: String,
+    src_vlan: u32,
+    dst_vlan: u32,
+    proto: u32,
+    in_if: u32,
+    out_if: u32,
+    mpls_label: Option<u32>,
+    exporter: Option<IpAddr>,
+    tcp_flags: Option<u8>,
+    dscp: Option<u8>,
+    icmp: Option<(u8, u8)>,
+    ip_version: Option<u8>,
+    src_geo: Option<(Option<String>, Option<u32>)>,
+    dst_geo: Option<(Option<String>, Option<u32>)>,
+    src_group: Option<Arc<str>>,
+    dst_group: Option<Arc<str>>,
+    app: Option<Arc<str>>,
+    datacenter: Option<Arc<str>>,
+    measurement: Option<Arc<str>>,
+    packets: u64,
+    bytes: u64,
+    packets_fwd: u64,
+    packets_rev: u64,
+    bytes_fwd: u64,
+    bytes_rev: u64,
+    first_observed: Option<SystemTime>,
+    last_observed: Option<SystemTime>,
+    count: u64,
+    m2: f64,
+}
+
+impl StateEntry {
+    fn new(key: &AggregatedKey, data: &CommunicationData) -> Self {
+        let geo = |geo: &Option<GeoInfo>| geo.as_ref().map(|geo| (geo.country.clone(), geo.asn));
+        Self {
+            time: key.time,
+            source: key.source.to_string(),
+            target: key.target.to_string(),
+            src_vlan: key.src_vlan,
+            dst_vlan: key.dst_vlan,
+            proto: key.proto,
+            in_if: key.in_if,
+            out_if: key.out_if,
+            mpls_label: key.mpls_label,
+            exporter: key.exporter,
+            tcp_flags: key.tcp_flags.map(TcpFlags::bits),
+            dscp: key.dscp,
+            icmp: key.icmp.map(|icmp| (icmp.icmp_type, icmp.code)),
+            ip_version: key.ip_version,
+            src_geo: geo(&key.src_geo),
+            dst_geo: geo(&key.dst_geo),
+            src_group: key.src_group.clone(),
+            dst_group: key.dst_group.clone(),
+            app: key.app.clone(),
+            datacenter: key.datacenter.clone(),
+            measurement: key.measurement.clone(),
+            packets: data.packets,
+            bytes: data.bytes,
+            packets_fwd: data.packets_fwd,
+            packets_rev: data.packets_rev,
+            bytes_fwd: data.bytes_fwd,
+            bytes_rev: data.bytes_rev,
+            first_observed: data.first_observed,
+            last_observed: data.last_observed,
+            count: data.count,
+            m2: data.m2,
+        }
+    }
+
+    fn into_entry(self) -> anyhow::Result<(AggregatedKey, CommunicationData)> {
+        let geo = |geo: Option<(Option<String>, Option<u32>)>| {
+            geo.map(|(country, asn)| GeoInfo { country, asn })
+        };
+        let key = AggregatedKey {
+            time: self.time,
+            source: self
+                .source
+                .parse::<Location>()
+                .with_context(|| format!("Invalid source `{}`.", self.source))?,
+            target: self
+                .target
+                .parse::<Location>()
+                .with_context(|| format!("Invalid target `{}`.", self.target))?,
+            src_vlan: self.src_vlan,
+            dst_vlan: self.dst_vlan,
+            proto: self.proto,
+            in_if: self.in_if,
+            out_if: self.out_if,
+            mpls_label: self.mpls_label,
+            exporter: self.exporter,
+            tcp_flags: self
+                .tcp_flags
+                .map(|bits| TcpFlags::from_bits(u32::from(bits))),
+            dscp: self.dscp,
+            icmp: self
+                .icmp
+                .map(|(icmp_type, code)| IcmpDetail { icmp_type, code }),
+            ip_version: self.ip_version,
+            src_geo: geo(self.src_geo),
+            dst_geo: geo(self.dst_geo),
+            src_group: self.src_group,
+            dst_group: self.dst_group,
+            app: self.app,
+            datacenter: self.datacenter,
+            measurement: self.measurement,
+        };
+        let data = CommunicationData {
+            packets: self.packets,
+            bytes: self.bytes,
+            packets_fwd: self.packets_fwd,
+            packets_rev: self.packets_rev,
+            bytes_fwd: self.bytes_fwd,
+            bytes_rev: self.bytes_rev,
+            first_observed: self.first_observed,
+            last_observed: self.last_observed,
+            count: self.count,
+            m2: self.m2,
+        };
+
+        Ok((key, data))
+    }
+}
+
+/// Every dimension and accumulator of the entry as JSON, the encoding of the recovery database.
+pub(crate) fn encode_entry(
+    key: &AggregatedKey,
+    data: &CommunicationData,
+) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&StateEntry::new(key, data))?)
+}
+
+/// Inverse of [`encode_entry`].
+pub(crate) fn decode_entry(bytes: &[u8]) -> anyhow::Result<(AggregatedKey, CommunicationData)> {
+    serde_json::from_slice::<StateEntry>(bytes)?.into_entry()
+}
+
+/// Writes the entries, the pending batch and the offsets into `path`, under a temporary name
+/// renamed once complete so a crash while saving leaves no truncated state behind.
+pub fn save(
+    path: &Path,
+    entries: &[(AggregatedKey, CommunicationData)],
+    pending: Option<&SavedBatch>,
+    offsets: Vec<PartitionOffset>,
+) -> anyhow::Result<()> {
+    let state_entries = |entries: &[(AggregatedKey, CommunicationData)]| -> Vec<StateEntry> {
+        entries
+            .iter()
+            .map(|(key, data)| StateEntry::new(key, data))
+            .collect()
+    };
+    let file = StateFile {
+        version: STATE_VERSION,
+        saved_at: chrono::Utc::now().to_rfc3339(),
+        offsets,
+        entries: state_entries(entries),
+        pending: pending.map(|pending| {
+            SavedBatchFile {
+                batch_id: pending.batch_id.clone(),
+                written_to: pending.written_to.clone(),
+                entries: state_entries(&pending.entries),
+            }
+        }),
+    };
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+
+    let mut writer = BufWriter::new(
+        fs::File::create(&temporary_path)
+            .with_context(|| format!("Unable to create `{}`.", path.display()))?,
+    );
+    serde_json::to_writer(&mut writer, &file)?;
+    writer.flush()?;
+    writer
+        .into_inner()
+        .map_err(|error| error.into_error())?
+        .sync_all()?;
+    fs::rename(&temporary_path, path)?;
+
+    Ok(())
+}
+
+/// State saved by the previous run, `None` if there is no state file.
+pub fn load(path: &Path) -> anyhow::Result<Option<State>> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Unable to read `{}`.", path.display()));
+        },
+    };
+
+    // Checked first, so an incompatible file is named as such instead of as a parse error.
+    let VersionOnly { version } = serde_json::from_slice(&content)
+        .with_context(|| format!("`{}` is not a state file.", path.display()))?;
+    if version != STATE_VERSION {
+        anyhow::bail!(
+            "State file `{}` has version {version}, this build only reads version \
+             {STATE_VERSION}. Flush it with the version that wrote it or delete it.",
+            path.display()
+        );
+    }
+    let file: StateFile = serde_json::from_slice(&content)
+        .with_context(|| format!("Invalid state file `{}`.", path.display()))?;
+    let entries = |entries: Vec<StateEntry>| -> anyhow::Result<Vec<_>> {
+        entries
+            .into_iter()
+            .map(StateEntry::into_entry)
+            .collect::<anyhow::Result<_>>()
+            .with_context(|| format!("Invalid state file `{}`.", path.display()))
+    };
+
+    Ok(Some(State {
+        entries: entries(file.entries)?,
+        offsets: file.offsets,
+        pending: file
+            .pending
+            .map(|pending| {
+                anyhow::Ok(SavedBatch {
+                    entries: entries(pending.entries)?,
+                    batch_id: pending.batch_id,
+                    written_to: pending.written_to,
+                })
+            })
+            .transpose()?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::influx::tests::key;
+
+    #[test]
+    fn pending_batches_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("lpa-state-pending-{}.json", std::process::id()));
+        let pending = SavedBatch {
+            entries: vec![(key(), CommunicationData::default())],
+            batch_id: Some("42".to_owned()),
+            written_to: vec![Sink::KafkaOutput, Sink::Postgres],
+        };
+
+        save(&path, &[], Some(&pending), Vec::new()).unwrap();
+        let state = load(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(state.entries.is_empty());
+        assert_eq!(state.pending, Some(pending));
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::Instant,
+};
+
+/// Fixed capacity circular buffer, pushing into a full buffer overwrites the oldest element.
+#[derive(Debug, Clone)]
+pub struct RingBuffer<T> {
+    elements: Vec<T>,
+    capacity: usize,
+    /// Index of the oldest element once the buffer is full.
+    start: usize,
+}
+
+impl<T> RingBuffer<T> {
+    #[must_use]
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        let capacity = capacity.get();
+        Self {
+            elements: Vec::with_capacity(capacity),
+            capacity,
+            start: 0,
+        }
+    }
+
+    pub fn push(&mut self, element: T) {
+        if self.elements.len() < self.capacity {
+            self.elements.push(element);
+        } else {
+            if let Some(oldest) = self.elements.get_mut(self.start) {
+                *oldest = element;
+            }
+            self.start = (self.start + 1) % self.capacity;
+        }
+    }
+
+    /// Iterates from the oldest to the newest element.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        let (newer, older) = self.elements.split_at(self.start);
+        older.iter().chain(newer.iter())
+    }
+
+    /// Iterates over at most `n` newest elements, newest first.
+    pub fn latest(&self, n: usize) -> impl Iterator<Item = &T> {
+        self.iter().rev().take(n)
+    }
+}
+
+/// Samples per minute, one sample is pushed every second.
+const SAMPLES_PER_MINUTE: usize = 60;
+
+/// History of bytes transferred per second, shared between the stats reporter and the metrics
+/// endpoint.
+#[derive(Debug)]
+pub struct ThroughputHistory {
+    samples: Mutex<RingBuffer<(Instant, u64)>>,
+}
+
+impl ThroughputHistory {
+    #[must_use]
+    pub fn new(history_minutes: usize) -> Self {
+        Self {
+            samples: Mutex::new(RingBuffer::new(
+                NonZeroUsize::new(history_minutes.max(1) * SAMPLES_PER_MINUTE)
+                    .unwrap_or(NonZeroUsize::MIN),
+            )),
+        }
+    }
+
+    pub fn record_second(&self, bytes: u64) {
+        if let Ok(mut samples) = self.samples.lock() {
+            samples.push((Instant::now(), bytes));
+        }
+    }
+
+    /// Average of the per-second samples of the last minute.
+    #[must_use]
+    pub fn bytes_per_minute_avg(&self) -> u64 {
+        let Ok(samples) = self.samples.lock() else {
+            return 0;
+        };
+        let (count, sum) = samples
+            .latest(SAMPLES_PER_MINUTE)
+            .fold((0, 0), |(count, sum), (_, bytes)| (count + 1, sum + bytes));
+        if count == 0 {
+            0
+        } else {
+            sum / count
+        }
+    }
+
+    /// Largest per-second sample of the last minute.
+    #[must_use]
+    pub fn bytes_per_minute_peak(&self) -> u64 {
+        let Ok(samples) = self.samples.lock() else {
+            return 0;
+        };
+        samples
+            .latest(SAMPLES_PER_MINUTE)
+            .map(|(_, bytes)| *bytes)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Exponential moving average of the bytes transferred per second.
+///
+/// Messages are counted with [`ThroughputTracker::record`] and folded into the average once per
+/// second by [`ThroughputTracker::tick`]. Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct ThroughputTracker {
+    alpha: f64,
+    /// Bytes recorded since the last tick.
+    pending: Arc<AtomicU64>,
+    /// Bits of the current average, NaN until the first tick.
+    ema: Arc<AtomicU64>,
+}
+
+impl ThroughputTracker {
+    /// `alpha` is the weight of the newest second, between 0 (exclusive) and 1.
+    #[must_use]
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha,
+            pending: Arc::new(AtomicU64::new(0)),
+            ema: Arc::new(AtomicU64::new(f64::NAN.to_bits())),
+        }
+    }
+
+    pub fn record(&self, bytes: u64) {
+        self.pending.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Folds the bytes recorded since the last call into the average as one second and returns
+    /// them.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn tick(&self) -> u64 {
+        let bytes = self.pending.swap(0, Ordering::AcqRel);
+        let previous = f64::from_bits(self.ema.load(Ordering::Relaxed));
+        let ema = if previous.is_nan() {
+            bytes as f64
+        } else {
+            self.alpha.mul_add(bytes as f64 - previous, previous)
+        };
+        self.ema.store(ema.to_bits(), Ordering::Relaxed);
+        bytes
+    }
+
+    #[must_use]
+    pub fn ema_bytes_per_second(&self) -> f64 {
+        let ema = f64::from_bits(self.ema.load(Ordering::Relaxed));
+        if ema.is_nan() {
+            0.0
+        } else {
+            ema
+        }
+    }
+}
+
+/// Bytes transferred per configured topic, counted like [`ThroughputTracker`].
+///
+/// The topics are fixed at startup, so recording takes no lock. Messages of other topics are
+/// only counted in the total.
+#[derive(Debug)]
+pub struct TopicThroughput {
+    topics: BTreeMap<String, TopicBytes>,
+}
+
+#[derive(Debug, Default)]
+struct TopicBytes {
+    /// Bytes recorded since the last tick.
+    pending: AtomicU64,
+    /// Bytes of the last completed second.
+    last_second: AtomicU64,
+}
+
+impl TopicThroughput {
+    #[must_use]
+    pub fn new(topics: &[String]) -> Self {
+        Self {
+            topics: topics
+                .iter()
+                .map(|topic| (topic.clone(), TopicBytes::default()))
+                .collect(),
+        }
+    }
+
+    pub fn record(&self, topic: &str, bytes: u64) {
+        if let Some(counters) = self.topics.get(topic) {
+            counters.pending.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Completes the current second and returns the bytes of every topic within it.
+    pub fn tick(&self) -> BTreeMap<&str, u64> {
+        self.topics
+            .iter()
+            .map(|(topic, counters)| {
+                let bytes = counters.pending.swap(0, Ordering::AcqRel);
+                counters.last_second.store(bytes, Ordering::Relaxed);
+                (topic.as_str(), bytes)
+            })
+            .collect()
+    }
+
+    /// Bytes of `topic` in the last completed second.
+    #[must_use]
+    pub fn last_second(&self, topic: &str) -> u64 {
+        self.topics
+            .get(topic)
+            .map_or(0, |counters| counters.last_second.load(Ordering::Relaxed))
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &str> {
+        self.topics.keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(buffer: &RingBuffer<u32>) -> Vec<u32> {
+        buffer.iter().copied().collect()
+    }
+
+    #[test]
+    fn capacity_of_one_keeps_the_newest() {
+        let mut buffer = RingBuffer::new(NonZeroUsize::new(1).unwrap());
+        assert!(contents(&buffer).is_empty());
+
+        buffer.push(1);
+        buffer.push(2);
+        assert_eq!(contents(&buffer), [2]);
+        assert_eq!(buffer.latest(5).copied().collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn iterates_from_oldest_before_wrapping() {
+        let mut buffer = RingBuffer::new(NonZeroUsize::new(4).unwrap());
+        buffer.push(1);
+        buffer.push(2);
+        buffer.push(3);
+
+        assert_eq!(contents(&buffer), [1, 2, 3]);
+        assert_eq!(buffer.latest(2).copied().collect::<Vec<_>>(), [3, 2]);
+    }
+
+    #[test]
+    fn wraparound_overwrites_the_oldest() {
+        let mut buffer = RingBuffer::new(NonZeroUsize::new(3).unwrap());
+        for element in 1..=7 {
+            buffer.push(element);
+        }
+
+        assert_eq!(contents(&buffer), [5, 6, 7]);
+        assert_eq!(buffer.latest(2).copied().collect::<Vec<_>>(), [7, 6]);
+        assert_eq!(buffer.latest(10).count(), 3);
+
+        // A full revolution ends where it started.
+        for element in 8..=10 {
+            buffer.push(element);
+        }
+        assert_eq!(contents(&buffer), [8, 9, 10]);
+    }
+
+    #[test]
+    fn ema_is_zero_before_the_first_tick() {
+        let tracker = ThroughputTracker::new(0.1);
+        tracker.record(500);
+
+        assert!(tracker.ema_bytes_per_second().abs() < f64::EPSILON);
+        assert_eq!(tracker.tick(), 500);
+        assert!((tracker.ema_bytes_per_second() - 500.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn ema_converges_to_the_rate() {
+        let tracker = ThroughputTracker::new(0.1);
+        // Idle first second, then 29 seconds alternating around 1000 bytes per second.
+        tracker.tick();
+        for second in 1..30 {
+            let bytes = if second % 2 == 0 { 900 } else { 1100 };
+            // Several messages per second, recorded through a clone like the consume loop.
+            let clone = tracker.clone();
+            clone.record(bytes / 2);
+            clone.record(bytes - bytes / 2);
+            assert_eq!(tracker.tick(), bytes);
+        }
+
+        let ema = tracker.ema_bytes_per_second();
+        assert!((ema - 1000.0).abs() < 50.0, "{ema}");
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{BuildHasher, BuildHasherDefault},
+};
+
+use crate::util::{self, AggregatedKey, CommunicationData};
+
+pub type Entries = Vec<(AggregatedKey, CommunicationData)>;
+
+/// Aggregates owned by one aggregation worker.
+///
+/// Only the operations of the worker are required, so the table behind them can be replaced
+/// without touching the consume loop.
+pub trait AggregationStore: Send + 'static {
+    /// Aggregate of `key`, inserted empty if it is not stored yet.
+    fn upsert(&mut self, key: AggregatedKey) -> &mut CommunicationData;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate heap size, cheap enough to be checked per message.
+    fn estimated_memory(&self) -> usize;
+
+    /// Takes the entries whose bucket of `seconds_alignment` is closed at `watermark`.
+    fn drain_closed(&mut self, watermark: u64, seconds_alignment: u64) -> Entries;
+
+    /// Takes every entry, `shrink` also releases the memory of the table.
+    fn drain_all(&mut self, shrink: bool) -> Entries;
+
+    /// Tables drained one at a time by [`Self::drain_shard`] and [`Self::drain_closed_shard`].
+    fn shards(&self) -> usize {
+        1
+    }
+
+    /// [`Self::drain_all`] of the table `shard`, nothing if it does not exist.
+    fn drain_shard(&mut self, shard: usize, shrink: bool) -> Entries {
+        if shard == 0 {
+            self.drain_all(shrink)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// [`Self::drain_closed`] of the table `shard`, nothing if it does not exist.
+    fn drain_closed_shard(
+        &mut self,
+        shard: usize,
+        watermark: u64,
+        seconds_alignment: u64,
+    ) -> Entries {
+        if shard == 0 {
+            self.drain_closed(watermark, seconds_alignment)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Copies every entry, the store keeps them.
+    fn entries(&self) -> Entries;
+
+    /// Oldest and newest bucket stored.
+    fn bucket_range(&self) -> (Option<u64>, Option<u64>);
+}
+
+impl AggregationStore for HashMap<AggregatedKey, CommunicationData> {
+    fn upsert(&mut self, key: AggregatedKey) -> &mut CommunicationData {
+        self.entry(key).or_default()
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn estimated_memory(&self) -> usize {
+        util::estimated_cache_memory(self)
+    }
+
+    fn drain_closed(&mut self, watermark: u64, seconds_alignment: u64) -> Entries {
+        let mut closed = Vec::new();
+        self.retain(|key, value| {
+            let is_closed = key.bucket_end(seconds_alignment) <= watermark;
+            if is_closed {
+                closed.push((key.clone(), value.clone()));
+            }
+            !is_closed
+        });
+        closed
+    }
+
+    fn drain_all(&mut self, shrink: bool) -> Entries {
+        let drained = self.drain().collect();
+        if shrink {
+            self.shrink_to_fit();
+        }
+        drained
+    }
+
+    fn entries(&self) -> Entries {
+        self.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn bucket_range(&self) -> (Option<u64>, Option<u64>) {
+        (
+            self.keys().map(|key| key.time).min(),
+            self.keys().map(|key| key.time).max(),
+        )
+    }
+}
+
+/// Tables selected by the hash of the key, `--aggregation-store sharded`.
+///
+/// Every table grows and is drained on its own, so a rehash or a drain of a large cache is
+/// split into steps of one shard instead of stalling the worker on a single table.
+pub struct ShardedStore {
+    shards: Vec<HashMap<AggregatedKey, CommunicationData>>,
+    hasher: BuildHasherDefault<DefaultHasher>,
+}
+
+impl ShardedStore {
+    /// `shards` tables, at least one.
+    #[must_use]
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| HashMap::new()).collect(),
+            hasher: BuildHasherDefault::default(),
+        }
+    }
+
+    fn shard_mut(&mut self, key: &AggregatedKey) -> &mut HashMap<AggregatedKey, CommunicationData> {
+        // The workers route by the same hash modulo their count, so the keys of one worker agree
+        // on it. The high bits are still spread and pick the shard.
+        let hash = self.hasher.hash_one(key) >> 32;
+        let shard = usize::try_from(hash).unwrap_or(0) % self.shards.len();
+        // In range, `new` keeps at least one shard.
+        #[allow(clippy::indexing_slicing)]
+        &mut self.shards[shard]
+    }
+}
+
+impl AggregationStore for ShardedStore {
+    fn upsert(&mut self, key: AggregatedKey) -> &mut CommunicationData {
+        self.shard_mut(&key).upsert(key)
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(HashMap::len).sum()
+    }
+
+    fn estimated_memory(&self) -> usize {
+        self.shards.iter().map(util::estimated_cache_memory).sum()
+    }
+
+    fn drain_closed(&mut self, watermark: u64, seconds_alignment: u64) -> Entries {
+        self.shards
+            .iter_mut()
+            .flat_map(|shard| shard.drain_closed(watermark, seconds_alignment))
+            .collect()
+    }
+
+    fn drain_all(&mut self, shrink: bool) -> Entries {
+        self.shards
+            .iter_mut()
+            .flat_map(|shard| shard.drain_all(shrink))
+            .collect()
+    }
+
+    fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn drain_shard(&mut self, shard: usize, shrink: bool) -> Entries {
+        self.shards
+            .get_mut(shard)
+            .map_or_else(Vec::new, |shard| shard.drain_all(shrink))
+    }
+
+    fn drain_closed_shard(
+        &mut self,
+        shard: usize,
+        watermark: u64,
+        seconds_alignment: u64,
+    ) -> Entries {
+        self.shards.get_mut(shard).map_or_else(Vec::new, |shard| {
+            shard.drain_closed(watermark, seconds_alignment)
+        })
+    }
+
+    fn entries(&self) -> Entries {
+        self.shards
+            .iter()
+            .flat_map(AggregationStore::entries)
+            .collect()
+    }
+
+    fn bucket_range(&self) -> (Option<u64>, Option<u64>) {
+        self.shards.iter().map(AggregationStore::bucket_range).fold(
+            (None, None),
+            |(oldest, newest), (shard_oldest, shard_newest)| {
+                let oldest = match (oldest, shard_oldest) {
+                    (Some(oldest), Some(shard_oldest)) => Some(oldest.min(shard_oldest)),
+                    (oldest, shard_oldest) => oldest.or(shard_oldest),
+                };
+                // `None` orders before any bucket.
+                (oldest, newest.max(shard_newest))
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::influx::tests::key;
+
+    /// Aggregates of a few buckets and VLANs, so keys repeat and spread over the shards.
+    fn records() -> impl Strategy<Value = Vec<(AggregatedKey, u64)>> {
+        proptest::collection::vec(
+            (0..10_u64, 0..8_u32, 1..1500_u64).prop_map(|(bucket, src_vlan, bytes)| {
+                let key = AggregatedKey {
+                    time: bucket * 60,
+                    src_vlan,
+                    ..key()
+                };
+                (key, bytes)
+            }),
+            0..200,
+        )
+    }
+
+    fn stores(
+        records: &[(AggregatedKey, u64)],
+        shards: usize,
+    ) -> (HashMap<AggregatedKey, CommunicationData>, ShardedStore) {
+        let mut table = HashMap::new();
+        let mut sharded = ShardedStore::new(shards);
+        for (key, bytes) in records {
+            table.upsert(key.clone()).record(1, *bytes, false);
+            sharded.upsert(key.clone()).record(1, *bytes, false);
+        }
+        (table, sharded)
+    }
+
+    fn sorted(mut entries: Entries) -> Entries {
+        entries.sort_by(|(left, _), (right, _)| left.cmp(right));
+        entries
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn sharded_store_matches_the_hash_map(
+            records in records(),
+            shards in 1..6_usize,
+            watermark in 0..720_u64,
+        ) {
+            let (mut table, mut sharded) = stores(&records, shards);
+            prop_assert_eq!(sharded.len(), AggregationStore::len(&table));
+            prop_assert_eq!(sharded.bucket_range(), table.bucket_range());
+            prop_assert_eq!(sorted(sharded.entries()), sorted(table.entries()));
+            prop_assert_eq!(
+                sorted(sharded.drain_closed(watermark, 60)),
+                sorted(table.drain_closed(watermark, 60))
+            );
+            prop_assert_eq!(sorted(sharded.drain_all(false)), sorted(table.drain_all(false)));
+            prop_assert!(sharded.is_empty());
+        }
+
+        #[test]
+        fn shards_drain_the_whole_store(
+            records in records(),
+            shards in 1..6_usize,
+            watermark in 0..720_u64,
+        ) {
+            let (mut table, mut sharded) = stores(&records, shards);
+            prop_assert_eq!(sharded.shards(), shards);
+            let closed = (0..sharded.shards())
+                .flat_map(|shard| sharded.drain_closed_shard(shard, watermark, 60))
+                .collect();
+            prop_assert_eq!(sorted(closed), sorted(table.drain_closed(watermark, 60)));
+            let drained = (0..sharded.shards())
+                .flat_map(|shard| sharded.drain_shard(shard, false))
+                .collect();
+            prop_assert_eq!(sorted(drained), sorted(table.drain_all(false)));
+            prop_assert!(sharded.is_empty());
+            prop_assert!(sharded.drain_shard(shards, false).is_empty());
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::{io, path::Path, sync::Arc};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UnixListener, UnixStream},
+    sync::broadcast,
+};
+
+use crate::{
+    kafka_output::OutputRecord,
+    util::{AggregatedKey, CommunicationData},
+};
+
+/// Flushes buffered per client, a client further behind misses the oldest ones.
+const CLIENT_BACKLOG: usize = 16;
+
+/// Streams every flushed batch as newline-delimited JSON to the clients of a Unix socket.
+pub struct FlushBroadcast {
+    sender: broadcast::Sender<Arc<Vec<u8>>>,
+}
+
+impl FlushBroadcast {
+    /// Binds the socket, replacing a stale one left behind by a previous run, and accepts clients
+    /// in the background.
+    pub fn listen(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+            _ => {},
+        }
+        let listener = UnixListener::bind(path)?;
+        let (sender, _) = broadcast::channel(CLIENT_BACKLOG);
+
+        let accept_sender = sender.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tracing::info!("Unix socket client connected.");
+                        tokio::spawn(serve_client(stream, accept_sender.subscribe()));
+                    },
+                    Err(error) => tracing::error!(%error, "Unable to accept a Unix socket client."),
+                }
+            }
+        });
+
+        Ok(Self { sender })
+    }
+
+    /// Sends the batch to every connected client. Nothing is serialized without clients.
+    pub fn publish(&self, batch: &[(AggregatedKey, CommunicationData)]) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+
+        let mut lines = Vec::new();
+        for (key, data) in batch {
+            if let Err(error) = serde_json::to_writer(&mut lines, &OutputRecord { key, data }) {
+                tracing::error!(%error, %key, "Unable to serialize output record.");
+                continue;
+            }
+            lines.push(b'\n');
+        }
+        // Fails only when the last client disconnected in the meantime.
+        let _ = self.sender.send(Arc::new(lines));
+    }
+}
+
+async fn serve_client(mut stream: UnixStream, mut flushes: broadcast::Receiver<Arc<Vec<u8>>>) {
+    loop {
+        let lines = match flushes.recv().await {
+            Ok(lines) => lines,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                tracing::warn!(
+                    skipped,
+                    "Unix socket client is too slow, flushes were skipped."
+                );
+                continue;
+            },
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if let Err(error) = stream.write_all(&lines).await {
+            tracing::info!(%error, "Unix socket client disconnected.");
+            return;
+        }
+    }
+}
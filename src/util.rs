@@ -1,13 +1,127 @@
-use std::net::IpAddr;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt,
+    hash::{BuildHasher, BuildHasherDefault},
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::Range,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::SystemTime,
+};
 
-use anyhow::anyhow;
+use chrono::SecondsFormat;
 use cidr_utils::cidr::IpCidr;
+use ip_network::IpNetwork;
+use ip_network_table::IpNetworkTable;
 use serde::{Serialize, Serializer};
 
+use crate::{
+    config::{BucketTimestamp, PayloadCompression, RollupDimension},
+    flowprotob::FlowMessage,
+    geoip::GeoInfo,
+};
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
 pub enum Location {
     Inside(IpAddr),
     Outside,
+    /// Inside hosts beyond the `--max-unique-sources`/`--max-unique-targets` limit of a bucket.
+    Overflow,
+    /// Endpoint whose address could not be parsed, only with `--allow-partial-flows`.
+    Unknown,
+}
+
+impl Location {
+    /// Masks the inside address to `prefix_len` bits. `None` keeps the full host address.
+    #[must_use]
+    pub fn with_prefix_len(self, prefix_len: Option<u8>) -> Self {
+        match (self, prefix_len) {
+            (Location::Inside(ip), Some(prefix_len)) => Location::Inside(mask_ip(ip, prefix_len)),
+            (location, _) => location,
+        }
+    }
+}
+
+/// Distinct inside hosts of one bucket.
+#[derive(Debug, Default)]
+struct HostWindow {
+    hosts: HashSet<IpAddr>,
+    overflowed: bool,
+}
+
+/// Limits the distinct inside hosts per bucket, hosts beyond the limit become
+/// [`Location::Overflow`] while the already seen ones keep aggregating.
+#[derive(Debug)]
+pub struct HostLimiter {
+    limit: Option<usize>,
+    windows: HashMap<u64, HostWindow>,
+}
+
+impl HostLimiter {
+    #[must_use]
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Returns the location to aggregate under and whether this is the first overflow of the
+    /// bucket.
+    pub fn admit(&mut self, time: u64, location: Location) -> (Location, bool) {
+        let (Some(limit), Location::Inside(host)) = (self.limit, location) else {
+            return (location, false);
+        };
+        let window = self.windows.entry(time).or_default();
+        if window.hosts.contains(&host) || window.hosts.len() < limit {
+            window.hosts.insert(host);
+            return (location, false);
+        }
+
+        let first_overflow = !window.overflowed;
+        window.overflowed = true;
+        (Location::Overflow, first_overflow)
+    }
+
+    /// Forgets the buckets the cache no longer holds.
+    pub fn retain(&mut self, mut keep: impl FnMut(u64) -> bool) {
+        self.windows.retain(|time, _| keep(*time));
+    }
+
+    pub fn clear(&mut self) {
+        self.windows.clear();
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Inside(ip) => write!(f, "{ip}"),
+            Location::Outside => f.write_str("outside"),
+            Location::Overflow => f.write_str("overflow"),
+            Location::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+/// Inverse of the `Display` implementation.
+impl FromStr for Location {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "outside" => Ok(Location::Outside),
+            "overflow" => Ok(Location::Overflow),
+            "unknown" => Ok(Location::Unknown),
+            _ => value.parse().map(Location::Inside),
+        }
+    }
 }
 
 impl Serialize for Location {
@@ -15,10 +129,109 @@ impl Serialize for Location {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&match self {
-            Location::Inside(ip) => ip.to_string(),
-            Location::Outside => "outside".to_owned(),
-        })
+        serializer.collect_str(self)
+    }
+}
+
+/// Type and code of an ICMP or ICMPv6 flow.
+#[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
+pub struct IcmpDetail {
+    pub icmp_type: u8,
+    pub code: u8,
+}
+
+impl IcmpDetail {
+    /// Common name of the type, unknown types keep the number. ICMPv6 numbers the types
+    /// differently, so `proto` tells the versions apart.
+    #[must_use]
+    pub fn type_name(self, proto: u32) -> String {
+        let name = match (proto, self.icmp_type) {
+            (1, 0) | (58, 129) => "echo-reply",
+            (1, 3) | (58, 1) => "destination-unreachable",
+            (1, 5) => "redirect",
+            (1, 8) | (58, 128) => "echo-request",
+            (1, 11) | (58, 3) => "time-exceeded",
+            (1, 12) | (58, 4) => "parameter-problem",
+            (58, 2) => "packet-too-big",
+            (58, 133) => "router-solicitation",
+            (58, 134) => "router-advertisement",
+            (58, 135) => "neighbor-solicitation",
+            (58, 136) => "neighbor-advertisement",
+            _ => return self.icmp_type.to_string(),
+        };
+        name.to_owned()
+    }
+
+    /// Common name of the code within its type, unknown codes keep the number.
+    #[must_use]
+    pub fn code_name(self, proto: u32) -> String {
+        let name = match (proto, self.icmp_type, self.code) {
+            (1, 3, 0) => "net-unreachable",
+            (1, 3, 1) => "host-unreachable",
+            (1, 3, 2) => "protocol-unreachable",
+            (1, 3, 3) | (58, 1, 4) => "port-unreachable",
+            (1, 3, 4) => "fragmentation-needed",
+            (1, 3, 13) | (58, 1, 1) => "administratively-prohibited",
+            (58, 1, 0) => "no-route",
+            (58, 1, 3) => "address-unreachable",
+            (1, 11, 0) => "ttl-exceeded",
+            (58, 3, 0) => "hop-limit-exceeded",
+            (1, 11, 1) | (58, 3, 1) => "reassembly-time-exceeded",
+            _ => return self.code.to_string(),
+        };
+        name.to_owned()
+    }
+}
+
+/// Set of TCP flags seen on a flow. Only the eight flags of the TCP header are kept, so the same
+/// set always compares, hashes and prints the same way.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
+pub struct TcpFlags(u8);
+
+impl TcpFlags {
+    /// Flags in the order they are printed, paired with their bit in the TCP header.
+    const LETTERS: [(u8, char); 8] = [
+        (0x02, 'S'),
+        (0x01, 'F'),
+        (0x04, 'R'),
+        (0x08, 'P'),
+        (0x10, 'A'),
+        (0x20, 'U'),
+        (0x40, 'E'),
+        (0x80, 'C'),
+    ];
+
+    #[must_use]
+    pub fn from_bits(bits: u32) -> Self {
+        Self(u8::try_from(bits & 0xFF).unwrap_or_default())
+    }
+
+    #[must_use]
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for TcpFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("none");
+        }
+        for (bit, letter) in Self::LETTERS {
+            if self.0 & bit != 0 {
+                write!(f, "{letter}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for TcpFlags {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
     }
 }
 
@@ -30,53 +243,1658 @@ pub struct AggregatedKey {
     pub src_vlan: u32,
     pub dst_vlan: u32,
     pub proto: u32,
+    /// SNMP interface index, zero unless `--include-interfaces` is set.
+    pub in_if: u32,
+    pub out_if: u32,
+    /// Top MPLS label, `None` for non-MPLS traffic or unless `--track-mpls` is set.
+    pub mpls_label: Option<u32>,
+    /// Device that reported the flow when `--include-exporter` is set, `None` if it is disabled
+    /// or the exporter address was invalid.
+    pub exporter: Option<IpAddr>,
+    /// Cumulative TCP flags when `--include-tcp-flags` is set, `None` for non-TCP flows.
+    pub tcp_flags: Option<TcpFlags>,
+    /// DSCP code point taken from the upper six bits of the ToS byte, `None` unless
+    /// `--include-dscp` is set.
+    pub dscp: Option<u8>,
+    /// ICMP type and code when `--include-icmp-detail` is set, `None` for non-ICMP flows.
+    pub icmp: Option<IcmpDetail>,
+    /// 4 or 6 when `--include-ip-version` is set. Implied by the addresses, so it only splits
+    /// keys whose endpoints are both outside.
+    pub ip_version: Option<u8>,
+    /// Country and ASN of an outside source or target when `--geo-ip-database` is set.
+    pub src_geo: Option<GeoInfo>,
+    pub dst_geo: Option<GeoInfo>,
+    /// Group of the most specific `--cidr-groups` CIDR containing the source and target address.
+    pub src_group: Option<Arc<str>>,
+    pub dst_group: Option<Arc<str>>,
+    /// Application label of the `--classification-rules` or `--classify`.
+    pub app: Option<Arc<str>>,
+    /// Topic the flow was consumed from when it has a `--topic-alias`.
+    pub datacenter: Option<Arc<str>>,
+    /// Measurement of the topic the flow was consumed from when it is listed in
+    /// `--topic-measurement-map`, `None` writes into `--influxdb-measurement`.
+    pub measurement: Option<Arc<str>>,
 }
 
-#[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+impl fmt::Display for AggregatedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match chrono::DateTime::from_timestamp(self.time as i64, 0) {
+            Some(time) => write!(f, "{}", time.to_rfc3339_opts(SecondsFormat::Secs, true))?,
+            None => write!(f, "{}", self.time)?,
+        }
+        write!(
+            f,
+            " | {} -> {} | vlan:{}->{} | ",
+            self.source, self.target, self.src_vlan, self.dst_vlan
+        )?;
+        match proto_name(self.proto) {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{}", self.proto),
+        }
+    }
+}
+
+impl AggregatedKey {
+    /// First second of the bucket, the `time` of the key.
+    #[must_use]
+    pub fn bucket_start(&self) -> u64 {
+        self.time
+    }
+
+    /// First second after a bucket of `alignment` seconds, the start of the next bucket.
+    #[must_use]
+    pub fn bucket_end(&self, alignment: u64) -> u64 {
+        self.time + alignment
+    }
+
+    /// Seconds covered by a bucket of `alignment` seconds, adjacent buckets do not overlap.
+    #[must_use]
+    pub fn bucket_range(&self, alignment: u64) -> Range<u64> {
+        self.bucket_start()..self.bucket_end(alignment)
+    }
+
+    /// IP version of the key, taken from an inside endpoint without `--include-ip-version`.
+    #[must_use]
+    pub fn ip_version(&self) -> Option<u8> {
+        self.ip_version.or_else(|| {
+            [self.source, self.target].into_iter().find_map(|location| {
+                match location {
+                    Location::Inside(IpAddr::V4(_)) => Some(4),
+                    Location::Inside(IpAddr::V6(_)) => Some(6),
+                    _ => None,
+                }
+            })
+        })
+    }
+
+    /// Orders the endpoint pair so both directions of a conversation share one key. Returns
+    /// whether the flow goes from the canonical `target` to `source`.
+    #[must_use]
+    pub fn canonicalize(self) -> (Self, bool) {
+        if self.source <= self.target {
+            return (self, false);
+        }
+
+        let key = Self {
+            source: self.target,
+            target: self.source,
+            src_vlan: self.dst_vlan,
+            dst_vlan: self.src_vlan,
+            in_if: self.out_if,
+            out_if: self.in_if,
+            src_geo: self.dst_geo,
+            dst_geo: self.src_geo,
+            src_group: self.dst_group,
+            dst_group: self.src_group,
+            ..self
+        };
+        (key, true)
+    }
+
+    /// Key of the rollup bucket this key falls into, with the dimensions missing in `dimensions`
+    /// aggregated away.
+    #[must_use]
+    pub fn rollup(
+        &self,
+        alignment: u64,
+        dimensions: &[RollupDimension],
+        cidr_tree: &CidrTree,
+    ) -> Self {
+        let keep = |dimension: RollupDimension| dimensions.contains(&dimension);
+        let collapse = |location| {
+            match location {
+                Location::Inside(ip) if !keep(RollupDimension::Hosts) => {
+                    match cidr_tree.longest_match(ip) {
+                        Some(cidr) => Location::Inside(mask_ip(ip, cidr.get_bits())),
+                        None => location,
+                    }
+                },
+                location => location,
+            }
+        };
+        let (src_vlan, dst_vlan) = if keep(RollupDimension::Vlans) {
+            (self.src_vlan, self.dst_vlan)
+        } else {
+            (0, 0)
+        };
+        let (in_if, out_if) = if keep(RollupDimension::Interfaces) {
+            (self.in_if, self.out_if)
+        } else {
+            (0, 0)
+        };
+        let geo = keep(RollupDimension::Geo);
+
+        Self {
+            time: self.time.div_euclid(alignment) * alignment,
+            source: collapse(self.source),
+            target: collapse(self.target),
+            src_vlan,
+            dst_vlan,
+            proto: self.proto,
+            in_if,
+            out_if,
+            mpls_label: self.mpls_label.filter(|_| keep(RollupDimension::Mpls)),
+            exporter: self.exporter.filter(|_| keep(RollupDimension::Exporter)),
+            tcp_flags: self.tcp_flags.filter(|_| keep(RollupDimension::TcpFlags)),
+            dscp: self.dscp.filter(|_| keep(RollupDimension::Dscp)),
+            icmp: self.icmp.filter(|_| keep(RollupDimension::Icmp)),
+            ip_version: self.ip_version,
+            src_geo: self.src_geo.clone().filter(|_| geo),
+            dst_geo: self.dst_geo.clone().filter(|_| geo),
+            // Groups are coarser than any other dimension, so they are always kept.
+            src_group: self.src_group.clone(),
+            dst_group: self.dst_group.clone(),
+            app: self.app.clone(),
+            datacenter: self.datacenter.clone(),
+            // Rollups of all topics are written into `--rollup-measurement`.
+            measurement: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq, PartialOrd, Clone)]
 pub struct CommunicationData {
     pub packets: u64,
     pub bytes: u64,
+    /// Split of the totals by direction relative to the key, only meaningful with
+    /// `--bidirectional`.
+    pub packets_fwd: u64,
+    pub packets_rev: u64,
+    pub bytes_fwd: u64,
+    pub bytes_rev: u64,
+    /// Wall-clock time the key was first and last aggregated, only with
+    /// `--track-observation-time`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_observed: Option<SystemTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_observed: Option<SystemTime>,
+    /// Flows sampled into the running variance of their bytes, only with `--track-variance`.
+    #[serde(skip)]
+    pub count: u64,
+    /// Sum of squared differences from the mean of Welford's algorithm. The mean itself follows
+    /// from `bytes / count`.
+    #[serde(skip)]
+    pub m2: f64,
 }
 
-fn parse_ip(etype: u32, addr: &Vec<u8>) -> anyhow::Result<Option<IpAddr>> {
-    match etype {
-        0x0800 => {
-            let ipv4: [u8; 4] = addr.as_slice().try_into().map_err(|error| {
-                tracing::error!(addr = ?error, "Failed to create IPv4.");
-                anyhow!("Received invalid addr for given `etype`.")
-            })?;
-            Ok(Some(IpAddr::from(ipv4)))
+impl CommunicationData {
+    pub fn record(&mut self, packets: u64, bytes: u64, reversed: bool) {
+        self.packets += packets;
+        self.bytes += bytes;
+        if reversed {
+            self.packets_rev += packets;
+            self.bytes_rev += bytes;
+        } else {
+            self.packets_fwd += packets;
+            self.bytes_fwd += bytes;
+        }
+    }
+
+    /// Adds the bytes of one flow to the running variance, after the flow was recorded.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn sample_bytes(&mut self, bytes: u64) {
+        let sampled = bytes as f64;
+        // The recorded total already contains the new flow.
+        let previous_mean = if self.count == 0 {
+            sampled
+        } else {
+            self.bytes.saturating_sub(bytes) as f64 / self.count as f64
+        };
+        self.count += 1;
+        self.m2 += (sampled - previous_mean) * (sampled - self.mean_bytes());
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub fn merge(&mut self, other: &Self) {
+        // Chan's parallel update, computed from the means before the totals are merged.
+        if self.count > 0 && other.count > 0 {
+            let (count, other_count) = (self.count as f64, other.count as f64);
+            let delta = other.mean_bytes() - self.mean_bytes();
+            self.m2 += other.m2 + delta * delta * count * other_count / (count + other_count);
+        } else {
+            self.m2 += other.m2;
+        }
+        self.count += other.count;
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+        self.packets_fwd += other.packets_fwd;
+        self.packets_rev += other.packets_rev;
+        self.bytes_fwd += other.bytes_fwd;
+        self.bytes_rev += other.bytes_rev;
+        self.first_observed = match (self.first_observed, other.first_observed) {
+            (Some(first), Some(other)) => Some(first.min(other)),
+            (first, other) => first.or(other),
+        };
+        self.last_observed = self.last_observed.max(other.last_observed);
+    }
+
+    pub fn observe(&mut self, now: SystemTime) {
+        self.first_observed.get_or_insert(now);
+        self.last_observed = Some(now);
+    }
+
+    /// Population variance of the sampled flow sizes, `None` without samples.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn variance(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.m2 / self.count as f64)
+    }
+
+    #[must_use]
+    pub fn std_dev(&self) -> Option<f64> {
+        self.variance().map(f64::sqrt)
+    }
+
+    /// Mean of the sampled flow sizes, every sampled flow is also counted in `bytes`.
+    #[allow(clippy::cast_precision_loss)]
+    fn mean_bytes(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.bytes as f64 / self.count as f64
+    }
+}
+
+/// Direction of traffic relative to an inside host.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+/// Key of the per-host rollup written with `--host-rollup`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct HostKey {
+    pub time: u64,
+    pub host: IpAddr,
+    pub direction: Direction,
+}
+
+/// Sums a batch of edges into the traffic sent and received by each inside host. Flows between
+/// two inside hosts count for both of them.
+#[must_use]
+pub fn host_totals(
+    batch: &[(AggregatedKey, CommunicationData)],
+) -> HashMap<HostKey, CommunicationData> {
+    let mut totals: HashMap<HostKey, CommunicationData> = HashMap::new();
+    let mut add = |time: u64, location: Location, direction, packets: u64, bytes: u64| {
+        let Location::Inside(host) = location else {
+            return;
+        };
+        if packets > 0 || bytes > 0 {
+            totals
+                .entry(HostKey {
+                    time,
+                    host,
+                    direction,
+                })
+                .or_default()
+                .record(packets, bytes, false);
+        }
+    };
+    for (key, value) in batch {
+        // Without `--bidirectional` everything is counted as forward traffic.
+        add(
+            key.time,
+            key.source,
+            Direction::Out,
+            value.packets_fwd,
+            value.bytes_fwd,
+        );
+        add(
+            key.time,
+            key.source,
+            Direction::In,
+            value.packets_rev,
+            value.bytes_rev,
+        );
+        add(
+            key.time,
+            key.target,
+            Direction::In,
+            value.packets_fwd,
+            value.bytes_fwd,
+        );
+        add(
+            key.time,
+            key.target,
+            Direction::Out,
+            value.packets_rev,
+            value.bytes_rev,
+        );
+    }
+
+    totals
+}
+
+/// Distinct peers counted exactly before a sketch switches to HyperLogLog.
+const EXACT_PEERS: usize = 64;
+/// Bits of the hash selecting the register, 2^10 registers give a standard error of about 3.3%.
+const PEER_REGISTER_BITS: u32 = 10;
+
+/// Distinct peers of one inside host, exact up to [`EXACT_PEERS`] and a HyperLogLog of 1 KiB
+/// above, so a scanning host costs a bounded amount of memory.
+#[derive(Debug, Clone)]
+pub enum PeerSketch {
+    Exact(HashSet<IpAddr>),
+    HyperLogLog(Box<[u8]>),
+}
+
+impl Default for PeerSketch {
+    fn default() -> Self {
+        Self::Exact(HashSet::new())
+    }
+}
+
+impl PeerSketch {
+    pub fn insert(&mut self, peer: IpAddr) {
+        match self {
+            Self::Exact(peers) => {
+                peers.insert(peer);
+                if peers.len() > EXACT_PEERS {
+                    let mut registers = vec![0; 1 << PEER_REGISTER_BITS].into_boxed_slice();
+                    for peer in peers.iter() {
+                        Self::add_hash(&mut registers, *peer);
+                    }
+                    *self = Self::HyperLogLog(registers);
+                }
+            },
+            Self::HyperLogLog(registers) => Self::add_hash(registers, peer),
+        }
+    }
+
+    /// Number of distinct peers, an estimate once above [`EXACT_PEERS`].
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn count(&self) -> u64 {
+        let registers = match self {
+            Self::Exact(peers) => return peers.len() as u64,
+            Self::HyperLogLog(registers) => registers,
+        };
+        let m = registers.len() as f64;
+        let sum: f64 = registers
+            .iter()
+            .map(|register| 2f64.powi(-i32::from(*register)))
+            .sum();
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let mut estimate = alpha * m * m / sum;
+        let zeros = registers.iter().filter(|register| **register == 0).count();
+        // Linear counting is more precise while many registers are still empty.
+        if estimate <= 2.5 * m && zeros > 0 {
+            estimate = m * (m / zeros as f64).ln();
+        }
+
+        // The estimate is positive and far below `u64::MAX`.
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let count = estimate.round() as u64;
+        count
+    }
+
+    fn add_hash(registers: &mut [u8], peer: IpAddr) {
+        let hash = BuildHasherDefault::<DefaultHasher>::default().hash_one(peer);
+        let index = hash >> (u64::BITS - PEER_REGISTER_BITS);
+        // Position of the first set bit of the rest, the sentinel bit caps it.
+        let rank =
+            ((hash << PEER_REGISTER_BITS) | (1 << (PEER_REGISTER_BITS - 1))).leading_zeros() + 1;
+        if let Some(register) = usize::try_from(index)
+            .ok()
+            .and_then(|index| registers.get_mut(index))
+        {
+            *register = (*register).max(u8::try_from(rank).unwrap_or(u8::MAX));
+        }
+    }
+}
+
+/// Distinct peers of the inside hosts per bucket and direction, written as `unique_peers` with
+/// `--peer-cardinality`.
+#[derive(Debug, Default)]
+pub struct PeerCounter {
+    sketches: HashMap<HostKey, PeerSketch>,
+}
+
+impl PeerCounter {
+    /// Counts `target` as a peer `source` sent to and `source` as a peer `target` received from.
+    /// Only inside hosts and known peers are tracked.
+    pub fn record(
+        &mut self,
+        time: u64,
+        (source, src_ip): (Location, Option<IpAddr>),
+        (target, dst_ip): (Location, Option<IpAddr>),
+    ) {
+        for (location, direction, peer) in [
+            (source, Direction::Out, dst_ip),
+            (target, Direction::In, src_ip),
+        ] {
+            if let (Location::Inside(host), Some(peer)) = (location, peer) {
+                self.sketches
+                    .entry(HostKey {
+                        time,
+                        host,
+                        direction,
+                    })
+                    .or_default()
+                    .insert(peer);
+            }
+        }
+    }
+
+    /// Takes the counts of the buckets `take` selects.
+    pub fn take(&mut self, mut take: impl FnMut(u64) -> bool) -> HashMap<HostKey, u64> {
+        let mut counts = HashMap::new();
+        self.sketches.retain(|key, sketch| {
+            if take(key.time) {
+                counts.insert(key.clone(), sketch.count());
+                return false;
+            }
+            true
+        });
+
+        counts
+    }
+}
+
+/// Reason for which a flow was dropped before it reached the cache.
+///
+/// `Arp` and `UnknownEtype` explain why an address could not be parsed, while `InvalidSrc` and
+/// `InvalidDst` record which endpoint caused the flow to be dropped. A single flow is therefore
+/// usually counted twice.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum SkipReason {
+    UnknownEtype,
+    Arp,
+    InvalidSrc,
+    InvalidDst,
+    /// The flow started longer than `--max-message-age-seconds` ago.
+    TooOld,
+    /// A timestamp is more than `--max-future-skew-secs` ahead and the action is `drop`.
+    InFuture,
+    /// A timestamp is before `--min-timestamp`.
+    BeforeFloor,
+    /// The address does not have the length of its etype.
+    MalformedAddr,
+    /// Exported with another protocol than `--flow-version`.
+    OtherFlowVersion,
+    /// The protocol is not in `--include-protos`.
+    ExcludedProto,
+    /// Neither VLAN is in `--include-vlans`.
+    ExcludedVlan,
+    /// Fewer packets or bytes than `--min-packets` or `--min-bytes`.
+    TooSmall,
+    /// More packets or bytes than `--max-packets` or `--max-bytes`.
+    TooLarge,
+    /// Both addresses are outside and `--skip-external-flows` is set.
+    ExternalFlow,
+    /// Both addresses are inside and `--skip-internal-flows` is set.
+    InternalFlow,
+    /// The flow was received longer than `--max-received-age-secs` ago.
+    ReceivedTooOld,
+}
+
+/// Per-reason counters of skipped flows shared between the consumer and the stats reporter.
+#[derive(Debug, Default)]
+pub struct SkipCounters {
+    unknown_etype: AtomicU64,
+    arp: AtomicU64,
+    invalid_src: AtomicU64,
+    invalid_dst: AtomicU64,
+    too_old: AtomicU64,
+    in_future: AtomicU64,
+    before_floor: AtomicU64,
+    malformed_addr: AtomicU64,
+    other_flow_version: AtomicU64,
+    excluded_proto: AtomicU64,
+    excluded_vlan: AtomicU64,
+    too_small: AtomicU64,
+    too_large: AtomicU64,
+    external_flow: AtomicU64,
+    internal_flow: AtomicU64,
+    received_too_old: AtomicU64,
+    /// Per-reason counts of the flows with an IPv4 and an IPv6 etype, indexed like
+    /// [`SkipReason::ALL`].
+    ipv4: [AtomicU64; SkipReason::ALL.len()],
+    ipv6: [AtomicU64; SkipReason::ALL.len()],
+    seen_etypes: Mutex<HashSet<u32>>,
+}
+
+impl SkipReason {
+    pub const ALL: [SkipReason; 16] = [
+        SkipReason::UnknownEtype,
+        SkipReason::Arp,
+        SkipReason::InvalidSrc,
+        SkipReason::InvalidDst,
+        SkipReason::TooOld,
+        SkipReason::InFuture,
+        SkipReason::BeforeFloor,
+        SkipReason::MalformedAddr,
+        SkipReason::OtherFlowVersion,
+        SkipReason::ExcludedProto,
+        SkipReason::ExcludedVlan,
+        SkipReason::TooSmall,
+        SkipReason::TooLarge,
+        SkipReason::ExternalFlow,
+        SkipReason::InternalFlow,
+        SkipReason::ReceivedTooOld,
+    ];
+
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::UnknownEtype => "unknown_etype",
+            SkipReason::Arp => "arp",
+            SkipReason::InvalidSrc => "invalid_src",
+            SkipReason::InvalidDst => "invalid_dst",
+            SkipReason::TooOld => "too_old",
+            SkipReason::InFuture => "in_future",
+            SkipReason::BeforeFloor => "before_floor",
+            SkipReason::MalformedAddr => "malformed_addr",
+            SkipReason::OtherFlowVersion => "other_flow_version",
+            SkipReason::ExcludedProto => "excluded_proto",
+            SkipReason::ExcludedVlan => "excluded_vlan",
+            SkipReason::TooSmall => "too_small",
+            SkipReason::TooLarge => "too_large",
+            SkipReason::ExternalFlow => "external_flow",
+            SkipReason::InternalFlow => "internal_flow",
+            SkipReason::ReceivedTooOld => "received_too_old",
+        }
+    }
+}
+
+impl SkipCounters {
+    fn counter(&self, reason: SkipReason) -> &AtomicU64 {
+        match reason {
+            SkipReason::UnknownEtype => &self.unknown_etype,
+            SkipReason::Arp => &self.arp,
+            SkipReason::InvalidSrc => &self.invalid_src,
+            SkipReason::InvalidDst => &self.invalid_dst,
+            SkipReason::TooOld => &self.too_old,
+            SkipReason::InFuture => &self.in_future,
+            SkipReason::BeforeFloor => &self.before_floor,
+            SkipReason::MalformedAddr => &self.malformed_addr,
+            SkipReason::OtherFlowVersion => &self.other_flow_version,
+            SkipReason::ExcludedProto => &self.excluded_proto,
+            SkipReason::ExcludedVlan => &self.excluded_vlan,
+            SkipReason::TooSmall => &self.too_small,
+            SkipReason::TooLarge => &self.too_large,
+            SkipReason::ExternalFlow => &self.external_flow,
+            SkipReason::InternalFlow => &self.internal_flow,
+            SkipReason::ReceivedTooOld => &self.received_too_old,
+        }
+    }
+
+    /// Also counted by the IP version of `etype`, flows of other etypes only in the total.
+    pub fn record(&self, reason: SkipReason, etype: u32) {
+        self.counter(reason).fetch_add(1, Ordering::Relaxed);
+        if let Some(counter) = self.ip_version_counter(reason, ip_version(etype)) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[must_use]
+    pub fn get(&self, reason: SkipReason) -> u64 {
+        self.counter(reason).load(Ordering::Relaxed)
+    }
+
+    /// Flows skipped for `reason` with the etype of IP version 4 or 6.
+    #[must_use]
+    pub fn get_ip_version(&self, reason: SkipReason, ip_version: u8) -> u64 {
+        self.ip_version_counter(reason, Some(ip_version))
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    fn ip_version_counter(&self, reason: SkipReason, ip_version: Option<u8>) -> Option<&AtomicU64> {
+        let counters = match ip_version {
+            Some(4) => &self.ipv4,
+            Some(6) => &self.ipv6,
+            _ => return None,
+        };
+        counters.get(reason as usize)
+    }
+
+    /// Counts an unknown etype and logs it only the first time the etype is seen, so a chatty
+    /// exporter cannot flood the logs.
+    fn record_unknown_etype(&self, etype: u32, addr: &[u8]) {
+        self.record(SkipReason::UnknownEtype, etype);
+
+        let first_occurrence = match self.seen_etypes.lock() {
+            Ok(mut seen_etypes) => seen_etypes.insert(etype),
+            Err(_) => false,
+        };
+        if first_occurrence {
+            tracing::warn!(
+                "Unknown etype: {etype:X}, addr: {addr:?}. Further occurrences are only counted."
+            );
+        }
+    }
+}
+
+/// Top label of the MPLS stack. Prefers the full label stack and falls back to the first label
+/// field for exporters that only fill that one.
+#[must_use]
+pub fn top_mpls_label(message: &FlowMessage) -> Option<u32> {
+    match message.mpls_labels.first() {
+        Some(label) => Some(*label),
+        None => message.has_mpls.then_some(message.mpls1_label),
+    }
+}
+
+/// Flush threshold that grows while the consumer lags behind, so a catch-up writes fewer and
+/// larger batches, and decays back to the configured size once caught up.
+#[derive(Debug)]
+pub struct AdaptiveBatchSize {
+    base: usize,
+    max: usize,
+    lag_threshold: u64,
+    current: usize,
+}
+
+impl AdaptiveBatchSize {
+    #[must_use]
+    pub fn new(base: usize, max: usize, lag_threshold: u64) -> Self {
+        Self {
+            base,
+            max,
+            lag_threshold,
+            current: base,
+        }
+    }
+
+    #[must_use]
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Doubles the threshold while `lag` seconds exceed the lag threshold and halves it otherwise.
+    /// Returns the new threshold when it changed.
+    pub fn update(&mut self, lag: u64) -> Option<usize> {
+        let next = if lag > self.lag_threshold {
+            self.current.saturating_mul(2).min(self.max)
+        } else {
+            (self.current / 2).max(self.base)
+        };
+        if next == self.current {
+            return None;
+        }
+
+        self.current = next;
+        Some(next)
+    }
+}
+
+/// Flush threshold driven by the consumer lag in messages: halved to half of the configured size
+/// while the lag is high, so the cache stays small while catching up, and doubled per update up
+/// to the maximum while the lag is low, so a steady consumer writes fewer and larger batches.
+#[derive(Debug)]
+pub struct AdaptiveBatchSizer {
+    base: usize,
+    max: usize,
+    high_lag: u64,
+    low_lag: u64,
+    current: usize,
+}
+
+impl AdaptiveBatchSizer {
+    #[must_use]
+    pub fn new(base: usize, max: usize, high_lag: u64, low_lag: u64) -> Self {
+        Self {
+            base,
+            max,
+            high_lag,
+            low_lag,
+            current: base,
+        }
+    }
+
+    #[must_use]
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Adjusts the threshold to the consumer `lag` in messages. Returns the new threshold when it
+    /// changed.
+    pub fn update(&mut self, lag: u64) -> Option<usize> {
+        let next = if lag > self.high_lag {
+            (self.base / 2).max(1)
+        } else if lag < self.low_lag {
+            self.current.saturating_mul(2).min(self.max)
+        } else {
+            self.current
+        };
+        if next == self.current {
+            return None;
+        }
+
+        self.current = next;
+        Some(next)
+    }
+}
+
+/// Estimated heap usage of an aggregation cache.
+///
+/// Counts every allocated bucket of the table with its control byte, so it is cheap enough to be
+/// checked per message. The few bytes of country codes owned by geo keys are not counted.
+#[must_use]
+pub fn estimated_cache_memory(cache: &HashMap<AggregatedKey, CommunicationData>) -> usize {
+    // The table keeps at least 1/8 of its buckets empty.
+    let buckets = cache.capacity() * 8 / 7;
+    buckets * (std::mem::size_of::<(AggregatedKey, CommunicationData)>() + 1)
+}
+
+/// Timestamp the flow is bucketed by and whether the preferred field was zero.
+///
+/// A missing preferred field falls back to the first non-zero of `time_flow_start`,
+/// `time_flow_end` and `time_received`, in that order.
+#[must_use]
+pub fn bucket_timestamp(message: &FlowMessage, preferred: BucketTimestamp) -> (u64, bool) {
+    let preferred_timestamp = match preferred {
+        BucketTimestamp::FlowStart => message.time_flow_start,
+        BucketTimestamp::FlowEnd => message.time_flow_end,
+        BucketTimestamp::Received => message.time_received,
+    };
+    if preferred_timestamp != 0 {
+        return (preferred_timestamp, false);
+    }
+
+    let fallback = [
+        message.time_flow_start,
+        message.time_flow_end,
+        message.time_received,
+    ]
+    .into_iter()
+    .find(|timestamp| *timestamp != 0)
+    .unwrap_or(0);
+    (fallback, true)
+}
+
+/// Normalized TCP flags of a TCP flow, `None` for every other protocol.
+#[must_use]
+pub fn tcp_flags(message: &FlowMessage) -> Option<TcpFlags> {
+    (message.proto == 6).then(|| TcpFlags::from_bits(message.tcp_flags))
+}
+
+/// ICMP type and code of an ICMP or ICMPv6 flow, `None` for every other protocol.
+#[must_use]
+pub fn icmp_detail(message: &FlowMessage) -> Option<IcmpDetail> {
+    matches!(message.proto, 1 | 58).then(|| {
+        IcmpDetail {
+            icmp_type: u8::try_from(message.icmp_type).unwrap_or(u8::MAX),
+            code: u8::try_from(message.icmp_code).unwrap_or(u8::MAX),
+        }
+    })
+}
+
+/// DSCP code point of the flow, the ECN bits of the ToS byte are dropped.
+#[must_use]
+pub fn dscp(message: &FlowMessage) -> u8 {
+    u8::try_from((message.ip_tos >> 2) & 0x3F).unwrap_or_default()
+}
+
+/// Standard class name of a DSCP code point, e.g. `EF` or `AF21`. Unknown code points keep the
+/// number.
+#[must_use]
+pub fn dscp_name(dscp: u8) -> String {
+    match dscp {
+        0 => "BE".to_owned(),
+        46 => "EF".to_owned(),
+        // AFxy is encoded as `xxxyy0`.
+        10 | 12 | 14 | 18 | 20 | 22 | 26 | 28 | 30 | 34 | 36 | 38 => {
+            format!("AF{}{}", dscp >> 3, (dscp >> 1) & 0b11)
+        },
+        // CS0 is the same code point as best effort and is reported as `BE`.
+        8 | 16 | 24 | 32 | 40 | 48 | 56 => format!("CS{}", dscp >> 3),
+        dscp => dscp.to_string(),
+    }
+}
+
+/// Well-known name of an IP protocol number.
+#[must_use]
+pub fn proto_name(proto: u32) -> Option<&'static str> {
+    Some(match proto {
+        1 => "ICMP",
+        2 => "IGMP",
+        6 => "TCP",
+        17 => "UDP",
+        47 => "GRE",
+        50 => "ESP",
+        51 => "AH",
+        58 => "ICMPv6",
+        89 => "OSPF",
+        132 => "SCTP",
+        _ => return None,
+    })
+}
+
+/// IP protocol number of a name known to [`proto_name`], case insensitive.
+#[must_use]
+pub fn proto_number(name: &str) -> Option<u32> {
+    (0..=u8::MAX.into())
+        .find(|proto| proto_name(*proto).is_some_and(|known| known.eq_ignore_ascii_case(name)))
+}
+
+/// Zeroes all host bits of `ip` beyond `prefix_len`. Prefix lengths longer than the address are
+/// clamped, so `/32` for IPv4 and `/128` for IPv6 leave the address untouched.
+#[must_use]
+pub fn mask_ip(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let host_bits = 32 - u32::from(prefix_len.min(32));
+            let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        },
+        IpAddr::V6(ip) => {
+            let host_bits = 128 - u32::from(prefix_len.min(128));
+            let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
         },
-        0x86DD if addr.len() == 16 => {
-            let ipv6: [u8; 16] = addr.as_slice().try_into().map_err(|error| {
-                tracing::error!(addr = ?error, "Failed to create IPv6.");
-                anyhow!("Received invalid addr for given `etype`.")
-            })?;
-            Ok(Some(IpAddr::from(ipv6)))
+    }
+}
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+const SNAPPY_MAGIC: &[u8] = b"\xFF\x06\x00\x00sNaPpY";
+
+/// Payload decompressed according to `--payload-compression`. Reading stops after
+/// `max_bytes`, so a corrupt or malicious frame cannot exhaust the memory.
+pub fn decompress_payload(
+    payload: &[u8],
+    compression: PayloadCompression,
+    max_bytes: usize,
+) -> Result<Cow<'_, [u8]>, String> {
+    let compression = match compression {
+        PayloadCompression::Auto if payload.starts_with(ZSTD_MAGIC) => PayloadCompression::Zstd,
+        PayloadCompression::Auto if payload.starts_with(SNAPPY_MAGIC) => PayloadCompression::Snappy,
+        PayloadCompression::Auto => PayloadCompression::None,
+        compression => compression,
+    };
+    let reader: Box<dyn Read + '_> = match compression {
+        PayloadCompression::None | PayloadCompression::Auto => return Ok(Cow::Borrowed(payload)),
+        PayloadCompression::Zstd => {
+            Box::new(
+                zstd::stream::read::Decoder::with_buffer(payload)
+                    .map_err(|error| format!("invalid zstd frame: {error}"))?,
+            )
+        },
+        PayloadCompression::Snappy => Box::new(snap::read::FrameDecoder::new(payload)),
+    };
+
+    let mut decompressed = Vec::new();
+    reader
+        .take(
+            u64::try_from(max_bytes)
+                .unwrap_or(u64::MAX)
+                .saturating_add(1),
+        )
+        .read_to_end(&mut decompressed)
+        .map_err(|error| format!("unable to decompress the payload: {error}"))?;
+    if decompressed.len() > max_bytes {
+        return Err(format!(
+            "payload decompresses to more than {max_bytes} bytes"
+        ));
+    }
+
+    Ok(Cow::Owned(decompressed))
+}
+
+/// Splits a payload in the Confluent Schema Registry wire format into the schema id and the
+/// protobuf message. The message indexes after the schema id are skipped, they select the message
+/// type within the schema and goflow schemas only have one.
+pub fn strip_confluent_envelope(payload: &[u8]) -> Result<(u32, &[u8]), String> {
+    let Some((0, rest)) = payload.split_first() else {
+        return Err("missing the magic byte of the Confluent envelope".to_owned());
+    };
+    let (schema_id, mut rest) = rest
+        .split_first_chunk::<4>()
+        .ok_or_else(|| "truncated schema id of the Confluent envelope".to_owned())?;
+    let truncated = || "truncated message indexes of the Confluent envelope".to_owned();
+
+    // Zigzag encoded count followed by the indexes, a single zero is the first message type.
+    let (count, after_count) = read_varint(rest).ok_or_else(truncated)?;
+    if count & 1 == 1 {
+        return Err("negative message index count in the Confluent envelope".to_owned());
+    }
+    rest = after_count;
+    for _ in 0..count >> 1 {
+        rest = read_varint(rest).ok_or_else(truncated)?.1;
+    }
+
+    Ok((u32::from_be_bytes(*schema_id), rest))
+}
+
+/// Protobuf base 128 varint at the start of `bytes` and the bytes after it.
+fn read_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0;
+    for (index, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7F) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, bytes.get(index + 1..)?));
+        }
+    }
+
+    None
+}
+
+/// IP version of an IPv4 or IPv6 etype.
+#[must_use]
+pub fn ip_version(etype: u32) -> Option<u8> {
+    match etype {
+        0x0800 => Some(4),
+        0x86DD => Some(6),
+        _ => None,
+    }
+}
+
+fn parse_ip(etype: u32, addr: &[u8], skip_counters: &SkipCounters) -> Option<IpAddr> {
+    match etype {
+        0x0800 | 0x86DD => {
+            let ip = match (etype, addr.len()) {
+                (0x0800, 4) => <[u8; 4]>::try_from(addr).ok().map(IpAddr::from),
+                // IPv4-mapped addresses are matched against the IPv4 CIDRs.
+                (0x86DD, 16) => {
+                    <[u8; 16]>::try_from(addr)
+                        .ok()
+                        .map(|ipv6| IpAddr::from(ipv6).to_canonical())
+                },
+                _ => None,
+            };
+            if ip.is_none() {
+                tracing::debug!(etype, ?addr, "Skipping malformed address.");
+                skip_counters.record(SkipReason::MalformedAddr, etype);
+            }
+
+            ip
         },
         // ARP
-        0x0806 => Ok(None),
+        0x0806 => {
+            skip_counters.record(SkipReason::Arp, etype);
+
+            None
+        },
         etype => {
-            tracing::warn!("Unknown etype: {etype:X}, addr: {addr:?}.");
+            skip_counters.record_unknown_etype(etype, addr);
 
-            Ok(None)
+            None
         },
     }
 }
 
-pub fn parse_location(
-    etype: u32,
-    addr: &Vec<u8>,
-    cidr_list: &Vec<IpCidr>,
-) -> anyhow::Result<Option<Location>> {
-    Ok(if let Some(ip) = parse_ip(etype, addr)? {
+/// Parses the address of the exporter (sampler) that reported the flow. The field carries no
+/// etype, so the address family is taken from its length. Empty or malformed addresses are `None`.
+#[must_use]
+pub fn parse_exporter(addr: &[u8]) -> Option<IpAddr> {
+    match addr.len() {
+        4 => <[u8; 4]>::try_from(addr).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(addr).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+/// Longest-prefix-match trie of the inside CIDRs, a lookup does not depend on their count.
+pub struct CidrTree {
+    table: IpNetworkTable<IpCidr>,
+}
+
+impl CidrTree {
+    #[must_use]
+    pub fn new(cidr_list: &[IpCidr]) -> Self {
+        let mut table = IpNetworkTable::new();
         for cidr in cidr_list {
-            if cidr.contains(ip) {
-                return Ok(Some(Location::Inside(ip)));
+            // Truncating cannot fail, the prefix length of a parsed CIDR is always in range.
+            if let Ok(network) = IpNetwork::new_truncate(cidr.first_as_ip_addr(), cidr.get_bits()) {
+                table.insert(network, *cidr);
             }
         }
-        Some(Location::Outside)
+        Self { table }
+    }
+
+    /// Most specific CIDR containing the address.
+    #[must_use]
+    pub fn longest_match(&self, ip: IpAddr) -> Option<&IpCidr> {
+        self.table.longest_match(ip).map(|(_, cidr)| cidr)
+    }
+}
+
+/// Longest prefix match of addresses against the `--cidr-groups` CIDRs.
+pub struct CidrGroups {
+    table: IpNetworkTable<Arc<str>>,
+    ungrouped: Arc<str>,
+}
+
+impl CidrGroups {
+    #[must_use]
+    pub fn new(groups: &[(IpCidr, String)]) -> Self {
+        let mut table = IpNetworkTable::new();
+        for (cidr, group) in groups {
+            if let Ok(network) = IpNetwork::new_truncate(cidr.first_as_ip_addr(), cidr.get_bits()) {
+                table.insert(network, Arc::from(group.as_str()));
+            }
+        }
+        Self {
+            table,
+            ungrouped: Arc::from("ungrouped"),
+        }
+    }
+
+    /// Group of the most specific CIDR containing the address, `ungrouped` without one.
+    #[must_use]
+    pub fn group(&self, ip: IpAddr) -> Arc<str> {
+        self.table
+            .longest_match(ip)
+            .map_or_else(|| self.ungrouped.clone(), |(_, group)| group.clone())
+    }
+}
+
+/// Parses the address and classifies it against the inside CIDRs. The address is returned as
+/// well, because `Location::Outside` does not keep it. Addresses that cannot be parsed are
+/// counted as skipped and `None`.
+pub fn parse_location(
+    etype: u32,
+    addr: &[u8],
+    cidr_tree: &CidrTree,
+    skip_counters: &SkipCounters,
+) -> Option<(IpAddr, Location)> {
+    let ip = parse_ip(etype, addr, skip_counters)?;
+    if cidr_tree.longest_match(ip).is_some() {
+        Some((ip, Location::Inside(ip)))
     } else {
-        None
-    })
+        Some((ip, Location::Outside))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_reasons_are_listed_in_declaration_order() {
+        for (index, reason) in SkipReason::ALL.into_iter().enumerate() {
+            assert_eq!(reason as usize, index, "{reason:?}");
+        }
+        let names: HashSet<_> = SkipReason::ALL
+            .into_iter()
+            .map(SkipReason::as_str)
+            .collect();
+        assert_eq!(names.len(), SkipReason::ALL.len());
+    }
+
+    #[test]
+    fn skips_are_counted_by_reason_and_ip_version() {
+        for reason in SkipReason::ALL {
+            let counters = SkipCounters::default();
+            counters.record(reason, 0x0800);
+            counters.record(reason, 0x86DD);
+            counters.record(reason, 0x86DD);
+            counters.record(reason, 0x0806);
+
+            for other in SkipReason::ALL {
+                let expected = if other == reason {
+                    (4, 1, 2)
+                } else {
+                    (0, 0, 0)
+                };
+                assert_eq!(
+                    (
+                        counters.get(other),
+                        counters.get_ip_version(other, 4),
+                        counters.get_ip_version(other, 6)
+                    ),
+                    expected,
+                    "{reason:?} recorded, {other:?} read"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unparsable_addresses_are_counted() {
+        let counters = SkipCounters::default();
+        let cidr_tree = CidrTree::new(&[]);
+
+        assert!(parse_location(0x0806, &[10, 0, 0, 1], &cidr_tree, &counters).is_none());
+        assert!(parse_location(0x1234, &[10, 0, 0, 1], &cidr_tree, &counters).is_none());
+        assert!(parse_location(0x1234, &[10, 0, 0, 2], &cidr_tree, &counters).is_none());
+        assert!(parse_location(0x0800, &[10, 0, 0], &cidr_tree, &counters).is_none());
+
+        assert_eq!(counters.get(SkipReason::Arp), 1);
+        assert_eq!(counters.get(SkipReason::UnknownEtype), 2);
+        assert_eq!(counters.get(SkipReason::MalformedAddr), 1);
+        assert_eq!(counters.get_ip_version(SkipReason::MalformedAddr, 4), 1);
+    }
+
+    #[test]
+    fn dscp_code_points_are_named() {
+        let named = [
+            (0, "BE"),
+            (46, "EF"),
+            (10, "AF11"),
+            (12, "AF12"),
+            (14, "AF13"),
+            (18, "AF21"),
+            (20, "AF22"),
+            (22, "AF23"),
+            (26, "AF31"),
+            (28, "AF32"),
+            (30, "AF33"),
+            (34, "AF41"),
+            (36, "AF42"),
+            (38, "AF43"),
+            (8, "CS1"),
+            (16, "CS2"),
+            (24, "CS3"),
+            (32, "CS4"),
+            (40, "CS5"),
+            (48, "CS6"),
+            (56, "CS7"),
+        ];
+        for (dscp, name) in named {
+            assert_eq!(dscp_name(dscp), name);
+        }
+    }
+
+    #[test]
+    fn unknown_dscp_code_points_keep_the_number() {
+        for dscp in [1, 11, 44, 63] {
+            assert_eq!(dscp_name(dscp), dscp.to_string());
+        }
+    }
+
+    #[test]
+    fn dscp_ignores_the_ecn_bits() {
+        for (ip_tos, expected) in [(0xB8, 46), (0xBB, 46), (0x28, 10), (0x03, 0)] {
+            let message = FlowMessage {
+                ip_tos,
+                ..FlowMessage::default()
+            };
+            assert_eq!(dscp(&message), expected, "{ip_tos:#04x}");
+        }
+    }
+
+    fn icmp(proto: u32, icmp_type: u32, icmp_code: u32) -> FlowMessage {
+        FlowMessage {
+            proto,
+            icmp_type,
+            icmp_code,
+            ..FlowMessage::default()
+        }
+    }
+
+    #[test]
+    fn icmp_detail_names_icmpv4_types_and_codes() {
+        for (icmp_type, code, type_name, code_name) in [
+            (0, 0, "echo-reply", "0"),
+            (3, 0, "destination-unreachable", "net-unreachable"),
+            (3, 1, "destination-unreachable", "host-unreachable"),
+            (3, 3, "destination-unreachable", "port-unreachable"),
+            (3, 4, "destination-unreachable", "fragmentation-needed"),
+            (
+                3,
+                13,
+                "destination-unreachable",
+                "administratively-prohibited",
+            ),
+            (5, 1, "redirect", "1"),
+            (8, 0, "echo-request", "0"),
+            (11, 0, "time-exceeded", "ttl-exceeded"),
+            (11, 1, "time-exceeded", "reassembly-time-exceeded"),
+            (12, 0, "parameter-problem", "0"),
+            // ICMPv6 only types are not named for ICMPv4.
+            (128, 0, "128", "0"),
+            (42, 7, "42", "7"),
+        ] {
+            let detail = icmp_detail(&icmp(1, icmp_type, code)).unwrap();
+            assert_eq!(
+                detail,
+                IcmpDetail {
+                    icmp_type: u8::try_from(icmp_type).unwrap(),
+                    code: u8::try_from(code).unwrap(),
+                }
+            );
+            assert_eq!(detail.type_name(1), type_name, "{icmp_type}/{code}");
+            assert_eq!(detail.code_name(1), code_name, "{icmp_type}/{code}");
+        }
+    }
+
+    #[test]
+    fn icmp_detail_names_icmpv6_types_and_codes() {
+        for (icmp_type, code, type_name, code_name) in [
+            (1, 0, "destination-unreachable", "no-route"),
+            (
+                1,
+                1,
+                "destination-unreachable",
+                "administratively-prohibited",
+            ),
+            (1, 3, "destination-unreachable", "address-unreachable"),
+            (1, 4, "destination-unreachable", "port-unreachable"),
+            (2, 0, "packet-too-big", "0"),
+            (3, 0, "time-exceeded", "hop-limit-exceeded"),
+            (3, 1, "time-exceeded", "reassembly-time-exceeded"),
+            (4, 0, "parameter-problem", "0"),
+            (128, 0, "echo-request", "0"),
+            (129, 0, "echo-reply", "0"),
+            (133, 0, "router-solicitation", "0"),
+            (134, 0, "router-advertisement", "0"),
+            (135, 0, "neighbor-solicitation", "0"),
+            (136, 0, "neighbor-advertisement", "0"),
+            // ICMPv4 only types are not named for ICMPv6.
+            (8, 0, "8", "0"),
+            (11, 0, "11", "0"),
+        ] {
+            let detail = icmp_detail(&icmp(58, icmp_type, code)).unwrap();
+            assert_eq!(detail.type_name(58), type_name, "{icmp_type}/{code}");
+            assert_eq!(detail.code_name(58), code_name, "{icmp_type}/{code}");
+        }
+    }
+
+    #[test]
+    fn icmp_detail_is_only_set_for_icmp() {
+        for proto in [0, 6, 17, 47] {
+            assert_eq!(icmp_detail(&icmp(proto, 3, 1)), None, "{proto}");
+        }
+        // Out of range values of broken exporters are clamped.
+        assert_eq!(
+            icmp_detail(&icmp(1, 300, 256)),
+            Some(IcmpDetail {
+                icmp_type: u8::MAX,
+                code: u8::MAX,
+            })
+        );
+    }
+
+    /// Serialized goflow message, its first byte is the tag of a field and never zero.
+    fn encoded_flow() -> Vec<u8> {
+        prost::Message::encode_to_vec(&FlowMessage {
+            etype: 0x0800,
+            src_addr: vec![10, 0, 0, 1],
+            dst_addr: vec![192, 0, 2, 1],
+            bytes: 100,
+            ..FlowMessage::default()
+        })
+    }
+
+    #[test]
+    fn confluent_envelope_is_stripped() {
+        let protobuf = encoded_flow();
+        // Magic byte, schema id 42 and the single zero of the first message type.
+        let mut payload = vec![0, 0, 0, 0, 42, 0];
+        payload.extend(&protobuf);
+
+        let (schema_id, stripped) = strip_confluent_envelope(&payload).unwrap();
+        assert_eq!(schema_id, 42);
+        assert_eq!(stripped, protobuf);
+        let message: FlowMessage = prost::Message::decode(stripped).unwrap();
+        assert_eq!(message.bytes, 100);
+
+        // Two message indexes, 1 and 128, select a nested message type.
+        let mut payload = vec![0, 0, 1, 0, 0, 4, 1, 0x80, 0x01];
+        payload.extend(&protobuf);
+        assert_eq!(
+            strip_confluent_envelope(&payload),
+            Ok((65_536, protobuf.as_slice()))
+        );
+    }
+
+    #[test]
+    fn payload_without_confluent_envelope_is_rejected() {
+        let protobuf = encoded_flow();
+        assert_ne!(protobuf.first(), Some(&0));
+
+        let cases: [(&[u8], &str); 7] = [
+            (&protobuf, "missing the magic byte"),
+            (&[], "missing the magic byte"),
+            (&[0, 0, 0, 42], "truncated schema id"),
+            (&[0, 0, 0, 0, 42], "truncated message indexes"),
+            (&[0, 0, 0, 0, 42, 4, 1], "truncated message indexes"),
+            (&[0, 0, 0, 0, 42, 0x80], "truncated message indexes"),
+            (&[0, 0, 0, 0, 42, 1, 0], "negative message index count"),
+        ];
+        for (payload, error) in cases {
+            let result = strip_confluent_envelope(payload);
+            assert!(
+                result
+                    .as_ref()
+                    .is_err_and(|message| message.contains(error)),
+                "{payload:?}: {result:?}"
+            );
+        }
+    }
+
+    fn timestamps(time_flow_start: u64, time_flow_end: u64, time_received: u64) -> FlowMessage {
+        FlowMessage {
+            time_flow_start,
+            time_flow_end,
+            time_received,
+            ..FlowMessage::default()
+        }
+    }
+
+    #[test]
+    fn bucket_timestamp_follows_the_mode() {
+        let message = timestamps(1_700_000_000, 1_700_000_030, 1_700_000_045);
+
+        assert_eq!(
+            bucket_timestamp(&message, BucketTimestamp::FlowStart),
+            (1_700_000_000, false)
+        );
+        assert_eq!(
+            bucket_timestamp(&message, BucketTimestamp::FlowEnd),
+            (1_700_000_030, false)
+        );
+        assert_eq!(
+            bucket_timestamp(&message, BucketTimestamp::Received),
+            (1_700_000_045, false)
+        );
+    }
+
+    #[test]
+    fn bucket_timestamp_falls_back_in_order() {
+        let no_start = timestamps(0, 1_700_000_030, 1_700_000_045);
+        assert_eq!(
+            bucket_timestamp(&no_start, BucketTimestamp::FlowStart),
+            (1_700_000_030, true)
+        );
+        let only_received = timestamps(0, 0, 1_700_000_045);
+        assert_eq!(
+            bucket_timestamp(&only_received, BucketTimestamp::FlowEnd),
+            (1_700_000_045, true)
+        );
+        let not_received = timestamps(1_700_000_000, 1_700_000_030, 0);
+        assert_eq!(
+            bucket_timestamp(&not_received, BucketTimestamp::Received),
+            (1_700_000_000, true)
+        );
+        assert_eq!(
+            bucket_timestamp(&timestamps(0, 0, 0), BucketTimestamp::FlowStart),
+            (0, true)
+        );
+    }
+
+    fn cidrs(cidrs: &[&str]) -> Vec<IpCidr> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn cidr_tree_matches_the_linear_longest_prefix() {
+        let cidr_list = cidrs(&[
+            "10.1.2.128/25",
+            "10.0.0.0/8",
+            "10.1.2.0/24",
+            "10.1.0.0/16",
+            "10.1.2.200/32",
+            "192.168.0.0/16",
+            "192.168.0.0/24",
+            "2001:db8::/32",
+            "2001:db8:1::/48",
+        ]);
+        let cidr_tree = CidrTree::new(&cidr_list);
+        let linear = |ip: IpAddr| {
+            cidr_list
+                .iter()
+                .filter(|cidr| cidr.contains(ip))
+                .max_by_key(|cidr| cidr.get_bits())
+        };
+
+        let mut ips: Vec<IpAddr> = [
+            "10.1.2.200",
+            "10.1.2.201",
+            "10.1.2.127",
+            "10.1.3.1",
+            "10.2.0.1",
+            "11.0.0.1",
+            "192.168.0.255",
+            "192.168.1.0",
+            "2001:db8:1::1",
+            "2001:db8:2::1",
+            "2001:db9::1",
+        ]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+        // Deterministic spread over the overlapping IPv4 ranges.
+        let mut state: u32 = 1;
+        for _ in 0..10_000 {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            ips.push(IpAddr::from(Ipv4Addr::from(0x0A01_0000 | (state >> 14))));
+        }
+
+        for ip in ips {
+            assert_eq!(cidr_tree.longest_match(ip), linear(ip), "{ip}");
+        }
+    }
+
+    fn cidr_groups(groups: &[(&str, &str)]) -> CidrGroups {
+        let groups: Vec<_> = groups
+            .iter()
+            .map(|(cidr, group)| (cidr.parse().unwrap(), (*group).to_string()))
+            .collect();
+        CidrGroups::new(&groups)
+    }
+
+    #[test]
+    fn cidr_groups_resolve_overlaps_to_the_most_specific_cidr() {
+        let groups = [
+            ("10.0.0.0/8", "corporate"),
+            ("10.1.0.0/16", "office"),
+            ("10.1.2.0/24", "lab"),
+            ("10.1.2.7/32", "printer"),
+            ("2001:db8::/32", "corporate"),
+            ("2001:db8:1::/48", "lab"),
+        ];
+        let expected = [
+            ("10.1.2.7", "printer"),
+            ("10.1.2.8", "lab"),
+            ("10.1.3.1", "office"),
+            ("10.2.0.1", "corporate"),
+            ("11.0.0.1", "ungrouped"),
+            ("2001:db8:1::1", "lab"),
+            ("2001:db8:2::1", "corporate"),
+            ("2001:db9::1", "ungrouped"),
+        ];
+
+        // The resolution does not depend on the order of the file.
+        let mut reversed = groups;
+        reversed.reverse();
+        for cidr_groups in [cidr_groups(&groups), cidr_groups(&reversed)] {
+            for (ip, group) in expected {
+                assert_eq!(&*cidr_groups.group(ip.parse().unwrap()), group, "{ip}");
+            }
+        }
+    }
+
+    #[test]
+    fn cidr_groups_keep_the_last_group_of_a_repeated_cidr() {
+        let cidr_groups = cidr_groups(&[("10.0.0.0/8", "old"), ("10.0.0.0/8", "new")]);
+
+        assert_eq!(&*cidr_groups.group("10.0.0.1".parse().unwrap()), "new");
+    }
+
+    /// Flow sizes with the population variances of `numpy.var` for all of them, the first 3 and
+    /// the last 4.
+    const FLOW_BYTES: [u64; 7] = [100, 1500, 40, 800, 64, 1200, 300];
+    const VARIANCE: f64 = 306_486.857_142_857_16;
+    const HEAD_VARIANCE: f64 = 455_022.222_222_222_25;
+    const TAIL_VARIANCE: f64 = 194_243.0;
+
+    fn sampled(flow_bytes: &[u64]) -> CommunicationData {
+        let mut data = CommunicationData::default();
+        for bytes in flow_bytes {
+            data.record(1, *bytes, false);
+            data.sample_bytes(*bytes);
+        }
+        data
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!(
+            (actual - expected).abs() <= expected * 1e-9,
+            "{actual} != {expected}"
+        );
+    }
+
+    #[test]
+    fn welford_variance_matches_the_reference() {
+        assert_eq!(CommunicationData::default().variance(), None);
+        assert_close(sampled(&FLOW_BYTES[..1]).variance(), 0.0);
+        assert_close(sampled(&FLOW_BYTES[..3]).variance(), HEAD_VARIANCE);
+        assert_close(sampled(&FLOW_BYTES[3..]).variance(), TAIL_VARIANCE);
+        assert_close(sampled(&FLOW_BYTES).variance(), VARIANCE);
+        assert_close(sampled(&FLOW_BYTES).std_dev(), VARIANCE.sqrt());
+    }
+
+    #[test]
+    fn merged_variance_matches_the_reference() {
+        let mut merged = sampled(&FLOW_BYTES[..3]);
+        merged.merge(&sampled(&FLOW_BYTES[3..]));
+
+        assert_eq!(merged.count, 7);
+        assert_eq!(merged.bytes, FLOW_BYTES.iter().sum::<u64>());
+        assert_close(merged.variance(), VARIANCE);
+    }
+
+    #[test]
+    fn merging_an_unsampled_side_keeps_the_variance() {
+        let mut merged = CommunicationData::default();
+        merged.merge(&sampled(&FLOW_BYTES));
+        assert_close(merged.variance(), VARIANCE);
+
+        let mut merged = sampled(&FLOW_BYTES);
+        merged.merge(&CommunicationData::default());
+        assert_close(merged.variance(), VARIANCE);
+    }
+
+    fn peers(count: u32) -> impl Iterator<Item = IpAddr> {
+        (0..count).map(|index| {
+            if index % 2 == 0 {
+                IpAddr::from(Ipv4Addr::from(0x0A00_0000 + index))
+            } else {
+                IpAddr::from(Ipv6Addr::from((0x2001_0DB8_u128 << 96) | u128::from(index)))
+            }
+        })
+    }
+
+    #[test]
+    fn peer_sketch_is_exact_up_to_the_threshold() {
+        let exact_peers = u32::try_from(EXACT_PEERS).unwrap();
+        let mut sketch = PeerSketch::default();
+        for peer in peers(exact_peers).chain(peers(exact_peers)) {
+            sketch.insert(peer);
+        }
+
+        assert!(matches!(sketch, PeerSketch::Exact(_)));
+        assert_eq!(sketch.count(), u64::from(exact_peers));
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn peer_sketch_estimate_stays_within_the_error_bound() {
+        // Three standard errors of 1.04 / sqrt(2^10) registers.
+        const MAX_ERROR: f64 = 0.1;
+
+        for cardinality in [65, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000] {
+            let mut sketch = PeerSketch::default();
+            // Repeated peers do not change the estimate.
+            for peer in peers(cardinality).chain(peers(cardinality)) {
+                sketch.insert(peer);
+            }
+
+            assert!(matches!(sketch, PeerSketch::HyperLogLog(_)));
+            let error = (sketch.count() as f64 - f64::from(cardinality)).abs();
+            assert!(
+                error <= MAX_ERROR * f64::from(cardinality),
+                "{cardinality}: {}",
+                sketch.count()
+            );
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn parse_ip_never_panics(
+            etype in proptest::prelude::any::<u32>(),
+            addr in proptest::collection::vec(proptest::prelude::any::<u8>(), 0..40),
+        ) {
+            let counters = SkipCounters::default();
+            let parsed = parse_ip(etype, &addr, &counters);
+
+            let skips: u64 = SkipReason::ALL.into_iter().map(|reason| counters.get(reason)).sum();
+            proptest::prop_assert_eq!(skips, u64::from(parsed.is_none()));
+        }
+
+        #[test]
+        fn ipv4_addresses_are_classified(addr in proptest::prelude::any::<[u8; 4]>()) {
+            let cidr_tree = CidrTree::new(&cidrs(&["10.0.0.0/8"]));
+            let counters = SkipCounters::default();
+
+            let parsed = parse_location(0x0800, &addr, &cidr_tree, &counters);
+
+            let ip = IpAddr::from(addr);
+            let location = if addr.starts_with(&[10]) {
+                Location::Inside(ip)
+            } else {
+                Location::Outside
+            };
+            proptest::prop_assert_eq!(parsed, Some((ip, location)));
+        }
+
+        #[test]
+        fn ipv6_addresses_are_classified(addr in proptest::prelude::any::<[u8; 16]>()) {
+            let cidr_tree = CidrTree::new(&cidrs(&["10.0.0.0/8", "2001:db8::/32"]));
+            let counters = SkipCounters::default();
+
+            let parsed = parse_location(0x86DD, &addr, &cidr_tree, &counters);
+
+            // IPv4-mapped addresses are classified as IPv4.
+            let mapped = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF];
+            let ip = match addr.strip_prefix(&mapped) {
+                Some(&[a, b, c, d]) => IpAddr::from([a, b, c, d]),
+                _ => IpAddr::from(addr),
+            };
+            let inside = addr.starts_with(&[0x20, 0x01, 0x0D, 0xB8])
+                || addr.starts_with(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 10]);
+            let location = if inside { Location::Inside(ip) } else { Location::Outside };
+            proptest::prop_assert_eq!(parsed, Some((ip, location)));
+        }
+
+        #[test]
+        fn malformed_addresses_are_skipped(
+            ipv6 in proptest::prelude::any::<bool>(),
+            addr in proptest::collection::vec(proptest::prelude::any::<u8>(), 0..40),
+        ) {
+            let (etype, length) = if ipv6 { (0x86DD, 16) } else { (0x0800, 4) };
+            proptest::prop_assume!(addr.len() != length);
+            let cidr_tree = CidrTree::new(&cidrs(&["0.0.0.0/0", "::/0"]));
+            let counters = SkipCounters::default();
+
+            proptest::prop_assert_eq!(parse_location(etype, &addr, &cidr_tree, &counters), None);
+            proptest::prop_assert_eq!(counters.get(SkipReason::MalformedAddr), 1);
+        }
+    }
 }
@@ -0,0 +1,355 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{BuildHasher, BuildHasherDefault},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    config::AggregationStoreKind,
+    store::{AggregationStore, Entries, ShardedStore},
+    util::{AggregatedKey, CommunicationData},
+};
+
+/// Commands queued per worker, a full queue holds up the consume loop.
+const QUEUE_CAPACITY: usize = 4096;
+
+enum Command {
+    Record {
+        key: AggregatedKey,
+        packets: u64,
+        bytes: u64,
+        reversed: bool,
+        observed: Option<SystemTime>,
+        sample_variance: bool,
+    },
+    /// Takes the entries of one table whose bucket is closed at the watermark.
+    TakeClosed {
+        shard: usize,
+        watermark: u64,
+        seconds_alignment: u64,
+        reply: oneshot::Sender<Entries>,
+    },
+    /// Takes every entry, of one table with `shard`. `shrink` also releases the memory of the
+    /// table.
+    Drain {
+        shard: Option<usize>,
+        shrink: bool,
+        reply: oneshot::Sender<Entries>,
+    },
+    Snapshot(oneshot::Sender<Snapshot>),
+    /// Merges an entry restored from a previous run.
+    Merge {
+        key: AggregatedKey,
+        value: CommunicationData,
+    },
+    /// Copies every entry, the shard keeps them.
+    Copy(oneshot::Sender<Entries>),
+}
+
+/// Exact state of the shards once every queued record is aggregated.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Snapshot {
+    pub entries: usize,
+    pub oldest_bucket: Option<u64>,
+    pub newest_bucket: Option<u64>,
+}
+
+/// Published by a worker before it replies, so the shard sizes are current once a flush returns.
+#[derive(Default)]
+struct Gauges {
+    entries: AtomicUsize,
+    memory: AtomicUsize,
+}
+
+impl Gauges {
+    fn publish(&self, store: &impl AggregationStore) {
+        self.entries.store(store.len(), Ordering::Relaxed);
+        self.memory
+            .store(store.estimated_memory(), Ordering::Relaxed);
+    }
+}
+
+/// Aggregation cache split into shards owned by worker tasks.
+///
+/// Flows are routed by the hash of their key, so the shards hold disjoint keys and a flushed
+/// batch is simply the concatenation of the shards. The queue of every worker is processed in
+/// order, so a flush sees every flow recorded before it.
+pub struct AggregationWorkers {
+    queues: Vec<mpsc::Sender<Command>>,
+    gauges: Vec<Arc<Gauges>>,
+    hasher: BuildHasherDefault<DefaultHasher>,
+    /// Tables of every worker, see [`AggregationStore::shards`].
+    shards: usize,
+}
+
+impl AggregationWorkers {
+    /// Spawns `workers` tasks, at least one, each with its own `store`. `shards` only applies
+    /// to the sharded store.
+    #[must_use]
+    pub fn new(workers: usize, store: AggregationStoreKind, shards: usize) -> Self {
+        let (queues, gauges) = (0..workers.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+                let gauges = Arc::new(Gauges::default());
+                match store {
+                    AggregationStoreKind::HashMap => {
+                        tokio::spawn(run_worker(receiver, gauges.clone(), HashMap::new()));
+                    },
+                    AggregationStoreKind::Sharded => {
+                        tokio::spawn(run_worker(
+                            receiver,
+                            gauges.clone(),
+                            ShardedStore::new(shards),
+                        ));
+                    },
+                }
+                (sender, gauges)
+            })
+            .unzip();
+
+        Self {
+            queues,
+            gauges,
+            hasher: BuildHasherDefault::default(),
+            shards: match store {
+                AggregationStoreKind::HashMap => 1,
+                AggregationStoreKind::Sharded => shards.max(1),
+            },
+        }
+    }
+
+    pub async fn record(
+        &self,
+        key: AggregatedKey,
+        packets: u64,
+        bytes: u64,
+        reversed: bool,
+        observed: Option<SystemTime>,
+        sample_variance: bool,
+    ) -> anyhow::Result<()> {
+        self.queue(&key)?
+            .send(Command::Record {
+                key,
+                packets,
+                bytes,
+                reversed,
+                observed,
+                sample_variance,
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("Aggregation worker stopped."))
+    }
+
+    /// Merges entries into the shards of their keys, e.g. the ones saved by a previous run.
+    pub async fn restore(&self, entries: Entries) -> anyhow::Result<()> {
+        for (key, value) in entries {
+            self.queue(&key)?
+                .send(Command::Merge { key, value })
+                .await
+                .map_err(|_| anyhow::anyhow!("Aggregation worker stopped."))?;
+        }
+
+        Ok(())
+    }
+
+    /// Queue of the shard owning `key`.
+    fn queue(&self, key: &AggregatedKey) -> anyhow::Result<&mpsc::Sender<Command>> {
+        let shard = self.hasher.hash_one(key) % u64::try_from(self.queues.len())?;
+        usize::try_from(shard)
+            .ok()
+            .and_then(|shard| self.queues.get(shard))
+            .ok_or_else(|| anyhow::anyhow!("Aggregation shard {shard} does not exist."))
+    }
+
+    /// Tables of every worker. Each one is taken by its own call of [`Self::take_closed`] or
+    /// [`Self::drain_shard`], so a large cache is written in steps.
+    #[must_use]
+    pub fn shards(&self) -> usize {
+        self.shards
+    }
+
+    /// Takes the entries of the table `shard` of every worker whose bucket of
+    /// `seconds_alignment` is closed at `watermark`.
+    pub async fn take_closed(
+        &self,
+        shard: usize,
+        watermark: u64,
+        seconds_alignment: u64,
+    ) -> anyhow::Result<Entries> {
+        self.collect(|reply| {
+            Command::TakeClosed {
+                shard,
+                watermark,
+                seconds_alignment,
+                reply,
+            }
+        })
+        .await
+    }
+
+    /// Takes every entry of every shard.
+    pub async fn drain(&self, shrink: bool) -> anyhow::Result<Entries> {
+        self.collect(|reply| {
+            Command::Drain {
+                shard: None,
+                shrink,
+                reply,
+            }
+        })
+        .await
+    }
+
+    /// Takes every entry of the table `shard` of every worker.
+    pub async fn drain_shard(&self, shard: usize, shrink: bool) -> anyhow::Result<Entries> {
+        self.collect(|reply| {
+            Command::Drain {
+                shard: Some(shard),
+                shrink,
+                reply,
+            }
+        })
+        .await
+    }
+
+    /// Copies every entry of every shard without taking them.
+    pub async fn entries(&self) -> anyhow::Result<Entries> {
+        self.collect(Command::Copy).await
+    }
+
+    pub async fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        for shard in self.ask(Command::Snapshot).await? {
+            snapshot.entries += shard.entries;
+            snapshot.oldest_bucket = match (snapshot.oldest_bucket, shard.oldest_bucket) {
+                (Some(oldest), Some(shard_oldest)) => Some(oldest.min(shard_oldest)),
+                (oldest, shard_oldest) => oldest.or(shard_oldest),
+            };
+            // `None` orders before any bucket.
+            snapshot.newest_bucket = snapshot.newest_bucket.max(shard.newest_bucket);
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Entries of all shards as of their last processed command, records still queued are not
+    /// counted.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.gauges
+            .iter()
+            .map(|gauges| gauges.entries.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Sum of [`AggregationStore::estimated_memory`] of the shards.
+    #[must_use]
+    pub fn estimated_memory(&self) -> usize {
+        self.gauges
+            .iter()
+            .map(|gauges| gauges.memory.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    async fn collect(
+        &self,
+        command: impl Fn(oneshot::Sender<Entries>) -> Command,
+    ) -> anyhow::Result<Entries> {
+        Ok(self.ask(command).await?.into_iter().flatten().collect())
+    }
+
+    /// Sends the command to every worker before waiting, so the shards work in parallel.
+    async fn ask<T>(
+        &self,
+        command: impl Fn(oneshot::Sender<T>) -> Command,
+    ) -> anyhow::Result<Vec<T>> {
+        let mut replies = Vec::with_capacity(self.queues.len());
+        for queue in &self.queues {
+            let (reply, receiver) = oneshot::channel();
+            queue
+                .send(command(reply))
+                .await
+                .map_err(|_| anyhow::anyhow!("Aggregation worker stopped."))?;
+            replies.push(receiver);
+        }
+
+        let mut results = Vec::with_capacity(replies.len());
+        for receiver in replies {
+            results.push(receiver.await?);
+        }
+
+        Ok(results)
+    }
+}
+
+/// Owns one shard until the queue is closed.
+async fn run_worker(
+    mut commands: mpsc::Receiver<Command>,
+    gauges: Arc<Gauges>,
+    mut store: impl AggregationStore,
+) {
+    while let Some(command) = commands.recv().await {
+        match command {
+            Command::Record {
+                key,
+                packets,
+                bytes,
+                reversed,
+                observed,
+                sample_variance,
+            } => {
+                let data = store.upsert(key);
+                data.record(packets, bytes, reversed);
+                if let Some(observed) = observed {
+                    data.observe(observed);
+                }
+                if sample_variance {
+                    data.sample_bytes(bytes);
+                }
+                gauges.publish(&store);
+            },
+            Command::TakeClosed {
+                shard,
+                watermark,
+                seconds_alignment,
+                reply,
+            } => {
+                let closed = store.drain_closed_shard(shard, watermark, seconds_alignment);
+                gauges.publish(&store);
+                let _ = reply.send(closed);
+            },
+            Command::Drain {
+                shard,
+                shrink,
+                reply,
+            } => {
+                let drained = match shard {
+                    Some(shard) => store.drain_shard(shard, shrink),
+                    None => store.drain_all(shrink),
+                };
+                gauges.publish(&store);
+                let _ = reply.send(drained);
+            },
+            Command::Snapshot(reply) => {
+                let (oldest_bucket, newest_bucket) = store.bucket_range();
+                let _ = reply.send(Snapshot {
+                    entries: store.len(),
+                    oldest_bucket,
+                    newest_bucket,
+                });
+            },
+            Command::Merge { key, value } => {
+                store.upsert(key).merge(&value);
+                gauges.publish(&store);
+            },
+            Command::Copy(reply) => {
+                let _ = reply.send(store.entries());
+            },
+        }
+    }
+}